@@ -0,0 +1,36 @@
+// Builds a model into an erased ROM with the native module and reads it
+// back: `npm run build && npm test`.
+const assert = require('assert')
+const fs = require('fs')
+const os = require('os')
+const path = require('path')
+const test = require('node:test')
+
+const kfs = require('..')
+
+test('build, list and extract', () => {
+  const model = fs.mkdtempSync(path.join(os.tmpdir(), 'regenkfs-node-'))
+  try {
+    fs.mkdirSync(path.join(model, 'etc'))
+    fs.writeFileSync(path.join(model, 'etc', 'motd'), 'hello\n')
+    const blank = Buffer.alloc(32 * 0x4000, 0xff)
+
+    const rom = kfs.build(blank, model, { verify: true })
+    assert.strictEqual(rom.length, blank.length)
+    assert.ok(blank.every((byte) => byte === 0xff), 'the input ROM was changed')
+
+    const entries = kfs.list(rom)
+    assert.deepStrictEqual(
+      entries.map((entry) => [entry.path, entry.kind, entry.size]),
+      [
+        ['/etc', 'directory', undefined],
+        ['/etc/motd', 'file', 6],
+      ]
+    )
+    assert.strictEqual(kfs.extract(rom, 'etc/motd').toString(), 'hello\n')
+    assert.throws(() => kfs.extract(rom, '/etc'), /is not a file/)
+    assert.throws(() => kfs.extract(rom, '/missing'), /is not in the filesystem/)
+  } finally {
+    fs.rmSync(model, { recursive: true })
+  }
+})
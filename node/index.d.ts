@@ -0,0 +1,33 @@
+/* tslint:disable */
+/* eslint-disable */
+
+/* auto-generated by NAPI-RS */
+
+export interface BuildOptions {
+  /** Keep a copy of the FAT in the pages below it. */
+  mirrorFat?: boolean
+  /** Read the filesystem back and compare it against the model. */
+  verify?: boolean
+}
+
+/** An entry of the filesystem, as `regenkfs ls -l` shows it. */
+export interface Entry {
+  path: string
+  /** `"file"`, `"directory"` or `"symlink"`. */
+  kind: string
+  flags?: number
+  size?: number
+  target?: string
+}
+
+/**
+ * Writes the model directory (or cpio archive) at `model` into a copy
+ * of `rom` and returns the new image.
+ */
+export function build(rom: Buffer, model: string, options?: BuildOptions): Buffer
+
+/** Every entry of the filesystem in `rom`, in FAT order. */
+export function list(rom: Buffer): Array<Entry>
+
+/** The contents of the file at `path` in the filesystem in `rom`. */
+export function extract(rom: Buffer, path: string): Buffer
@@ -0,0 +1,33 @@
+// Loads the native module `npm run build` (`napi build --platform`) leaves
+// next to this file, named for the platform it was built on.
+const { existsSync } = require('fs')
+const { join } = require('path')
+
+function abi() {
+  switch (process.platform) {
+    case 'linux': {
+      const report = process.report && process.report.getReport()
+      const glibc = report && report.header && report.header.glibcVersionRuntime
+      return glibc ? '-gnu' : '-musl'
+    }
+    case 'win32':
+      return '-msvc'
+    default:
+      return ''
+  }
+}
+
+const names = [`regenkfs.${process.platform}-${process.arch}${abi()}.node`, 'regenkfs.node']
+const found = names.map((name) => join(__dirname, name)).find((path) => existsSync(path))
+if (!found) {
+  throw new Error(
+    `No native regenkfs module for ${process.platform}-${process.arch}; ` +
+      `run \`npm run build\` in ${__dirname} first (looked for ${names.join(', ')}).`
+  )
+}
+
+const { build, list, extract } = require(found)
+
+module.exports.build = build
+module.exports.list = list
+module.exports.extract = extract
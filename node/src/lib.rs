@@ -0,0 +1,104 @@
+//! Node.js bindings: build a filesystem into a ROM image, list it and
+//! read files back out, all on `Buffer`s so no temporary files or
+//! platform-specific binaries are needed.
+#[macro_use]
+extern crate napi_derive;
+
+use std::path::Path;
+
+use napi::bindgen_prelude::Buffer;
+use napi::{Error, Result};
+use regenkfs::fat::Entry as FatEntry;
+use regenkfs::{Context, Image, Options};
+
+fn to_js(err: std::io::Error) -> Error {
+    Error::from_reason(err.to_string())
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct BuildOptions {
+    /// Keep a copy of the FAT in the pages below it.
+    pub mirror_fat: Option<bool>,
+    /// Read the filesystem back and compare it against the model.
+    pub verify: Option<bool>,
+}
+
+/// An entry of the filesystem, as `regenkfs ls -l` shows it.
+#[napi(object)]
+pub struct Entry {
+    pub path: String,
+    /// `"file"`, `"directory"` or `"symlink"`.
+    pub kind: String,
+    pub flags: Option<u32>,
+    pub size: Option<u32>,
+    pub target: Option<String>,
+}
+
+/// Writes the model directory (or cpio archive) at `model` into a copy
+/// of `rom` and returns the new image.
+#[napi]
+pub fn build(rom: Buffer, model: String, options: Option<BuildOptions>) -> Result<Buffer> {
+    let options = options.unwrap_or_default();
+    let options = Options {
+        quiet: true,
+        mirror_fat: options.mirror_fat.unwrap_or(false),
+        verify: options.verify.unwrap_or(false),
+        ..Options::default()
+    };
+    let mut context =
+        Context::in_memory(rom.to_vec(), Path::new(&model), options).map_err(to_js)?;
+    context.run().map_err(to_js)?;
+    let rom = context.into_output().map_err(to_js)?.into_inner();
+    Ok(rom.into())
+}
+
+/// Every entry of the filesystem in `rom`, in FAT order.
+#[napi]
+pub fn list(rom: Buffer) -> Result<Vec<Entry>> {
+    let image = Image::open(&rom).map_err(to_js)?;
+    let nodes = image.nodes().map_err(to_js)?;
+    Ok(nodes
+        .into_iter()
+        .map(|node| match node.record.entry {
+            FatEntry::File { flags, length, .. } => Entry {
+                path: node.path,
+                kind: "file".to_string(),
+                flags: Some(flags.into()),
+                size: Some(length),
+                target: None,
+            },
+            FatEntry::Directory { flags, .. } => Entry {
+                path: node.path,
+                kind: "directory".to_string(),
+                flags: Some(flags.into()),
+                size: None,
+                target: None,
+            },
+            FatEntry::Symlink { target, .. } => Entry {
+                path: node.path,
+                kind: "symlink".to_string(),
+                flags: None,
+                size: None,
+                target: Some(target),
+            },
+        })
+        .collect())
+}
+
+/// The contents of the file at `path` in the filesystem in `rom`.
+#[napi]
+pub fn extract(rom: Buffer, path: String) -> Result<Buffer> {
+    let image = Image::open(&rom).map_err(to_js)?;
+    let path = regenkfs::extract::normalize(&path);
+    let node = image
+        .nodes()
+        .map_err(to_js)?
+        .into_iter()
+        .find(|node| node.path == path)
+        .ok_or_else(|| Error::from_reason(format!("{} is not in the filesystem.", path)))?;
+    match node.record.entry {
+        FatEntry::File { .. } => Ok(image.read_file(&node.record.entry).map_err(to_js)?.into()),
+        _ => Err(Error::from_reason(format!("{} is not a file.", path))),
+    }
+}
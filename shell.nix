@@ -13,7 +13,7 @@ let
   # rust = pkgs.rustChannels.nightly.rust.override {
   #   extensions = [ "rust-src" ];
   # };
-  rust = pkgs.rustChannels.nightly.rust.override {
+  rust = pkgs.rustChannels.stable.rust.override {
     extensions = [ "rust-src" "rls-preview"
                    "rust-analysis" "rustfmt-preview" ];
   };
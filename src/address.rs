@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io::{Error, Write};
+
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::*;
+
+/// A run of a file's data within one flash page. `start` and `end` are
+/// offsets into the page, and `end` is the file's last byte in the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub page: u8,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:04x}..{:02x}:{:04x}",
+            self.page, self.start, self.page, self.end
+        )
+    }
+}
+
+/// Where the data of a file lives, merging sections that follow one
+/// another in the same page into a single span.
+pub fn spans(image: &Image, section_id: u16, length: u32) -> Result<Vec<Span>, Error> {
+    let mut spans: Vec<Span> = Vec::new();
+    for (section_id, len) in image.chain(section_id, length)? {
+        let [index, page] = section_id.to_le_bytes();
+        let start = u16::from(index) * image.geometry.block_size();
+        let end = start + len - 1;
+        match spans.last_mut() {
+            Some(last) if last.page == page && last.end + 1 == start => last.end = end,
+            _ => spans.push(Span { page, start, end }),
+        }
+    }
+    Ok(spans)
+}
+
+/// Writes a `<path> <span>...` line for every file stored in the image,
+/// in FAT order. Empty files have no spans.
+pub fn write_addresses<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    for node in image.nodes()? {
+        if let Entry::File {
+            length, section_id, ..
+        } = node.record.entry
+        {
+            write!(out, "{}", node.path)?;
+            for span in spans(image, section_id, length)? {
+                write!(out, " {}", span)?;
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a symbol file labelling the FAT entry (`fat:<path>`, at its id
+/// byte, where the kernel starts reading it backwards) of every entry and
+/// each data block (`<path>`, then `<path>+1`, ...) of every
+/// file. Each line is a banked `pp:oooo label` address, which is what
+/// Z80 debuggers load for paged memory, so flash can be labelled while
+/// stepping through the kernel.
+pub fn write_symbols<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    writeln!(out, "; pp:oooo label")?;
+    for node in image.nodes()? {
+        let top = node.record.addr + u32::from(node.record.length) - 1;
+        let (page, offset) = div_rem(top, u32::from(image.geometry.page_length()));
+        writeln!(out, "{:02x}:{:04x} fat:{}", page, offset, node.path)?;
+        if let Entry::File {
+            length, section_id, ..
+        } = node.record.entry
+        {
+            for (i, (section_id, _)) in image.chain(section_id, length)?.iter().enumerate() {
+                let [index, page] = section_id.to_le_bytes();
+                let offset = u16::from(index) * image.geometry.block_size();
+                match i {
+                    0 => writeln!(out, "{:02x}:{:04x} {}", page, offset, node.path)?,
+                    _ => writeln!(out, "{:02x}:{:04x} {}+{}", page, offset, node.path, i)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::page_map::PageMap;
+use crate::section::SectionId;
+
+/// Chooses where each block of data goes.
+pub trait Allocator {
+    /// A free section in `map` for the next block to be written, or
+    /// `None` when the data pages are full. Until the caller marks it
+    /// allocated in `map`, asking again gives the same section.
+    fn next_section(&mut self, map: &PageMap) -> Option<SectionId>;
+}
+
+/// Lays sections out one after the other, as genkfs does: every block
+/// goes right after the one before it, whichever file it belongs to,
+/// skipping sections already in use, such as those `--cache` keeps.
+#[derive(Debug, Clone)]
+pub struct Sequential {
+    cursor: SectionId,
+}
+
+impl Sequential {
+    /// Starts at the first section of `page`.
+    pub fn new(page: u8) -> Sequential {
+        Sequential {
+            cursor: SectionId::first(page),
+        }
+    }
+}
+
+impl Allocator for Sequential {
+    fn next_section(&mut self, map: &PageMap) -> Option<SectionId> {
+        while map.is_used(self.cursor.into()) {
+            if !map.pages().contains(&self.cursor.page()) {
+                return None;
+            }
+            self.cursor = self.cursor.next_within(map.max_index())?;
+        }
+        Some(self.cursor)
+    }
+}
+
+/// The order files' data is placed in. FAT order, and so the order of
+/// directory listings, is the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// In FAT order, as genkfs does.
+    #[default]
+    Sequential,
+    /// Biggest files first, so they get contiguous runs of sections
+    /// before smaller files break up the free space.
+    LargestFirst,
+}
+
+impl Placement {
+    /// Sorts `files` into the order their data is placed in, given each
+    /// one's length and whether it's pinned to a page by `key`. Pinned
+    /// files go first, so nothing else takes their pages. The sort is
+    /// stable, so files are otherwise kept in FAT order.
+    pub fn order<T>(self, files: &mut [T], key: impl Fn(&T) -> (bool, u32)) {
+        files.sort_by_key(|file| {
+            let (pinned, length) = key(file);
+            let length = match self {
+                Placement::Sequential => 0,
+                Placement::LargestFirst => length,
+            };
+            (!pinned, Reverse(length))
+        });
+    }
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Placement, String> {
+        match s {
+            "sequential" => Ok(Placement::Sequential),
+            "largest-first" => Ok(Placement::LargestFirst),
+            _ => Err(format!(
+                "Unknown placement {} (expected sequential or largest-first).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Placement::Sequential => "sequential",
+            Placement::LargestFirst => "largest-first",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_skips_sections_in_use() {
+        let mut map = PageMap::new(0x04, 0x05);
+        for index in &[1, 2, 4] {
+            map.allocate(SectionId::new(0x04, *index).unwrap()).unwrap();
+        }
+        let mut allocator = Sequential::new(0x04);
+        let next = allocator.next_section(&map).unwrap();
+        assert_eq!(next, SectionId::new(0x04, 3).unwrap());
+        assert_eq!(allocator.next_section(&map), Some(next));
+        map.allocate(next).unwrap();
+        assert_eq!(
+            allocator.next_section(&map),
+            SectionId::new(0x04, 5),
+            "04:04 is in use"
+        );
+    }
+
+    #[test]
+    fn sequential_placement_keeps_fat_order() {
+        let mut files = vec![
+            ("a", false, 10),
+            ("b", false, 900),
+            ("c", true, 5),
+            ("d", false, 0),
+        ];
+        Placement::Sequential.order(&mut files, |&(_, pinned, length)| (pinned, length));
+        let names: Vec<&str> = files.iter().map(|file| file.0).collect();
+        assert_eq!(names, ["c", "a", "b", "d"], "only the pinned file moves");
+    }
+
+    #[test]
+    fn largest_first_places_big_files_first() {
+        let mut files = vec![
+            ("a", false, 10),
+            ("b", false, 900),
+            ("c", true, 5),
+            ("d", false, 0),
+            ("e", false, 900),
+            ("f", true, 50),
+            ("g", false, u32::MAX),
+        ];
+        Placement::LargestFirst.order(&mut files, |&(_, pinned, length)| (pinned, length));
+        let names: Vec<&str> = files.iter().map(|file| file.0).collect();
+        // Pinned files are still first, and ties keep their FAT order.
+        assert_eq!(names, ["f", "c", "g", "b", "e", "a", "d"]);
+    }
+
+    #[test]
+    fn placements_parse() {
+        for placement in &[Placement::Sequential, Placement::LargestFirst] {
+            assert_eq!(placement.to_string().parse(), Ok(*placement));
+        }
+        assert_eq!(
+            "biggest".parse::<Placement>(),
+            Err("Unknown placement biggest (expected sequential or largest-first).".to_string())
+        );
+    }
+
+    #[test]
+    fn sequential_runs_out_after_the_last_page() {
+        let mut map = PageMap::new(0x04, 0x04);
+        let mut allocator = Sequential::new(0x04);
+        while let Some(id) = allocator.next_section(&map) {
+            map.allocate(id).unwrap();
+        }
+        assert_eq!(map.free_blocks(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::context::{Context, Options};
+use crate::stage;
+
+/// Placeholder in output paths replaced by each target's file stem.
+pub const ROM_PLACEHOLDER: &str = "{rom}";
+
+fn for_target(path: &Option<PathBuf>, rom: &Path) -> Option<PathBuf> {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    path.as_ref()
+        .map(|p| PathBuf::from(p.to_string_lossy().replace(ROM_PLACEHOLDER, &stem)))
+}
+
+impl Options {
+    /// Options for building one of several targets, with `{rom}` in the
+    /// output paths replaced by the target's file stem.
+    pub fn for_target(&self, rom: &Path) -> Options {
+        Options {
+            output: for_target(&self.output, rom),
+            checksums: for_target(&self.checksums, rom),
+            manifest: for_target(&self.manifest, rom),
+            report: for_target(&self.report, rom),
+            trace_writes: for_target(&self.trace_writes, rom),
+            record_writes: for_target(&self.record_writes, rom),
+            addresses: for_target(&self.addresses, rom),
+            symbols: for_target(&self.symbols, rom),
+            cache: for_target(&self.cache, rom),
+            ..self.clone()
+        }
+    }
+
+    // Every output path given.
+    fn outputs(&self) -> Vec<&Path> {
+        [
+            &self.output,
+            &self.checksums,
+            &self.manifest,
+            &self.report,
+            &self.trace_writes,
+            &self.record_writes,
+            &self.addresses,
+            &self.symbols,
+            &self.cache,
+        ]
+        .iter()
+        .filter_map(|p| p.as_deref())
+        .collect()
+    }
+
+    // Output paths that would be shared by every target of a batch.
+    fn shared_outputs(&self) -> Vec<&Path> {
+        self.outputs()
+            .into_iter()
+            .filter(|p| !p.to_string_lossy().contains(ROM_PLACEHOLDER))
+            .collect()
+    }
+}
+
+// Fails if two targets would write the same output, as ROMs of the same
+// file stem in different directories do.
+fn check_collisions(targets: &[PathBuf], options: &Options) -> Result<(), Error> {
+    let mut written: HashMap<PathBuf, &Path> = HashMap::new();
+    for rom in targets {
+        for path in options.for_target(rom).outputs() {
+            if let Some(other) = written.insert(path.to_path_buf(), rom) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} and {} would both write {}, as {} is only the file stem; rename one of them.",
+                        other.display(),
+                        rom.display(),
+                        path.display(),
+                        ROM_PLACEHOLDER
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The outcome of building one target.
+pub type Outcome = (PathBuf, Result<(), Error>);
+
+/// Writes `model` into every ROM of `targets` concurrently, returning
+/// each target's outcome in the order given.
+pub fn build_all(
+    targets: &[PathBuf],
+    model: &Path,
+    options: &Options,
+) -> Result<Vec<Outcome>, Error> {
+    if targets.len() > 1 {
+        if let Some(path) = options.shared_outputs().first() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} would be overwritten by every target; add {} to its name.",
+                    path.display(),
+                    ROM_PLACEHOLDER
+                ),
+            ));
+        }
+        check_collisions(targets, options)?;
+    }
+    let jobs = stage::thread_count(options.threads).min(targets.len());
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(Vec::with_capacity(targets.len()));
+    // Each image is independent, so workers just take the next target
+    // until none are left. Progress is silenced since it would
+    // interleave; callers report the outcomes instead.
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let rom = match targets.get(i) {
+                    Some(rom) => rom,
+                    None => break,
+                };
+                // Workers read their models on one thread each, so there
+                // are never more threads than --threads.
+                let options = Options {
+                    quiet: true,
+                    threads: NonZeroUsize::new(1),
+                    ..options.for_target(rom)
+                };
+                let result = Context::with_options(rom, model, options).and_then(|mut c| c.run());
+                outcomes.lock().unwrap().push((i, (rom.clone(), result)));
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(i, _)| *i);
+    Ok(outcomes.into_iter().map(|(_, outcome)| outcome).collect())
+}
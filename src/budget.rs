@@ -0,0 +1,238 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::extract::normalize;
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::scan::AllocationMap;
+use crate::*;
+
+/// What a budget limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The total length of the files at or below a path.
+    Path(String),
+    /// Bytes taken up by FAT entries.
+    Fat,
+    /// Bytes taken up by data sections, counting whole blocks.
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budget {
+    pub target: Target,
+    pub limit: u64,
+}
+
+/// Parses a size in bytes, optionally suffixed with K/KiB, M/MiB,
+/// blocks or pages.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let unit = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1024,
+        "M" | "MiB" => 1024 * 1024,
+        "block" | "blocks" => u64::from(BLOCK_SIZE),
+        "page" | "pages" => u64::from(PAGE_LENGTH),
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses a budget file. Each line holds a target, either an absolute
+/// path, `fat` or `data`, followed by its limit:
+///
+/// ```text
+/// # /var must stay under 32 KiB
+/// /var 32K
+/// fat 2 pages
+/// ```
+pub fn parse_budgets(text: &str) -> Result<Vec<Budget>, Error> {
+    let mut budgets = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed budget line: {}", line),
+            )
+        };
+        let (target, size) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let target = match target {
+            "fat" => Target::Fat,
+            "data" => Target::Data,
+            path if path.starts_with('/') => Target::Path(normalize(path)),
+            _ => return Err(invalid()),
+        };
+        let limit = parse_size(size.trim()).ok_or_else(invalid)?;
+        budgets.push(Budget { target, limit });
+    }
+    Ok(budgets)
+}
+
+pub fn read_budgets(path: &Path) -> Result<Vec<Budget>, Error> {
+    parse_budgets(&fs::read_to_string(path)?)
+}
+
+/// How many bytes of `target` the image uses.
+pub fn usage(image: &Image, map: &AllocationMap, target: &Target) -> Result<u64, Error> {
+    Ok(match target {
+        Target::Path(path) => image
+            .nodes()?
+            .iter()
+            .filter(|node| {
+                path.is_empty()
+                    || node.path == *path
+                    || (node.path.starts_with(path.as_str())
+                        && node.path[path.len()..].starts_with('/'))
+            })
+            .map(|node| match node.record.entry {
+                Entry::File { length, .. } => u64::from(length),
+                _ => 0,
+            })
+            .sum(),
+        Target::Fat => {
+            u64::from(image.geometry.fat_end(image.fat_start)) - u64::from(map.fat_ptr())
+        }
+        Target::Data => map.used_blocks() as u64 * u64::from(image.geometry.block_size()),
+    })
+}
+
+/// A message for every budget the image exceeds.
+pub fn check(image: &Image, map: &AllocationMap, budgets: &[Budget]) -> Result<Vec<String>, Error> {
+    let mut exceeded = Vec::new();
+    for budget in budgets {
+        let used = usage(image, map, &budget.target)?;
+        if used > budget.limit {
+            let name = match &budget.target {
+                Target::Path(path) if path.is_empty() => "/",
+                Target::Path(path) => path,
+                Target::Fat => "The FAT",
+                Target::Data => "File data",
+            };
+            exceeded.push(format!(
+                "{} takes {} bytes, over its budget of {}.",
+                name, used, budget.limit
+            ));
+        }
+    }
+    Ok(exceeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{insert, Tree};
+    use crate::source::Memory;
+    use crate::{Context, Options};
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn rom() -> Vec<u8> {
+        let mut tree = BTreeMap::new();
+        insert(&mut tree, "var/log", Tree::File(vec![1; 1000])).unwrap();
+        insert(&mut tree, "var/tmp/a", Tree::File(vec![2; 300])).unwrap();
+        insert(&mut tree, "various", Tree::File(vec![3; 50])).unwrap();
+        let mut context = Context::with_source(
+            PathBuf::from("<memory>"),
+            Box::new(Memory::new("<memory>", tree)),
+            Cursor::new(vec![0xFF; 32 * usize::from(PAGE_LENGTH)]),
+            Options {
+                quiet: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        context.run().unwrap();
+        context.into_output().unwrap().into_inner()
+    }
+
+    #[test]
+    fn sizes_take_units() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("100B"), Some(100));
+        assert_eq!(parse_size("32K"), Some(32 * 1024));
+        assert_eq!(parse_size("2 MiB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("3 blocks"), Some(3 * u64::from(BLOCK_SIZE)));
+        assert_eq!(parse_size("1 page"), Some(u64::from(PAGE_LENGTH)));
+        assert_eq!(parse_size("1G"), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("18446744073709551615K"), None);
+    }
+
+    #[test]
+    fn budget_files_parse() {
+        let budgets = parse_budgets("# Limits\n\n/var/ 32K\n  fat 2 pages\ndata\t100\n").unwrap();
+        assert_eq!(
+            budgets,
+            [
+                Budget {
+                    target: Target::Path("/var".to_string()),
+                    limit: 32 * 1024,
+                },
+                Budget {
+                    target: Target::Fat,
+                    limit: 2 * u64::from(PAGE_LENGTH),
+                },
+                Budget {
+                    target: Target::Data,
+                    limit: 100,
+                },
+            ]
+        );
+        assert!(parse_budgets("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for line in &["/var", "var 1K", "/var 1 parsec", "fat", "/var K"] {
+            let err = parse_budgets(&format!("fat 1K\n{}\n", line)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), format!("Malformed budget line: {}", line));
+        }
+    }
+
+    #[test]
+    fn paths_count_the_files_below_them() {
+        let rom = rom();
+        let image = Image::open(&rom).unwrap();
+        let map = AllocationMap::scan_with(&rom, image.geometry, image.dat_start, image.fat_start)
+            .unwrap();
+        let usage = |path: &str| usage(&image, &map, &Target::Path(path.to_string())).unwrap();
+        assert_eq!(usage("/var"), 1300, "/various isn't below /var");
+        assert_eq!(usage("/var/log"), 1000);
+        assert_eq!(usage(""), 1350);
+        assert_eq!(usage("/missing"), 0);
+        let data = super::usage(&image, &map, &Target::Data).unwrap();
+        // Four blocks for /var/log, two for /var/tmp/a and one for /various.
+        assert_eq!(data, 7 * u64::from(BLOCK_SIZE));
+        assert!(super::usage(&image, &map, &Target::Fat).unwrap() > 0);
+    }
+
+    #[test]
+    fn only_exceeded_budgets_are_reported() {
+        let rom = rom();
+        let image = Image::open(&rom).unwrap();
+        let map = AllocationMap::scan_with(&rom, image.geometry, image.dat_start, image.fat_start)
+            .unwrap();
+        let budgets =
+            parse_budgets("/var 1300\n/var/log 999\n/ 1K\ndata 1 block\nfat 1M\n").unwrap();
+        assert_eq!(
+            check(&image, &map, &budgets).unwrap(),
+            [
+                "/var/log takes 1000 bytes, over its budget of 999.",
+                "/ takes 1350 bytes, over its budget of 1024.",
+                "File data takes 1792 bytes, over its budget of 256.",
+            ]
+        );
+    }
+}
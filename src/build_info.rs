@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::model::Hidden;
+use crate::sha256::{hex, sha256, Sha256};
+use crate::source::{self, KfsSource, Kind, Member};
+
+/// Where `--build-info` puts the file saying how an image was built.
+pub const PATH: &str = "/etc/build-info";
+
+/// When the build happened: `SOURCE_DATE_EPOCH`, if it's set, so
+/// reproducible builds stay reproducible, or else now. Seconds since
+/// the Unix epoch.
+pub fn build_time() -> Result<u64, Error> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Error: SOURCE_DATE_EPOCH={} isn't a number of seconds.",
+                    epoch
+                ),
+            )
+        }),
+        Err(_) => Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs())),
+    }
+}
+
+// `time` as an ISO 8601 date and time in UTC.
+fn utc(time: u64) -> String {
+    let (days, seconds) = (time / 86400, time % 86400);
+    // Howard Hinnant's civil_from_days, for days since 1970-01-01.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// SHA-256 of the model: every path in it, in order, each with its
+/// kind and the data of a file or the target of a symlink, so two models
+/// hash the same exactly when they'd make the same files.
+pub fn model_sha256(source: &dyn KfsSource) -> Result<[u8; 32], Error> {
+    let mut members = BTreeMap::new();
+    source::walk(source, &mut |path, member| {
+        let described = match member.kind {
+            Kind::File { .. } => {
+                let mut data = Vec::new();
+                source.open(path)?.read_to_end(&mut data)?;
+                format!("file {}", hex(&sha256(&data)))
+            }
+            Kind::Directory => "directory".to_string(),
+            Kind::Symlink => format!("symlink {}", source.read_link(path)?),
+            Kind::Other => "other".to_string(),
+        };
+        members.insert(path.to_string(), described);
+        Ok(true)
+    })?;
+    let mut model = Sha256::new();
+    for (path, described) in &members {
+        model.update(format!("{} {}\n", path, described).as_bytes());
+    }
+    Ok(model.finish())
+}
+
+/// The text of the build info file, one `key: value` per line:
+///
+/// ```text
+/// tool: regenkfs 0.1.0
+/// built: 2026-10-14T09:30:00Z
+/// model-sha256: 5b41362bc82b7f3d56edc5a306db22105707d01ff4819e26faef9724a2d406c9
+/// ```
+pub fn contents(time: u64, model_sha256: &[u8; 32]) -> Vec<u8> {
+    format!(
+        "tool: regenkfs {}\nbuilt: {}\nmodel-sha256: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        utc(time),
+        hex(model_sha256)
+    )
+    .into_bytes()
+}
+
+/// A model with the build info file added at `PATH`, making `/etc` if
+/// the model has none, and taking the place of any build info the model
+/// already had.
+pub struct WithBuildInfo {
+    inner: Rc<dyn KfsSource>,
+    info: Vec<u8>,
+    // Whether the model has its own `/etc`.
+    etc: bool,
+    replaces: bool,
+}
+
+impl WithBuildInfo {
+    pub fn new(inner: Rc<dyn KfsSource>, info: Vec<u8>) -> Result<WithBuildInfo, Error> {
+        let etc = match inner
+            .list("")?
+            .into_iter()
+            .find(|member| member.name == "etc")
+        {
+            None => false,
+            Some(Member {
+                kind: Kind::Directory,
+                ..
+            }) => true,
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Error: --build-info needs {} to be a directory.",
+                        inner.display("/etc")
+                    ),
+                ))
+            }
+        };
+        let replaces = etc
+            && inner
+                .list("/etc")?
+                .iter()
+                .any(|member| member.name == "build-info");
+        Ok(WithBuildInfo {
+            inner,
+            info,
+            etc,
+            replaces,
+        })
+    }
+
+    /// Whether the model had a `/etc/build-info` of its own.
+    pub fn replaces(&self) -> bool {
+        self.replaces
+    }
+}
+
+impl KfsSource for WithBuildInfo {
+    fn list(&self, path: &str) -> Result<Vec<Member>, Error> {
+        match path {
+            "" => {
+                let mut members = self.inner.list("")?;
+                if !self.etc {
+                    members.push(Member {
+                        name: "etc".into(),
+                        kind: Kind::Directory,
+                    });
+                }
+                Ok(members)
+            }
+            "/etc" => {
+                let mut members = if self.etc {
+                    self.inner.list("/etc")?
+                } else {
+                    Vec::new()
+                };
+                members.retain(|member| member.name != "build-info");
+                members.push(Member {
+                    name: "build-info".into(),
+                    kind: Kind::File {
+                        length: self.info.len() as u64,
+                    },
+                });
+                Ok(members)
+            }
+            _ => self.inner.list(path),
+        }
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn Read>, Error> {
+        if path == PATH {
+            return Ok(Box::new(Cursor::new(self.info.clone())));
+        }
+        self.inner.open(path)
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, Error> {
+        self.inner.read_link(path)
+    }
+
+    fn host_path(&self, path: &str) -> Option<PathBuf> {
+        match path {
+            PATH => None,
+            _ => self.inner.host_path(path),
+        }
+    }
+
+    fn display(&self, path: &str) -> String {
+        if path == PATH || (path == "/etc" && !self.etc) {
+            return format!("{} (--build-info)", path);
+        }
+        self.inner.display(path)
+    }
+
+    fn mtime(&self, path: &str) -> Result<Option<(u64, u32)>, Error> {
+        if path == PATH {
+            return Ok(None);
+        }
+        self.inner.mtime(path)
+    }
+
+    fn real_path(&self, path: &str) -> Result<Option<PathBuf>, Error> {
+        if path == "/etc" && !self.etc {
+            return Ok(None);
+        }
+        self.inner.real_path(path)
+    }
+
+    fn has_content(&self, path: &str, hidden: Hidden) -> Result<bool, Error> {
+        match path {
+            "" | "/etc" => Ok(true),
+            _ => self.inner.has_content(path, hidden),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::sha256::hex;
+
+/// Where one model file was stored by a previous build, and what it
+/// looked like then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Modification time as seconds and nanoseconds since the epoch, or
+    /// `None` for files that don't have one, like those of a cpio model.
+    pub mtime: Option<(u64, u32)>,
+    pub size: u32,
+    pub sha256: [u8; 32],
+    /// The file's section chain, in order.
+    pub sections: Vec<u16>,
+}
+
+/// What a build wrote, so the next build into the same ROM can keep the
+/// data of files that haven't changed instead of writing it again.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Cache {
+    /// The layout and options that decide where data goes. A build with
+    /// a different key can't reuse anything.
+    pub key: String,
+    /// CRC-32 of the data pages the build left behind, so the cache is
+    /// only trusted for the ROM it describes.
+    pub crc: u32,
+    /// Records by path in the filesystem.
+    pub files: BTreeMap<String, Record>,
+}
+
+/// The modification time of `path` in the form `Record::mtime` keeps.
+pub fn mtime(path: &Path) -> Result<Option<(u64, u32)>, Error> {
+    let modified = fs::metadata(path)?.modified().ok();
+    Ok(modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| (since.as_secs(), since.subsec_nanos())))
+}
+
+/// Writes a cache in the format read by `read_cache`: a line per file of
+/// `<mtime> <size> <sha256> <sections> <path>`, where a missing mtime or
+/// an empty chain is `-`.
+pub fn write_cache<W: Write>(out: &mut W, cache: &Cache) -> Result<(), Error> {
+    writeln!(out, "# regenkfs build cache")?;
+    writeln!(out, "key {}", cache.key)?;
+    writeln!(out, "crc {:08x}", cache.crc)?;
+    for (path, record) in &cache.files {
+        let mtime = match record.mtime {
+            Some((secs, nanos)) => format!("{}.{:09}", secs, nanos),
+            None => "-".to_string(),
+        };
+        let sections = if record.sections.is_empty() {
+            "-".to_string()
+        } else {
+            let ids: Vec<String> = record
+                .sections
+                .iter()
+                .map(|id| format!("{:04x}", id))
+                .collect();
+            ids.join(",")
+        };
+        writeln!(
+            out,
+            "file {} {} {} {} {}",
+            mtime,
+            record.size,
+            hex(&record.sha256),
+            sections,
+            path
+        )?;
+    }
+    Ok(())
+}
+
+pub fn read_cache<R: BufRead>(input: R) -> Result<Cache, Error> {
+    let invalid = |line: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Malformed cache line: {}", line),
+        )
+    };
+    let mut cache = Cache::default();
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some(("key", key)) => cache.key = key.to_string(),
+            Some(("crc", crc)) => {
+                cache.crc = u32::from_str_radix(crc, 16).map_err(|_| invalid(&line))?
+            }
+            Some(("file", rest)) => {
+                let (path, record) = parse_record(rest).ok_or_else(|| invalid(&line))?;
+                cache.files.insert(path, record);
+            }
+            _ => return Err(invalid(&line)),
+        }
+    }
+    Ok(cache)
+}
+
+// The fields of a `file` line after the keyword. The path comes last so
+// that it may hold spaces.
+fn parse_record(line: &str) -> Option<(String, Record)> {
+    let mut fields = line.splitn(5, ' ');
+    let mtime = match fields.next()? {
+        "-" => None,
+        mtime => {
+            let (secs, nanos) = mtime.split_once('.')?;
+            Some((secs.parse().ok()?, nanos.parse().ok()?))
+        }
+    };
+    let size = fields.next()?.parse().ok()?;
+    let digest = fields.next()?;
+    if digest.len() != 64 {
+        return None;
+    }
+    let mut sha256 = [0; 32];
+    for (i, byte) in sha256.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(digest.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    let sections = match fields.next()? {
+        "-" => Vec::new(),
+        ids => ids
+            .split(',')
+            .map(|id| u16::from_str_radix(id, 16).ok())
+            .collect::<Option<_>>()?,
+    };
+    let path = fields.next()?.to_string();
+    let record = Record {
+        mtime,
+        size,
+        sha256,
+        sections,
+    };
+    Some((path, record))
+}
+
+/// Reads the cache at `path`, or returns `None` if there isn't one yet.
+pub fn load_cache(path: &Path) -> Result<Option<Cache>, Error> {
+    match File::open(path) {
+        Ok(file) => read_cache(BufReader::new(file)).map(Some),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Cache {
+        let mut files = BTreeMap::new();
+        files.insert(
+            "/bin/my prog".to_string(),
+            Record {
+                mtime: Some((1_700_000_000, 5)),
+                size: 300,
+                sha256: [0xAB; 32],
+                sections: vec![0x0401, 0x0402],
+            },
+        );
+        files.insert(
+            "/empty".to_string(),
+            Record {
+                mtime: None,
+                size: 0,
+                sha256: [0; 32],
+                sections: Vec::new(),
+            },
+        );
+        Cache {
+            key: "ti84p 04 1f".to_string(),
+            crc: 0xDEADBEEF,
+            files,
+        }
+    }
+
+    #[test]
+    fn caches_round_trip() {
+        let mut written = Vec::new();
+        write_cache(&mut written, &cache()).unwrap();
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.contains("file 1700000000.000000005 300 abab"));
+        assert!(text.contains(" 0401,0402 /bin/my prog\n"));
+        assert!(text.contains("file - 0 "));
+        assert_eq!(read_cache(&written[..]).unwrap(), cache());
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        let digest = "ab".repeat(32);
+        for line in &[
+            "crc xyz".to_string(),
+            "size 12".to_string(),
+            format!("file - 12 {} 0401", digest),
+            format!("file - 12 {} 0401 ", &digest[2..]),
+            format!("file 12 12 {} 0401 /a", digest),
+            format!("file - twelve {} 0401 /a", digest),
+            format!("file - 12 {} 04zz /a", digest),
+        ] {
+            let err = read_cache(line.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", line);
+            assert!(err.to_string().contains("Malformed cache line"));
+        }
+        assert_eq!(read_cache(&b"# comment\n\n"[..]).unwrap(), Cache::default());
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{BufRead, Error, ErrorKind, Write};
+
+use crate::*;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3), as used by zip and gzip.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+/// The CRC-32 of data whose start had `crc`, once `bytes` follow it.
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, &b| {
+        CRC32_TABLE[usize::from(crc as u8 ^ b)] ^ (crc >> 8)
+    })
+}
+
+/// Checksums each of `pages` of a ROM image.
+pub fn page_sums(
+    rom: &[u8],
+    geometry: Geometry,
+    pages: std::ops::RangeInclusive<u8>,
+) -> Result<Vec<(u8, u32)>, Error> {
+    pages
+        .map(|page| {
+            let start = geometry.page_offset(page) as usize;
+            rom.get(start..start + usize::from(geometry.page_length()))
+                .map(|bytes| (page, crc32(bytes)))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Page {:02x} is past the end of the ROM.", page),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Writes page checksums in the sidecar format read by `read_sums`.
+pub fn write_sums<W: Write>(out: &mut W, sums: &[(u8, u32)]) -> Result<(), Error> {
+    writeln!(out, "# regenkfs page checksums (CRC32)")?;
+    for (page, crc) in sums {
+        writeln!(out, "{:02x} {:08x}", page, crc)?;
+    }
+    Ok(())
+}
+
+pub fn read_sums<R: BufRead>(input: R) -> Result<Vec<(u8, u32)>, Error> {
+    let invalid = |line: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Malformed checksum line: {}", line),
+        )
+    };
+    let mut sums = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (page, crc) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(page), Some(crc), None) => (page, crc),
+            _ => return Err(invalid(line)),
+        };
+        sums.push((
+            u8::from_str_radix(page, 16).map_err(|_| invalid(line))?,
+            u32::from_str_radix(crc, 16).map_err(|_| invalid(line))?,
+        ));
+    }
+    Ok(sums)
+}
+
+/// Returns the pages whose contents no longer match `sums`.
+pub fn verify_sums(rom: &[u8], geometry: Geometry, sums: &[(u8, u32)]) -> Result<Vec<u8>, Error> {
+    let mut bad = Vec::new();
+    for &(page, crc) in sums {
+        let (_, actual) = page_sums(rom, geometry, page..=page)?[0];
+        if actual != crc {
+            bad.push(page);
+        }
+    }
+    Ok(bad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{Error, Write};
+
+use crate::*;
+
+/// The blocks of one page that differ between two ROMs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageDiff {
+    pub page: usize,
+    pub blocks: Vec<usize>,
+    /// Number of differing bytes across those blocks.
+    pub bytes: usize,
+}
+
+/// Compares two ROMs page by page and block by block, over the length
+/// they have in common.
+pub fn compare(a: &[u8], b: &[u8]) -> Vec<PageDiff> {
+    let page_length = usize::from(PAGE_LENGTH);
+    let block_size = usize::from(BLOCK_SIZE);
+    let len = a.len().min(b.len());
+    let mut diffs = Vec::new();
+    for (page, (a, b)) in a[..len]
+        .chunks(page_length)
+        .zip(b[..len].chunks(page_length))
+        .enumerate()
+    {
+        if a == b {
+            continue;
+        }
+        let mut diff = PageDiff {
+            page,
+            blocks: Vec::new(),
+            bytes: 0,
+        };
+        for (block, (a, b)) in a.chunks(block_size).zip(b.chunks(block_size)).enumerate() {
+            let bytes = a.iter().zip(b).filter(|(a, b)| a != b).count();
+            if bytes > 0 {
+                diff.blocks.push(block);
+                diff.bytes += bytes;
+            }
+        }
+        diffs.push(diff);
+    }
+    diffs
+}
+
+fn hex_row(bytes: &[u8], other: &[u8]) -> String {
+    bytes
+        .iter()
+        .zip(other)
+        .map(|(a, b)| {
+            if a == b {
+                format!("{:02x} ", a)
+            } else {
+                format!("{:02x}*", a)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+/// Writes the rows of 16 bytes that differ within `block` of `page`,
+/// `a`'s above `b`'s, with differing bytes marked by a `*`.
+pub fn write_hexdump<W: Write>(
+    out: &mut W,
+    a: &[u8],
+    b: &[u8],
+    page: usize,
+    block: usize,
+) -> Result<(), Error> {
+    let start = page * usize::from(PAGE_LENGTH) + block * usize::from(BLOCK_SIZE);
+    let end = (start + usize::from(BLOCK_SIZE)).min(a.len()).min(b.len());
+    for row in (start..end).step_by(16) {
+        let row_end = (row + 16).min(end);
+        let (a, b) = (&a[row..row_end], &b[row..row_end]);
+        if a != b {
+            writeln!(out, "    {:#08x}  a: {}", row, hex_row(a, b))?;
+            writeln!(out, "              b: {}", hex_row(b, a))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roms() -> (Vec<u8>, Vec<u8>) {
+        let a = vec![0xFF; 4 * usize::from(PAGE_LENGTH)];
+        let mut b = a.clone();
+        let page = usize::from(PAGE_LENGTH);
+        let block = usize::from(BLOCK_SIZE);
+        b[page + 2 * block + 0x12] = 0;
+        b[page + 2 * block + 0x13] = 1;
+        b[page + 5 * block] = 2;
+        b[3 * page + page - 1] = 3;
+        (a, b)
+    }
+
+    #[test]
+    fn differing_blocks_are_listed_by_page() {
+        let (a, b) = roms();
+        assert!(compare(&a, &a).is_empty());
+        assert_eq!(
+            compare(&a, &b),
+            [
+                PageDiff {
+                    page: 1,
+                    blocks: vec![2, 5],
+                    bytes: 3,
+                },
+                PageDiff {
+                    page: 3,
+                    blocks: vec![usize::from(PAGE_LENGTH / BLOCK_SIZE) - 1],
+                    bytes: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_common_length_is_compared() {
+        let (a, b) = roms();
+        let page = usize::from(PAGE_LENGTH);
+        assert_eq!(compare(&a[..2 * page], &b).len(), 1);
+        assert_eq!(compare(&b, &a[..page + 1]), []);
+        assert_eq!(
+            compare(&a, &b[..4 * page - 1]).len(),
+            1,
+            "the last byte is cut"
+        );
+        // A part page at the end is still compared, up to where it ends.
+        let cut = page + 2 * usize::from(BLOCK_SIZE) + 0x13;
+        assert_eq!(compare(&a[..cut], &b[..cut])[0].bytes, 1);
+        assert!(compare(&[], &b).is_empty());
+    }
+
+    #[test]
+    fn hexdumps_show_rows_that_differ() {
+        let (a, b) = roms();
+        let mut out = Vec::new();
+        write_hexdump(&mut out, &a, &b, 1, 2).unwrap();
+        let row = usize::from(PAGE_LENGTH) + 2 * usize::from(BLOCK_SIZE) + 0x10;
+        let mut a_row = "ff ".repeat(16);
+        a_row.replace_range(6..12, "ff*ff*");
+        let mut b_row = "ff ".repeat(16);
+        b_row.replace_range(6..12, "00*01*");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "    {:#08x}  a: {}\n              b: {}\n",
+                row, a_row, b_row
+            )
+        );
+    }
+
+    #[test]
+    fn hexdumps_stop_at_the_shorter_rom() {
+        let (a, b) = roms();
+        let page = usize::from(PAGE_LENGTH);
+        let block = usize::from(BLOCK_SIZE);
+        let mut out = Vec::new();
+        // Cut inside the differing row, and past the end of both.
+        write_hexdump(&mut out, &a, &b[..page + 2 * block + 0x13], 1, 2).unwrap();
+        let dump = String::from_utf8(out).unwrap();
+        assert_eq!(dump.lines().count(), 2);
+        assert!(dump.ends_with("b: ff ff 00*\n"), "{}", dump);
+        let mut out = Vec::new();
+        write_hexdump(&mut out, &a, &b, 8, 0).unwrap();
+        write_hexdump(&mut out, &a, &b[..page], 1, 2).unwrap();
+        assert!(out.is_empty());
+    }
+}
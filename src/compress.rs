@@ -0,0 +1,157 @@
+use std::io::{Error, ErrorKind};
+
+/// The run-length encoding `--experimental-compress` stores file data
+/// in, flagged with `KFS_FLAG_COMPRESSED`: the length of the data,
+/// 24 bits little-endian, then packets of
+///
+/// * a byte `n` below 0x80 and `n + 1` bytes to copy, or
+/// * a byte `n` from 0x80 up and one byte to repeat `n - 0x80 + 3`
+///   times.
+///
+/// Simple enough for a Z80 to undo a block at a time.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let length = (data.len() as u32).to_le_bytes();
+    let mut packed = length[..3].to_vec();
+    // Where the literals not yet packed start.
+    let mut start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&byte| byte == data[i])
+            .count();
+        if run >= MIN_RUN {
+            push_literals(&mut packed, &data[start..i]);
+            packed.push((0x80 + run - MIN_RUN) as u8);
+            packed.push(data[i]);
+            i += run;
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    push_literals(&mut packed, &data[start..]);
+    packed
+}
+
+fn push_literals(packed: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        packed.push((chunk.len() - 1) as u8);
+        packed.extend_from_slice(chunk);
+    }
+}
+
+const MAX_LITERALS: usize = 0x80;
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 0x7F + MIN_RUN;
+
+/// Undoes `compress`.
+pub fn decompress(packed: &[u8]) -> Result<Vec<u8>, Error> {
+    let corrupt = |what: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupt compressed data: {}.", what),
+        )
+    };
+    let header = packed.get(..3).ok_or_else(|| corrupt("no length"))?;
+    let length = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    let mut data = Vec::with_capacity(length);
+    let mut rest = &packed[3..];
+    while let Some((&n, tail)) = rest.split_first() {
+        let n = usize::from(n);
+        if n < 0x80 {
+            let copied = tail
+                .get(..n + 1)
+                .ok_or_else(|| corrupt("literals cut short"))?;
+            data.extend_from_slice(copied);
+            rest = &tail[n + 1..];
+        } else {
+            let (&byte, tail) = tail.split_first().ok_or_else(|| corrupt("run cut short"))?;
+            data.resize(data.len() + n - 0x80 + MIN_RUN, byte);
+            rest = tail;
+        }
+        if data.len() > length {
+            return Err(corrupt("longer than its length"));
+        }
+    }
+    if data.len() != length {
+        return Err(corrupt("shorter than its length"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let packed = compress(data);
+        assert_eq!(decompress(&packed).unwrap(), data);
+        packed
+    }
+
+    #[test]
+    fn empty_data_is_just_its_length() {
+        assert_eq!(round_trip(&[]), [0, 0, 0]);
+    }
+
+    #[test]
+    fn runs_longer_than_a_packet_are_split() {
+        for &length in &[MIN_RUN, MAX_RUN, MAX_RUN + 1, MAX_RUN + MIN_RUN - 1, 1000] {
+            let packed = round_trip(&vec![0xAA; length]);
+            assert!(
+                packed.len() <= 3 + 2 * (length / MAX_RUN) + MIN_RUN,
+                "{}",
+                length
+            );
+        }
+        assert_eq!(compress(&[7; MAX_RUN]), [MAX_RUN as u8, 0, 0, 0xFF, 7]);
+    }
+
+    #[test]
+    fn short_runs_stay_literal() {
+        assert_eq!(compress(b"aab"), [3, 0, 0, 2, b'a', b'a', b'b']);
+        round_trip(b"aabbaab");
+    }
+
+    #[test]
+    fn incompressible_data_grows_by_a_byte_a_packet() {
+        // No byte repeats the one before it.
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let packed = round_trip(&data);
+        let packets = data.len().div_ceil(MAX_LITERALS);
+        assert_eq!(packed.len(), 3 + data.len() + packets);
+    }
+
+    #[test]
+    fn mixed_runs_and_literals_round_trip() {
+        let mut data = b"header".to_vec();
+        data.extend(vec![0; 300]);
+        data.extend((0..=255).collect::<Vec<u8>>());
+        data.extend(vec![0xFF; 5]);
+        round_trip(&data);
+    }
+
+    #[test]
+    fn cut_short_streams_are_errors() {
+        let mut data = vec![1, 2, 3];
+        data.extend(vec![9; 200]);
+        data.extend(b"tail");
+        let packed = compress(&data);
+        for end in 0..packed.len() {
+            let err = decompress(&packed[..end]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn streams_longer_than_their_length_are_errors() {
+        let mut packed = compress(b"abc");
+        packed[0] = 2;
+        assert!(decompress(&packed)
+            .unwrap_err()
+            .to_string()
+            .contains("longer than its length"));
+    }
+}
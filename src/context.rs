@@ -0,0 +1,2223 @@
+use std::fmt;
+use std::io::{BufWriter, SeekFrom};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::allocator::{Allocator, Placement, Sequential};
+use crate::build_info::WithBuildInfo;
+use crate::cache::Cache;
+use crate::diagnostic::Diagnostic;
+use crate::erase::Blanking;
+use crate::fat::Entry;
+use crate::fragmentation::Fragmentation;
+use crate::geometry::Platform;
+use crate::hints::Hint;
+use crate::integrity::Integrity;
+use crate::kernel::{GeometryRecord, KERNEL_PAGES};
+use crate::layout::{Layout, Magic};
+use crate::model::{Filter, Sort};
+use crate::page_map::PageMap;
+use crate::paranoid::Bounds;
+use crate::replay::Recorder;
+use crate::rom_file::RomFile;
+use crate::section::SectionId;
+use crate::sha256::{hex, sha256, Sha256};
+use crate::source::{KfsSource, Kind, Member};
+use crate::spec::{version_byte, MAGIC};
+use crate::stage::{self, Staged};
+use crate::stats::{Stats, StatsFormat};
+use crate::window::{Span, Window};
+use crate::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Cursor, Error, ErrorKind, Read};
+use std::num::NonZeroUsize;
+use std::{
+    convert::{TryFrom, TryInto},
+    io::prelude::*,
+};
+
+/// Knobs controlling how a filesystem is written.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Don't print progress to stdout.
+    pub quiet: bool,
+    /// Copy the ROM here and write the filesystem into the copy, leaving
+    /// the original untouched.
+    pub output: Option<PathBuf>,
+    /// Also write a copy of the FAT to the pages directly below it.
+    pub mirror_fat: bool,
+    /// Sidecar file to record a CRC32 of every written page in.
+    pub checksums: Option<PathBuf>,
+    /// File to write a SHA-256 manifest of the stored files to.
+    pub manifest: Option<PathBuf>,
+    /// File to write a JSON build report to.
+    pub report: Option<PathBuf>,
+    /// Warn when the model is missing parts of the KnightOS layout.
+    pub lint: bool,
+    /// File of size budgets the build fails if it exceeds.
+    pub budgets: Option<PathBuf>,
+    /// Read the filesystem back after writing and compare it against the
+    /// model.
+    pub verify: bool,
+    /// File to log every write to the ROM in: its offset, length and
+    /// what it was.
+    pub trace_writes: Option<PathBuf>,
+    /// The calculator to lay the filesystem out for, rather than the one
+    /// with as much flash as the ROM is long.
+    pub platform: Option<Platform>,
+    /// Write the filesystem where the layout or the platform puts it even
+    /// if the kernel's opt-in geometry record says otherwise.
+    pub ignore_kernel_geometry: bool,
+    /// File to log the offset and bytes of every write to the ROM in,
+    /// for `replay::Log` to make again on a blank ROM.
+    pub record_writes: Option<PathBuf>,
+    /// The order file data is laid out in.
+    pub placement: Placement,
+    /// File of placement hints for individual files, such as aligning
+    /// executables to a fresh page, and of flags to give them.
+    pub hints: Option<PathBuf>,
+    /// File to write the flash address ranges of every stored file to,
+    /// or `-` for stdout.
+    pub addresses: Option<PathBuf>,
+    /// File to write debugger symbols for the FAT entries and data
+    /// blocks to.
+    pub symbols: Option<PathBuf>,
+    /// A layout file overriding where the data and FAT pages go.
+    pub layout: Option<PathBuf>,
+    /// Leave out directories of the model that hold no files or links,
+    /// even in subdirectories.
+    pub prune_empty: bool,
+    /// Whether dotfiles and dot-directories of the model are written.
+    pub hidden: model::Hidden,
+    /// Leave out files too large for KFS with a warning, instead of
+    /// failing the build.
+    pub skip_too_large: bool,
+    /// Write symlinks to directories as the directories they point to,
+    /// failing on any that loops back into its own ancestors.
+    pub follow_dir_symlinks: bool,
+    /// File declaring symlinks of a model directory checked out without
+    /// them, as `source::parse_symlinks` reads.
+    pub symlinks: Option<PathBuf>,
+    /// File remembering where the last build into this ROM put each file.
+    /// Files whose size and modification time (or else contents) are
+    /// unchanged keep their data where it is, and only the rest is
+    /// written again.
+    pub cache: Option<PathBuf>,
+    /// The order entries of each directory are written in.
+    pub sort: model::Sort,
+    /// SHA-256 (in hex) the finished ROM image must have, for
+    /// reproducible builds. The build fails if it doesn't.
+    pub expect_sha256: Option<String>,
+    /// Which filesystem of the layout file to write: a `[name]` table
+    /// of it, rather than its top-level keys.
+    pub instance: Option<String>,
+    /// A list of model paths, one per line or NUL-terminated, as `find`
+    /// prints them (or `-` to read it from stdin). Only the entries it
+    /// names, and the directories above them, are written.
+    pub files_from: Option<PathBuf>,
+    /// Print the build's metrics (sizes, pages used, free space and how
+    /// long it took) to stdout in this format once it's done.
+    pub stats_format: Option<StatsFormat>,
+    /// Leave out what git ignores of a model directory in a working copy.
+    pub use_gitignore: bool,
+    /// Globs of model paths, such as `etc/**`, whose entries get the
+    /// read-only flag. A `read_only` hint sets it too.
+    pub read_only: Vec<String>,
+    /// Which pages are blanked before the filesystem is written.
+    pub blanking: Blanking,
+    /// Where in the ROM file the filesystem is, when it is part of a
+    /// larger image such as a full flash dump.
+    pub offset: Option<Span>,
+    /// Leave out model entries that can't be read, with a warning, and
+    /// fail only once the rest of the image is written.
+    pub keep_going: bool,
+    /// Files the model may hold before the build fails, before anything
+    /// is written.
+    pub max_files: Option<u64>,
+    /// Bytes the model's files may hold in all, likewise.
+    pub max_total_size: Option<u64>,
+    /// Threads to read (and hash, for the cache) a model directory's
+    /// files ahead of writing them on, or to build several images with,
+    /// or `None` for one per core.
+    pub threads: Option<NonZeroUsize>,
+    /// Bytes to buffer writes to the ROM and reads of model files in,
+    /// rather than `BufWriter`'s and `BufReader`'s default of 8 KiB.
+    pub buffer_size: Option<usize>,
+    /// Write a ROM on disk through io_uring, batching its many small
+    /// positioned writes into one syscall. Needs the `io-uring` feature,
+    /// on Linux; in-memory ROMs ignore it.
+    pub io_uring: bool,
+    /// Make a read-only ROM writable for the build, putting its
+    /// permissions back afterwards, instead of failing.
+    pub chmod: bool,
+    /// Print warnings as JSON objects, one per line, as
+    /// `diagnostic::Diagnostic` describes.
+    pub errors_json: bool,
+    /// Check every write against the format's invariants before making
+    /// it, failing the build on the first that would break one, as
+    /// `paranoid::Bounds::check` lists. Always on in debug builds.
+    pub paranoid: bool,
+    /// Add a `build_info::PATH` file saying which regenkfs built the
+    /// image, when, and from what model, so an image can be identified
+    /// in the field.
+    pub build_info: bool,
+    /// Reserve the last data page for an `integrity::Integrity` page,
+    /// a hash of the data and FAT pages written once they are.
+    pub integrity_page: bool,
+    /// Experimental: end each file's FAT entry with a CRC-32 of its data,
+    /// flagged with `KFS_FLAG_CHECKSUM`, which the kernel doesn't know.
+    pub entry_checksums: bool,
+    /// Experimental: store a file's data compressed, as
+    /// `compress::compress` describes, if that makes it smaller, flagged
+    /// with `KFS_FLAG_COMPRESSED`, which the kernel doesn't know.
+    /// Compressed files aren't reused from the cache.
+    pub compress: bool,
+}
+
+/// The kinds of ROM region a build writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Region {
+    Blank,
+    Magic,
+    SectionHeader,
+    Block,
+    Fat,
+    FatMirror,
+    Integrity,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Region::Blank => "blanked pages",
+            Region::Magic => "page magic",
+            Region::SectionHeader => "section headers",
+            Region::Block => "data blocks",
+            Region::Fat => "FAT",
+            Region::FatMirror => "FAT mirror",
+            Region::Integrity => "integrity page",
+        })
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Takes an exclusive advisory lock (flock on Unix, LockFileEx on
+// Windows) on the ROM being written, held until the file is closed.
+fn lock(file: &File, path: &Path) -> Result<(), Error> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Error::new(
+            ErrorKind::WouldBlock,
+            format!(
+                "{} is locked by another process (another build or an emulator?).",
+                path.display()
+            ),
+        )),
+        // Some filesystems can't lock at all; carry on without it.
+        Err(TryLockError::Error(e)) if e.kind() == ErrorKind::Unsupported => Ok(()),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+// A ROM made writable for the build by `--chmod`, given back the
+// permissions it had when dropped.
+struct Writable {
+    path: PathBuf,
+    permissions: fs::Permissions,
+    errors_json: bool,
+}
+
+impl Drop for Writable {
+    fn drop(&mut self) {
+        if let Err(e) = fs::set_permissions(&self.path, self.permissions.clone()) {
+            let path = self.path.display().to_string();
+            let warning = format!("Cannot make {} read-only again: {}", path, e);
+            Diagnostic::warning("chmod-restore", Some(&path), &warning).print(self.errors_json);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn describe(permissions: &fs::Permissions) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    format!("mode {:04o}", permissions.mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn describe(permissions: &fs::Permissions) -> String {
+    match permissions.readonly() {
+        true => "the read-only attribute set".to_string(),
+        false => "the read-only attribute clear".to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn writable(permissions: &fs::Permissions) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    fs::Permissions::from_mode(permissions.mode() | 0o200)
+}
+
+#[cfg(not(unix))]
+fn writable(permissions: &fs::Permissions) -> fs::Permissions {
+    let mut permissions = permissions.clone();
+    permissions.set_readonly(false);
+    permissions
+}
+
+// Opens the ROM at `path` for writing with `open`. With `options.chmod`, a
+// read-only one is made writable until the `Writable` is dropped;
+// otherwise it fails saying how to fix it.
+fn open_rom(
+    open: &OpenOptions,
+    path: &Path,
+    options: &Options,
+) -> Result<(File, Option<Writable>), Error> {
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let mut restore = None;
+    if let Some(permissions) = permissions
+        .clone()
+        .filter(|permissions| options.chmod && permissions.readonly())
+    {
+        fs::set_permissions(path, writable(&permissions))?;
+        restore = Some(Writable {
+            path: path.to_path_buf(),
+            permissions,
+            errors_json: options.errors_json,
+        });
+    }
+    match open.open(long_path::extended(path)) {
+        Ok(file) => Ok((file, restore)),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let fix = match &permissions {
+                Some(permissions) if permissions.readonly() => {
+                    let command = if cfg!(windows) {
+                        "attrib -r"
+                    } else {
+                        "chmod u+w"
+                    };
+                    format!(
+                        "Make it writable with `{} {}`, or pass --chmod to do so just for this build.",
+                        command,
+                        path.display()
+                    )
+                }
+                Some(_) => "Only its owner (or an administrator) can open it for writing; \
+                            run the build as them, or copy it with -o."
+                    .to_string(),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!(
+                            "Error: Cannot create {}, as its directory is not writable.",
+                            path.display()
+                        ),
+                    ))
+                }
+            };
+            let permissions = permissions.as_ref().map_or(String::new(), describe);
+            Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Error: {} is not writable ({}). {}",
+                    path.display(),
+                    permissions,
+                    fix
+                ),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Works out where the filesystem of a ROM of `length` bytes goes, as a
+/// build would: from `options.layout` or `options.platform`, or else
+/// from the kernel's geometry record in `kernel`, the ROM's first pages,
+/// unless `options.ignore_kernel_geometry`. Returns the layout and the
+/// first FAT page.
+pub fn resolve_layout(
+    options: &Options,
+    kernel: &[u8],
+    length: u64,
+) -> Result<(Layout, u8), Error> {
+    let layouts = match &options.layout {
+        Some(path) => layout::read_layouts(path)?,
+        None if options.instance.is_some() => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Picking a filesystem by name needs a layout file.",
+            ))
+        }
+        None => {
+            let layout = options
+                .platform
+                .map_or_else(Layout::default, Layout::for_platform);
+            vec![(String::new(), layout)]
+        }
+    };
+    let instance = options.instance.as_deref().unwrap_or_default();
+    let mut layout = layout::select(&layouts, instance, length)?;
+    if !options.ignore_kernel_geometry && options.instance.is_none() {
+        let kernel = &kernel[..kernel
+            .len()
+            .min(usize::from(KERNEL_PAGES) * usize::from(PAGE_LENGTH))];
+        if let Some(kernel) = GeometryRecord::find(kernel)? {
+            match options.layout.is_some() || options.platform.is_some() {
+                true => kernel.check(&layout, length)?,
+                false => layout = kernel.layout(),
+            }
+        }
+    }
+    let fat_start = layout.fat_start_for(length)?;
+    if u64::from(layout.geometry.fat_end(fat_start)) > length {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("FAT page {:02x} is past the end of the ROM.", fat_start),
+        ));
+    }
+    Ok((layout, fat_start))
+}
+
+/// Opens the filesystem of `rom` where [`resolve_layout`] puts it.
+pub fn open_image<'a>(options: &Options, rom: &'a [u8]) -> Result<Image<'a>, Error> {
+    let (layout, fat_start) = resolve_layout(options, rom, rom.len() as u64)?;
+    Image::open_with(rom, layout.geometry, layout.dat_start, fat_start)
+}
+
+/// Changes the ROM at `path` (or the span of it, as for `window::read`)
+/// with `edit`, holding the same lock a build does and, with
+/// `options.chmod`, making a read-only ROM writable meanwhile. Only the
+/// pages `edit` changed are written back, in place.
+pub fn edit_rom<T, F>(
+    path: &Path,
+    span: Option<Span>,
+    options: &Options,
+    edit: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&mut [u8]) -> Result<T, Error>,
+{
+    let (path, suffix) = window::split_path(path);
+    let (mut file, _writable) =
+        open_rom(OpenOptions::new().read(true).write(true), &path, options)?;
+    lock(&file, &path)?;
+    let mut before = Vec::new();
+    file.read_to_end(&mut before)?;
+    let mut image = before.clone();
+    let result = match suffix.or(span) {
+        Some(span) => edit(span.slice_mut(&mut image)?)?,
+        None => edit(&mut image)?,
+    };
+    let page_length = usize::from(PAGE_LENGTH);
+    for (page, (old, new)) in before
+        .chunks(page_length)
+        .zip(image.chunks(page_length))
+        .enumerate()
+    {
+        if old != new {
+            file.seek(SeekFrom::Start((page * page_length) as u64))?;
+            file.write_all(new)?;
+        }
+    }
+    file.sync_data()?;
+    Ok(result)
+}
+
+// A section id read from a cache file.
+fn cached_section(id: u16) -> Result<SectionId, Error> {
+    SectionId::try_from(id).map_err(|id| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid section {:04x} in the build cache.", id),
+        )
+    })
+}
+
+// Progress output, silenced by `Options::quiet`.
+macro_rules! say {
+    ($ctx:expr, $($arg:tt)*) => {
+        if !$ctx.options.quiet {
+            print!($($arg)*);
+        }
+    };
+}
+
+macro_rules! sayln {
+    ($ctx:expr, $($arg:tt)*) => {
+        if !$ctx.options.quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Writes a filesystem into an output ROM image.
+///
+/// The output is anything seekable that can be both read and written: a
+/// ROM file modified in place, a copy of one, or an in-memory buffer.
+pub struct Context<'a, W: Read + Write + Seek = Window<RomFile>> {
+    rom_path: PathBuf,
+    model: &'a Path,
+    fat_start: u8,
+    dat_start: u8,
+    rom: BufWriter<W>,
+    // Kept until the ROM has been written, with `--chmod`.
+    writable: Option<Writable>,
+    options: Options,
+    warnings: Vec<String>,
+    trace: Option<BufWriter<File>>,
+    recorder: Option<Recorder<BufWriter<File>>>,
+    writes: Option<Vec<(Region, Range<u64>)>>,
+    allocated: PageMap,
+    allocator: Box<dyn Allocator>,
+    // Data pages whose magic number has been written.
+    magic_pages: BTreeSet<u8>,
+    // Files whose data is still to be placed.
+    pending: Vec<Pending>,
+    // Placement hints by path in the filesystem.
+    hints: BTreeMap<String, Hint>,
+    layout: Layout,
+    geometry: Geometry,
+    // The real paths of the directories being walked when following
+    // directory symlinks, to catch links that loop.
+    walking: Vec<PathBuf>,
+    // The last build's cache, while it still describes the ROM. Files
+    // reused from it are taken out, so what's left is stale.
+    cache: Option<Cache>,
+    // Where the model is read from: the host directory, or an archive
+    // read into memory. Opened by `run` unless given up front.
+    source: Option<Rc<dyn KfsSource>>,
+    // Which entries of the model are written, and those listed by
+    // `--files-from` not yet met in it.
+    filter: Filter,
+    unlisted: BTreeSet<String>,
+    // Model entries left out or stored empty by `--keep-going`, as they
+    // couldn't be read.
+    unreadable: BTreeSet<String>,
+    // What this build stores, for the next build's cache.
+    records: BTreeMap<String, cache::Record>,
+    // Whether `run` first builds into a copy of the ROM in memory, so
+    // a model that doesn't fit fails before the ROM is touched. Set for
+    // ROM files.
+    preflight: bool,
+    // Whether this is that copy, which keeps its warnings to itself.
+    rehearsal: bool,
+    // Model files already read, and hashed for the cache, on
+    // `options.threads` threads.
+    staged: BTreeMap<String, Staged>,
+    // For ROM files, what each page held before this build first wrote
+    // to it, so a build that fails can be undone.
+    undo: Option<BTreeMap<u64, Vec<u8>>>,
+}
+
+// A file whose FAT entry has room reserved at `addr`.
+struct Pending {
+    addr: u32,
+    length: u32,
+    entry: Entry,
+    // Where the file is in the filesystem, for messages.
+    path: String,
+    hint: Hint,
+}
+
+impl<'a> Context<'a, Window<RomFile>> {
+    pub fn new(rom_path: &'a Path, model: &'a Path) -> Result<Context<'a, Window<RomFile>>, Error> {
+        Context::with_options(rom_path, model, Options::default())
+    }
+
+    /// Writes into `rom_path` in place, or into a copy of it when
+    /// `options.output` names a different file. In the latter case the
+    /// source is only ever opened for reading. The filesystem is the
+    /// span of the file `options.offset` or a `file:offset` suffix of
+    /// `rom_path` names, or else all of it.
+    pub fn with_options(
+        rom_path: &'a Path,
+        model: &'a Path,
+        options: Options,
+    ) -> Result<Context<'a, Window<RomFile>>, Error> {
+        let name = rom_path;
+        let (rom_path, suffix) = window::split_path(rom_path);
+        let rom_path = rom_path.as_path();
+        if !rom_path.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Unable to open {}.", rom_path.display()),
+            ));
+        }
+
+        let (path, rom, writable) = match &options.output {
+            // Copying a file onto itself would truncate it.
+            Some(output) if !same_file(rom_path, output) => {
+                let mut source = File::open(long_path::extended(rom_path))?;
+                // Truncated only once locked, so a build still writing
+                // to it is left alone.
+                let (mut rom, writable) = open_rom(
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(false),
+                    output,
+                    &options,
+                )?;
+                lock(&rom, output)?;
+                rom.set_len(0)?;
+                io::copy(&mut source, &mut rom)?;
+                (output.clone(), rom, writable)
+            }
+            // This opens the file like fopen(rom_file, "r+") in C.
+            _ => {
+                let (rom, writable) = open_rom(
+                    OpenOptions::new().read(true).write(true).truncate(false),
+                    rom_path,
+                    &options,
+                )?;
+                lock(&rom, rom_path)?;
+                (name.to_path_buf(), rom, writable)
+            }
+        };
+        let span = suffix.or(options.offset).unwrap_or_default();
+        let rom = RomFile::open(rom, options.io_uring)?;
+        let mut context = Context::with_output(path, model, Window::new(rom, span)?, options)?;
+        context.writable = writable;
+        context.preflight = true;
+        context.undo = Some(BTreeMap::new());
+        Ok(context)
+    }
+}
+
+impl<'a> Context<'a, Cursor<Vec<u8>>> {
+    /// Writes into an in-memory copy of `rom`; retrieve the result with
+    /// `into_output`.
+    pub fn in_memory(
+        rom: Vec<u8>,
+        model: &'a Path,
+        options: Options,
+    ) -> Result<Context<'a, Cursor<Vec<u8>>>, Error> {
+        Context::with_output(PathBuf::from("<memory>"), model, Cursor::new(rom), options)
+    }
+}
+
+impl<'a, W: Read + Write + Seek> Context<'a, W> {
+    /// Writes into an already opened ROM image. `name` is only used in
+    /// messages and reports.
+    pub fn with_output(
+        name: PathBuf,
+        model: &'a Path,
+        rom: W,
+        options: Options,
+    ) -> Result<Context<'a, W>, Error> {
+        // A model is either a directory, a cpio, tar or zip archive of
+        // one or `-` for a tar stream on stdin.
+        if !model.is_dir() && !model.is_file() && model != Path::new("-") {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Unable to open {}.", model.display()),
+            ));
+        }
+        Context::open(name, model, None, rom, options)
+    }
+
+    /// Writes the model in `source`, such as an in-memory `Vfs`, into an
+    /// already opened ROM image.
+    pub fn with_source(
+        name: PathBuf,
+        source: Box<dyn KfsSource>,
+        rom: W,
+        options: Options,
+    ) -> Result<Context<'static, W>, Error> {
+        Context::open(
+            name,
+            Path::new("<source>"),
+            Some(source.into()),
+            rom,
+            options,
+        )
+    }
+
+    fn open(
+        name: PathBuf,
+        model: &'a Path,
+        source: Option<Rc<dyn KfsSource>>,
+        mut rom: W,
+        options: Options,
+    ) -> Result<Context<'a, W>, Error> {
+        let length = rom.seek(SeekFrom::End(0))?;
+        let mut kernel = Vec::new();
+        rom.seek(SeekFrom::Start(0))?;
+        (&mut rom)
+            .take(u64::from(KERNEL_PAGES) * u64::from(PAGE_LENGTH))
+            .read_to_end(&mut kernel)?;
+        let (mut layout, fat_start) = resolve_layout(&options, &kernel, length)?;
+        let dat_start = layout.dat_start;
+        let fat_pages = if options.mirror_fat { 2 } else { 1 } * layout.geometry.fat_pages();
+        if u16::from(fat_start) < u16::from(dat_start) + u16::from(fat_pages) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "No room for data pages between page {:02x} and the FAT at {:02x}.",
+                    dat_start, fat_start
+                ),
+            ));
+        }
+        if options.integrity_page {
+            let page = fat_start - fat_pages;
+            if page == dat_start {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "No room for both data pages and the integrity page between page {:02x} and the FAT at {:02x}.",
+                        dat_start, fat_start
+                    ),
+                ));
+            }
+            layout.reserved.push(page..=page);
+        }
+        let trace = match &options.trace_writes {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let recorder = match &options.record_writes {
+            Some(path) => Some(Recorder::new(BufWriter::new(File::create(path)?), length)?),
+            None => None,
+        };
+        Ok(Context {
+            rom_path: name,
+            model,
+            fat_start,
+            dat_start,
+            rom: match options.buffer_size {
+                Some(size) => BufWriter::with_capacity(size, rom),
+                None => BufWriter::new(rom),
+            },
+            options,
+            warnings: Vec::new(),
+            trace,
+            recorder,
+            writes: None,
+            allocated: PageMap::new(0, 0),
+            allocator: Box::new(Sequential::new(dat_start)),
+            magic_pages: BTreeSet::new(),
+            pending: Vec::new(),
+            hints: BTreeMap::new(),
+            geometry: layout.geometry,
+            layout,
+            walking: Vec::new(),
+            cache: None,
+            source,
+            filter: Filter::default(),
+            unlisted: BTreeSet::new(),
+            unreadable: BTreeSet::new(),
+            records: BTreeMap::new(),
+            writable: None,
+            preflight: false,
+            rehearsal: false,
+            staged: BTreeMap::new(),
+            undo: None,
+        })
+    }
+
+    /// Starts recording the byte range of every write, for `touched`.
+    pub fn record_writes(&mut self) {
+        self.writes.get_or_insert_with(Vec::new);
+    }
+
+    /// The byte ranges written since `record_writes`, merged where they
+    /// touch and sorted by region and offset.
+    pub fn touched(&self) -> Vec<(Region, Range<u64>)> {
+        let mut writes = self.writes.clone().unwrap_or_default();
+        writes.sort_by_key(|(region, range)| (*region, range.start));
+        let mut merged: Vec<(Region, Range<u64>)> = Vec::new();
+        for (region, range) in writes {
+            match merged.last_mut() {
+                Some((last, prev)) if *last == region && range.start <= prev.end => {
+                    prev.end = prev.end.max(range.end)
+                }
+                _ => merged.push((region, range)),
+            }
+        }
+        merged
+    }
+
+    /// Flushes and returns the output ROM image.
+    pub fn into_output(self) -> Result<W, Error> {
+        self.rom.into_inner().map_err(|e| e.into_error())
+    }
+
+    // Reads the whole output image back.
+    fn read_back(&mut self) -> Result<Vec<u8>, Error> {
+        self.rom.flush()?;
+        let rom = self.rom.get_mut();
+        let mut bytes = Vec::new();
+        rom.seek(SeekFrom::Start(0))?;
+        rom.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Writes `bytes` at `offset`, logging the write to the trace file
+    // as `what`.
+    fn write_at(
+        &mut self,
+        offset: u64,
+        bytes: &[u8],
+        region: Region,
+        what: fmt::Arguments,
+    ) -> Result<(), Error> {
+        if self.options.paranoid || cfg!(debug_assertions) {
+            self.bounds()
+                .check(offset, bytes, region)
+                .map_err(|problem| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Error: paranoid check failed: {} at {:#x} ({}).",
+                            problem, offset, what
+                        ),
+                    )
+                })?;
+        }
+        self.save_pages(offset..offset + bytes.len() as u64)?;
+        if let Some(trace) = &mut self.trace {
+            writeln!(trace, "{:#08x} {:5} {}", offset, bytes.len(), what)?;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(offset, bytes)?;
+        }
+        if let Some(writes) = &mut self.writes {
+            writes.push((region, offset..offset + bytes.len() as u64));
+        }
+        self.rom.seek(SeekFrom::Start(offset))?;
+        self.rom.write_all(bytes)
+    }
+
+    // Keeps what the pages `range` falls in held before the build first
+    // writes to them, for `roll_back`.
+    fn save_pages(&mut self, range: Range<u64>) -> Result<(), Error> {
+        let undo = match &mut self.undo {
+            Some(undo) if !range.is_empty() => undo,
+            _ => return Ok(()),
+        };
+        let page_length = u64::from(self.geometry.page_length());
+        for page in range.start / page_length..=(range.end - 1) / page_length {
+            if undo.contains_key(&page) {
+                continue;
+            }
+            self.rom.flush()?;
+            let rom = self.rom.get_mut();
+            rom.seek(SeekFrom::Start(page * page_length))?;
+            let mut saved = Vec::with_capacity(page_length as usize);
+            (&mut *rom).take(page_length).read_to_end(&mut saved)?;
+            undo.insert(page, saved);
+        }
+        Ok(())
+    }
+
+    // Puts every page the build wrote to back as it was, leaving the ROM
+    // as though the build never ran.
+    fn roll_back(&mut self) -> Result<(), Error> {
+        let undo = match self.undo.take() {
+            Some(undo) if !undo.is_empty() => undo,
+            _ => return Ok(()),
+        };
+        let page_length = u64::from(self.geometry.page_length());
+        for (page, saved) in &undo {
+            self.rom.seek(SeekFrom::Start(page * page_length))?;
+            self.rom.write_all(saved)?;
+        }
+        self.rom.flush()?;
+        sayln!(
+            self,
+            "The build failed, so the {} pages it wrote to in {} were put back.",
+            undo.len(),
+            self.rom_path.display()
+        );
+        Ok(())
+    }
+
+    // Stores `entry` below `fatptr`, moving it down past the entry.
+    fn write_fat(&mut self, entry: &Entry, fatptr: &mut u32) -> Result<(), Error> {
+        let addr = self.reserve_fat(entry, fatptr)?;
+        self.write_entry_at(entry, addr, None)
+    }
+
+    // Makes room for `entry` below `fatptr` without writing it yet,
+    // returning where it goes. Its size doesn't depend on its section.
+    fn reserve_fat(&mut self, entry: &Entry, fatptr: &mut u32) -> Result<u32, Error> {
+        let checksum = if entry.has_checksum() {
+            spec::ENTRY_CHECKSUM_LENGTH
+        } else {
+            0
+        };
+        let length = (entry.encode()?.len() + checksum) as u32;
+        let bottom = self.geometry.fat_end(self.fat_start) - self.geometry.fat_length();
+        *fatptr = fatptr
+            .checked_sub(length)
+            .filter(|&addr| addr >= bottom)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Error: the FAT is full; its {} pages have no room for {}.",
+                        self.geometry.fat_pages(),
+                        entry.name()
+                    ),
+                )
+            })?;
+        Ok(*fatptr)
+    }
+
+    // Writes `entry` at `addr`, with the `checksum` of a file's data if
+    // its flags say it has one.
+    fn write_entry_at(
+        &mut self,
+        entry: &Entry,
+        addr: u32,
+        checksum: Option<u32>,
+    ) -> Result<(), Error> {
+        let mut bytes = match checksum {
+            Some(checksum) => entry.encode_with_checksum(checksum)?,
+            None => entry.encode()?,
+        };
+        bytes.reverse();
+        let name = entry.name();
+        self.write_at(
+            u64::from(addr),
+            &bytes,
+            Region::Fat,
+            format_args!("FAT entry {}", name),
+        )?;
+        if self.options.mirror_fat {
+            self.write_at(
+                u64::from(addr - self.geometry.fat_length()),
+                &bytes,
+                Region::FatMirror,
+                format_args!("FAT mirror {}", name),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Where `--paranoid` lets writes go.
+    fn bounds(&self) -> Bounds {
+        let fat_end = u64::from(self.geometry.fat_end(self.fat_start));
+        let fat_length = u64::from(self.geometry.fat_length());
+        Bounds {
+            geometry: self.geometry,
+            data_pages: self.data_pages(),
+            fat: fat_end - fat_length..fat_end,
+            mirror: if self.options.mirror_fat {
+                Some(fat_end - 2 * fat_length..fat_end - fat_length)
+            } else {
+                None
+            },
+        }
+    }
+
+    // Hashes the filesystem into its integrity page, the last data
+    // page, which the layout reserves for it.
+    fn write_integrity(&mut self) -> Result<(), Error> {
+        let page = self.dat_end();
+        let rom = self.read_back()?;
+        let integrity = Integrity::compute(
+            &rom,
+            self.geometry,
+            self.dat_start..=page - 1,
+            self.fat_start,
+        )?;
+        self.write_at(
+            self.geometry.page_offset(page),
+            &integrity.encode(self.geometry.page_length()),
+            Region::Integrity,
+            format_args!("integrity page {:02x}", page),
+        )?;
+        self.rom.flush()
+    }
+
+    // Last page that may hold data sections.
+    fn dat_end(&self) -> u8 {
+        if self.options.mirror_fat {
+            self.fat_start - 2 * self.geometry.fat_pages()
+        } else {
+            self.fat_start - self.geometry.fat_pages()
+        }
+    }
+
+    // Writes up to a block of `file` into a section, returning what it
+    // read.
+    fn write_block<R: Read>(
+        &mut self,
+        file: &mut R,
+        section_id: SectionId,
+    ) -> Result<Vec<u8>, Error> {
+        let mut block = Vec::with_capacity(usize::from(self.geometry.block_size()));
+        file.by_ref()
+            .take(u64::from(self.geometry.block_size()))
+            .read_to_end(&mut block)?;
+        self.write_at(
+            self.geometry.block_offset(section_id),
+            &block,
+            Region::Block,
+            format_args!("block {}", section_id),
+        )?;
+        Ok(block)
+    }
+
+    // Writes the magic number of a data page the first time a section
+    // lands in it.
+    fn write_magic(&mut self, page: u8) -> Result<(), Error> {
+        if page > self.dat_end() || self.layout.is_reserved(page) || !self.magic_pages.insert(page)
+        {
+            return Ok(());
+        }
+        self.write_at(
+            self.geometry.page_offset(page),
+            &[MAGIC[0], MAGIC[1], MAGIC[2], version_byte(KFS_VERSION)],
+            Region::Magic,
+            format_args!("magic page {:02x}", page),
+        )
+    }
+
+    // Allocates sections for `length` bytes of data, laying them out
+    // from the start of page `start` rather than with the allocator if
+    // one is given.
+    fn allocate_chain(&mut self, length: u32, start: Option<u8>) -> Result<Vec<SectionId>, Error> {
+        let blocks = length.div_ceil(u32::from(self.geometry.block_size()));
+        let mut chain = Vec::with_capacity(blocks as usize);
+        let mut from_start = start.map(Sequential::new);
+        for _ in 0..blocks {
+            let section = match &mut from_start {
+                Some(sequential) => sequential.next_section(&self.allocated),
+                None => self.allocator.next_section(&self.allocated),
+            };
+            let section = section
+                .filter(|section| section.page() <= self.dat_end())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        if self.options.mirror_fat {
+                            "Error: data would overwrite the mirrored FAT."
+                        } else {
+                            "Error: data would overwrite the FAT."
+                        },
+                    )
+                })?;
+            self.allocated.allocate(section)?;
+            self.write_magic(section.page())?;
+            // As in genkfs, filling a page opens the next one.
+            if section.index() == self.geometry.max_index() {
+                self.write_magic(section.page() + 1)?;
+            }
+            chain.push(section);
+        }
+        Ok(chain)
+    }
+
+    // Writes the data of `file` into the sections of `chain`, which were
+    // allocated for `length` bytes. The length was taken from the file's
+    // metadata earlier, so if the file has since grown or shrunk, its FAT
+    // entry and chain no longer match it and the build fails. Returns the
+    // SHA-256 of the data when there's a cache to record it in (`digest`,
+    // if that's known already), and its CRC-32 with
+    // `--experimental-entry-checksums`.
+    fn write_dat<R: Read>(
+        &mut self,
+        file: &mut R,
+        chain: &[SectionId],
+        path: &str,
+        length: u32,
+        digest: Option<[u8; 32]>,
+    ) -> Result<(Option<[u8; 32]>, Option<u32>), Error> {
+        let mut pSID: u16 = 0xFFFF;
+        let mut read: u64 = 0;
+        let mut hasher = match digest {
+            Some(_) => None,
+            None => self.options.cache.as_ref().map(|_| Sha256::new()),
+        };
+        let mut crc = self.options.entry_checksums.then_some(0);
+        for (i, &current) in chain.iter().enumerate() {
+            let nSID: u16 = chain.get(i + 1).map_or(KFS_NO_SECTION, |&next| next.into());
+
+            /* Section header */
+            pSID &= 0x7FFF; // Mark this section in use
+
+            // Warning: original C code uses fwrite which is
+            // arch-dependent.  We choose little endian here.
+            let [p0, p1] = pSID.to_le_bytes();
+            let [n0, n1] = nSID.to_le_bytes();
+            self.write_at(
+                self.geometry.header_offset(current),
+                &[p0, p1, n0, n1],
+                Region::SectionHeader,
+                format_args!("section header {}", current),
+            )?;
+
+            /* Block data */
+            let block = self.write_block(file, current)?;
+            read += block.len() as u64;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&block);
+            }
+            if let Some(crc) = &mut crc {
+                *crc = checksum::crc32_update(*crc, &block);
+            }
+
+            pSID = current.into();
+        }
+        read += file.read(&mut [0; 1])? as u64;
+        if read != u64::from(length) {
+            let change = if read > u64::from(length) {
+                "grew"
+            } else {
+                "shrank"
+            };
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Error: {} {} from {} bytes while being written.",
+                    path, change, length
+                ),
+            ));
+        }
+        Ok((digest.or_else(|| hasher.map(Sha256::finish)), crc))
+    }
+
+    fn add_symlink(
+        &mut self,
+        parent: u16,
+        name: &str,
+        target: &str,
+        fatptr: &mut u32,
+    ) -> Result<(), Error> {
+        let entry = Entry::Symlink {
+            parent,
+            name: name.to_string(),
+            target: target.to_string(),
+        };
+        self.write_fat(&entry, fatptr)
+    }
+
+    // The flags of the entry at `path` in the filesystem: all clear but
+    // for read-only, if a hint or `--read-only` asks for it.
+    fn flags(&self, path: &str) -> u8 {
+        let relative = path.trim_start_matches('/');
+        let read_only = self.hints.get(path).is_some_and(|hint| hint.read_only)
+            || self
+                .options
+                .read_only
+                .iter()
+                .any(|pattern| glob::matches(pattern.trim_start_matches('/'), relative));
+        if read_only {
+            !KFS_FLAG_READ_ONLY
+        } else {
+            0xFF
+        }
+    }
+
+    // Writes a directory entry, assigning it the next directory id.
+    fn add_directory(
+        &mut self,
+        parent: u16,
+        name: &str,
+        path: &str,
+        parent_id: &mut u16,
+        fatptr: &mut u32,
+    ) -> Result<(), Error> {
+        let entry = Entry::Directory {
+            parent,
+            id: *parent_id + 1,
+            flags: self.flags(path),
+            name: name.to_string(),
+        };
+        self.write_fat(&entry, fatptr)?;
+        *parent_id += 1;
+        Ok(())
+    }
+
+    fn add_file(
+        &mut self,
+        parent: u16,
+        name: &str,
+        path: &str,
+        display: &str,
+        len: u64,
+        fatptr: &mut u32,
+    ) -> Result<(), Error> {
+        if len > KFS_MAX_FILE_LEN {
+            if !self.options.skip_too_large {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Error: {} is larger than the maximum file size.", display),
+                ));
+            }
+            let warning = format!(
+                "Skipped {}, which is larger than the maximum file size.",
+                display
+            );
+            self.warn("file-too-large", Some(display), warning);
+            return Ok(());
+        }
+        // Now safe to coerce len into u32
+        let len: u32 = len.try_into().unwrap();
+        let path = path.to_string();
+        let hint = self.hints.get(&path).copied().unwrap_or_default();
+        let checksum = if self.options.entry_checksums {
+            !KFS_FLAG_CHECKSUM
+        } else {
+            0xFF
+        };
+        let entry = Entry::File {
+            parent,
+            flags: self.flags(&path) & checksum,
+            length: len,
+            // Filled in once the data is placed.
+            section_id: KFS_NO_SECTION,
+            name: name.to_string(),
+        };
+        let pending = Pending {
+            addr: self.reserve_fat(&entry, fatptr)?,
+            length: len,
+            entry,
+            path,
+            hint,
+        };
+        if let Some(sections) = self.reusable(&pending)? {
+            // Its data is still where the last build put it.
+            let mut entry = pending.entry;
+            if let Entry::File { section_id, .. } = &mut entry {
+                *section_id = sections.first().copied().unwrap_or(KFS_NO_SECTION);
+            }
+            let checksum = match entry.has_checksum() {
+                true => {
+                    let mut data = Vec::new();
+                    self.open_file(&pending.path)?.read_to_end(&mut data)?;
+                    Some(checksum::crc32(&data))
+                }
+                false => None,
+            };
+            return self.write_entry_at(&entry, pending.addr, checksum);
+        }
+        let pinned = self.hints.values().any(|hint| hint.pin.is_some());
+        match self.options.placement {
+            // With a cache, new data waits until the stale data is erased.
+            Placement::Sequential if !pinned && self.cache.is_none() => self.place(pending),
+            // Placed once every file is known.
+            _ => {
+                self.pending.push(pending);
+                Ok(())
+            }
+        }
+    }
+
+    // Allocates a file's sections, then writes its FAT entry and data.
+    fn place(&mut self, pending: Pending) -> Result<(), Error> {
+        let Pending {
+            addr,
+            length,
+            mut entry,
+            path,
+            hint,
+        } = pending;
+        let source = self.source();
+        // Opened first, so a file gone since the model was walked takes
+        // no sections. Its FAT entry is already reserved.
+        let mut file = match self.open_file(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                let warning = format!(
+                    "Stored {} empty, as it couldn't be read: {}.",
+                    source.display(&path),
+                    err
+                );
+                self.unreadable(&path, warning, err)?;
+                if let Entry::File { length, .. } = &mut entry {
+                    *length = 0;
+                }
+                let checksum = entry.has_checksum().then(|| checksum::crc32(&[]));
+                return self.write_entry_at(&entry, addr, checksum);
+            }
+        };
+        let (mut file, length) = if self.options.compress && length > 0 {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            let packed = compress::compress(&data);
+            // A file that changed size is left for `write_dat` to report.
+            if data.len() == length as usize && packed.len() < data.len() {
+                let packed_length = packed.len() as u32;
+                if let Entry::File { flags, length, .. } = &mut entry {
+                    *flags &= !KFS_FLAG_COMPRESSED;
+                    *length = packed_length;
+                }
+                (
+                    Box::new(Cursor::new(packed)) as Box<dyn Read>,
+                    packed_length,
+                )
+            } else {
+                (Box::new(Cursor::new(data)) as Box<dyn Read>, length)
+            }
+        } else {
+            (file, length)
+        };
+        let start = if length > 0 {
+            self.hinted_start(&path, hint)?
+        } else {
+            None
+        };
+        let chain = self.allocate_chain(length, start)?;
+        if let Entry::File { section_id, .. } = &mut entry {
+            *section_id = chain.first().map_or(KFS_NO_SECTION, |&first| first.into());
+        }
+        // An entry with a checksum waits for the data it sums.
+        if !entry.has_checksum() {
+            self.write_entry_at(&entry, addr, None)?;
+        }
+        let mtime = match self.options.cache {
+            Some(_) => source.mtime(&path)?,
+            None => None,
+        };
+        // Hashed already, unless what's written is compressed.
+        let digest = match self.options.compress {
+            false => self.staged.get(&path).and_then(|file| file.sha256),
+            true => None,
+        };
+        let (digest, crc) = self.write_dat(&mut file, &chain, &path, length, digest)?;
+        if entry.has_checksum() {
+            self.write_entry_at(&entry, addr, crc)?;
+        }
+        if let Some(sha256) = digest {
+            let record = cache::Record {
+                mtime,
+                size: length,
+                sha256,
+                sections: chain.iter().map(|&id| id.into()).collect(),
+            };
+            self.records.insert(path, record);
+        }
+        Ok(())
+    }
+
+    // The sections of a file the cache says are already in the ROM, if
+    // the file hasn't changed since: it has the same size and either the
+    // same modification time or, failing that, the same contents. Files
+    // the cache has nothing on for their path, such as renamed ones, can
+    // take the data of a file it does that has the same contents. Hinted
+    // files are always placed again.
+    fn reusable(&mut self, pending: &Pending) -> Result<Option<Vec<u16>>, Error> {
+        let cache = match &self.cache {
+            Some(cache) if !pending.hint.places() && !self.options.compress => cache,
+            _ => return Ok(None),
+        };
+        let same_size = |record: &&cache::Record| record.size == pending.length;
+        let by_path = cache.files.get(&pending.path).filter(same_size);
+        let source = self.source();
+        let mtime = source.mtime(&pending.path)?;
+        let found = match by_path {
+            Some(record) if mtime.is_some() && mtime == record.mtime => Some(pending.path.clone()),
+            // Only read when some file of the last build could match.
+            _ if cache.files.values().any(|record| same_size(&record)) => {
+                let digest = match self.staged.get(&pending.path).and_then(|file| file.sha256) {
+                    Some(digest) => digest,
+                    None => {
+                        let mut data = Vec::new();
+                        source.open(&pending.path)?.read_to_end(&mut data)?;
+                        sha256(&data)
+                    }
+                };
+                let matches =
+                    |record: &cache::Record| same_size(&record) && record.sha256 == digest;
+                match by_path {
+                    Some(record) if matches(record) => Some(pending.path.clone()),
+                    _ => cache
+                        .files
+                        .iter()
+                        .find(|(_, record)| matches(record))
+                        .map(|(path, _)| path.clone()),
+                }
+            }
+            _ => None,
+        };
+        let record = match (found, &mut self.cache) {
+            (Some(found), Some(cache)) => cache.files.remove(&found).unwrap(),
+            _ => return Ok(None),
+        };
+        let sections = record.sections.clone();
+        self.records
+            .insert(pending.path.clone(), cache::Record { mtime, ..record });
+        Ok(Some(sections))
+    }
+
+    // Frees and erases the sections of the files left in the last build's
+    // cache, which this build didn't keep, so new data can go there.
+    fn erase_stale(&mut self, cache: Cache) -> Result<(), Error> {
+        let blank = vec![0xFF; usize::from(self.geometry.block_size())];
+        for record in cache.files.values() {
+            for &id in &record.sections {
+                let id = cached_section(id)?;
+                self.allocated.free(id);
+                self.write_at(
+                    self.geometry.header_offset(id),
+                    &[0xFF; 4],
+                    Region::Blank,
+                    format_args!("stale section header {}", id),
+                )?;
+                self.write_at(
+                    self.geometry.block_offset(id),
+                    &blank,
+                    Region::Blank,
+                    format_args!("stale block {}", id),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // The key a cache must have for this build to reuse it: everything
+    // that decides which sections exist and which are off limits.
+    fn cache_key(&self) -> String {
+        let reserved: Vec<String> = self
+            .layout
+            .reserved
+            .iter()
+            .map(|range| format!("{:02x}-{:02x}", range.start(), range.end()))
+            .collect();
+        format!(
+            "geometry={:x}/{:x} dat={:02x} fat={:02x} mirror={} reserved={}",
+            self.geometry.page_length(),
+            self.geometry.block_size(),
+            self.dat_start,
+            self.fat_start,
+            self.options.mirror_fat,
+            reserved.join(",")
+        )
+    }
+
+    // CRC-32 of the data pages as they are in the ROM.
+    fn data_crc(&mut self) -> Result<u32, Error> {
+        let rom = self.read_back()?;
+        let start = self.geometry.page_offset(self.dat_start) as usize;
+        let end = self.geometry.page_offset(self.dat_end() + 1) as usize;
+        Ok(checksum::crc32(&rom[start..end]))
+    }
+
+    // The last build's cache, if it was for this layout and the data pages
+    // are still as that build left them.
+    fn load_cache(&mut self) -> Result<Option<Cache>, Error> {
+        let path = match &self.options.cache {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+        let cache = match cache::load_cache(&path)? {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+        if cache.key != self.cache_key() || cache.crc != self.data_crc()? {
+            sayln!(
+                self,
+                "Build cache {} is out of date, writing every file.",
+                path.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(cache))
+    }
+
+    fn write_cache(&mut self) -> Result<(), Error> {
+        let path = match &self.options.cache {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let cache = Cache {
+            key: self.cache_key(),
+            crc: self.data_crc()?,
+            files: std::mem::take(&mut self.records),
+        };
+        let mut out = BufWriter::new(File::create(&path)?);
+        cache::write_cache(&mut out, &cache)?;
+        out.flush()?;
+        sayln!(self, "Build cache written to {}.", path.display());
+        Ok(())
+    }
+
+    // The page a file's hint says its data must start on, if any.
+    fn hinted_start(&self, name: &str, hint: Hint) -> Result<Option<u8>, Error> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+        match hint.pin {
+            Some(page) if !self.allocated.pages().contains(&page) => invalid(format!(
+                "Error: cannot pin {} to page {:02x}, which is not a data page.",
+                name, page
+            )),
+            Some(page) if hint.align && self.allocated.used_in(page) > 0 => invalid(format!(
+                "Error: cannot align {} to page {:02x}, which is already in use.",
+                name, page
+            )),
+            Some(page)
+                if self.allocated.used_in(page) == usize::from(self.geometry.max_index()) =>
+            {
+                invalid(format!(
+                    "Error: page {:02x} has no room left for {}.",
+                    page, name
+                ))
+            }
+            Some(page) => Ok(Some(page)),
+            None if hint.align => self.fresh_page(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // The first data page nothing has been allocated in, for `name` to
+    // be aligned to.
+    fn fresh_page(&self, name: &str) -> Result<u8, Error> {
+        self.allocated
+            .pages()
+            .find(|&page| self.allocated.used_in(page) == 0)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Error: no free page left to align {} to.", name),
+                )
+            })
+    }
+
+    // Whether the model entry at `path`, shown as `display`, is to be
+    // written, given any `--files-from` list and `--use-gitignore`.
+    fn selects(&mut self, path: &str, display: &str) -> bool {
+        let build_info = self.options.build_info && (path == build_info::PATH || path == "/etc");
+        if self.filter == Filter::default() || build_info {
+            return true;
+        }
+        self.unlisted.remove(path);
+        if self.filter.is_ignored(path) {
+            sayln!(self, "Skipping ignored {}...", display);
+        }
+        self.filter.keeps(path)
+    }
+
+    // With `--keep-going`, records the model entry at `path` as one that
+    // couldn't be read and warns; otherwise fails with `err`.
+    fn unreadable(&mut self, path: &str, warning: String, err: Error) -> Result<(), Error> {
+        if !self.options.keep_going {
+            return Err(err);
+        }
+        let display = self.source().display(path);
+        self.warn("unreadable", Some(&display), warning);
+        self.unreadable.insert(path.to_string());
+        Ok(())
+    }
+
+    // Prints `warning`, as JSON with `--errors-json`, and keeps it for
+    // the report.
+    fn warn(&mut self, code: &str, path: Option<&str>, warning: String) {
+        if !self.rehearsal {
+            Diagnostic::warning(code, path, &warning).print(self.options.errors_json);
+        }
+        self.warnings.push(warning);
+    }
+
+    fn source(&self) -> Rc<dyn KfsSource> {
+        Rc::clone(self.source.as_ref().expect("the model is opened first"))
+    }
+
+    // The file at `path`, from memory if it was staged.
+    fn open_file(&self, path: &str) -> Result<Box<dyn Read>, Error> {
+        match self.staged.get(path) {
+            Some(file) => Ok(Box::new(Cursor::new(Rc::clone(&file.data)))),
+            None => self.source().open(path),
+        }
+    }
+
+    // Reads the files among `members` of the directory at `dir` that the
+    // build will write ahead of it, on `options.threads` threads. Only
+    // as much as the data pages hold is kept in memory; the rest is read
+    // as it's written.
+    fn stage(&mut self, dir: &str, members: &[Member]) {
+        let threads = stage::thread_count(self.options.threads);
+        if threads == 1 {
+            return;
+        }
+        let source = self.source();
+        let mut room = (self.data_pages().count() as u64 * u64::from(self.geometry.page_length()))
+            .saturating_sub(
+                self.staged
+                    .values()
+                    .map(|file| file.data.len() as u64)
+                    .sum(),
+            );
+        let mut files = Vec::new();
+        for member in members {
+            let path = format!("{}/{}", dir, member.name.to_string_lossy());
+            let length = match member.kind {
+                Kind::File { length } => length,
+                _ => continue,
+            };
+            let kept = self.options.hidden.keeps(&member.name.to_string_lossy())
+                && (self.filter == Filter::default() || self.filter.keeps(&path));
+            if !kept || length > room || self.staged.contains_key(&path) {
+                continue;
+            }
+            if let Some(host) = source.host_path(&path) {
+                room -= length;
+                files.push((path, host));
+            }
+        }
+        let hash = self.options.cache.is_some();
+        self.staged.extend(stage::read_all(&files, threads, hash));
+    }
+
+    // Like `unreadable`, for an entry left out of the filesystem.
+    fn skip_unreadable(&mut self, path: &str, display: &str, err: Error) -> Result<(), Error> {
+        let warning = format!("Skipped {}, which couldn't be read: {}.", display, err);
+        self.unreadable(path, warning, err)
+    }
+
+    // Writes the entries of the model directory at `dir`, whose members
+    // are `members`.
+    fn write_recursive(
+        &mut self,
+        dir: &str,
+        mut members: Vec<Member>,
+        parent_id: &mut u16,
+        fatptr: &mut u32,
+    ) -> Result<(), Error> {
+        let parent: u16 = *parent_id;
+        let source = self.source();
+        let real = source.real_path(dir)?;
+        if let Some(real) = &real {
+            self.walking.push(real.clone());
+        }
+
+        // By name rather than path, so the order can't depend on how
+        // paths are split into components.
+        let sort = self.options.sort;
+        members.sort_by(|a, b| match sort {
+            Sort::Bytes => a.name.as_encoded_bytes().cmp(b.name.as_encoded_bytes()),
+            sort => sort.compare(&a.name.to_string_lossy(), &b.name.to_string_lossy()),
+        });
+        self.stage(dir, &members);
+        for member in members {
+            let path = format!("{}/{}", dir, member.name.to_string_lossy());
+            let display = source.display(&path);
+            if !self.selects(&path, &display) {
+                continue;
+            }
+            if !self.options.hidden.keeps(&member.name.to_string_lossy()) {
+                sayln!(self, "Skipping hidden {}...", display);
+                continue;
+            }
+            let name: &str = member.name.to_str().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Filename {} does not contain valid UTF-8.", display),
+                )
+            })?;
+
+            match member.kind {
+                Kind::Symlink => {
+                    let target = source.read_link(&path)?;
+                    sayln!(self, "Adding link from {} to {}...", display, target);
+                    self.add_symlink(parent, name, &target, fatptr)?
+                }
+                Kind::Directory => {
+                    // Listed before its entry is written, so a directory
+                    // that can't be is left out whole.
+                    let children = match source.list(&path) {
+                        Ok(children) => children,
+                        Err(err) => {
+                            self.skip_unreadable(&path, &display, err)?;
+                            continue;
+                        }
+                    };
+                    if self.options.prune_empty
+                        && !source.has_content(&path, self.options.hidden)?
+                    {
+                        sayln!(self, "Skipping empty directory {}...", display);
+                        continue;
+                    }
+                    if let Some(real) = source.real_path(&path)? {
+                        if self.walking.contains(&real) {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Error: {} loops back to {}.", display, real.display()),
+                            ));
+                        }
+                    }
+                    sayln!(self, "Adding {}...", display);
+                    self.add_directory(parent, name, &path, parent_id, fatptr)?;
+                    self.write_recursive(&path, children, parent_id, fatptr)?
+                }
+                Kind::File { length } => {
+                    if let Err(err) = self.open_file(&path) {
+                        self.skip_unreadable(&path, &display, err)?;
+                        continue;
+                    }
+                    sayln!(self, "Adding {}...", display);
+                    self.add_file(parent, name, &path, &display, length, fatptr)?
+                }
+                Kind::Other => {
+                    // Gone since the directory was listed.
+                    let err = Error::new(
+                        ErrorKind::NotFound,
+                        format!("{} is gone, or isn't a file, directory or symlink", display),
+                    );
+                    self.skip_unreadable(&path, &display, err)?;
+                }
+            }
+        }
+        if real.is_some() {
+            self.walking.pop();
+        }
+        Ok(())
+    }
+
+    // Returns the number of data pages (low byte) and fat pages (high
+    // byte) written.
+    fn write_filesystem(&mut self) -> Result<u16, Error> {
+        let mut parent_id: u16 = 0;
+        self.allocated = PageMap::with_geometry(self.dat_start, self.dat_end(), self.geometry);
+        self.magic_pages = BTreeSet::new();
+        self.magic_pages.insert(self.dat_start);
+        for page in self.allocated.pages() {
+            if self.layout.is_reserved(page) {
+                self.allocated.reserve(page);
+            }
+        }
+        if let Some(cache) = &self.cache {
+            for record in cache.files.values() {
+                for &id in &record.sections {
+                    let id = cached_section(id)?;
+                    self.allocated.allocate(id)?;
+                    self.magic_pages.insert(id.page());
+                }
+            }
+        }
+        self.hints = match &self.options.hints {
+            Some(path) => hints::read_hints(path)?,
+            None => BTreeMap::new(),
+        };
+        let mut fatptr: u32 = self.geometry.fat_end(self.fat_start);
+        let fatptr_start: u32 = fatptr;
+        /* Write the first DAT page's magic number */
+        let dat_start = self.dat_start;
+        self.write_at(
+            self.geometry.page_offset(dat_start),
+            MAGIC,
+            Region::Magic,
+            format_args!("magic page {:02x}", dat_start),
+        )?;
+        if self.layout.magic == Magic::All {
+            for page in self.allocated.pages() {
+                if !self.layout.is_reserved(page) {
+                    self.write_magic(page)?;
+                }
+            }
+        }
+        let members = self.source().list("")?;
+        self.write_recursive("", members, &mut parent_id, &mut fatptr)?;
+        if let Some(path) = self.unlisted.iter().next() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Error: {} is in the file list but not in the model.", path),
+            ));
+        }
+        if let Some(cache) = self.cache.take() {
+            self.erase_stale(cache)?;
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        self.options
+            .placement
+            .order(&mut pending, |file| (file.hint.pin.is_some(), file.length));
+        for file in pending {
+            self.place(file)?;
+        }
+        // Writes are only buffered (or queued, with io_uring) until here.
+        self.rom.flush()?;
+
+        let (quot, rem) = div_rem(
+            fatptr_start - fatptr,
+            u32::from(self.geometry.page_length()),
+        );
+        // Given that pages are sufficiently large, it's safe to
+        // downgrade number size here.
+        let mut result: u16 = quot.try_into().unwrap();
+        if rem > 0 {
+            result += 1;
+        }
+        result <<= 8;
+        // The last data page opened
+        let last_page = *self.magic_pages.iter().next_back().unwrap();
+        // sectionId's high byte is a page number
+        result = if cfg!(feature = "c-undef") {
+            // C original has undefined behavior:  result += (sectionId >> 8) - dat_start + 1;
+            result.wrapping_add(u16::from(last_page).wrapping_sub(u16::from(self.dat_start))) + 1
+        } else {
+            // Safe version
+            result + u16::from(last_page) - u16::from(self.dat_start) + 1
+        };
+        Ok(result)
+    }
+    // Builds into a copy of the ROM in memory, laying out the whole
+    // filesystem (FAT entries, data sections and the pages they take) as
+    // the real build will, so a model that doesn't fit fails before the
+    // first byte of the ROM is written rather than leaving it half
+    // written.
+    fn rehearse(&mut self) -> Result<(), Error> {
+        let options = Options {
+            quiet: true,
+            trace_writes: None,
+            record_writes: None,
+            ..self.options.clone()
+        };
+        let rom = Cursor::new(self.read_back()?);
+        let mut rehearsal = Context::open(
+            self.rom_path.clone(),
+            self.model,
+            Some(self.source()),
+            rom,
+            options,
+        )?;
+        rehearsal.rehearsal = true;
+        rehearsal.cache = self.cache.clone();
+        rehearsal.filter = self.filter.clone();
+        rehearsal.unlisted = self.unlisted.clone();
+        rehearsal.blank_pages()?;
+        rehearsal.write_filesystem()?;
+        // What it read ahead, the build writes.
+        self.staged = std::mem::take(&mut rehearsal.staged);
+        Ok(())
+    }
+
+    // Blanks the pages `options.blanking` picks (by default every page
+    // from dat_start to fat_start), skipping pages that are already blank
+    // so rebuilding into a fresh ROM rewrites nothing.
+    fn blank_pages(&mut self) -> Result<(), Error> {
+        let page_length = usize::from(self.geometry.page_length());
+        let mut blank_page = vec![0xFF; page_length];
+        let mut current = vec![0; page_length];
+        self.rom.flush()?;
+        let pages = self.rom.get_mut().seek(SeekFrom::End(0))? / page_length as u64;
+        let blanked = match self
+            .options
+            .blanking
+            .pages(self.dat_start, self.fat_start, pages)
+        {
+            Some(blanked) => blanked,
+            None => return Ok(()),
+        };
+        // Every page is read before any is written, so the writes can be
+        // buffered together.
+        let mut stale = Vec::new();
+        for p in blanked {
+            // Data the cache says is still good stays put.
+            if self.layout.is_reserved(p) || (self.cache.is_some() && p <= self.dat_end()) {
+                continue;
+            }
+            blank_page[0] = if p <= self.dat_end() { b'K' } else { 0xFF };
+            let offset = self.geometry.page_offset(p);
+            let rom = self.rom.get_mut();
+            rom.seek(SeekFrom::Start(offset))?;
+            if rom.read_exact(&mut current).is_err() || current[..] != blank_page[..] {
+                stale.push(p);
+            }
+        }
+        for p in stale {
+            blank_page[0] = if p <= self.dat_end() { b'K' } else { 0xFF };
+            self.write_at(
+                self.geometry.page_offset(p),
+                &blank_page,
+                Region::Blank,
+                format_args!("blank page {:02x}", p),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Builds the filesystem. A ROM file is left either fully updated or,
+    /// if any step of the build fails, as it was.
+    pub fn run(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.build() {
+            return Err(match self.roll_back() {
+                Ok(()) => err,
+                Err(undo) => Error::new(
+                    err.kind(),
+                    format!(
+                        "{} Putting {} back failed too, so it is half written: {}",
+                        err,
+                        self.rom_path.display(),
+                        undo
+                    ),
+                ),
+            });
+        }
+        if !self.unreadable.is_empty() {
+            return Err(Error::other(format!(
+                "Error: {} model entries couldn't be read; the image was written without them.",
+                self.unreadable.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn build(&mut self) -> Result<(), Error> {
+        let started = Instant::now();
+        if self.source.is_none() {
+            let source = source::open_model(
+                self.model,
+                self.options.follow_dir_symlinks,
+                self.options.buffer_size,
+                self.options.symlinks.as_deref(),
+            )?;
+            self.source = Some(source.into());
+        }
+        let limits = model::Limits {
+            files: self.options.max_files,
+            total_size: self.options.max_total_size,
+        };
+        if limits != model::Limits::default() {
+            limits.check(&*self.source(), self.options.hidden)?;
+        }
+        if self.options.lint {
+            let entries = lint::entries(&*self.source())?;
+            for warning in lint::lint(&entries) {
+                self.warn("lint", None, warning);
+            }
+        }
+        if self.options.build_info {
+            let model_sha256 = build_info::model_sha256(&*self.source())?;
+            let info = build_info::contents(build_info::build_time()?, &model_sha256);
+            let source = WithBuildInfo::new(self.source(), info)?;
+            if source.replaces() {
+                let path = self.source().display(build_info::PATH);
+                let warning = format!("Replaced {} with the build info.", path);
+                self.warn("build-info-replaced", Some(&path), warning);
+            }
+            self.source = Some(Rc::new(source));
+        }
+        let budgets = match &self.options.budgets {
+            Some(path) => budget::read_budgets(path)?,
+            None => Vec::new(),
+        };
+        self.cache = self.load_cache()?;
+        if let Some(path) = &self.options.files_from {
+            let selection = model::read_selection(path)?;
+            self.unlisted = selection.listed.clone();
+            self.filter.selection = Some(selection);
+        }
+        if self.options.use_gitignore && self.model.is_dir() {
+            self.filter.ignored = model::git_ignored(self.model)?;
+        }
+        if self.preflight {
+            self.rehearse()?;
+        }
+        self.blank_pages()?;
+
+        let result = self.write_filesystem()?;
+        if self.options.integrity_page {
+            self.write_integrity()?;
+        }
+        if !budgets.is_empty() {
+            self.check_budgets(&budgets)?;
+        }
+        if self.options.verify {
+            self.verify()?;
+        }
+        let digest = hex(&sha256(&self.read_back()?));
+        if let Some(expected) = &self.options.expect_sha256 {
+            if !expected.eq_ignore_ascii_case(&digest) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "SHA-256 of {} is {}, not the expected {}.",
+                        self.rom_path.display(),
+                        digest,
+                        expected
+                    ),
+                ));
+            }
+        }
+        sayln!(
+            self,
+            "Filesystem successfully written to {}.",
+            self.rom_path.display()
+        );
+        say!(self, "Indexes of written data pages: ");
+        let [lo, hi] = result.to_le_bytes();
+        for page in (self.dat_start..).take(usize::from(lo)) {
+            if !self.layout.is_reserved(page) {
+                say!(self, "{:02x} ", page)
+            }
+        }
+        say!(self, "\nIndexes of written FAT pages: ");
+        for i in 0..u32::from(hi) {
+            say!(self, "{:02x} ", u32::from(self.fat_start) - i)
+        }
+        if self.options.mirror_fat {
+            say!(self, "\nIndexes of mirrored FAT pages: ");
+            for i in 0..u32::from(hi) {
+                say!(
+                    self,
+                    "{:02x} ",
+                    u32::from(self.fat_start - self.geometry.fat_pages()) - i
+                )
+            }
+        }
+        sayln!(
+            self,
+            "\nThe rest of the pages (except kernels' 00-03) are empty."
+        );
+        sayln!(self, "SHA-256 of the image: {}", digest);
+        self.write_artifacts(result, &digest)?;
+        self.write_cache()?;
+        if let Some(format) = self.options.stats_format {
+            self.write_stats(format, started.elapsed())?;
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.flush()?;
+        }
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write_stats(&mut self, format: StatsFormat, duration: Duration) -> Result<(), Error> {
+        let rom = self.read_back()?;
+        let image = Image::open_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        let map = AllocationMap::scan_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        let stats = Stats::collect(&image, &map, self.geometry, self.fat_start, duration);
+        let stdout = io::stdout();
+        stats::write_stats(&mut stdout.lock(), &stats, format)
+    }
+
+    fn check_budgets(&mut self, budgets: &[budget::Budget]) -> Result<(), Error> {
+        let rom = self.read_back()?;
+        let image = Image::open_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        let map = AllocationMap::scan_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        let exceeded = budget::check(&image, &map, budgets)?;
+        if exceeded.is_empty() {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Size budgets exceeded:\n  {}", exceeded.join("\n  ")),
+        ))
+    }
+
+    fn verify(&mut self) -> Result<(), Error> {
+        let rom = self.read_back()?;
+        let image = Image::open_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        // What couldn't be read isn't expected in the image either.
+        let mut filter = self.filter.clone();
+        filter.ignored.extend(self.unreadable.iter().cloned());
+        let problems = verify::verify(&image, &*self.source(), &self.options, &filter)?;
+        if !problems.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Read-back verification failed:\n  {}",
+                    problems.join("\n  ")
+                ),
+            ));
+        }
+        sayln!(self, "Verified the filesystem against the model.");
+        Ok(())
+    }
+
+    // Writes the sidecar files requested in the options, reading the
+    // finished filesystem back out of the ROM.
+    fn write_artifacts(&mut self, result: u16, digest: &str) -> Result<(), Error> {
+        let o = &self.options;
+        if o.checksums.is_none()
+            && o.manifest.is_none()
+            && o.report.is_none()
+            && o.addresses.is_none()
+            && o.symbols.is_none()
+        {
+            return Ok(());
+        }
+        let rom = self.read_back()?;
+        let o = &self.options;
+        let image = Image::open_with(&rom, self.geometry, self.dat_start, self.fat_start)?;
+        if let Some(path) = &o.checksums {
+            let sums = checksum::page_sums(&rom, self.geometry, self.dat_start..=self.fat_start)?;
+            let mut out = BufWriter::new(File::create(path)?);
+            checksum::write_sums(&mut out, &sums)?;
+            out.flush()?;
+            sayln!(self, "Page checksums written to {}.", path.display());
+        }
+        if let Some(path) = &o.manifest {
+            let mut out = BufWriter::new(File::create(path)?);
+            manifest::write_manifest(&mut out, &image)?;
+            out.flush()?;
+            sayln!(self, "Manifest written to {}.", path.display());
+        }
+        if let Some(path) = &o.addresses {
+            if path == Path::new("-") {
+                let stdout = io::stdout();
+                address::write_addresses(&mut stdout.lock(), &image)?;
+            } else {
+                let mut out = BufWriter::new(File::create(path)?);
+                address::write_addresses(&mut out, &image)?;
+                out.flush()?;
+                sayln!(self, "Flash addresses written to {}.", path.display());
+            }
+        }
+        if let Some(path) = &o.symbols {
+            let mut out = BufWriter::new(File::create(path)?);
+            address::write_symbols(&mut out, &image)?;
+            out.flush()?;
+            sayln!(self, "Debugger symbols written to {}.", path.display());
+        }
+        if let Some(path) = &o.report {
+            let report = self.report(&image, result, digest)?;
+            fs::write(path, format!("{:#}\n", report))?;
+            sayln!(self, "Build report written to {}.", path.display());
+        }
+        Ok(())
+    }
+
+    fn report(&self, image: &Image, result: u16, digest: &str) -> Result<json::Value, Error> {
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+        let mut files = Vec::new();
+        for node in image.nodes()? {
+            if let fat::Entry::File {
+                length, section_id, ..
+            } = node.record.entry
+            {
+                let sections: Vec<String> = image
+                    .chain(section_id, length)?
+                    .iter()
+                    .map(|(section_id, _)| format!("{:04x}", section_id))
+                    .collect();
+                files.push(json::Value::object(vec![
+                    ("path", node.path.into()),
+                    ("size", length.into()),
+                    ("sections", sections.into()),
+                ]));
+            }
+        }
+        let [lo, hi] = result.to_le_bytes();
+        Ok(json::Value::object(vec![
+            ("tool", env!("CARGO_PKG_NAME").into()),
+            ("version", env!("CARGO_PKG_VERSION").into()),
+            ("rom", self.rom_path.display().to_string().into()),
+            ("model", self.model.display().to_string().into()),
+            ("sha256", digest.into()),
+            (
+                "geometry",
+                json::Value::object(vec![
+                    ("page_length", self.geometry.page_length().into()),
+                    ("block_size", self.geometry.block_size().into()),
+                    ("fat_pages", self.geometry.fat_pages().into()),
+                    ("dat_start", self.dat_start.into()),
+                    ("fat_start", self.fat_start.into()),
+                ]),
+            ),
+            (
+                "options",
+                json::Value::object(vec![
+                    ("mirror_fat", self.options.mirror_fat.into()),
+                    ("checksums", path(&self.options.checksums).into()),
+                    ("manifest", path(&self.options.manifest).into()),
+                    ("lint", self.options.lint.into()),
+                    ("budgets", path(&self.options.budgets).into()),
+                    ("verify", self.options.verify.into()),
+                    ("trace_writes", path(&self.options.trace_writes).into()),
+                    (
+                        "ignore_kernel_geometry",
+                        self.options.ignore_kernel_geometry.into(),
+                    ),
+                    (
+                        "platform",
+                        self.options.platform.map(|p| p.to_string()).into(),
+                    ),
+                    ("record_writes", path(&self.options.record_writes).into()),
+                    ("placement", self.options.placement.to_string().into()),
+                    ("hints", path(&self.options.hints).into()),
+                    ("addresses", path(&self.options.addresses).into()),
+                    ("symbols", path(&self.options.symbols).into()),
+                    ("layout", path(&self.options.layout).into()),
+                    ("prune_empty", self.options.prune_empty.into()),
+                    ("hidden", self.options.hidden.to_string().into()),
+                    ("skip_too_large", self.options.skip_too_large.into()),
+                    (
+                        "follow_dir_symlinks",
+                        self.options.follow_dir_symlinks.into(),
+                    ),
+                    ("symlinks", path(&self.options.symlinks).into()),
+                    ("cache", path(&self.options.cache).into()),
+                    ("sort", self.options.sort.to_string().into()),
+                    ("expect_sha256", self.options.expect_sha256.clone().into()),
+                    ("instance", self.options.instance.clone().into()),
+                    ("files_from", path(&self.options.files_from).into()),
+                    ("use_gitignore", self.options.use_gitignore.into()),
+                    ("read_only", self.options.read_only.clone().into()),
+                    ("blanking", self.options.blanking.to_string().into()),
+                    (
+                        "offset",
+                        self.options.offset.map(|span| span.to_string()).into(),
+                    ),
+                    ("keep_going", self.options.keep_going.into()),
+                    ("max_files", self.options.max_files.into()),
+                    ("max_total_size", self.options.max_total_size.into()),
+                    (
+                        "buffer_size",
+                        self.options.buffer_size.map(|n| n as u64).into(),
+                    ),
+                    ("io_uring", self.options.io_uring.into()),
+                    ("chmod", self.options.chmod.into()),
+                    ("errors_json", self.options.errors_json.into()),
+                    ("paranoid", self.options.paranoid.into()),
+                    ("build_info", self.options.build_info.into()),
+                    ("integrity_page", self.options.integrity_page.into()),
+                    ("entry_checksums", self.options.entry_checksums.into()),
+                    ("compress", self.options.compress.into()),
+                    (
+                        "threads",
+                        self.options.threads.map(|n| n.get() as u64).into(),
+                    ),
+                ]),
+            ),
+            ("files", json::Value::Array(files)),
+            ("fragmentation", Fragmentation::analyze(image)?.to_json()),
+            ("warnings", self.warnings.clone().into()),
+            ("data_pages", lo.into()),
+            ("fat_pages", hi.into()),
+        ]))
+    }
+
+    /// The layout the filesystem is written with.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The pages data may be written to, between the kernel and the FAT
+    /// (or its mirror).
+    pub fn data_pages(&self) -> RangeInclusive<u8> {
+        self.dat_start..=self.dat_end()
+    }
+
+    /// First (topmost) FAT page.
+    pub fn fat_start(&self) -> u8 {
+        self.fat_start
+    }
+
+    /// The data blocks allocated by the last `run`.
+    pub fn page_map(&self) -> &PageMap {
+        &self.allocated
+    }
+
+    /// Problems that did not stop the build.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::image::Image;
+use crate::layout::Layout;
+use crate::random::Rng;
+use crate::section::SectionId;
+use crate::spec::{MAGIC, SECTION_HEADER_LENGTH};
+use crate::AllocationMap;
+
+/// The part of a filesystem `corrupt` damages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// The FAT entries in use.
+    Fat,
+    /// The data blocks in use.
+    Dat,
+    /// The magic numbers and section headers of the data pages in use.
+    Headers,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Target, String> {
+        match s {
+            "fat" => Ok(Target::Fat),
+            "dat" => Ok(Target::Dat),
+            "headers" => Ok(Target::Headers),
+            _ => Err(format!(
+                "Unknown target {} (expected fat, dat or headers).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Target::Fat => "FAT",
+            Target::Dat => "data",
+            Target::Headers => "section headers",
+        })
+    }
+}
+
+/// A flipped bit: the offset of its byte in the ROM, and which bit of
+/// it, 0 being the lowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Flip {
+    pub offset: u64,
+    pub bit: u8,
+}
+
+/// The byte ranges of `target` in the filesystem of `rom` laid out as
+/// `layout`, with its FAT at `fat_start`.
+pub fn regions(
+    rom: &[u8],
+    layout: &Layout,
+    fat_start: u8,
+    target: Target,
+) -> Result<Vec<Range<u64>>, Error> {
+    let image = Image::open_with(rom, layout.geometry, layout.dat_start, fat_start)?;
+    let geometry = image.geometry;
+    let map = AllocationMap::scan_with(rom, geometry, image.dat_start, image.fat_start)?;
+    let mut regions = Vec::new();
+    if target == Target::Fat {
+        regions.push(u64::from(map.fat_ptr())..u64::from(geometry.fat_end(image.fat_start)));
+        return Ok(regions);
+    }
+    for page in map.pages() {
+        if page != image.dat_start && map.page_map().used_in(page) == 0 {
+            continue;
+        }
+        if target == Target::Headers {
+            let magic = geometry.page_offset(page);
+            regions.push(magic..magic + MAGIC.len() as u64 + 1);
+        }
+        for index in 1..=geometry.max_index() {
+            let id = SectionId::new(page, index).unwrap();
+            if !map.is_used(id.into()) {
+                continue;
+            }
+            let (start, length) = match target {
+                Target::Headers => (geometry.header_offset(id), SECTION_HEADER_LENGTH),
+                _ => (geometry.block_offset(id), geometry.block_size()),
+            };
+            regions.push(start..start + u64::from(length));
+        }
+    }
+    Ok(regions)
+}
+
+/// Flips `count` different bits of `target` in `rom`, picked with
+/// `rng`, returning them in order.
+pub fn corrupt(
+    rom: &mut [u8],
+    layout: &Layout,
+    fat_start: u8,
+    target: Target,
+    count: u64,
+    rng: &mut Rng,
+) -> Result<Vec<Flip>, Error> {
+    let regions = regions(rom, layout, fat_start, target)?;
+    let bits: u64 = regions
+        .iter()
+        .map(|region| (region.end - region.start) * 8)
+        .sum();
+    if count > bits {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "The {} has only {} bits, so {} can't be flipped.",
+                target, bits, count
+            ),
+        ));
+    }
+    let mut picked = BTreeSet::new();
+    while (picked.len() as u64) < count {
+        picked.insert(rng.below(bits));
+    }
+    // In order, so the regions are walked once to find them all.
+    let mut flips = Vec::with_capacity(picked.len());
+    let mut regions = regions.iter();
+    let mut region = regions.next();
+    let mut skipped = 0;
+    for bit in picked {
+        while let Some(range) = region {
+            let length = (range.end - range.start) * 8;
+            if bit < skipped + length {
+                break;
+            }
+            skipped += length;
+            region = regions.next();
+        }
+        let range = region.expect("every picked bit is in a region");
+        let flip = Flip {
+            offset: range.start + (bit - skipped) / 8,
+            bit: ((bit - skipped) % 8) as u8,
+        };
+        rom[flip.offset as usize] ^= 1 << flip.bit;
+        flips.push(flip);
+    }
+    Ok(flips)
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read};
+
+use crate::model::{insert, Tree};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+fn invalid(what: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid cpio archive: {}.", what),
+    )
+}
+
+// Read without allocating `len` bytes up front, as a bad header can ask
+// for far more than the archive holds.
+fn read_exact<R: Read>(input: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    input.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len {
+        return Err(invalid("archive ends inside a member".to_string()));
+    }
+    Ok(buf)
+}
+
+fn ascii_field(field: &[u8], radix: u32) -> Result<u32, Error> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|s| u32::from_str_radix(s, radix).ok())
+        .ok_or_else(|| {
+            invalid(format!(
+                "bad header field {:?}",
+                String::from_utf8_lossy(field)
+            ))
+        })
+}
+
+// Mode, name size and file size of the next member, along with how its
+// name and data are padded.
+struct Header {
+    mode: u32,
+    namesize: usize,
+    filesize: usize,
+    align: usize,
+}
+
+fn read_header<R: Read>(input: &mut R) -> Result<Header, Error> {
+    let magic = read_exact(input, 6)?;
+    match &magic[..] {
+        // SVR4 "newc", with or without checksums.
+        b"070701" | b"070702" => {
+            let rest = read_exact(input, 104)?;
+            let field = |i: usize| ascii_field(&rest[i * 8..][..8], 16);
+            Ok(Header {
+                mode: field(1)?,
+                namesize: field(11)? as usize,
+                filesize: field(6)? as usize,
+                align: 4,
+            })
+        }
+        // POSIX.1 portable "odc".
+        b"070707" => {
+            let rest = read_exact(input, 70)?;
+            Ok(Header {
+                mode: ascii_field(&rest[12..18], 8)?,
+                namesize: ascii_field(&rest[53..59], 8)? as usize,
+                filesize: ascii_field(&rest[59..70], 8)? as usize,
+                align: 1,
+            })
+        }
+        // The old binary format, as written by `cpio -o` by default.
+        [0xC7, 0x71, ..] | [0x71, 0xC7, ..] => {
+            let rest = read_exact(input, 20)?;
+            let header: Vec<u8> = magic.iter().chain(rest.iter()).copied().collect();
+            let word = |i: usize| {
+                let bytes = [header[i * 2], header[i * 2 + 1]];
+                u32::from(if magic[0] == 0xC7 {
+                    u16::from_le_bytes(bytes)
+                } else {
+                    u16::from_be_bytes(bytes)
+                })
+            };
+            Ok(Header {
+                mode: word(3),
+                namesize: word(10) as usize,
+                filesize: ((word(11) << 16) | word(12)) as usize,
+                align: 2,
+            })
+        }
+        _ => Err(invalid("unknown header magic".to_string())),
+    }
+}
+
+fn pad<R: Read>(input: &mut R, offset: usize, align: usize) -> Result<(), Error> {
+    read_exact(input, (align - offset % align) % align).map(|_| ())
+}
+
+/// Reads a cpio archive (newc, odc or old binary) into a model tree.
+pub fn read_cpio<R: Read>(input: &mut R) -> Result<BTreeMap<String, Tree>, Error> {
+    let mut root = BTreeMap::new();
+    loop {
+        let header = read_header(input)?;
+        // newc pads the 110 byte header and name together; binary pads
+        // the 26 byte header and name together.
+        let header_len = match header.align {
+            4 => 110,
+            2 => 26,
+            _ => 76,
+        };
+        let name = read_exact(input, header.namesize)?;
+        pad(input, header_len + header.namesize, header.align)?;
+        let name = String::from_utf8(name[..header.namesize.saturating_sub(1)].to_vec())
+            .map_err(|_| invalid("member name is not valid UTF-8".to_string()))?;
+        if name == "TRAILER!!!" {
+            return Ok(root);
+        }
+        let data = read_exact(input, header.filesize)?;
+        pad(input, header.filesize, header.align)?;
+        let node = match header.mode & S_IFMT {
+            S_IFDIR => Tree::Directory(BTreeMap::new()),
+            S_IFREG => Tree::File(data),
+            S_IFLNK => Tree::Symlink(
+                String::from_utf8(data)
+                    .map_err(|_| invalid(format!("link target of {} is not valid UTF-8", name)))?,
+            ),
+            _ => return Err(invalid(format!("{} is a special file", name))),
+        };
+        insert(&mut root, &name, node)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A newc member, padded as cpio writes it.
+    fn newc(out: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        out.extend_from_slice(b"070701");
+        for field in &fields {
+            out.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().div_ceil(4) * 4, 0);
+        out.extend_from_slice(data);
+        out.resize(out.len().div_ceil(4) * 4, 0);
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut out = Vec::new();
+        newc(&mut out, "bin", S_IFDIR | 0o755, b"");
+        newc(&mut out, "bin/init", S_IFREG | 0o755, b"init");
+        newc(&mut out, "init", S_IFLNK | 0o777, b"bin/init");
+        newc(&mut out, "TRAILER!!!", 0, b"");
+        out
+    }
+
+    #[test]
+    fn reads_newc_archives() {
+        let root = read_cpio(&mut &archive()[..]).unwrap();
+        let mut expected = BTreeMap::new();
+        insert(&mut expected, "bin/init", Tree::File(b"init".to_vec())).unwrap();
+        insert(&mut expected, "init", Tree::Symlink("bin/init".to_string())).unwrap();
+        assert_eq!(root, expected);
+    }
+
+    #[test]
+    fn reads_odc_archives() {
+        let mut out = Vec::new();
+        for (name, mode, data) in [
+            ("motd", S_IFREG | 0o644, &b"hello"[..]),
+            ("TRAILER!!!", 0, b""),
+        ] {
+            out.extend_from_slice(b"070707");
+            out.extend_from_slice(format!("{:012o}{:06o}", 0, mode).as_bytes());
+            out.extend_from_slice(format!("{:035o}", 0).as_bytes());
+            out.extend_from_slice(format!("{:06o}{:011o}", name.len() + 1, data.len()).as_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(data);
+        }
+        let root = read_cpio(&mut &out[..]).unwrap();
+        assert_eq!(root["motd"], Tree::File(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let archive = archive();
+        assert!(read_cpio(&mut &archive[..50]).is_err());
+        // Without its trailer.
+        assert!(read_cpio(&mut &archive[..archive.len() - 124]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut magic = archive();
+        magic[0] = b'1';
+        assert!(read_cpio(&mut &magic[..]).is_err());
+        // The mode field, not in hex.
+        let mut mode = archive();
+        mode[6 + 8..6 + 16].copy_from_slice(b"0000G1ED");
+        assert!(read_cpio(&mut &mode[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_end() {
+        let mut out = Vec::new();
+        newc(&mut out, "motd", S_IFREG | 0o644, b"hello");
+        // The file size field.
+        out[6 + 6 * 8..6 + 7 * 8].copy_from_slice(b"FFFFFFFF");
+        assert!(read_cpio(&mut &out[..]).is_err());
+        let mut out = Vec::new();
+        newc(&mut out, "motd", S_IFREG | 0o644, b"hello");
+        // The name size field.
+        out[6 + 11 * 8..6 + 12 * 8].copy_from_slice(b"FFFFFFFF");
+        assert!(read_cpio(&mut &out[..]).is_err());
+    }
+}
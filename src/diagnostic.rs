@@ -0,0 +1,149 @@
+use std::io::Error;
+
+use crate::json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A warning or error as `--errors-json` prints it: one JSON object per
+/// line on stderr, such as
+///
+/// ```text
+/// {"severity":"warning","code":"file-too-large","path":"model/bin/big","message":"Skipped model/bin/big, which is larger than the maximum file size."}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What went wrong, in kebab-case; for errors, the `io::ErrorKind`.
+    pub code: String,
+    /// The file it is about, if it's about one.
+    pub path: Option<String>,
+    pub message: String,
+}
+
+// `NotFound` as `not-found`.
+fn kebab_case(name: &str) -> String {
+    let mut kebab = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && !kebab.is_empty() {
+            kebab.push('-');
+        }
+        kebab.push(c.to_ascii_lowercase());
+    }
+    kebab
+}
+
+impl Diagnostic {
+    pub fn warning(code: &str, path: Option<&str>, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: code.to_string(),
+            path: path.map(str::to_string),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn error(err: &Error) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: kebab_case(&format!("{:?}", err.kind())),
+            path: None,
+            message: err.get_ref().map_or(err.to_string(), |e| e.to_string()),
+        }
+    }
+
+    /// The diagnostic as an object, without the `Error: ` most error
+    /// messages start with.
+    pub fn to_json(&self) -> Value {
+        let message = self.message.strip_prefix("Error: ");
+        Value::object(vec![
+            ("severity", self.severity.name().into()),
+            ("code", self.code.as_str().into()),
+            ("path", self.path.clone().into()),
+            ("message", message.unwrap_or(&self.message).into()),
+        ])
+    }
+
+    /// Prints the diagnostic on stderr: as JSON with `json`, or else as
+    /// the tool always has.
+    pub fn print(&self, json: bool) {
+        match (json, self.severity) {
+            (true, _) => eprintln!("{}", self.to_json()),
+            (false, Severity::Warning) => eprintln!("Warning: {}", self.message),
+            (false, Severity::Error) => eprintln!("{}", self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn error_kinds_are_codes() {
+        assert_eq!(kebab_case("NotFound"), "not-found");
+        assert_eq!(kebab_case("InvalidData"), "invalid-data");
+        assert_eq!(kebab_case("Other"), "other");
+        let err = Error::new(ErrorKind::PermissionDenied, "Error: cannot open x.");
+        assert_eq!(
+            Diagnostic::error(&err),
+            Diagnostic {
+                severity: Severity::Error,
+                code: "permission-denied".to_string(),
+                path: None,
+                message: "Error: cannot open x.".to_string(),
+            }
+        );
+        // An error without a message of its own says what its kind is.
+        let err = Diagnostic::error(&Error::from(ErrorKind::UnexpectedEof));
+        assert_eq!(err.code, "unexpected-eof");
+        assert_eq!(
+            err.message,
+            Error::from(ErrorKind::UnexpectedEof).to_string()
+        );
+    }
+
+    #[test]
+    fn errors_drop_their_prefix_in_json() {
+        let err = Error::new(
+            ErrorKind::InvalidData,
+            "Error: The model holds more than 1 files.",
+        );
+        assert_eq!(
+            Diagnostic::error(&err).to_json().to_string(),
+            "{\"severity\":\"error\",\"code\":\"invalid-data\",\"path\":null,\
+             \"message\":\"The model holds more than 1 files.\"}"
+        );
+    }
+
+    #[test]
+    fn warnings_carry_their_path() {
+        let warning = Diagnostic::warning(
+            "file-too-large",
+            Some("model/bin/\"big\""),
+            "Skipped model/bin/\"big\",\nwhich is too large.",
+        );
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(
+            warning.to_json().to_string(),
+            "{\"severity\":\"warning\",\"code\":\"file-too-large\",\
+             \"path\":\"model/bin/\\\"big\\\"\",\
+             \"message\":\"Skipped model/bin/\\\"big\\\",\\nwhich is too large.\"}"
+        );
+        let warning = Diagnostic::warning("lint", None, "Missing /bin/init.");
+        assert!(warning.to_json().to_string().contains("\"path\":null"));
+    }
+}
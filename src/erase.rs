@@ -0,0 +1,220 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+
+use crate::layout::{Layout, Magic};
+use crate::spec::{version_byte, MAGIC};
+use crate::*;
+
+/// Which pages a build blanks before writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Blanking {
+    /// The data and FAT pages, as genkfs does.
+    #[default]
+    Filesystem,
+    /// None: the pages are trusted to be erased already, as in a fresh
+    /// image, which saves reading them all.
+    None,
+    /// The filesystem's pages and those above the FAT, up to the
+    /// kernel's privileged sector and boot page at the top of the ROM.
+    All,
+}
+
+/// Pages at the top of a ROM holding the kernel's privileged sector and
+/// boot page, which blanking never touches.
+pub const KERNEL_TOP_PAGES: u8 = 4;
+
+impl fmt::Display for Blanking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Blanking::Filesystem => "filesystem",
+            Blanking::None => "none",
+            Blanking::All => "all",
+        })
+    }
+}
+
+impl Blanking {
+    /// The pages blanked in a ROM of `pages` pages whose filesystem spans
+    /// `dat_start` to `fat_start`, if any.
+    pub fn pages(self, dat_start: u8, fat_start: u8, pages: u64) -> Option<RangeInclusive<u8>> {
+        match self {
+            Blanking::Filesystem => Some(dat_start..=fat_start),
+            Blanking::None => None,
+            Blanking::All => {
+                let below_kernel = pages.saturating_sub(u64::from(KERNEL_TOP_PAGES) + 1);
+                let last = u8::try_from(below_kernel).unwrap_or(u8::MAX).max(fat_start);
+                Some(dat_start..=last)
+            }
+        }
+    }
+}
+
+/// Erases `pages` of a ROM image to 0xFF, as flash reads after a sector
+/// erase.
+pub fn erase(rom: &mut [u8], geometry: Geometry, pages: RangeInclusive<u8>) -> Result<(), Error> {
+    let start = geometry.page_offset(*pages.start()) as usize;
+    let end = geometry.page_offset(*pages.end()) as usize + usize::from(geometry.page_length());
+    let range = rom.get_mut(start..end).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Page {:02x} is past the end of the ROM.", pages.end()),
+        )
+    })?;
+    range.fill(0xFF);
+    Ok(())
+}
+
+/// Erases the data and FAT pages of the filesystem `layout` puts in a
+/// ROM image, whose FAT starts at `fat_start`, leaving the pages the
+/// layout reserves as they are.
+pub fn strip(rom: &mut [u8], layout: &Layout, fat_start: u8) -> Result<(), Error> {
+    for page in (layout.dat_start..=fat_start).filter(|&page| !layout.is_reserved(page)) {
+        erase(rom, layout.geometry, page..=page)?;
+    }
+    Ok(())
+}
+
+/// Writes an empty filesystem into a ROM image where `layout` puts it:
+/// strips it, marks each data page as genkfs blanks it and gives the
+/// first one its magic number, or every one with `Magic::All`. Reserved
+/// pages are left alone and the FAT without entries. With `mirror_fat`
+/// the pages below the FAT are kept for its copy.
+pub fn format(
+    rom: &mut [u8],
+    layout: &Layout,
+    fat_start: u8,
+    mirror_fat: bool,
+) -> Result<(), Error> {
+    let (geometry, dat_start) = (layout.geometry, layout.dat_start);
+    let fat_pages = if mirror_fat { 2 } else { 1 } * geometry.fat_pages();
+    let dat_end = fat_start
+        .checked_sub(fat_pages)
+        .filter(|&dat_end| dat_end >= dat_start)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "ROM is too small for a filesystem."))?;
+    strip(rom, layout, fat_start)?;
+    for page in (dat_start..=dat_end).filter(|&page| !layout.is_reserved(page)) {
+        let start = geometry.page_offset(page) as usize;
+        if page == dat_start || layout.magic == Magic::All {
+            rom[start..start + 3].copy_from_slice(MAGIC);
+            rom[start + 3] = version_byte(KFS_VERSION);
+        } else {
+            rom[start] = b'K';
+        }
+    }
+    Ok(())
+}
+
+fn parse_page(s: &str) -> Result<u8, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("{} is not a page number.", s))
+}
+
+/// Parses a page, such as `0x10`, or a range of them: `0x10..0x14` for
+/// pages 10 to 13, or `0x10..=0x14` to include 14 too.
+pub fn parse_pages(s: &str) -> Result<RangeInclusive<u8>, String> {
+    let range = if let Some((start, end)) = s.split_once("..=") {
+        parse_page(start)?..=parse_page(end)?
+    } else if let Some((start, end)) = s.split_once("..") {
+        let (start, end) = (parse_page(start)?, parse_page(end)?);
+        if end <= start {
+            return Err(format!("{} is an empty range of pages.", s));
+        }
+        start..=end - 1
+    } else {
+        let page = parse_page(s)?;
+        page..=page
+    };
+    if range.is_empty() {
+        return Err(format!("{} is an empty range of pages.", s));
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_and_ranges_parse() {
+        assert_eq!(parse_pages("0x10"), Ok(0x10..=0x10));
+        assert_eq!(parse_pages("16"), Ok(0x10..=0x10));
+        assert_eq!(parse_pages("0x10..0x14"), Ok(0x10..=0x13));
+        assert_eq!(parse_pages("0x10..=0x14"), Ok(0x10..=0x14));
+        assert_eq!(parse_pages("0x14..=0x14"), Ok(0x14..=0x14));
+        assert_eq!(parse_pages("0..=0xff"), Ok(0x00..=0xFF));
+        assert_eq!(parse_pages("0xfe..0xff"), Ok(0xFE..=0xFE));
+    }
+
+    #[test]
+    fn malformed_pages_are_errors() {
+        for s in &[
+            "",
+            "0x",
+            "ten",
+            "0x1g",
+            "-1",
+            "0x10..",
+            "..0x10",
+            "0x10...0x12",
+        ] {
+            let err = parse_pages(s).unwrap_err();
+            assert!(err.ends_with("is not a page number."), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn pages_past_ff_are_errors() {
+        assert_eq!(
+            parse_pages("0x100"),
+            Err("0x100 is not a page number.".to_string())
+        );
+        assert_eq!(
+            parse_pages("256"),
+            Err("256 is not a page number.".to_string())
+        );
+        assert!(parse_pages("0x10..=0x100").is_err());
+        // An exclusive range can't reach page ff's successor either.
+        assert!(parse_pages("0xf0..0x100").is_err());
+    }
+
+    #[test]
+    fn empty_ranges_are_errors() {
+        for s in &["0x14..0x14", "0x14..0x10", "0x14..=0x10"] {
+            assert_eq!(
+                parse_pages(s),
+                Err(format!("{} is an empty range of pages.", s))
+            );
+        }
+    }
+
+    #[test]
+    fn erasing_past_the_end_is_an_error() {
+        let geometry = Geometry::default();
+        let mut rom = vec![0; 4 * usize::from(geometry.page_length())];
+        erase(&mut rom, geometry, 0x01..=0x02).unwrap();
+        let page = usize::from(geometry.page_length());
+        assert!(rom[..page].iter().all(|&b| b == 0));
+        assert!(rom[page..3 * page].iter().all(|&b| b == 0xFF));
+        assert!(rom[3 * page..].iter().all(|&b| b == 0));
+        let err = erase(&mut rom, geometry, 0x03..=0x04).unwrap_err();
+        assert_eq!(err.to_string(), "Page 04 is past the end of the ROM.");
+    }
+
+    #[test]
+    fn blanking_all_stops_below_the_kernel() {
+        assert_eq!(
+            Blanking::Filesystem.pages(0x04, 0x18, 32),
+            Some(0x04..=0x18)
+        );
+        assert_eq!(Blanking::None.pages(0x04, 0x18, 32), None);
+        assert_eq!(Blanking::All.pages(0x04, 0x18, 32), Some(0x04..=0x1B));
+        // Never below the FAT, nor past page ff.
+        assert_eq!(Blanking::All.pages(0x04, 0x18, 8), Some(0x04..=0x18));
+        assert_eq!(Blanking::All.pages(0x04, 0x18, 1024), Some(0x04..=0xFF));
+    }
+}
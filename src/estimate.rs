@@ -0,0 +1,103 @@
+use std::io::Error;
+use std::path::Path;
+
+use crate::context::{Context, Options};
+use crate::layout::{self, Layout};
+use crate::scan::AllocationMap;
+use crate::*;
+
+/// What a model needs of a ROM, found by building it into the largest
+/// ROM there is, so every option that changes the layout counts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    /// FAT entries, and the bytes they take.
+    pub entries: usize,
+    pub fat_bytes: u32,
+    /// Bytes the FAT has room for.
+    pub fat_length: u32,
+    pub blocks: usize,
+    pub block_size: u16,
+    /// Data pages holding any block, and the number of pages from the
+    /// first data page to the last one used.
+    pub pages_used: usize,
+    pub pages_spanned: usize,
+    /// Pages in the smallest ROM that holds the filesystem, kernel pages
+    /// included.
+    pub rom_pages: usize,
+    pub page_length: u16,
+}
+
+impl Estimate {
+    /// The smallest ROM, in bytes.
+    pub fn rom_length(&self) -> u64 {
+        self.rom_pages as u64 * u64::from(self.page_length)
+    }
+}
+
+// Page indices are a byte, so no ROM has more pages than this.
+const MAX_PAGES: usize = 0x100;
+
+/// Estimates what writing `model` with `options` takes. `options.cache`
+/// and the sidecar outputs are ignored.
+pub fn estimate(model: &Path, options: &Options) -> Result<Estimate, Error> {
+    let page_length = match &options.layout {
+        Some(path) => {
+            let layouts = layout::read_layouts(path)?;
+            let instance = options.instance.as_deref().unwrap_or_default();
+            layouts
+                .iter()
+                .find(|(name, _)| name == instance)
+                .map_or(Layout::default().geometry, |(_, layout)| layout.geometry)
+                .page_length()
+        }
+        None => PAGE_LENGTH,
+    };
+    let options = Options {
+        quiet: true,
+        output: None,
+        checksums: None,
+        manifest: None,
+        report: None,
+        trace_writes: None,
+        record_writes: None,
+        addresses: None,
+        symbols: None,
+        cache: None,
+        stats_format: None,
+        expect_sha256: None,
+        ..options.clone()
+    };
+    let rom = vec![0xFF; MAX_PAGES * usize::from(page_length)];
+    let mut context = Context::in_memory(rom, model, options)?;
+    context.run()?;
+    let geometry = context.layout().geometry;
+    let data_pages = context.data_pages();
+    let fat_start = context.fat_start();
+    let fixed_fat = context.layout().fat_start.is_some();
+    let rom = context.into_output()?.into_inner();
+    let map = AllocationMap::scan_with(&rom, geometry, *data_pages.start(), fat_start)?;
+    let used: Vec<u8> = map
+        .pages()
+        .filter(|&page| map.page_map().used_in(page) > 0)
+        .collect();
+    // The first data page always holds the magic number, used or not.
+    let last = used.last().copied().unwrap_or(*data_pages.start());
+    let rom_pages = if fixed_fat {
+        usize::from(fat_start) + 1
+    } else {
+        // Pages above the last data page: the FAT, any mirror and the
+        // kernel's pages at the top stay the same size in any ROM.
+        usize::from(last) + MAX_PAGES - usize::from(*data_pages.end())
+    };
+    Ok(Estimate {
+        entries: map.records().len(),
+        fat_bytes: geometry.fat_end(fat_start) - map.fat_ptr(),
+        fat_length: geometry.fat_length(),
+        blocks: map.used_blocks(),
+        block_size: geometry.block_size(),
+        pages_used: used.len(),
+        pages_spanned: usize::from(last - data_pages.start()) + 1,
+        rom_pages,
+        page_length,
+    })
+}
@@ -0,0 +1,281 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::fat::Entry;
+use crate::glob::Filter;
+use crate::image::{Image, Node};
+use crate::*;
+
+/// Normalizes a filesystem path to the `/a/b` form used by `Node::path`,
+/// with the root being the empty string.
+pub fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .fold(String::new(), |acc, part| acc + "/" + part)
+}
+
+/// The nodes at or below `path`, along with the path of each relative
+/// to the parent of `path`.
+pub fn select(image: &Image, path: &str) -> Result<Vec<(String, Node)>, Error> {
+    let path = normalize(path);
+    // Everything is kept relative to the selected entry's parent so that
+    // extracting /lib/libc yields libc/...
+    let base = match path.rfind('/') {
+        Some(i) => &path[..i],
+        None => "",
+    };
+    let selected: Vec<(String, Node)> = image
+        .nodes()?
+        .into_iter()
+        .filter(|node| {
+            path.is_empty()
+                || node.path == path
+                || (node.path.starts_with(&path) && node.path[path.len()..].starts_with('/'))
+        })
+        .map(|node| (node.path[base.len() + 1..].to_string(), node))
+        .collect();
+    if selected.is_empty() && !path.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} is not in the filesystem.", path),
+        ));
+    }
+    Ok(selected)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, path: &Path) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        format!("Cannot create symlink {} on this platform.", path.display()),
+    ))
+}
+
+/// How entry flags are carried over to extracted files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Permissions {
+    /// Leave permissions to the host's defaults.
+    #[default]
+    None,
+    /// Map the executable and read-only flags onto the host's permission
+    /// bits. The hidden flag has no host equivalent and is ignored.
+    Flags,
+}
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Permissions, String> {
+        match s {
+            "none" => Ok(Permissions::None),
+            "flags" => Ok(Permissions::Flags),
+            _ => Err(format!(
+                "Unknown permission mapping {} (expected none or flags).",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Only entries this keeps are extracted.
+    pub filter: Filter,
+    pub permissions: Permissions,
+    /// Don't print progress to stdout.
+    pub quiet: bool,
+}
+
+/// Host permission bits for an entry under `permissions`.
+pub fn mode(permissions: Permissions, flags: u8, is_dir: bool) -> u32 {
+    let mode =
+        if is_dir || (permissions == Permissions::Flags && has_flag(flags, KFS_FLAG_EXECUTABLE)) {
+            0o755
+        } else {
+            0o644
+        };
+    if permissions == Permissions::Flags && has_flag(flags, KFS_FLAG_READ_ONLY) {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+/// Somewhere extracted entries are written to. Paths are relative and
+/// `/` separated, and parents always come before their children.
+pub trait Sink {
+    fn directory(&mut self, path: &str, flags: u8) -> Result<(), Error>;
+    fn file(&mut self, path: &str, flags: u8, data: &[u8]) -> Result<(), Error>;
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), Error>;
+    /// Called once every entry has been written.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Extracts into a directory on the host.
+pub struct HostSink<'a> {
+    to: &'a Path,
+    permissions: Permissions,
+    written: Vec<(PathBuf, u8, bool)>,
+}
+
+impl<'a> HostSink<'a> {
+    pub fn new(to: &'a Path, permissions: Permissions) -> Result<HostSink<'a>, Error> {
+        fs::create_dir_all(to)?;
+        Ok(HostSink {
+            to,
+            permissions,
+            written: Vec::new(),
+        })
+    }
+
+    // Where `path` goes below `to`, making its parents on the way. An
+    // earlier entry may have been a symlink pointing anywhere on the
+    // host, so no parent may be one.
+    fn dest(&self, path: &str) -> Result<PathBuf, Error> {
+        let mut dest = self.to.to_path_buf();
+        let mut parts = path.split('/').peekable();
+        while let Some(part) = parts.next() {
+            dest.push(part);
+            if parts.peek().is_some() {
+                not_through_symlink(&dest, path)?;
+                match fs::create_dir(&dest) {
+                    Err(err) if err.kind() != ErrorKind::AlreadyExists => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+        Ok(dest)
+    }
+}
+
+// Fails if `dest`, where the entry at `path` is to go (or one of its
+// parents), is a symlink that writing would follow.
+fn not_through_symlink(dest: &Path, path: &str) -> Result<(), Error> {
+    if dest
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Refusing to extract {} through the symlink {}.",
+                path,
+                dest.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Whether `relative` is safe to put below the directory extracted to:
+// each part a plain name, with nothing that climbs out of it, makes it
+// absolute or is a separator on some host.
+fn is_safe(relative: &str) -> bool {
+    relative
+        .split('/')
+        .all(|part| !part.is_empty() && part != "." && part != ".." && !part.contains(['\\', '\0']))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+impl<'a> Sink for HostSink<'a> {
+    fn directory(&mut self, path: &str, flags: u8) -> Result<(), Error> {
+        let dest = self.dest(path)?;
+        not_through_symlink(&dest, path)?;
+        fs::create_dir_all(&dest)?;
+        self.written.push((dest, flags, true));
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str, flags: u8, data: &[u8]) -> Result<(), Error> {
+        let dest = self.dest(path)?;
+        not_through_symlink(&dest, path)?;
+        fs::write(&dest, data)?;
+        self.written.push((dest, flags, false));
+        Ok(())
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), Error> {
+        let dest = self.dest(path)?;
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(&dest)?;
+        }
+        symlink(target, &dest)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.permissions == Permissions::None {
+            return Ok(());
+        }
+        // Children first, so read-only directories don't get in the way.
+        for (dest, flags, is_dir) in self.written.iter().rev() {
+            set_mode(dest, mode(self.permissions, *flags, *is_dir))?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the entry at `path` (a file, symlink or whole directory)
+/// into `sink`. Returns the number of entries written.
+pub fn extract_to<S: Sink>(
+    image: &Image,
+    path: &str,
+    sink: &mut S,
+    options: &ExtractOptions,
+) -> Result<usize, Error> {
+    let selected: Vec<(String, Node)> = select(image, path)?
+        .into_iter()
+        .filter(|(_, node)| options.filter.keeps(&node.path))
+        .collect();
+    for (relative, node) in &selected {
+        if !is_safe(relative) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Refusing to extract unsafe path {}.", node.path),
+            ));
+        }
+        if !options.quiet {
+            eprintln!("Extracting {}...", node.path);
+        }
+        match &node.record.entry {
+            Entry::Directory { flags, .. } => sink.directory(relative, *flags)?,
+            entry @ Entry::File { flags, .. } => {
+                sink.file(relative, *flags, &image.read_file(entry)?)?
+            }
+            Entry::Symlink { target, .. } => sink.symlink(relative, target)?,
+        }
+    }
+    sink.finish()?;
+    Ok(selected.len())
+}
+
+/// Extracts the entry at `path` into the host directory `to`.
+pub fn extract(
+    image: &Image,
+    path: &str,
+    to: &Path,
+    options: &ExtractOptions,
+) -> Result<usize, Error> {
+    let mut sink = HostSink::new(to, options.permissions)?;
+    extract_to(image, path, &mut sink, options)
+}
@@ -0,0 +1,484 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+
+use crate::spec::entry;
+use crate::*;
+
+/// A FAT entry. In the ROM each entry is stored byte-reversed, growing
+/// down from the top of the FAT; `encode` and `decode` work in file
+/// order, i.e. starting with the type id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    File {
+        parent: u16,
+        flags: u8,
+        length: u32,
+        section_id: u16,
+        name: String,
+    },
+    Directory {
+        parent: u16,
+        id: u16,
+        flags: u8,
+        name: String,
+    },
+    Symlink {
+        parent: u16,
+        name: String,
+        target: String,
+    },
+}
+
+impl Entry {
+    pub fn parent(&self) -> u16 {
+        match self {
+            Entry::File { parent, .. }
+            | Entry::Directory { parent, .. }
+            | Entry::Symlink { parent, .. } => *parent,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Entry::File { name, .. }
+            | Entry::Directory { name, .. }
+            | Entry::Symlink { name, .. } => name,
+        }
+    }
+
+    /// Encodes the entry in file order: the type id, the length of what
+    /// follows it (16 bits), then the type-specific fields, all little
+    /// endian.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let too_long = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Filename too long: {}", self.name()),
+            )
+        };
+        let mut bytes = vec![0, 0, 0];
+        bytes.extend_from_slice(&self.parent().to_le_bytes());
+        match self {
+            Entry::File {
+                flags,
+                length,
+                section_id,
+                name,
+                ..
+            } => {
+                bytes[0] = KFS_FILE_ID;
+                if u64::from(*length) > KFS_MAX_FILE_LEN {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} is larger than the maximum file size.", name),
+                    ));
+                }
+                bytes.push(*flags);
+                bytes.extend_from_slice(&length.to_le_bytes()[..3]);
+                bytes.extend_from_slice(&section_id.to_le_bytes());
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+            }
+            Entry::Directory {
+                id, flags, name, ..
+            } => {
+                bytes[0] = KFS_DIR_ID;
+                bytes.extend_from_slice(&id.to_le_bytes());
+                bytes.push(*flags);
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+            }
+            Entry::Symlink { name, target, .. } => {
+                bytes[0] = KFS_SYM_ID;
+                // Offset of the target from the start of the name.
+                let offset: u8 = (name.len() + 1).try_into().map_err(|_| too_long())?;
+                bytes.push(offset);
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+                bytes.extend_from_slice(target.as_bytes());
+                bytes.push(0);
+            }
+        }
+        let elen: u16 = (bytes.len() - 3).try_into().map_err(|_| too_long())?;
+        bytes[1..=2].copy_from_slice(&elen.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Encodes a file entry as `encode` does, with the `checksum` of its
+    /// data after the name, as `KFS_FLAG_CHECKSUM` has it.
+    pub fn encode_with_checksum(&self, checksum: u32) -> Result<Vec<u8>, Error> {
+        let mut bytes = self.encode()?;
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        let elen: u16 = (bytes.len() - 3).try_into().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Filename too long: {}", self.name()),
+            )
+        })?;
+        bytes[1..=2].copy_from_slice(&elen.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Whether the entry is a file with a checksum after its name.
+    pub fn has_checksum(&self) -> bool {
+        matches!(self, Entry::File { flags, .. } if has_flag(*flags, KFS_FLAG_CHECKSUM))
+    }
+
+    /// Decodes a single entry given in file order (i.e. already reversed
+    /// from how it is stored in the ROM). `addr` is only used in errors.
+    pub fn decode(bytes: &[u8], addr: u32) -> Result<Entry, Error> {
+        let min = match bytes.first() {
+            Some(&KFS_FILE_ID) => entry::FILE_MIN_LENGTH,
+            Some(&KFS_DIR_ID) => entry::DIR_MIN_LENGTH,
+            Some(&KFS_SYM_ID) => entry::SYM_MIN_LENGTH,
+            _ => return Err(corrupt(addr, "unknown entry type")),
+        };
+        if bytes.len() < min {
+            return Err(corrupt(addr, "entry too short"));
+        }
+        let parent = le16(bytes, entry::PARENT);
+        Ok(match bytes[0] {
+            KFS_FILE_ID => {
+                let length = &bytes[entry::FILE_LENGTH..entry::FILE_LENGTH + 3];
+                Entry::File {
+                    parent,
+                    flags: bytes[entry::FILE_FLAGS],
+                    length: u32::from_le_bytes([length[0], length[1], length[2], 0]),
+                    section_id: le16(bytes, entry::FILE_SECTION_ID),
+                    name: read_name(bytes, entry::FILE_NAME, addr)?,
+                }
+            }
+            KFS_DIR_ID => Entry::Directory {
+                parent,
+                id: le16(bytes, entry::DIR_ID),
+                flags: bytes[entry::DIR_FLAGS],
+                name: read_name(bytes, entry::DIR_NAME, addr)?,
+            },
+            _ => {
+                let name = read_name(bytes, entry::SYM_NAME, addr)?;
+                let offset = usize::from(bytes[entry::SYM_TARGET_OFFSET]);
+                let target = read_name(bytes, entry::SYM_NAME + offset, addr)?;
+                Entry::Symlink {
+                    parent,
+                    name,
+                    target,
+                }
+            }
+        })
+    }
+}
+
+/// A FAT entry along with where it lives in the ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Lowest address occupied by the entry.
+    pub addr: u32,
+    /// Length of the entry in bytes, including the id and length fields.
+    pub length: u16,
+    pub entry: Entry,
+    /// The CRC-32 of a file's data, if its entry has one.
+    pub checksum: Option<u32>,
+}
+
+// The checksum after the name of a file entry given in file order, if
+// it has one.
+fn read_checksum(bytes: &[u8], entry: &Entry, addr: u32) -> Result<Option<u32>, Error> {
+    if !entry.has_checksum() {
+        return Ok(None);
+    }
+    let at = entry::FILE_NAME + entry.name().len() + 1;
+    let checksum = bytes
+        .get(at..at + spec::ENTRY_CHECKSUM_LENGTH)
+        .ok_or_else(|| corrupt(addr, "checksum out of bounds"))?;
+    Ok(Some(u32::from_le_bytes(checksum.try_into().unwrap())))
+}
+
+fn corrupt(addr: u32, what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Corrupt FAT entry at {:#x}: {}.", addr, what),
+    )
+}
+
+// Reads a NUL terminated string starting at `bytes[at]`.
+fn read_name(bytes: &[u8], at: usize, addr: u32) -> Result<String, Error> {
+    let tail = bytes
+        .get(at..)
+        .ok_or_else(|| corrupt(addr, "name out of bounds"))?;
+    let len = tail
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| corrupt(addr, "unterminated name"))?;
+    String::from_utf8(tail[..len].to_vec()).map_err(|_| corrupt(addr, "name is not valid UTF-8"))
+}
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+/// Reads every entry of the FAT whose first page is `fat_start`, in the
+/// order they were written. Also returns the address one past the
+/// lowest byte in use, i.e. where the next entry would end.
+pub fn read_fat(
+    rom: &[u8],
+    geometry: Geometry,
+    fat_start: u8,
+) -> Result<(Vec<Record>, u32), Error> {
+    let mut fatptr: u32 = geometry.fat_end(fat_start);
+    let limit: u32 = geometry
+        .fat_end(fat_start)
+        .saturating_sub(geometry.fat_length());
+    if rom.len() < fatptr as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "ROM is too small to contain a FAT.",
+        ));
+    }
+    let mut records = Vec::new();
+    while fatptr >= limit + 3 {
+        let top = fatptr as usize;
+        match rom[top - 1] {
+            KFS_FILE_ID | KFS_DIR_ID | KFS_SYM_ID => {}
+            _ => break,
+        }
+        let elen = u16::from_le_bytes([rom[top - 2], rom[top - 3]]);
+        let length = elen
+            .checked_add(3)
+            .ok_or_else(|| corrupt(fatptr, "bad entry length"))?;
+        if fatptr < limit + u32::from(length) {
+            return Err(corrupt(fatptr, "entry runs past the end of the FAT"));
+        }
+        let addr = fatptr - u32::from(length);
+        let mut bytes = rom[addr as usize..top].to_vec();
+        bytes.reverse();
+        let entry = Entry::decode(&bytes, addr)?;
+        let checksum = read_checksum(&bytes, &entry, addr)?;
+        records.push(Record {
+            addr,
+            length,
+            entry,
+            checksum,
+        });
+        fatptr = addr;
+    }
+    Ok((records, fatptr))
+}
+
+/// Compares the FAT whose first page is `fat_start` against its mirror,
+/// the `geometry.fat_length()` bytes below it, returning the addresses
+/// of primary FAT bytes that differ.
+pub fn compare_mirror(rom: &[u8], geometry: Geometry, fat_start: u8) -> Result<Vec<u32>, Error> {
+    let end = geometry.fat_end(fat_start);
+    let offset = geometry.fat_length();
+    let start = end.checked_sub(2 * offset).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "ROM is too small for a mirrored FAT.",
+        )
+    })? + offset;
+    if rom.len() < end as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "ROM is too small to contain a FAT.",
+        ));
+    }
+    Ok((start..end)
+        .filter(|&addr| rom[addr as usize] != rom[(addr - offset) as usize])
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> Entry {
+        Entry::File {
+            parent: 0x0102,
+            flags: 0xFF,
+            length: 0x03_0405,
+            section_id: 0x0607,
+            name: "ab".to_string(),
+        }
+    }
+
+    fn directory() -> Entry {
+        Entry::Directory {
+            parent: 0x0001,
+            id: 0x0203,
+            flags: 0xFE,
+            name: "d".to_string(),
+        }
+    }
+
+    fn symlink() -> Entry {
+        Entry::Symlink {
+            parent: 0x0000,
+            name: "ln".to_string(),
+            target: "x/y".to_string(),
+        }
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn encodes_file() {
+        assert_eq!(
+            file().encode().unwrap(),
+            [
+                KFS_FILE_ID, 0x0B, 0x00, // Length of the rest: 9 + name
+                0x02, 0x01, // Parent
+                0xFF, // Flags
+                0x05, 0x04, 0x03, // File length, 24 bits
+                0x07, 0x06, // Section id
+                b'a', b'b', 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_directory() {
+        assert_eq!(
+            directory().encode().unwrap(),
+            [
+                KFS_DIR_ID, 0x07, 0x00, // Length of the rest: 6 + name
+                0x01, 0x00, // Parent
+                0x03, 0x02, // Directory id
+                0xFE, // Flags
+                b'd', 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_symlink() {
+        assert_eq!(
+            symlink().encode().unwrap(),
+            [
+                KFS_SYM_ID, 0x0A, 0x00, // Length of the rest: 5 + name + target
+                0x00, 0x00, // Parent
+                0x03, // Offset of the target from the name
+                b'l', b'n', 0, b'x', b'/', b'y', 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for entry in [file(), directory(), symlink()] {
+            let bytes = entry.encode().unwrap();
+            assert_eq!(Entry::decode(&bytes, 0).unwrap(), entry);
+        }
+    }
+
+    #[test]
+    fn encodes_largest_file_length() {
+        let entry = Entry::File {
+            parent: 0,
+            flags: 0xFF,
+            length: KFS_MAX_FILE_LEN as u32,
+            section_id: 0x0401,
+            name: "big".to_string(),
+        };
+        let bytes = entry.encode().unwrap();
+        assert_eq!(bytes[6..=8], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(Entry::decode(&bytes, 0).unwrap(), entry);
+    }
+
+    #[test]
+    fn rejects_unencodable_entries() {
+        let too_big = Entry::File {
+            parent: 0,
+            flags: 0xFF,
+            length: KFS_MAX_FILE_LEN as u32 + 1,
+            section_id: 0x0401,
+            name: "big".to_string(),
+        };
+        assert!(too_big.encode().is_err());
+        let long_name = Entry::Directory {
+            parent: 0,
+            id: 1,
+            flags: 0xFF,
+            name: "a".repeat(0x10000),
+        };
+        assert!(long_name.encode().is_err());
+        // The target offset is a single byte.
+        let long_link = Entry::Symlink {
+            parent: 0,
+            name: "a".repeat(255),
+            target: "b".to_string(),
+        };
+        assert!(long_link.encode().is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_entries() {
+        assert!(Entry::decode(&[0x00, 0x00, 0x00], 0).is_err());
+        assert!(Entry::decode(&[KFS_DIR_ID, 0x06, 0x00, 0x00], 0).is_err());
+        let mut unterminated = directory().encode().unwrap();
+        unterminated.pop();
+        assert!(Entry::decode(&unterminated, 0).is_err());
+    }
+
+    #[test]
+    fn reads_entries_stored_reversed() {
+        let pages = 32;
+        let mut rom = vec![0xFF; pages * usize::from(PAGE_LENGTH)];
+        let fat_start = fat_start_for(rom.len() as u64).unwrap();
+        let mut fatptr = (usize::from(fat_start) + 1) * usize::from(PAGE_LENGTH);
+        let entries = [directory(), file(), symlink()];
+        for entry in &entries {
+            let mut bytes = entry.encode().unwrap();
+            bytes.reverse();
+            fatptr -= bytes.len();
+            rom[fatptr..][..bytes.len()].copy_from_slice(&bytes);
+        }
+        // The type id ends up at the top of each entry.
+        let top = (usize::from(fat_start) + 1) * usize::from(PAGE_LENGTH);
+        assert_eq!(rom[top - 1], KFS_DIR_ID);
+
+        let (records, end) = read_fat(&rom, Geometry::default(), fat_start).unwrap();
+        assert_eq!(end as usize, fatptr);
+        let read: Vec<Entry> = records.into_iter().map(|record| record.entry).collect();
+        assert_eq!(read, entries);
+    }
+
+    #[test]
+    fn reads_entry_checksums() {
+        let mut rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+        let fat_start = fat_start_for(rom.len() as u64).unwrap();
+        let top = (usize::from(fat_start) + 1) * usize::from(PAGE_LENGTH);
+        let entry = Entry::File {
+            parent: 0x0102,
+            flags: !KFS_FLAG_CHECKSUM,
+            length: 0x03_0405,
+            section_id: 0x0607,
+            name: "ab".to_string(),
+        };
+        let mut bytes = entry.encode_with_checksum(0x1234_5678).unwrap();
+        // The checksum is counted in the length, after the name.
+        assert_eq!(bytes[1], 0x0F);
+        assert_eq!(bytes[bytes.len() - 4..], [0x78, 0x56, 0x34, 0x12]);
+        bytes.reverse();
+        rom[top - bytes.len()..top].copy_from_slice(&bytes);
+
+        let (records, _) = read_fat(&rom, Geometry::default(), fat_start).unwrap();
+        assert_eq!(records[0].entry, entry);
+        assert_eq!(records[0].checksum, Some(0x1234_5678));
+    }
+
+    #[test]
+    fn compares_mirrors_a_fat_length_apart() {
+        let geometry = Geometry::new(0x1000, 0x100)
+            .unwrap()
+            .with_fat_pages(2)
+            .unwrap();
+        let mut rom = vec![0xFF; 16 * 0x1000];
+        let fat_start = 11;
+        // The FAT is pages 10 and 11, its mirror pages 8 and 9.
+        rom[0xBFFF] = KFS_DIR_ID;
+        rom[0x9FFF] = KFS_DIR_ID;
+        assert_eq!(compare_mirror(&rom, geometry, fat_start).unwrap(), []);
+
+        rom[0x8005] = 0x00;
+        assert_eq!(compare_mirror(&rom, geometry, fat_start).unwrap(), [0xA005]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::model::{insert, Tree};
+use crate::random::Rng;
+use crate::*;
+
+/// How big `generate` makes files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sizes {
+    /// Up to a block each.
+    Small,
+    /// Mostly small, with the odd file a few pages long: each order of
+    /// magnitude up to 64 KiB is as likely as the next.
+    #[default]
+    Mixed,
+    /// From 1 KiB up to 64 KiB.
+    Large,
+}
+
+impl Sizes {
+    fn pick(self, rng: &mut Rng) -> usize {
+        let (smallest, largest) = match self {
+            Sizes::Small => return rng.below(u64::from(BLOCK_SIZE) + 1) as usize,
+            Sizes::Mixed => (0, 16),
+            Sizes::Large => (10, 16),
+        };
+        let bits = smallest + rng.below(largest - smallest + 1);
+        rng.below(1 << bits) as usize
+    }
+}
+
+impl FromStr for Sizes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Sizes, String> {
+        match s {
+            "small" => Ok(Sizes::Small),
+            "mixed" => Ok(Sizes::Mixed),
+            "large" => Ok(Sizes::Large),
+            _ => Err(format!(
+                "Unknown size distribution {} (expected small, mixed or large).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Sizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sizes::Small => "small",
+            Sizes::Mixed => "mixed",
+            Sizes::Large => "large",
+        })
+    }
+}
+
+/// The shape of the tree `generate` makes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shape {
+    /// How deep directories nest below `/`.
+    pub depth: u32,
+    pub files: u32,
+    pub sizes: Sizes,
+    /// Symlinks to make for every file, from 0 up to 1.
+    pub symlink_ratio: f64,
+    /// Also make the entries kernels tend to get wrong: an empty file,
+    /// files of exactly one block and of one block and a byte, an empty
+    /// directory, a symlink with the longest name a FAT entry holds, and
+    /// symlinks that go up with `..`, point at directories or lead
+    /// nowhere.
+    pub niche_cases: bool,
+}
+
+impl Default for Shape {
+    fn default() -> Shape {
+        Shape {
+            depth: 3,
+            files: 20,
+            sizes: Sizes::default(),
+            symlink_ratio: 0.1,
+            niche_cases: false,
+        }
+    }
+}
+
+// A name of a few lowercase letters and digits, made unique by `n`.
+fn name(rng: &mut Rng, n: u32) -> String {
+    const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    let length = 1 + rng.below(8);
+    let mut name: String = (0..length)
+        .map(|_| char::from(LETTERS[rng.below(LETTERS.len() as u64) as usize]))
+        .collect();
+    name.push_str(&n.to_string());
+    name
+}
+
+fn data(rng: &mut Rng, length: usize) -> Vec<u8> {
+    (0..length).map(|_| rng.next_u64() as u8).collect()
+}
+
+/// Makes a pseudo-random model of `shape` from `seed`, the same one
+/// for the same seed and shape every time.
+pub fn generate(seed: u64, shape: &Shape) -> Result<BTreeMap<String, Tree>, Error> {
+    if !(0.0..=1.0).contains(&shape.symlink_ratio) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "A symlink ratio of {} isn't between 0 and 1.",
+                shape.symlink_ratio
+            ),
+        ));
+    }
+    let mut rng = Rng::new(seed);
+    let mut root = BTreeMap::new();
+    // Every directory, with how deep it is; `/` is "".
+    let mut dirs = vec![(String::new(), 0)];
+    let mut files = Vec::new();
+    // Entries made so far, to make names unique.
+    let mut made = 0;
+    for _ in 0..shape.files / 4 {
+        let parents: Vec<_> = dirs
+            .iter()
+            .filter(|(_, depth)| *depth < shape.depth)
+            .collect();
+        if parents.is_empty() {
+            break;
+        }
+        let (parent, depth) = parents[rng.below(parents.len() as u64) as usize].clone();
+        let path = format!("{}/{}", parent, name(&mut rng, made));
+        made += 1;
+        insert(&mut root, &path, Tree::Directory(BTreeMap::new()))?;
+        dirs.push((path, depth + 1));
+    }
+    for _ in 0..shape.files {
+        let (dir, _) = &dirs[rng.below(dirs.len() as u64) as usize];
+        let path = format!("{}/{}", dir, name(&mut rng, made));
+        made += 1;
+        let length = shape.sizes.pick(&mut rng);
+        insert(&mut root, &path, Tree::File(data(&mut rng, length)))?;
+        files.push(path);
+    }
+    let symlinks = (f64::from(shape.files) * shape.symlink_ratio).round() as u32;
+    for _ in 0..symlinks {
+        let (dir, _) = &dirs[rng.below(dirs.len() as u64) as usize];
+        let path = format!("{}/{}", dir, name(&mut rng, made));
+        made += 1;
+        let target = if files.is_empty() || rng.below(4) == 0 {
+            dirs[rng.below(dirs.len() as u64) as usize].0.clone()
+        } else {
+            files[rng.below(files.len() as u64) as usize].clone()
+        };
+        let target = if target.is_empty() { "/" } else { &target };
+        insert(&mut root, &path, Tree::Symlink(target.to_string()))?;
+    }
+    if shape.niche_cases {
+        let block = usize::from(BLOCK_SIZE);
+        let niche: Vec<(String, Tree)> = vec![
+            ("/niche/empty".to_string(), Tree::File(Vec::new())),
+            (
+                "/niche/block".to_string(),
+                Tree::File(data(&mut rng, block)),
+            ),
+            (
+                "/niche/block-and-a-byte".to_string(),
+                Tree::File(data(&mut rng, block + 1)),
+            ),
+            (
+                "/niche/empty-dir".to_string(),
+                Tree::Directory(BTreeMap::new()),
+            ),
+            // Its name's length is stored in a byte, with the NUL.
+            (
+                format!("/niche/{}", "l".repeat(254)),
+                Tree::Symlink("empty".to_string()),
+            ),
+            (
+                "/niche/up".to_string(),
+                Tree::Symlink("../niche/block".to_string()),
+            ),
+            (
+                "/niche/dir".to_string(),
+                Tree::Symlink("/niche/empty-dir".to_string()),
+            ),
+            (
+                "/niche/dangling".to_string(),
+                Tree::Symlink("nowhere".to_string()),
+            ),
+        ];
+        for (path, node) in niche {
+            insert(&mut root, &path, node)?;
+        }
+    }
+    Ok(root)
+}
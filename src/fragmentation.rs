@@ -0,0 +1,74 @@
+use std::fmt;
+use std::io::Error;
+
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::json;
+
+/// How scattered the section chains of an image's files are. Every hop
+/// from one section to the next costs a FAT lookup on the calculator,
+/// and one onto another page also costs a page swap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    pub files: usize,
+    /// Files whose chain crosses at least one page boundary.
+    pub cross_page: usize,
+    /// Hops between consecutive sections, over every file.
+    pub hops: usize,
+    /// Hops that land on a different page.
+    pub page_hops: usize,
+}
+
+impl Fragmentation {
+    pub fn analyze(image: &Image) -> Result<Fragmentation, Error> {
+        let mut stats = Fragmentation::default();
+        for record in image.records() {
+            if let Entry::File {
+                length, section_id, ..
+            } = record.entry
+            {
+                let chain = image.chain(section_id, length)?;
+                let page_hops = chain
+                    .windows(2)
+                    .filter(|pair| pair[0].0 >> 8 != pair[1].0 >> 8)
+                    .count();
+                stats.files += 1;
+                stats.hops += chain.len().saturating_sub(1);
+                stats.page_hops += page_hops;
+                if page_hops > 0 {
+                    stats.cross_page += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Average hops per file.
+    pub fn average_hops(&self) -> f64 {
+        if self.files == 0 {
+            0.0
+        } else {
+            self.hops as f64 / self.files as f64
+        }
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        json::Value::object(vec![
+            ("files", self.files.into()),
+            ("cross_page", self.cross_page.into()),
+            ("hops", self.hops.into()),
+            ("page_hops", self.page_hops.into()),
+            ("average_hops", self.average_hops().into()),
+        ])
+    }
+}
+
+impl fmt::Display for Fragmentation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Files:                  {}", self.files)?;
+        writeln!(f, "Crossing a page:        {}", self.cross_page)?;
+        writeln!(f, "Section hops:           {}", self.hops)?;
+        writeln!(f, "Hops onto another page: {}", self.page_hops)?;
+        write!(f, "Average hops per file:  {:.2}", self.average_hops())
+    }
+}
@@ -0,0 +1,341 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::section::{SectionId, MAX_INDEX};
+use crate::*;
+
+/// The page length, block size and FAT size of a filesystem. Stock
+/// KnightOS uses `PAGE_LENGTH`, `BLOCK_SIZE` and `FAT_PAGES`; forks with
+/// other flash sectors can pick their own, within what section ids and
+/// headers can express.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    page_length: u16,
+    block_size: u16,
+    fat_pages: u8,
+}
+
+impl Default for Geometry {
+    fn default() -> Geometry {
+        Geometry {
+            page_length: PAGE_LENGTH,
+            block_size: BLOCK_SIZE,
+            fat_pages: FAT_PAGES,
+        }
+    }
+}
+
+impl Geometry {
+    /// Checks that both sizes are powers of two, that a page holds
+    /// between 2 and `MAX_INDEX + 1` blocks, and that its first block
+    /// has room for every section header.
+    pub fn new(page_length: u16, block_size: u16) -> Result<Geometry, Error> {
+        let invalid = |why: &str| {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unusable geometry of {:#x}-byte pages and {:#x}-byte blocks: {}.",
+                    page_length, block_size, why
+                ),
+            ))
+        };
+        if !page_length.is_power_of_two() || !block_size.is_power_of_two() {
+            return invalid("sizes must be powers of two");
+        }
+        if block_size >= page_length {
+            return invalid("a page needs at least two blocks");
+        }
+        let blocks = page_length / block_size;
+        if blocks > u16::from(MAX_INDEX) + 1 {
+            return invalid(&format!(
+                "a page can have at most {} blocks",
+                u16::from(MAX_INDEX) + 1
+            ));
+        }
+        if blocks * 4 > block_size {
+            return invalid("the section headers don't fit in the first block");
+        }
+        Ok(Geometry {
+            page_length,
+            block_size,
+            fat_pages: FAT_PAGES,
+        })
+    }
+
+    /// The same geometry with a FAT of `fat_pages` pages.
+    pub fn with_fat_pages(self, fat_pages: u8) -> Result<Geometry, Error> {
+        if fat_pages == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unusable geometry: the FAT needs at least one page.",
+            ));
+        }
+        Ok(Geometry { fat_pages, ..self })
+    }
+
+    pub fn page_length(self) -> u16 {
+        self.page_length
+    }
+
+    pub fn block_size(self) -> u16 {
+        self.block_size
+    }
+
+    pub fn fat_pages(self) -> u8 {
+        self.fat_pages
+    }
+
+    /// The highest block index of a page.
+    pub fn max_index(self) -> u8 {
+        (self.page_length / self.block_size - 1) as u8
+    }
+
+    /// ROM offset of the start of `page`.
+    pub fn page_offset(self, page: u8) -> u64 {
+        u64::from(page) * u64::from(self.page_length)
+    }
+
+    /// ROM offset of a section's block.
+    pub fn block_offset(self, id: SectionId) -> u64 {
+        self.page_offset(id.page()) + u64::from(id.index()) * u64::from(self.block_size)
+    }
+
+    /// ROM offset of a section's pSID/nSID header.
+    pub fn header_offset(self, id: SectionId) -> u64 {
+        self.page_offset(id.page()) + u64::from(spec::section_header_offset(id.index()))
+    }
+
+    /// Bytes reserved for the FAT, which is also the distance from a FAT
+    /// byte to its copy when the FAT is mirrored.
+    pub fn fat_length(self) -> u32 {
+        u32::from(self.fat_pages) * u32::from(self.page_length)
+    }
+
+    /// Address one past the top of the FAT whose first page is
+    /// `fat_start`, where the first entry ends.
+    pub fn fat_end(self, fat_start: u8) -> u32 {
+        (u32::from(fat_start) + 1) * u32::from(self.page_length)
+    }
+
+    /// Computes the first FAT page for a ROM of `length` bytes: the one
+    /// KnightOS uses on the calculator with that much flash, if there is
+    /// one, or else the page nine from the top.
+    pub fn fat_start_for(self, length: u64) -> Result<u8, Error> {
+        let pages = length / u64::from(self.page_length);
+        if cfg!(feature = "c-undef") {
+            // C original has undefined behavior: context.fat_start = length / PAGE_LENGTH - 0x9;
+            Ok(TryInto::<u8>::try_into(pages).unwrap().wrapping_sub(9))
+        } else {
+            if self.page_length == PAGE_LENGTH {
+                if let Some(platform) = Platform::for_length(length) {
+                    return Ok(platform.fat_start);
+                }
+            }
+            pages
+                .checked_sub(9)
+                .and_then(|fat_start| TryInto::<u8>::try_into(fat_start).ok())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "A ROM of {} bytes is {} pages, too few or too many to put the FAT nine pages from the top.",
+                            length, pages
+                        ),
+                    )
+                })
+        }
+    }
+}
+
+/// A calculator KnightOS runs on, with where its filesystem goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Platform {
+    /// The name of the platform in the KnightOS kernel, in lower case.
+    pub name: &'static str,
+    /// Bytes of flash.
+    pub length: u64,
+    pub dat_start: u8,
+    pub fat_start: u8,
+    /// The first of the pages at the top of flash the kernel keeps for
+    /// its privileged sector and the boot code.
+    pub privileged: u8,
+}
+
+/// The calculators KnightOS supports, as its kernel lays them out: the
+/// kernel's Makefile sets `FAT` and `PRIVEDGED` for each `PLATFORM`
+/// (<https://github.com/KnightOS/kernel/blob/master/Makefile>), which
+/// are `fat_start` and `privileged` here. Data starts on page 04, past
+/// the kernel's own pages, as genkfs has it. On every calculator the
+/// FAT comes out nine pages from the top, with four pages between it
+/// and the privileged ones, but the table keeps the kernel's numbers
+/// rather than working them out.
+pub const PLATFORMS: &[Platform] = &[
+    Platform {
+        name: "ti73",
+        length: 0x80000,
+        dat_start: 0x04,
+        fat_start: 0x17,
+        privileged: 0x1C,
+    },
+    Platform {
+        name: "ti83p",
+        length: 0x80000,
+        dat_start: 0x04,
+        fat_start: 0x17,
+        privileged: 0x1C,
+    },
+    Platform {
+        name: "ti83pse",
+        length: 0x200000,
+        dat_start: 0x04,
+        fat_start: 0x77,
+        privileged: 0x7C,
+    },
+    Platform {
+        name: "ti84p",
+        length: 0x100000,
+        dat_start: 0x04,
+        fat_start: 0x37,
+        privileged: 0x3C,
+    },
+    Platform {
+        name: "ti84pse",
+        length: 0x200000,
+        dat_start: 0x04,
+        fat_start: 0x77,
+        privileged: 0x7C,
+    },
+    Platform {
+        name: "ti84pcse",
+        length: 0x400000,
+        dat_start: 0x04,
+        fat_start: 0xF7,
+        privileged: 0xFC,
+    },
+];
+
+impl Platform {
+    /// The first platform with `length` bytes of flash.
+    pub fn for_length(length: u64) -> Option<Platform> {
+        PLATFORMS
+            .iter()
+            .find(|platform| platform.length == length)
+            .copied()
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        let name = s.to_ascii_lowercase().replace('-', "").replace('+', "p");
+        PLATFORMS
+            .iter()
+            .find(|platform| platform.name == name)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = PLATFORMS.iter().map(|platform| platform.name).collect();
+                format!(
+                    "Unknown platform {} (expected one of {}).",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(name: &str) -> Platform {
+        name.parse().unwrap()
+    }
+
+    // Each calculator's FAT and PRIVEDGED in the kernel's Makefile.
+    #[test]
+    fn platforms_are_laid_out_as_the_kernel_has_them() {
+        let kernel = [
+            ("ti73", 0x80000, 0x17, 0x1C),
+            ("ti83p", 0x80000, 0x17, 0x1C),
+            ("ti83pse", 0x200000, 0x77, 0x7C),
+            ("ti84p", 0x100000, 0x37, 0x3C),
+            ("ti84pse", 0x200000, 0x77, 0x7C),
+            ("ti84pcse", 0x400000, 0xF7, 0xFC),
+        ];
+        assert_eq!(PLATFORMS.len(), kernel.len());
+        for &(name, length, fat_start, privileged) in &kernel {
+            let platform = platform(name);
+            assert_eq!(platform.name, name);
+            assert_eq!(platform.length, length, "{}", name);
+            assert_eq!(platform.dat_start, 0x04, "{}", name);
+            assert_eq!(platform.fat_start, fat_start, "{}", name);
+            assert_eq!(platform.privileged, privileged, "{}", name);
+            assert_eq!(platform.to_string(), name);
+            // Privileged to the top is what blanking leaves alone.
+            let pages = length / u64::from(PAGE_LENGTH);
+            assert_eq!(
+                pages - u64::from(privileged),
+                u64::from(crate::erase::KERNEL_TOP_PAGES),
+                "{}",
+                name
+            );
+            // The C original's arithmetic overflows on the TI-84+CSE.
+            if cfg!(not(feature = "c-undef")) {
+                let fat = Geometry::default().fat_start_for(length).unwrap();
+                assert_eq!(fat, fat_start, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn calculators_sharing_a_flash_size_share_a_layout() {
+        assert_eq!(Platform::for_length(0x80000).unwrap().name, "ti73");
+        assert_eq!(Platform::for_length(0x200000).unwrap().name, "ti83pse");
+        assert_eq!(Platform::for_length(0x100000).unwrap().name, "ti84p");
+        assert_eq!(Platform::for_length(0x400000).unwrap().name, "ti84pcse");
+        assert_eq!(Platform::for_length(0x40000), None);
+    }
+
+    #[test]
+    fn platforms_go_by_their_marketing_names_too() {
+        for (alias, name) in &[
+            ("TI-73", "ti73"),
+            ("TI-83+", "ti83p"),
+            ("ti83+", "ti83p"),
+            ("TI-83+SE", "ti83pse"),
+            ("TI-83PSE", "ti83pse"),
+            ("TI-84+", "ti84p"),
+            ("TI-84+SE", "ti84pse"),
+            ("TI-84+CSE", "ti84pcse"),
+            ("ti-84pcse", "ti84pcse"),
+            ("TI84pCSE", "ti84pcse"),
+        ] {
+            assert_eq!(platform(alias).name, *name, "{}", alias);
+        }
+        assert_eq!(
+            "TI-89".parse::<Platform>(),
+            Err("Unknown platform TI-89 (expected one of ti73, ti83p, ti83pse, ti84p, ti84pse, ti84pcse).".to_string())
+        );
+    }
+
+    // The C original's arithmetic wraps instead.
+    #[cfg(not(feature = "c-undef"))]
+    #[test]
+    fn other_sizes_put_the_fat_nine_pages_from_the_top() {
+        let geometry = Geometry::default();
+        let page = u64::from(PAGE_LENGTH);
+        assert_eq!(geometry.fat_start_for(48 * page).unwrap(), 48 - 9);
+        assert!(geometry.fat_start_for(8 * page).is_err());
+        assert!(geometry.fat_start_for(0).is_err());
+        assert!(geometry.fat_start_for(300 * page).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+/// Whether `text` matches the shell-style `pattern`.
+///
+/// `?` matches one character and `*` any run of characters other than
+/// `/`; `**` also crosses `/`, and `[abc]`, `[a-z]`, `[!a-z]` match
+/// character classes.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    Matcher {
+        memo: vec![None; (pattern.len() + 1) * (text.len() + 1)],
+        pattern: &pattern,
+        text: &text,
+    }
+    .at(0, 0)
+}
+
+// Matches by trying every way each `*` could end, remembering what each
+// (pattern, text) position came to so that a pattern like `*a*a*a*b`
+// takes polynomial time rather than backtracking exponentially.
+struct Matcher<'a> {
+    pattern: &'a [char],
+    text: &'a [char],
+    memo: Vec<Option<bool>>,
+}
+
+impl Matcher<'_> {
+    // Whether `pattern[p..]` matches `text[t..]`.
+    fn at(&mut self, p: usize, t: usize) -> bool {
+        let key = p * (self.text.len() + 1) + t;
+        if let Some(matched) = self.memo[key] {
+            return matched;
+        }
+        let matched = self.step(p, t);
+        self.memo[key] = Some(matched);
+        matched
+    }
+
+    fn step(&mut self, p: usize, t: usize) -> bool {
+        let (pattern, text) = (self.pattern, self.text);
+        match pattern.get(p) {
+            None => t == text.len(),
+            Some('*') if pattern.get(p + 1) == Some(&'*') => {
+                // `**/` also matches no directories at all.
+                if pattern.get(p + 2) == Some(&'/') && self.at(p + 3, t) {
+                    return true;
+                }
+                (t..=text.len()).any(|i| self.at(p + 2, i))
+            }
+            Some('*') => (t..=text.len())
+                .take_while(|&i| i == t || text[i - 1] != '/')
+                .any(|i| self.at(p + 1, i)),
+            Some('?') => match text.get(t) {
+                Some(&c) if c != '/' => self.at(p + 1, t + 1),
+                _ => false,
+            },
+            Some('[') => match (text.get(t), class_end(&pattern[p..])) {
+                (Some(&c), Some(end)) => {
+                    class_matches(&pattern[p + 1..p + end], c) && self.at(p + end + 1, t + 1)
+                }
+                (Some(&c), None) => c == '[' && self.at(p + 1, t + 1),
+                (None, _) => false,
+            },
+            Some(&c) => text.get(t) == Some(&c) && self.at(p + 1, t + 1),
+        }
+    }
+}
+
+// Index of the `]` closing the class opened at `pattern[0]`.
+fn class_end(pattern: &[char]) -> Option<usize> {
+    let start = match pattern.get(1) {
+        Some('!') => 3,
+        _ => 2,
+    };
+    (start..pattern.len()).find(|&i| pattern[i] == ']')
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut i = 0;
+    let mut found = false;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+pub fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Expands wildcards in the final component of `pattern` against the
+/// filesystem, returning matches sorted by name. Patterns without
+/// wildcards are returned as is.
+pub fn expand(pattern: &Path) -> Result<Vec<PathBuf>, Error> {
+    let name = match pattern.file_name().and_then(|name| name.to_str()) {
+        Some(name) if has_wildcards(name) => name,
+        _ => return Ok(vec![pattern.to_path_buf()]),
+    };
+    let dir = match pattern.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(file_name) = entry.file_name().to_str() {
+            if matches(name, file_name) {
+                paths.push(pattern.with_file_name(file_name));
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Include and exclude patterns applied to filesystem paths.
+///
+/// A pattern containing `/` is matched against the whole path (the
+/// leading `/` is optional), any other pattern against the last
+/// component only, so `*.cfg` matches `/etc/net.cfg`.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        matches(
+            pattern.trim_start_matches('/'),
+            path.trim_start_matches('/'),
+        )
+    } else {
+        matches(pattern, path.rsplit('/').next().unwrap_or(path))
+    }
+}
+
+impl Filter {
+    /// Whether anything is filtered at all.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether `path` or one of its ancestors is excluded.
+    pub fn excludes(&self, path: &str) -> bool {
+        let mut prefix = path;
+        loop {
+            if self.exclude.iter().any(|p| path_matches(p, prefix)) {
+                return true;
+            }
+            match prefix.rfind('/') {
+                Some(i) if i > 0 => prefix = &prefix[..i],
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether `path` should be kept.
+    pub fn keeps(&self, path: &str) -> bool {
+        !self.excludes(path)
+            && (self.include.is_empty() || self.include.iter().any(|p| path_matches(p, path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_match_only_themselves() {
+        assert!(matches("motd", "motd"));
+        assert!(!matches("motd", "motd2"));
+        assert!(!matches("motd", "mot"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn star_matches_within_a_component() {
+        assert!(matches("*.cfg", "net.cfg"));
+        assert!(matches("*.cfg", ".cfg"));
+        assert!(matches("a*b*c", "aXbYc"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.cfg", "etc/net.cfg"));
+        assert!(matches("*/net.cfg", "etc/net.cfg"));
+        assert!(!matches("*", "a/b"));
+    }
+
+    #[test]
+    fn double_star_crosses_slashes() {
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("**/*.cfg", "etc/net/net.cfg"));
+        assert!(
+            matches("**/*.cfg", "net.cfg"),
+            "`**/` matches no directories"
+        );
+        assert!(matches("etc/**/motd", "etc/motd"));
+        assert!(matches("etc/**/motd", "etc/a/b/motd"));
+        assert!(!matches("etc/**/motd", "etca/motd"));
+        assert!(matches("bin/**", "bin/sh"));
+        assert!(!matches("bin/**", "sbin/sh"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character_but_not_a_slash() {
+        assert!(matches("?.bin", "a.bin"));
+        assert!(!matches("?.bin", ".bin"));
+        assert!(!matches("?.bin", "ab.bin"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(matches("\u{e9}?", "\u{e9}\u{e8}"));
+    }
+
+    #[test]
+    fn classes_match_sets_ranges_and_their_negation() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(!matches("[abc].txt", "d.txt"));
+        assert!(matches("file[0-9]", "file7"));
+        assert!(!matches("file[0-9]", "filex"));
+        assert!(matches("file[!0-9]", "filex"));
+        assert!(!matches("file[!0-9]", "file7"));
+        assert!(matches("[a-cx-z]", "y"));
+        assert!(matches("[]]", "]"), "a leading ] is part of the class");
+        assert!(matches("[", "["), "an unclosed [ is literal");
+        assert!(!matches("[a]", ""));
+    }
+
+    #[test]
+    fn many_stars_dont_backtrack_exponentially() {
+        let text = "a".repeat(200);
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(!matches("**a**a**a**a**a**a**a**a**b", &text));
+        assert!(matches("*a*a*a*a*a*a*a*a*a*a", &text));
+    }
+
+    #[test]
+    fn wildcards_are_recognised() {
+        assert!(has_wildcards("*.rom"));
+        assert!(has_wildcards("rom?"));
+        assert!(has_wildcards("rom[12]"));
+        assert!(!has_wildcards("blank.rom"));
+    }
+
+    #[test]
+    fn filters_match_names_or_whole_paths() {
+        let filter = Filter {
+            include: vec!["*.cfg".to_string(), "/bin/*".to_string()],
+            exclude: vec!["secret".to_string()],
+        };
+        assert!(filter.keeps("/etc/net.cfg"));
+        assert!(filter.keeps("/bin/sh"));
+        assert!(!filter.keeps("/etc/motd"));
+        assert!(!filter.keeps("/etc/secret/net.cfg"), "an excluded ancestor");
+        assert!(Filter::default().is_empty());
+        assert!(Filter::default().keeps("/anything"));
+    }
+}
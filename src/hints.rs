@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::extract::normalize;
+use crate::toml::{self, Value};
+
+/// How one file's data should be placed, and how its entry is flagged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Hint {
+    /// Start the file at block 1 of a page no other data uses yet, as
+    /// the kernel needs for executables it maps in place.
+    pub align: bool,
+    /// Start the file on this flash page, so its address stays put from
+    /// one build to the next whatever else changes.
+    pub pin: Option<u8>,
+    /// Set the entry's read-only flag.
+    pub read_only: bool,
+}
+
+impl Hint {
+    /// Whether the hint decides where the file's data goes.
+    pub fn places(&self) -> bool {
+        self.align || self.pin.is_some()
+    }
+}
+
+/// Parses a placement hints file, a TOML table per file in the model:
+///
+/// ```text
+/// ["/bin/init"]
+/// align = true
+///
+/// ["/lib/core"]
+/// pin = 0x08
+///
+/// ["/etc/passwd"]
+/// read_only = true
+/// ```
+///
+/// Pinned files are placed before all others, each starting at the
+/// first free block of its page and carrying on past it if it has to.
+pub fn parse_hints(text: &str) -> Result<BTreeMap<String, Hint>, Error> {
+    let mut hints = BTreeMap::new();
+    for table in toml::parse(text)? {
+        if table.name.is_empty() {
+            if let Some((key, _)) = table.entries.first() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Hint {} is not under a [path] table.", key),
+                ));
+            }
+            continue;
+        }
+        if !table.name.starts_with('/') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Hint table [{}] is not an absolute path.", table.name),
+            ));
+        }
+        let mut hint = Hint::default();
+        for (key, value) in &table.entries {
+            match (key.as_str(), value) {
+                ("align", Value::Boolean(align)) => hint.align = *align,
+                ("align", value) => {
+                    return Err(toml::expected(&table.name, key, "a boolean", value))
+                }
+                ("pin", Value::Integer(page)) => {
+                    hint.pin = Some(u8::try_from(*page).map_err(|_| {
+                        // In hex as pages are, but `-0x1` rather than its
+                        // two's complement.
+                        let sign = if *page < 0 { "-" } else { "" };
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!(
+                                "Pin {}{:#x} for {} is not a page number.",
+                                sign,
+                                page.unsigned_abs(),
+                                table.name
+                            ),
+                        )
+                    })?)
+                }
+                ("pin", value) => {
+                    return Err(toml::expected(&table.name, key, "a page number", value))
+                }
+                ("read_only", Value::Boolean(read_only)) => hint.read_only = *read_only,
+                ("read_only", value) => {
+                    return Err(toml::expected(&table.name, key, "a boolean", value))
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown hint {} for {}.", key, table.name),
+                    ))
+                }
+            }
+        }
+        hints.insert(normalize(&table.name), hint);
+    }
+    Ok(hints)
+}
+
+pub fn read_hints(path: &Path) -> Result<BTreeMap<String, Hint>, Error> {
+    parse_hints(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse_hints(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn tables_give_each_file_its_hint() {
+        let hints = parse_hints(
+            "# Mapped in place\n[\"/bin/init\"]\nalign = true\n\n[\"/bin//sh/\"]\nalign = false\n",
+        )
+        .unwrap();
+        assert_eq!(hints.len(), 2);
+        assert!(hints["/bin/init"].align);
+        assert!(hints["/bin/init"].places());
+        assert_eq!(hints["/bin/sh"], Hint::default(), "paths are normalized");
+        assert!(!hints["/bin/sh"].places());
+        assert!(parse_hints("").unwrap().is_empty());
+        assert_eq!(
+            parse_hints("[\"/etc\"]\n").unwrap()["/etc"],
+            Hint::default()
+        );
+    }
+
+    #[test]
+    fn malformed_hints_are_errors() {
+        assert_eq!(
+            error("align = true\n"),
+            "Hint align is not under a [path] table."
+        );
+        assert_eq!(
+            error("[\"bin/init\"]\nalign = true\n"),
+            "Hint table [bin/init] is not an absolute path."
+        );
+        assert_eq!(
+            error("[\"/bin/init\"]\nalign = 1\n"),
+            "[/bin/init] align should be a boolean, not an integer."
+        );
+        assert_eq!(
+            error("[\"/bin/init\"]\naligned = true\n"),
+            "Unknown hint aligned for /bin/init."
+        );
+        assert!(error("[\"/bin/init\"\nalign = true\n").starts_with("Line 1: "));
+    }
+
+    #[test]
+    fn pins_are_page_numbers() {
+        let hints = parse_hints("[\"/lib/core\"]\npin = 0x08\n[\"/top\"]\npin = 255\n").unwrap();
+        assert_eq!(hints["/lib/core"].pin, Some(0x08));
+        assert!(hints["/lib/core"].places());
+        assert!(!hints["/lib/core"].align);
+        assert_eq!(hints["/top"].pin, Some(0xFF));
+        assert_eq!(
+            error("[\"/lib/core\"]\npin = 0x100\n"),
+            "Pin 0x100 for /lib/core is not a page number."
+        );
+        assert_eq!(
+            error("[\"/lib/core\"]\npin = -1\n"),
+            "Pin -0x1 for /lib/core is not a page number."
+        );
+        assert_eq!(
+            error("[\"/lib/core\"]\npin = \"08\"\n"),
+            "[/lib/core] pin should be a page number, not a string."
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::process::Command;
+
+use crate::toml::{self, Value};
+
+/// Placeholder in hook commands replaced by the path of the built ROM,
+/// quoted for the shell.
+pub const ROM_PLACEHOLDER: &str = "{rom}";
+
+// Quotes `arg` so the shell passes it through as one word.
+fn quote(arg: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", arg.replace('"', "\"\""))
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// The shell running `command`: sh on Unix, cmd on Windows.
+fn shell(command: &str) -> Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = Command::new(shell);
+    shell.arg(flag).arg(command);
+    shell
+}
+
+/// Runs the shell `command` with `{rom}` replaced by `rom`, such as
+/// `z80e {rom}` to boot a freshly built image, failing unless it exits
+/// successfully.
+pub fn run(command: &str, rom: &Path) -> Result<(), Error> {
+    run_with(command, rom, None)
+}
+
+/// Runs an `on_failure` hook as `run` does, with the message of the
+/// error the build failed with in `REGENKFS_ERROR`.
+pub fn run_on_failure(command: &str, rom: &Path, error: &Error) -> Result<(), Error> {
+    run_with(command, rom, Some(error))
+}
+
+fn run_with(command: &str, rom: &Path, error: Option<&Error>) -> Result<(), Error> {
+    let command = command.replace(ROM_PLACEHOLDER, &quote(&rom.to_string_lossy()));
+    let mut shell = shell(&command);
+    if let Some(error) = error {
+        shell.env(
+            "REGENKFS_ERROR",
+            error.get_ref().map_or(error.to_string(), |e| e.to_string()),
+        );
+    }
+    let status = shell
+        .status()
+        .map_err(|err| Error::new(err.kind(), format!("Cannot run {}: {}", command, err)))?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "Error: {} failed ({}).",
+            command, status
+        )));
+    }
+    Ok(())
+}
+
+/// Shell commands a configuration file runs around each build.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Hooks {
+    /// Before anything is read, such as to compile the model's programs.
+    pub pre_build: Vec<String>,
+    /// After each image has been written, such as to sign or upload it,
+    /// with `{rom}` standing for the image.
+    pub post_build: Vec<String>,
+    /// When the build, or one of the other hooks, fails. Like
+    /// `pre_build`, these get the ROM as given on the command line.
+    pub on_failure: Vec<String>,
+}
+
+// A hook is one command or an array of them.
+fn commands(key: &str, value: &Value) -> Result<Vec<String>, Error> {
+    let expected = || toml::expected("hooks", key, "a string or an array of strings", value);
+    match value {
+        Value::String(command) => Ok(vec![command.clone()]),
+        Value::Array(commands) => commands
+            .iter()
+            .map(|command| match command {
+                Value::String(command) => Ok(command.clone()),
+                _ => Err(expected()),
+            })
+            .collect(),
+        _ => Err(expected()),
+    }
+}
+
+/// Parses the hooks of a configuration file, from its `[hooks]` table:
+///
+/// ```text
+/// [hooks]
+/// pre_build = "make -C programs install"
+/// post_build = ["sign {rom}", "scp {rom} ci:roms/"]
+/// on_failure = "notify-send 'KFS build failed'"
+/// ```
+pub fn parse_config(text: &str) -> Result<Hooks, Error> {
+    let mut hooks = Hooks::default();
+    for table in toml::parse(text)? {
+        match table.name.as_str() {
+            "hooks" => {}
+            "" if table.entries.is_empty() => continue,
+            "" => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown config key {}.", table.entries[0].0),
+                ))
+            }
+            name => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown config table [{}].", name),
+                ))
+            }
+        }
+        for (key, value) in &table.entries {
+            let hook = match key.as_str() {
+                "pre_build" => &mut hooks.pre_build,
+                "post_build" => &mut hooks.post_build,
+                "on_failure" => &mut hooks.on_failure,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown hook {}.", key),
+                    ))
+                }
+            };
+            *hook = commands(key, value)?;
+        }
+    }
+    Ok(hooks)
+}
+
+pub fn read_config(path: &Path) -> Result<Hooks, Error> {
+    parse_config(&fs::read_to_string(path)?)
+}
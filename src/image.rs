@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use std::convert::TryFrom;
+
+use crate::fat::{read_fat, Entry, Record};
+use crate::kernel::{GeometryRecord, KERNEL_PAGES};
+use crate::section::SectionId;
+use crate::spec::{version_byte, MAGIC};
+use crate::*;
+
+/// A filesystem read back out of a ROM image.
+pub struct Image<'a> {
+    rom: &'a [u8],
+    pub geometry: Geometry,
+    pub dat_start: u8,
+    pub fat_start: u8,
+    records: Vec<Record>,
+}
+
+/// Checks that the first data page starts with the `KFS` magic number
+/// and a version this tool writes, so a ROM that isn't a KFS image (or
+/// is one laid out differently) is refused rather than misread.
+pub fn check_magic(rom: &[u8], geometry: Geometry, dat_start: u8) -> Result<(), Error> {
+    let offset = geometry.page_offset(dat_start) as usize;
+    let magic = rom.get(offset..offset + 4).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Data page {:02x} is past the end of the ROM.", dat_start),
+        )
+    })?;
+    if &magic[..3] != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Page {:02x} doesn't start with the KFS magic number; this isn't a KFS filesystem.",
+                dat_start
+            ),
+        ));
+    }
+    if magic[3] != version_byte(KFS_VERSION) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Page {:02x} holds an unsupported KFS version byte {:#04x}.",
+                dat_start, magic[3]
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// A FAT entry along with its absolute path in the filesystem.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub path: String,
+    pub record: Record,
+}
+
+impl<'a> Image<'a> {
+    /// Opens a ROM image, with the geometry of the kernel's geometry
+    /// record if it has one, or else derived from its length.
+    pub fn open(rom: &'a [u8]) -> Result<Image<'a>, Error> {
+        let kernel = &rom[..rom
+            .len()
+            .min(usize::from(KERNEL_PAGES) * usize::from(PAGE_LENGTH))];
+        if let Some(kernel) = GeometryRecord::find(kernel)? {
+            return Image::open_with(rom, kernel.geometry, kernel.dat_start, kernel.fat_start);
+        }
+        let fat_start = fat_start_for(rom.len() as u64)?;
+        Image::open_with(rom, Geometry::default(), 0x04, fat_start)
+    }
+
+    pub fn open_with(
+        rom: &'a [u8],
+        geometry: Geometry,
+        dat_start: u8,
+        fat_start: u8,
+    ) -> Result<Image<'a>, Error> {
+        check_magic(rom, geometry, dat_start)?;
+        let (records, _) = read_fat(rom, geometry, fat_start)?;
+        Ok(Image {
+            rom,
+            geometry,
+            dat_start,
+            fat_start,
+            records,
+        })
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Every entry with its absolute path, in FAT order.
+    pub fn nodes(&self) -> Result<Vec<Node>, Error> {
+        let mut nodes = Vec::with_capacity(self.records.len());
+        self.walk(|path, record| {
+            nodes.push(Node {
+                path: path.to_string(),
+                record: record.clone(),
+            })
+        })?;
+        Ok(nodes)
+    }
+
+    /// Calls `visit` with the absolute path of every entry, in FAT order.
+    /// Only the paths of directories are kept along the way.
+    pub fn walk<F: FnMut(&str, &Record)>(&self, mut visit: F) -> Result<(), Error> {
+        let mut dirs: HashMap<u16, String> = HashMap::new();
+        dirs.insert(0, String::new());
+        for record in &self.records {
+            let parent = dirs.get(&record.entry.parent()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Entry {} refers to missing directory {}.",
+                        record.entry.name(),
+                        record.entry.parent()
+                    ),
+                )
+            })?;
+            let path = format!("{}/{}", parent, record.entry.name());
+            visit(&path, record);
+            if let Entry::Directory { id, .. } = record.entry {
+                dirs.insert(id, path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the section chain starting at `section_id`, returning the
+    /// sections visited along with how many bytes each holds.
+    pub fn chain(&self, section_id: u16, length: u32) -> Result<Vec<(u16, u16)>, Error> {
+        let mut sections = Vec::new();
+        let mut section_id = section_id;
+        let mut remaining = length;
+        let geometry = self.geometry;
+        while remaining > 0 {
+            let id = SectionId::try_from(section_id)
+                .ok()
+                .filter(|id| id.page() >= self.dat_start && id.index() <= geometry.max_index())
+                .filter(|id| {
+                    geometry.page_offset(id.page()) + u64::from(geometry.page_length())
+                        <= self.rom.len() as u64
+                })
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid section {:04x}.", section_id),
+                    )
+                })?;
+            let header = geometry.header_offset(id) as usize;
+            let take = remaining.min(u32::from(geometry.block_size()));
+            sections.push((section_id, take as u16));
+            remaining -= take;
+            let nSID = u16::from_le_bytes([self.rom[header + 2], self.rom[header + 3]]);
+            if remaining > 0 && nSID == KFS_NO_SECTION {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Section chain ends early at {:04x}.", section_id),
+                ));
+            }
+            section_id = nSID;
+        }
+        Ok(sections)
+    }
+
+    /// The data of the file `entry`, decompressed if it was stored
+    /// compressed.
+    pub fn read_file(&self, entry: &Entry) -> Result<Vec<u8>, Error> {
+        match entry {
+            Entry::File {
+                flags,
+                length,
+                section_id,
+                ..
+            } => {
+                let stored = self.contents(*section_id, *length)?;
+                if has_flag(*flags, KFS_FLAG_COMPRESSED) {
+                    compress::decompress(&stored)
+                } else {
+                    Ok(stored)
+                }
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a file.", entry.name()),
+            )),
+        }
+    }
+
+    /// Reads the contents of a file entry, as stored.
+    pub fn contents(&self, section_id: u16, length: u32) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(length as usize);
+        for (section_id, len) in self.chain(section_id, length)? {
+            let id = SectionId::try_from(section_id).unwrap();
+            let start = self.geometry.block_offset(id) as usize;
+            data.extend_from_slice(&self.rom[start..start + usize::from(len)]);
+        }
+        Ok(data)
+    }
+}
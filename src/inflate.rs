@@ -0,0 +1,257 @@
+use std::io::{Error, ErrorKind};
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// The order code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid(what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid deflate data: {}.", what),
+    )
+}
+
+// Reads bits least significant first, as deflate packs them.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, n: u8) -> Result<u32, Error> {
+        let mut value = 0;
+        for i in 0..n {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or_else(|| invalid("it ends early"))?;
+            value |= u32::from(byte >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    // Skips to the next byte boundary and returns the bytes from there.
+    fn align(&mut self) -> usize {
+        self.pos = self.pos.div_ceil(8) * 8;
+        self.pos / 8
+    }
+}
+
+// A canonical Huffman code, by how many codes there are of each length
+// and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&symbol| lengths[usize::from(symbol)] != 0)
+            .collect();
+        symbols.sort_by_key(|&symbol| lengths[usize::from(symbol)]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        // The first code of each length and the index of its symbol.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("a code is not in its table"))
+    }
+}
+
+// The code lengths of a dynamic block's literal/length and distance
+// codes.
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), Error> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths);
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (length, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let last = *lengths
+                    .last()
+                    .ok_or_else(|| invalid("a length repeats before the first"))?;
+                (last, 3 + bits.bits(2)?)
+            }
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literals + distances {
+        return Err(invalid("code lengths run past the table"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals]),
+        Huffman::new(&lengths[literals..]),
+    ))
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), Error> {
+    loop {
+        let symbol = usize::from(literals.decode(bits)?);
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                let length = *LENGTH_BASE
+                    .get(i)
+                    .ok_or_else(|| invalid("a length is out of range"))?;
+                let length = usize::from(length) + bits.bits(LENGTH_EXTRA[i])? as usize;
+                let i = usize::from(distances.decode(bits)?);
+                let distance = *DISTANCE_BASE
+                    .get(i)
+                    .ok_or_else(|| invalid("a distance is out of range"))?;
+                let distance = usize::from(distance) + bits.bits(DISTANCE_EXTRA[i])? as usize;
+                if distance > out.len() {
+                    return Err(invalid("a distance reaches before the start"));
+                }
+                // Byte by byte, as the copy may overlap what it writes.
+                for _ in 0..length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses raw deflate data (RFC 1951), as zip archives hold it.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut bits = Bits { data, pos: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                let start = bits.align();
+                let header = data
+                    .get(start..start + 4)
+                    .ok_or_else(|| invalid("it ends early"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("a stored block's length is corrupt"));
+                }
+                let block = data
+                    .get(start + 4..start + 4 + usize::from(length))
+                    .ok_or_else(|| invalid("it ends early"))?;
+                out.extend_from_slice(block);
+                bits.pos = (start + 4 + usize::from(length)) * 8;
+            }
+            1 => {
+                let (literals, distances) = fixed_tables();
+                codes(&mut bits, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("a block has the reserved type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "hello hello hello" in one fixed block, the second and third
+    // "hello" copied from the first.
+    const FIXED: [u8; 10] = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+
+    #[test]
+    fn inflates_each_kind_of_block() {
+        assert_eq!(inflate(&FIXED).unwrap(), b"hello hello hello");
+        let stored = [
+            0x01, 0x06, 0x00, 0xf9, 0xff, b's', b't', b'o', b'r', b'e', b'd',
+        ];
+        assert_eq!(inflate(&stored).unwrap(), b"stored");
+        let dynamic = [
+            0x05, 0xc1, 0x01, 0x01, 0x00, 0x00, 0x08, 0xc3, 0xa0, 0xac, 0xec, 0xf6, 0xcf, 0x20,
+            0x00, 0x54, 0x6d, 0x07, 0x50, 0xb5, 0xdd, 0x03,
+        ];
+        assert_eq!(inflate(&dynamic).unwrap(), b"aaaaaaaabbbbccd".repeat(2));
+    }
+
+    #[test]
+    fn inflates_blocks_one_after_another() {
+        let mut data = vec![0x00, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        data.extend_from_slice(&FIXED);
+        assert_eq!(inflate(&data).unwrap(), b"abchello hello hello");
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        assert!(inflate(&[]).is_err());
+        assert!(inflate(&FIXED[..6]).is_err());
+        // A stored block longer than what follows it.
+        assert!(inflate(&[0x01, 0x06, 0x00, 0xf9, 0xff, b's']).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        // A stored block whose length doesn't match its complement.
+        assert!(inflate(&[0x01, 0x06, 0x00, 0xf8, 0xff, 0, 0, 0, 0, 0, 0]).is_err());
+        // The reserved block type.
+        assert!(inflate(&[0x07]).is_err());
+        // A fixed block copying from before the start.
+        assert!(inflate(&[0x03, 0x02, 0x00]).is_err());
+        // A dynamic block repeating a code length before there is one.
+        assert!(inflate(&[0x05, 0x00, 0x12, 0x00]).is_err());
+    }
+}
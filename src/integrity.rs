@@ -0,0 +1,157 @@
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+
+use crate::sha256::{hex, Sha256};
+use crate::spec::INTEGRITY_MAGIC;
+use crate::*;
+
+/// The page `--integrity-page` writes below the FAT, so a kernel or
+/// bootloader can check at boot that the filesystem is as built. It
+/// starts with
+///
+/// ```text
+/// offset  length  field
+/// 0       4       INTEGRITY_MAGIC
+/// 4       1       version, 0
+/// 5       1       first data page hashed
+/// 6       1       last data page hashed
+/// 7       1       first (topmost) FAT page
+/// 8       1       FAT pages hashed, down from it
+/// 9       32      SHA-256 of those data pages, then those FAT pages,
+///                 each in full and in ascending page order
+/// ```
+///
+/// and is erased after that. A filesystem written to at run time no
+/// longer matches it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Integrity {
+    pub data_pages: RangeInclusive<u8>,
+    pub fat_start: u8,
+    pub fat_pages: u8,
+    pub sha256: [u8; 32],
+}
+
+const VERSION: u8 = 0;
+const HEADER_LENGTH: usize = 9;
+
+// The bytes of `pages` of `rom`.
+fn pages(rom: &[u8], geometry: Geometry, pages: RangeInclusive<u8>) -> Result<&[u8], Error> {
+    let start = geometry.page_offset(*pages.start()) as usize;
+    let end = geometry.page_offset(*pages.end()) as usize + usize::from(geometry.page_length());
+    rom.get(start..end).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Page {:02x} is past the end of the ROM.", pages.end()),
+        )
+    })
+}
+
+impl Integrity {
+    /// Hashes `data_pages` and the FAT at `fat_start` as they are in
+    /// `rom`.
+    pub fn compute(
+        rom: &[u8],
+        geometry: Geometry,
+        data_pages: RangeInclusive<u8>,
+        fat_start: u8,
+    ) -> Result<Integrity, Error> {
+        let fat_pages = geometry.fat_pages();
+        let first_fat = (u16::from(fat_start) + 1)
+            .checked_sub(u16::from(fat_pages))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "A FAT of {} pages can't start at page {:02x}.",
+                        fat_pages, fat_start
+                    ),
+                )
+            })? as u8;
+        let mut hasher = Sha256::new();
+        hasher.update(pages(rom, geometry, data_pages.clone())?);
+        hasher.update(pages(rom, geometry, first_fat..=fat_start)?);
+        Ok(Integrity {
+            data_pages,
+            fat_start,
+            fat_pages,
+            sha256: hasher.finish(),
+        })
+    }
+
+    /// The whole integrity page, `page_length` bytes.
+    pub fn encode(&self, page_length: u16) -> Vec<u8> {
+        let mut page = vec![0xFF; usize::from(page_length)];
+        page[..4].copy_from_slice(INTEGRITY_MAGIC);
+        page[4] = VERSION;
+        page[5] = *self.data_pages.start();
+        page[6] = *self.data_pages.end();
+        page[7] = self.fat_start;
+        page[8] = self.fat_pages;
+        page[HEADER_LENGTH..HEADER_LENGTH + 32].copy_from_slice(&self.sha256);
+        page
+    }
+
+    /// Reads an integrity page, if `page` is one.
+    pub fn decode(page: &[u8]) -> Option<Integrity> {
+        if !page.starts_with(INTEGRITY_MAGIC) || page.get(4) != Some(&VERSION) {
+            return None;
+        }
+        let header = page.get(..HEADER_LENGTH + 32)?;
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&header[HEADER_LENGTH..]);
+        Some(Integrity {
+            data_pages: header[5]..=header[6],
+            fat_start: header[7],
+            fat_pages: header[8],
+            sha256,
+        })
+    }
+}
+
+/// Finds the integrity page among the pages from `dat_start` up to the
+/// FAT at `fat_start`, returning where it is and what it says.
+pub fn find(
+    rom: &[u8],
+    geometry: Geometry,
+    dat_start: u8,
+    fat_start: u8,
+) -> Option<(u8, Integrity)> {
+    (dat_start..fat_start).find_map(|page| {
+        let start = geometry.page_offset(page) as usize;
+        let integrity = Integrity::decode(rom.get(start..)?)?;
+        Some((page, integrity))
+    })
+}
+
+/// Checks the filesystem against its integrity page, if it has one,
+/// returning the page.
+pub fn check(
+    rom: &[u8],
+    geometry: Geometry,
+    dat_start: u8,
+    fat_start: u8,
+) -> Result<Option<u8>, Error> {
+    let (page, recorded) = match find(rom, geometry, dat_start, fat_start) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let geometry = geometry.with_fat_pages(recorded.fat_pages)?;
+    let actual = Integrity::compute(
+        rom,
+        geometry,
+        recorded.data_pages.clone(),
+        recorded.fat_start,
+    )?;
+    if actual.sha256 != recorded.sha256 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "The filesystem doesn't match the integrity page {:02x}: its SHA-256 is {}, not {}.",
+                page,
+                hex(&actual.sha256),
+                hex(&recorded.sha256)
+            ),
+        ));
+    }
+    Ok(Some(page))
+}
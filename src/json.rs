@@ -0,0 +1,181 @@
+use std::fmt;
+
+/// A minimal JSON value, just enough for the reports this tool emits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Builds an object from `(key, value)` pairs, keeping their order.
+    pub fn object<K: Into<String>>(pairs: Vec<(K, Value)>) -> Value {
+        Value::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+macro_rules! int_from {
+    ($($t:ty),*) => {$(
+        impl From<$t> for Value {
+            fn from(n: $t) -> Value {
+                Value::Int(n as i64)
+            }
+        }
+    )*};
+}
+int_from!(u8, u16, u32, u64, usize, i64);
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Value {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Value {
+        Value::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        // `{:#}` pretty prints with two space indentation.
+        let pretty = f.alternate();
+        let newline = |f: &mut fmt::Formatter, depth: usize| {
+            if pretty {
+                write!(f, "\n{:1$}", "", depth * 2)
+            } else {
+                Ok(())
+            }
+        };
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(n) if n.is_finite() => write!(f, "{}", n),
+            Value::Float(_) => f.write_str("null"),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) if items.is_empty() => f.write_str("[]"),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    newline(f, depth + 1)?;
+                    item.write(f, depth + 1)?;
+                }
+                newline(f, depth)?;
+                f.write_str("]")
+            }
+            Value::Object(pairs) if pairs.is_empty() => f.write_str("{}"),
+            Value::Object(pairs) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    newline(f, depth + 1)?;
+                    write_string(f, key)?;
+                    f.write_str(if pretty { ": " } else { ":" })?;
+                    value.write(f, depth + 1)?;
+                }
+                newline(f, depth)?;
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        let value = Value::from("a \"quoted\" \\ path\n\r\t\u{1}é");
+        assert_eq!(value.to_string(), r#""a \"quoted\" \\ path\n\r\t\u0001é""#);
+        let object = Value::object(vec![("k\"ey", Value::Null)]);
+        assert_eq!(object.to_string(), r#"{"k\"ey":null}"#);
+    }
+
+    #[test]
+    fn nests_arrays_and_objects() {
+        let value = Value::object(vec![
+            ("pages", Value::from(vec![4u8, 5])),
+            (
+                "options",
+                Value::object(vec![("mirror_fat", true.into()), ("layout", Value::Null)]),
+            ),
+            ("empty", Value::Array(Vec::new())),
+            ("none", Value::object(Vec::<(&str, Value)>::new())),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"pages":[4,5],"options":{"mirror_fat":true,"layout":null},"empty":[],"none":{}}"#
+        );
+        assert_eq!(
+            format!("{:#}", value),
+            "{\n  \"pages\": [\n    4,\n    5\n  ],\n  \"options\": {\n    \"mirror_fat\": true,\n    \
+             \"layout\": null\n  },\n  \"empty\": [],\n  \"none\": {}\n}"
+        );
+    }
+
+    #[test]
+    fn writes_what_json_cant_hold_as_null() {
+        let value = Value::from(vec![f64::NAN, f64::INFINITY, 1.5]);
+        assert_eq!(value.to_string(), "[null,null,1.5]");
+        assert_eq!(Value::from(None::<u8>).to_string(), "null");
+    }
+}
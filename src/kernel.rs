@@ -0,0 +1,120 @@
+//! An opt-in record of where a kernel expects its filesystem.
+//!
+//! Upstream KnightOS kernels don't describe their filesystem anywhere in
+//! the ROM: its pages are constants built into their code for each
+//! calculator (those `geometry::PLATFORMS` lists), so there is no header
+//! to read them from. This record is regenkfs's own, for kernels that
+//! choose to carry it, such as forks that move the filesystem; a ROM
+//! without one is laid out from `--layout`, `--platform` or its length.
+use std::io::{Error, ErrorKind};
+
+use crate::layout::Layout;
+use crate::*;
+
+/// Starts the geometry record a kernel may carry in its first pages.
+/// Not part of KnightOS: a kernel has one only if it was built to.
+pub const MAGIC: &[u8; 8] = b"KFSGEOM\0";
+
+/// Bytes of the record: the magic number, a version byte (0), dat_start,
+/// fat_start, fat_pages, then the page length and block size as
+/// little-endian words, and two bytes left erased.
+pub const RECORD_LENGTH: usize = 18;
+
+/// Pages at the bottom of the ROM holding the kernel, which are searched
+/// for the record.
+pub const KERNEL_PAGES: u8 = 4;
+
+/// Where the kernel in a ROM expects its filesystem, as the geometry
+/// record it opted into says. Finding none says nothing about the
+/// kernel: upstream ones never carry it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeometryRecord {
+    pub geometry: Geometry,
+    pub dat_start: u8,
+    pub fat_start: u8,
+    /// ROM offset of the record.
+    pub offset: usize,
+}
+
+fn invalid(offset: usize, what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("The kernel's geometry record at {:#x} {}.", offset, what),
+    )
+}
+
+impl GeometryRecord {
+    /// Looks for the record in `kernel`, the first `KERNEL_PAGES` pages of
+    /// a ROM or as much of them as there is. `None` for a kernel without
+    /// one, as upstream kernels are.
+    pub fn find(kernel: &[u8]) -> Result<Option<GeometryRecord>, Error> {
+        let offset = match kernel
+            .windows(MAGIC.len())
+            .position(|window| window == &MAGIC[..])
+        {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let record = kernel
+            .get(offset..offset + RECORD_LENGTH)
+            .ok_or_else(|| invalid(offset, "is cut short"))?;
+        if record[8] != 0 {
+            return Err(invalid(
+                offset,
+                &format!("has unsupported version {}", record[8]),
+            ));
+        }
+        let page_length = u16::from_le_bytes([record[12], record[13]]);
+        let block_size = u16::from_le_bytes([record[14], record[15]]);
+        let geometry = Geometry::new(page_length, block_size)
+            .and_then(|geometry| geometry.with_fat_pages(record[11]))
+            .map_err(|err| invalid(offset, &format!("is unusable: {}", err)))?;
+        Ok(Some(GeometryRecord {
+            geometry,
+            dat_start: record[9],
+            fat_start: record[10],
+            offset,
+        }))
+    }
+
+    /// The layout to write the filesystem with when nothing else is
+    /// asked for.
+    pub fn layout(self) -> Layout {
+        Layout {
+            geometry: self.geometry,
+            dat_start: self.dat_start,
+            fat_start: Some(self.fat_start),
+            ..Layout::default()
+        }
+    }
+
+    /// Fails unless the filesystem `layout` puts in a ROM of `length`
+    /// bytes is where the kernel expects it.
+    pub fn check(self, layout: &Layout, length: u64) -> Result<(), Error> {
+        let fat_start = layout.fat_start_for(length)?;
+        if (layout.geometry, layout.dat_start, fat_start)
+            == (self.geometry, self.dat_start, self.fat_start)
+        {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Error: The kernel expects its filesystem in pages {:02x}-{:02x} \
+                 ({:#x}-byte pages, {:#x}-byte blocks, {} FAT pages), \
+                 but it would be written to pages {:02x}-{:02x} \
+                 ({:#x}-byte pages, {:#x}-byte blocks, {} FAT pages).",
+                self.dat_start,
+                self.fat_start,
+                self.geometry.page_length(),
+                self.geometry.block_size(),
+                self.geometry.fat_pages(),
+                layout.dat_start,
+                fat_start,
+                layout.geometry.page_length(),
+                layout.geometry.block_size(),
+                layout.geometry.fat_pages()
+            ),
+        ))
+    }
+}
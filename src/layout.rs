@@ -0,0 +1,262 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::geometry::Platform;
+use crate::toml::{self, Table, Value};
+use crate::*;
+
+/// Which data pages get the `KFS` magic number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Magic {
+    /// Only pages that data is written to, as genkfs does.
+    #[default]
+    Used,
+    /// Every data page that isn't reserved, used or not.
+    All,
+}
+
+impl FromStr for Magic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Magic, String> {
+        match s {
+            "used" => Ok(Magic::Used),
+            "all" => Ok(Magic::All),
+            _ => Err(format!(
+                "Unknown magic placement {} (expected used or all).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Magic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Magic::Used => "used",
+            Magic::All => "all",
+        })
+    }
+}
+
+/// The geometry of a filesystem, for kernels and hardware that place it
+/// differently from stock KnightOS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub geometry: Geometry,
+    /// First data page. Pages below it belong to the kernel.
+    pub dat_start: u8,
+    /// First (topmost) FAT page, or `None` to derive it from the ROM's
+    /// length.
+    pub fat_start: Option<u8>,
+    /// Data pages left alone: never blanked and never given data.
+    pub reserved: Vec<RangeInclusive<u8>>,
+    pub magic: Magic,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            geometry: Geometry::default(),
+            dat_start: 0x04,
+            fat_start: None,
+            reserved: Vec::new(),
+            magic: Magic::Used,
+        }
+    }
+}
+
+impl Layout {
+    /// The stock layout of `platform`, whatever the size of the ROM.
+    pub fn for_platform(platform: Platform) -> Layout {
+        Layout {
+            dat_start: platform.dat_start,
+            fat_start: Some(platform.fat_start),
+            ..Layout::default()
+        }
+    }
+
+    /// Whether `page` lies in a reserved range.
+    pub fn is_reserved(&self, page: u8) -> bool {
+        self.reserved.iter().any(|range| range.contains(&page))
+    }
+
+    /// The first FAT page in a ROM of `length` bytes.
+    pub fn fat_start_for(&self, length: u64) -> Result<u8, Error> {
+        match self.fat_start {
+            Some(fat_start) => Ok(fat_start),
+            None => self.geometry.fat_start_for(length),
+        }
+    }
+
+    /// The bytes of a ROM of `length` bytes the filesystem spans, from
+    /// its first data page to the top of its FAT.
+    pub fn span(&self, length: u64) -> Result<Range<u64>, Error> {
+        let fat_end = self.geometry.fat_end(self.fat_start_for(length)?);
+        Ok(self.geometry.page_offset(self.dat_start)..u64::from(fat_end))
+    }
+}
+
+fn describe(name: &str) -> String {
+    if name.is_empty() {
+        "the top-level filesystem".to_string()
+    } else {
+        format!("filesystem [{}]", name)
+    }
+}
+
+/// Picks the filesystem called `name` (`""` for the top-level one) out
+/// of `layouts`, checking that in a ROM of `length` bytes it doesn't
+/// overlap any of the others.
+pub fn select(layouts: &[(String, Layout)], name: &str, length: u64) -> Result<Layout, Error> {
+    let (_, layout) =
+        layouts
+            .iter()
+            .find(|(other, _)| other == name)
+            .ok_or_else(|| match name {
+                "" => {
+                    invalid("The layout has only named filesystems; pick one of them.".to_string())
+                }
+                name => invalid(format!("The layout has no filesystem [{}].", name)),
+            })?;
+    let span = layout.span(length)?;
+    for (other, other_layout) in layouts.iter().filter(|(other, _)| other != name) {
+        let other_span = other_layout.span(length)?;
+        if span.start < other_span.end && other_span.start < span.end {
+            return Err(invalid(format!(
+                "In the layout, {} at {:#x}..{:#x} overlaps {} at {:#x}..{:#x}.",
+                describe(name),
+                span.start,
+                span.end,
+                describe(other),
+                other_span.start,
+                other_span.end
+            )));
+        }
+    }
+    Ok(layout.clone())
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn integer<T: TryFrom<i64>>(table: &str, key: &str, value: &Value) -> Result<T, Error> {
+    match value {
+        Value::Integer(n) => {
+            T::try_from(*n).map_err(|_| invalid(format!("{} {:#x} is out of range.", key, n)))
+        }
+        value => Err(toml::expected(table, key, "an integer", value)),
+    }
+}
+
+// A reserved range is either one page or a `[first, last]` pair.
+fn page_range(table: &str, value: &Value) -> Result<RangeInclusive<u8>, Error> {
+    match value {
+        Value::Array(pair) if pair.len() == 2 => {
+            let first = integer(table, "reserved", &pair[0])?;
+            let last = integer(table, "reserved", &pair[1])?;
+            if first > last {
+                return Err(invalid(format!(
+                    "Reserved range {:#x}..{:#x} is backwards.",
+                    first, last
+                )));
+            }
+            Ok(first..=last)
+        }
+        value => {
+            let page = integer(table, "reserved", value)?;
+            Ok(page..=page)
+        }
+    }
+}
+
+// The layout of one instance, from the keys of its table.
+fn parse_instance(table: &Table) -> Result<Layout, Error> {
+    let name = table.name.as_str();
+    let mut layout = Layout::default();
+    let mut page_length = PAGE_LENGTH;
+    let mut block_size = BLOCK_SIZE;
+    let mut fat_pages = FAT_PAGES;
+    for (key, value) in &table.entries {
+        match key.as_str() {
+            "page_length" => page_length = integer(name, key, value)?,
+            "block_size" => block_size = integer(name, key, value)?,
+            "fat_pages" => fat_pages = integer(name, key, value)?,
+            "dat_start" => layout.dat_start = integer(name, key, value)?,
+            "fat_start" => layout.fat_start = Some(integer(name, key, value)?),
+            "reserved" => match value {
+                Value::Array(ranges) => {
+                    layout.reserved = ranges
+                        .iter()
+                        .map(|range| page_range(name, range))
+                        .collect::<Result<_, _>>()?
+                }
+                value => return Err(toml::expected(name, key, "an array", value)),
+            },
+            "magic" => match value {
+                Value::String(magic) => layout.magic = magic.parse().map_err(invalid)?,
+                value => return Err(toml::expected(name, key, "a string", value)),
+            },
+            _ => return Err(invalid(format!("Unknown layout key {}.", key))),
+        }
+    }
+    layout.geometry = Geometry::new(page_length, block_size)?.with_fat_pages(fat_pages)?;
+    if layout.is_reserved(layout.dat_start) {
+        return Err(invalid(format!(
+            "The first data page {:02x} cannot be reserved.",
+            layout.dat_start
+        )));
+    }
+    Ok(layout)
+}
+
+/// Parses a layout file of top-level keys, all optional:
+///
+/// ```text
+/// page_length = 0x4000
+/// block_size = 0x100
+/// fat_pages = 4
+/// dat_start = 0x04
+/// fat_start = 0x3b            # otherwise derived from the ROM's length
+/// reserved = [[0x10, 0x13], 0x20]
+/// magic = "used"              # or "all"
+/// ```
+pub fn parse_layout(text: &str) -> Result<Layout, Error> {
+    match parse_layouts(text)?
+        .into_iter()
+        .find(|(name, _)| name.is_empty())
+    {
+        Some((_, layout)) => Ok(layout),
+        None => Err(invalid(
+            "The layout has no top-level filesystem.".to_string(),
+        )),
+    }
+}
+
+/// Parses a layout file that may describe several filesystems in one
+/// ROM, as for A/B experiments: the top-level keys give one, and each
+/// `[name]` table another, with the same keys. Returns each by name, the
+/// top-level one being `""`. A file with only tables has no top-level
+/// filesystem.
+pub fn parse_layouts(text: &str) -> Result<Vec<(String, Layout)>, Error> {
+    let tables = toml::parse(text)?;
+    let only_tables = tables.len() > 1;
+    let mut layouts = Vec::new();
+    for table in &tables {
+        if table.name.is_empty() && table.entries.is_empty() && only_tables {
+            continue;
+        }
+        layouts.push((table.name.clone(), parse_instance(table)?));
+    }
+    Ok(layouts)
+}
+
+pub fn read_layouts(path: &Path) -> Result<Vec<(String, Layout)>, Error> {
+    parse_layouts(&fs::read_to_string(path)?)
+}
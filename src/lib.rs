@@ -0,0 +1,87 @@
+//! Reading and writing KnightOS KFS filesystems.
+#![allow(non_snake_case)]
+use std::io::Error;
+
+pub mod address;
+pub mod allocator;
+pub mod batch;
+pub mod budget;
+pub mod build_info;
+pub mod cache;
+pub mod checksum;
+pub mod cmp;
+pub mod compress;
+pub mod context;
+pub mod corrupt;
+pub mod cpio;
+pub mod diagnostic;
+pub mod erase;
+pub mod estimate;
+pub mod extract;
+pub mod fat;
+pub mod fixture;
+pub mod fragmentation;
+pub mod geometry;
+pub mod glob;
+pub mod hints;
+pub mod hook;
+pub mod image;
+pub mod inflate;
+pub mod integrity;
+pub mod json;
+pub mod kernel;
+pub mod layout;
+pub mod lint;
+pub mod listing;
+pub mod long_path;
+pub mod manifest;
+pub mod minimize;
+pub mod model;
+pub mod page_fill;
+pub mod page_map;
+pub mod paranoid;
+pub mod random;
+pub mod replay;
+pub mod rom_file;
+pub mod scan;
+pub mod section;
+pub mod sha256;
+pub mod source;
+pub mod spec;
+pub mod stage;
+pub mod stats;
+pub mod tar;
+pub mod toml;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod validate;
+pub mod verify;
+pub mod window;
+pub mod zip;
+
+pub use context::{Context, Options, Region};
+pub use geometry::Geometry;
+pub use image::Image;
+pub use page_map::PageMap;
+pub use scan::AllocationMap;
+pub use section::SectionId;
+
+pub use spec::{
+    has_flag, BLOCK_SIZE, FAT_PAGES, KFS_DIR_ID, KFS_FILE_ID, KFS_FLAG_CHECKSUM,
+    KFS_FLAG_COMPRESSED, KFS_FLAG_EXECUTABLE, KFS_FLAG_HIDDEN, KFS_FLAG_READ_ONLY,
+    KFS_MAX_FILE_LEN, KFS_NO_SECTION, KFS_SYM_ID, KFS_VERSION, MIRROR_OFFSET, PAGE_LENGTH,
+};
+
+pub(crate) fn div_rem<T: std::ops::Div<Output = T> + std::ops::Rem<Output = T> + Copy>(
+    x: T,
+    y: T,
+) -> (T, T) {
+    let quot = x / y;
+    let rem = x % y;
+    (quot, rem)
+}
+
+/// Computes the first FAT page for a ROM of `length` bytes.
+pub fn fat_start_for(length: u64) -> Result<u8, Error> {
+    Geometry::default().fat_start_for(length)
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::io::Error;
+
+use crate::source::{self, KfsSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Directory,
+    File,
+    Symlink,
+}
+
+/// What KnightOS expects to find at boot: the kernel starts /bin/init,
+/// which reads /etc/inittab, and programs load libraries from /lib.
+pub const REQUIRED: &[(&str, Kind)] = &[
+    ("/bin", Kind::Directory),
+    ("/etc", Kind::Directory),
+    ("/lib", Kind::Directory),
+    ("/bin/init", Kind::File),
+    ("/etc/inittab", Kind::File),
+];
+
+/// The type of every path in the model in `source`.
+pub fn entries(source: &dyn KfsSource) -> Result<HashMap<String, Kind>, Error> {
+    let mut entries = HashMap::new();
+    source::walk(source, &mut |path, member| {
+        let kind = match member.kind {
+            source::Kind::Directory => Kind::Directory,
+            source::Kind::Symlink => Kind::Symlink,
+            _ => Kind::File,
+        };
+        entries.insert(path.to_string(), kind);
+        Ok(true)
+    })?;
+    Ok(entries)
+}
+
+/// Warnings for every piece of the expected KnightOS layout the model is
+/// missing. A symlink is accepted wherever a file is expected.
+pub fn lint(entries: &HashMap<String, Kind>) -> Vec<String> {
+    REQUIRED
+        .iter()
+        .filter_map(|&(path, kind)| match (entries.get(path), kind) {
+            (Some(found), _) if *found == kind => None,
+            (Some(Kind::Symlink), Kind::File) => None,
+            (Some(_), Kind::Directory) => Some(format!("{} is not a directory.", path)),
+            (Some(_), _) => Some(format!("{} is not a file.", path)),
+            (None, Kind::Directory) => Some(format!("Missing directory {}.", path)),
+            (None, _) => Some(format!("Missing {}, which KnightOS needs to boot.", path)),
+        })
+        .collect()
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, Write};
+use std::str::FromStr;
+
+use crate::fat::Entry;
+use crate::glob;
+use crate::image::{Image, Node};
+use crate::*;
+
+/// A column of a long listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// `d`, `-` or `l`, as ls shows it.
+    Type,
+    Flags,
+    Size,
+    /// First section of a file's data.
+    Section,
+    /// Id of the directory holding the entry.
+    Parent,
+    /// The path, marked up as in a short listing.
+    Name,
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Column, String> {
+        match s {
+            "type" => Ok(Column::Type),
+            "flags" => Ok(Column::Flags),
+            "size" => Ok(Column::Size),
+            "section" => Ok(Column::Section),
+            "parent" => Ok(Column::Parent),
+            "name" => Ok(Column::Name),
+            _ => Err(format!(
+                "Unknown column {} (expected type, flags, size, section, parent or name).",
+                s
+            )),
+        }
+    }
+}
+
+/// The columns of a long listing, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns(pub Vec<Column>);
+
+impl Default for Columns {
+    fn default() -> Columns {
+        Columns(vec![
+            Column::Type,
+            Column::Flags,
+            Column::Size,
+            Column::Section,
+            Column::Parent,
+            Column::Name,
+        ])
+    }
+}
+
+/// Parses a comma-separated list of column names, such as `size,name`.
+impl FromStr for Columns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Columns, String> {
+        s.split(',')
+            .map(|column| column.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Columns)
+    }
+}
+
+/// A path as a short listing shows it: directories end in `/` and
+/// symlinks show their target.
+pub struct Name<'n>(pub &'n Node);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0.record.entry {
+            Entry::Directory { .. } => write!(f, "{}/", self.0.path),
+            Entry::Symlink { target, .. } => write!(f, "{} -> {}", self.0.path, target),
+            Entry::File { .. } => write!(f, "{}", self.0.path),
+        }
+    }
+}
+
+fn cell(node: &Node, column: Column) -> String {
+    let entry = &node.record.entry;
+    match (column, entry) {
+        (Column::Type, Entry::Directory { .. }) => "d".to_string(),
+        (Column::Type, Entry::File { .. }) => "-".to_string(),
+        (Column::Type, Entry::Symlink { .. }) => "l".to_string(),
+        (Column::Flags, Entry::File { flags, .. })
+        | (Column::Flags, Entry::Directory { flags, .. }) => {
+            format!("{:02x}", flags)
+        }
+        (Column::Flags, Entry::Symlink { .. }) => "--".to_string(),
+        // The largest KFS file has 8 digits.
+        (Column::Size, Entry::File { length, .. }) => format!("{:8}", length),
+        (Column::Size, _) => format!("{:>8}", "-"),
+        (Column::Section, Entry::File { section_id, .. }) if *section_id != KFS_NO_SECTION => {
+            format!("{:04x}", section_id)
+        }
+        (Column::Section, _) => "   -".to_string(),
+        (Column::Parent, entry) => format!("{:04x}", entry.parent()),
+        (Column::Name, _) => Name(node).to_string(),
+    }
+}
+
+/// Writes a line per node with the given columns, separated by spaces.
+pub fn write_long<W: Write>(out: &mut W, nodes: &[&Node], columns: &Columns) -> Result<(), Error> {
+    for node in nodes {
+        let cells: Vec<String> = columns.0.iter().map(|&column| cell(node, column)).collect();
+        writeln!(out, "{}", cells.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Where a symlink at `link` pointing to `target` leads, as a normalized
+/// path. `..` stops at the root, as it does in the kernel.
+pub fn resolve(link: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    if !target.starts_with('/') {
+        parts.extend(link.split('/').filter(|part| !part.is_empty()));
+        parts.pop();
+    }
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts
+        .iter()
+        .fold(String::new(), |acc, part| acc + "/" + part)
+}
+
+fn dot_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes a Graphviz graph of the directory tree: an edge from each
+/// directory to what it holds, and a dashed one from each symlink to
+/// where it resolves, drawn in red if nothing is there.
+pub fn write_dot<W: Write>(out: &mut W, nodes: &[&Node]) -> Result<(), Error> {
+    let ids: HashMap<&str, String> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.path.as_str(), format!("n{}", i)))
+        .chain(std::iter::once(("", "root".to_string())))
+        .collect();
+    let mut edges = Vec::new();
+    let mut missing = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        // Unless the parent was left out of the listing.
+        if let Some(parent) = ids.get(parent_of(&node.path)) {
+            edges.push(format!("{} -> n{};", parent, i));
+        }
+        if let Entry::Symlink { target, .. } = &node.record.entry {
+            let resolved = resolve(&node.path, target);
+            match ids.get(resolved.as_str()) {
+                Some(to) => edges.push(format!("n{} -> {} [style=dashed];", i, to)),
+                None => {
+                    edges.push(format!(
+                        "n{} -> missing{} [style=dashed, color=red];",
+                        i,
+                        missing.len()
+                    ));
+                    missing.push(resolved);
+                }
+            }
+        }
+    }
+    writeln!(out, "digraph tree {{")?;
+    writeln!(out, "  rankdir=LR;")?;
+    if edges.iter().any(|edge| edge.contains("root")) {
+        writeln!(out, "  root [label=\"/\", shape=folder];")?;
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        let name = node.path.rsplit('/').next().unwrap_or_default();
+        let shape = match node.record.entry {
+            Entry::Directory { .. } => "folder",
+            Entry::File { .. } => "box",
+            Entry::Symlink { .. } => "cds",
+        };
+        writeln!(
+            out,
+            "  n{} [label=\"{}\", shape={}];",
+            i,
+            dot_label(name),
+            shape
+        )?;
+    }
+    for (i, path) in missing.iter().enumerate() {
+        writeln!(
+            out,
+            "  missing{} [label=\"{}\", shape=box, style=dashed, color=red];",
+            i,
+            dot_label(path)
+        )?;
+    }
+    for edge in edges {
+        writeln!(out, "  {}", edge)?;
+    }
+    writeln!(out, "}}")
+}
+
+// The path of the directory holding `path`, `""` for the root.
+fn parent_of(path: &str) -> &str {
+    path.rfind('/').map_or("", |i| &path[..i])
+}
+
+/// The entries whose name matches the glob `pattern`, or whose whole path
+/// does if `pattern` holds a `/`. Entries are matched while the FAT is
+/// scanned, so only the matches are kept.
+pub fn find(image: &Image, pattern: &str) -> Result<Vec<Node>, Error> {
+    let whole_path = pattern.contains('/');
+    let mut found = Vec::new();
+    image.walk(|path, record| {
+        let name = if whole_path {
+            path
+        } else {
+            record.entry.name()
+        };
+        if glob::matches(pattern, name) {
+            found.push(Node {
+                path: path.to_string(),
+                record: record.clone(),
+            });
+        }
+    })?;
+    Ok(found)
+}
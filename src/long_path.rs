@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+/// `path` as Windows opens it past the 260-character `MAX_PATH` limit:
+/// made absolute, with `\\?\` (or `\\?\UNC\` for a share) in front, so a
+/// deep model tree or a ROM far down a build directory still opens.
+/// Paths like that are taken literally, so `/` must not be used to join
+/// anything onto it.
+///
+/// Elsewhere, and for a path that can't be made absolute, `path` as it
+/// is.
+#[cfg(windows)]
+pub fn extended(path: &Path) -> PathBuf {
+    let absolute = match std::path::absolute(path) {
+        Ok(absolute) => absolute,
+        Err(_) => return path.to_path_buf(),
+    };
+    match absolute.to_str().and_then(verbatim) {
+        Some(extended) => PathBuf::from(extended),
+        // Already verbatim, a device, or not Unicode.
+        None => absolute,
+    }
+}
+
+// The `\\?\` form of `absolute`, a drive or share path `std::path::absolute`
+// has already taken any `.` and `..` out of. Only string handling, so it's
+// tested on every platform.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn verbatim(absolute: &str) -> Option<String> {
+    let absolute = absolute.replace('/', r"\");
+    let (prefix, rest) = match absolute.strip_prefix(r"\\") {
+        Some(rest) if rest.starts_with(['?', '.']) => return None,
+        Some(rest) => (r"\\?\UNC".to_string(), rest),
+        None => match absolute.as_bytes() {
+            [drive, b':', b'\\', ..] if drive.is_ascii_alphabetic() => {
+                (format!(r"\\?\{}:", char::from(*drive)), &absolute[3..])
+            }
+            _ => return None,
+        },
+    };
+    let parts: Vec<&str> = rest.split('\\').filter(|part| !part.is_empty()).collect();
+    match parts.is_empty() {
+        true => Some(format!(r"{}\", prefix)),
+        false => Some(format!(r"{}\{}", prefix, parts.join(r"\"))),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drive_paths_get_the_verbatim_prefix() {
+        assert_eq!(
+            verbatim(r"C:\build\out\rom.img").as_deref(),
+            Some(r"\\?\C:\build\out\rom.img")
+        );
+        assert_eq!(
+            verbatim("d:/model//lib/").as_deref(),
+            Some(r"\\?\d:\model\lib")
+        );
+        assert_eq!(verbatim(r"C:\").as_deref(), Some(r"\\?\C:\"));
+    }
+
+    #[test]
+    fn shares_get_the_verbatim_unc_prefix() {
+        assert_eq!(
+            verbatim(r"\\server\share\model").as_deref(),
+            Some(r"\\?\UNC\server\share\model")
+        );
+    }
+
+    #[test]
+    fn verbatim_device_and_relative_paths_are_left_alone() {
+        for path in &[
+            r"\\?\C:\model",
+            r"\\?\UNC\server\share",
+            r"\\.\COM1",
+            r"model\lib",
+            r"\model",
+            "C:model",
+        ] {
+            assert_eq!(verbatim(path), None, "{}", path);
+        }
+    }
+}
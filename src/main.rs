@@ -1,368 +1,1720 @@
-#![allow(non_snake_case)]
-use std::path::Path;
-use std::path::PathBuf;
-use std::{
-    ffi::OsString,
-    io::{BufReader, BufWriter, SeekFrom},
-};
-
-use io::{Error, ErrorKind};
-use std::fs::{self, DirEntry, File, OpenOptions};
-use std::io;
-use std::io::Read;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Error, ErrorKind, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::{convert::TryInto, io::prelude::*};
+use std::str::FromStr;
+use structopt::clap;
 use structopt::StructOpt;
 
-const PAGE_LENGTH: u16 = 0x4000;
-const BLOCK_SIZE: u16 = 0x100;
-const KFS_FILE_ID: u8 = 0x7F;
-const KFS_DIR_ID: u8 = 0xBF;
-const KFS_SYM_ID: u8 = 0xDF;
-const KFS_VERSION: u8 = 0x0;
-
-const KFS_MAX_FILE_LEN: u64 = 0xFFFFFF;
+use regenkfs::context::{edit_rom, open_image, resolve_layout};
+use regenkfs::diagnostic::Diagnostic;
+use regenkfs::fragmentation::Fragmentation;
+use regenkfs::geometry::Platform;
+use regenkfs::kernel::{GeometryRecord, KERNEL_PAGES};
+use regenkfs::tar::TarSink;
+use regenkfs::window::Span;
+use regenkfs::zip::ZipSink;
+use regenkfs::{
+    allocator, batch, budget, checksum, cmp, corrupt, erase, estimate, extract, fat, fixture, glob,
+    hook, integrity, listing, manifest, minimize, model, page_fill, random, replay, source,
+    validate, verify, window, AllocationMap, Context, Options, PAGE_LENGTH,
+};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "regenkfs")]
+#[structopt(
+    name = "regenkfs",
+    usage = "regenkfs [FLAGS] <input> <model>\n    regenkfs <SUBCOMMAND>"
+)]
 /// A reimplementation of the KnightOS genkfs tool in Rust.
 ///
 struct Opt {
-    /// The ROM file to write the filesystem to.
+    // Writing a filesystem is what regenkfs does without a subcommand,
+    // as genkfs did, so its flags are at the top level too.
+    #[structopt(flatten)]
+    build: BuildOpt,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct BuildOpt {
+    /// The ROM file to write the filesystem to. May be a glob such as 'roms/*.rom'.
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    input: Option<PathBuf>,
 
-    /// Path to a directory that will be copied into / on the new filesystem.
-    model: PathBuf,
+    /// Path to a directory (or a cpio archive of one) that will be copied into / on the new
+    /// filesystem, or - to read a tar archive of it from stdin.
+    model: Option<PathBuf>,
+
+    /// Another ROM file to write the same filesystem to. May be repeated.
+    #[structopt(short, long = "target", parse(from_os_str), number_of_values = 1)]
+    targets: Vec<PathBuf>,
+
+    /// Copy the ROM to this file and write the filesystem into the copy instead; - writes the finished image to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Don't print progress.
+    #[structopt(short, long)]
+    quiet: bool,
+
+    /// Also write a copy of the FAT to the pages directly below it.
+    #[structopt(long)]
+    mirror_fat: bool,
+
+    /// Record a CRC32 of every written page in this sidecar file.
+    #[structopt(long, parse(from_os_str))]
+    checksums: Option<PathBuf>,
+
+    /// Write the SHA-256 and size of every stored file to this manifest.
+    #[structopt(long, parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    /// Write a JSON report of the build (geometry, options, file placement) to this file.
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+
+    /// Warn if the model is missing /bin, /etc, /lib or the files KnightOS needs to boot.
+    #[structopt(long)]
+    lint: bool,
+
+    /// Fail the build if it exceeds any of the size budgets in this file.
+    #[structopt(long, parse(from_os_str))]
+    budgets: Option<PathBuf>,
+
+    /// After writing, read the filesystem back and check every entry against the model.
+    #[structopt(long)]
+    verify: bool,
+
+    /// Log the offset, length and meaning of every write to the ROM to this file.
+    #[structopt(long, parse(from_os_str))]
+    trace_writes: Option<PathBuf>,
+
+    /// Save the offset and bytes of every write to the ROM to this log, which the replay subcommand applies again.
+    #[structopt(long, parse(from_os_str))]
+    record_writes: Option<PathBuf>,
+
+    /// Build in memory and print the ROM byte ranges that would be modified, writing nothing.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Order to lay file data out in: sequential (FAT order) or largest-first.
+    #[structopt(long, default_value = "sequential")]
+    placement: allocator::Placement,
+
+    /// Read per-file placement hints and flags (such as `align = true`, `pin = 0x08` or `read_only = true`) from this TOML file.
+    #[structopt(long, parse(from_os_str))]
+    hints: Option<PathBuf>,
+
+    /// Write each file's flash address ranges (page:offset..page:offset) to this file, or '-'
+    /// for stdout.
+    #[structopt(long, parse(from_os_str))]
+    addresses: Option<PathBuf>,
+
+    /// Write a debugger symbol file labelling each entry's FAT address and each data block.
+    #[structopt(long, parse(from_os_str))]
+    symbols: Option<PathBuf>,
+
+    /// Read the filesystem's geometry (dat_start, fat_start, reserved pages...) from this TOML
+    /// file.
+    #[structopt(long, parse(from_os_str))]
+    layout: Option<PathBuf>,
+
+    /// Lay the filesystem out as KnightOS does on this calculator (ti73, ti83p, ti83pse, ti84p, ti84pse, ti84pcse) instead of the one with as much flash as the ROM is long.
+    #[structopt(long, conflicts_with = "layout")]
+    platform: Option<regenkfs::geometry::Platform>,
+
+    /// Write the filesystem where --layout or --platform puts it even if the kernel in pages 00-03 carries a KFSGEOM geometry record saying otherwise (upstream KnightOS kernels don't have one).
+    #[structopt(long)]
+    ignore_kernel_geometry: bool,
+
+    /// Leave out directories that hold no files or links, even in subdirectories.
+    #[structopt(long)]
+    prune_empty: bool,
+
+    /// Whether to pack dotfiles and dot-directories: include or exclude.
+    #[structopt(long, default_value = "include")]
+    hidden: regenkfs::model::Hidden,
+
+    /// Skip files over the 16 MiB KFS limit with a warning instead of failing.
+    #[structopt(long)]
+    skip_too_large: bool,
+
+    /// Pack symlinks to directories as the directories they point to.
+    #[structopt(long)]
+    follow_dir_symlinks: bool,
+
+    /// Treat the paths this file lists as `path -> target` as symlinks, for models checked out where symlinks can't be made.
+    #[structopt(long, parse(from_os_str))]
+    symlinks: Option<PathBuf>,
+
+    /// Remember where each file went in this file, so rebuilding the same ROM only rewrites files that changed.
+    #[structopt(long, parse(from_os_str))]
+    cache: Option<PathBuf>,
+
+    /// Order to write each directory's entries in: bytes (as genkfs in the C locale), or natural (prog2 before prog10).
+    #[structopt(long, default_value = "bytes")]
+    sort: regenkfs::model::Sort,
+
+    /// Fail unless the finished ROM image has this SHA-256, given in hex.
+    #[structopt(long)]
+    expect_sha256: Option<String>,
+
+    /// Write the filesystem described by this [name] table of the --layout file, rather than its top-level keys.
+    #[structopt(long)]
+    instance: Option<String>,
+
+    /// Pack only the model paths listed in this file (or - for stdin), one per line or NUL-terminated as from find -print0.
+    #[structopt(long, parse(from_os_str))]
+    files_from: Option<PathBuf>,
+
+    /// Print build metrics to stdout when done, as json or prometheus; use with -q to get only them.
+    #[structopt(long)]
+    stats_format: Option<regenkfs::stats::StatsFormat>,
+
+    /// Leave out files git ignores when the model is in a working copy.
+    #[structopt(long)]
+    use_gitignore: bool,
+
+    /// Set the read-only flag on entries matching this glob, such as 'etc/**'; may be given more than once.
+    #[structopt(long, number_of_values = 1)]
+    read_only: Vec<String>,
+
+    /// Don't blank the data and FAT pages first, trusting them to be erased already, as in a fresh image.
+    #[structopt(long, conflicts_with = "blank-all")]
+    no_blank: bool,
+
+    /// Also blank the pages above the FAT, all but the kernel's privileged sector and boot page at the top.
+    #[structopt(long)]
+    blank_all: bool,
+
+    /// Where the filesystem starts in the ROM file, such as 0x100000 in a full flash dump, optionally with +length; ROM paths also take it as a file:offset suffix.
+    #[structopt(long)]
+    offset: Option<Span>,
+
+    /// Leave out model files and directories that can't be read, with a warning, and only fail once the image is written.
+    #[structopt(long)]
+    keep_going: bool,
+
+    /// Fail before writing anything if the model holds more than this many files.
+    #[structopt(long)]
+    max_files: Option<u64>,
+
+    /// Fail before writing anything if the model's files hold more than this, such as 4M.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_total_size: Option<u64>,
+
+    /// Threads to read and hash model files on ahead of the build, and to build images on when there are several targets (each then reading its model on one); the default is one per core.
+    #[structopt(long)]
+    threads: Option<std::num::NonZeroUsize>,
+
+    /// Bytes to buffer ROM writes and model file reads in, such as 1M, instead of 8K; larger buffers help on network filesystems.
+    #[structopt(long, parse(try_from_str = parse_buffer_size))]
+    buffer_size: Option<usize>,
+
+    /// Write the ROM through io_uring, submitting its small writes in batches (Linux, built with the io-uring feature).
+    #[structopt(long)]
+    io_uring: bool,
+
+    /// If the ROM is read-only, make it writable for the build and read-only again afterwards.
+    #[structopt(long)]
+    chmod: bool,
+
+    /// Print warnings and errors on stderr as JSON objects (severity, code, path, message), one per line.
+    #[structopt(long)]
+    errors_json: bool,
+
+    /// Check every write against the KFS format's invariants (magic only at page starts, section headers only in block 0, FAT entries sized to their names) and stop at the first that breaks one.
+    #[structopt(long)]
+    paranoid: bool,
+
+    /// Add /etc/build-info, saying the regenkfs version, the build time (SOURCE_DATE_EPOCH, if set) and the SHA-256 of the model.
+    #[structopt(long)]
+    build_info: bool,
+
+    /// Keep the last data page for a SHA-256 of the data and FAT pages, for a kernel or bootloader (or fsck) to check the filesystem against.
+    #[structopt(long)]
+    integrity_page: bool,
+
+    /// Experimental: end each file's FAT entry with a CRC-32 of its data, flagged with 0x80 in its flags, for fsck to check. The kernel doesn't know the flag.
+    #[structopt(long)]
+    experimental_entry_checksums: bool,
+
+    /// Experimental: store file data run-length encoded where that makes it smaller, flagged with 0x40 in its flags. extract and verify undo it; the kernel doesn't know the flag.
+    #[structopt(long)]
+    experimental_compress: bool,
+
+    /// Run this shell command after a successful build, such as 'z80e {rom}' to boot the image; {rom} is the path written. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    run_after: Vec<String>,
+
+    /// Read pre_build, post_build and on_failure shell hooks from the [hooks] table of this TOML file.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
 }
 
-struct Context<'a> {
-    rom_path: PathBuf,
-    model: &'a Path,
-    fat_start: u8,
-    dat_start: u8,
-    rom: BufWriter<File>,
+#[derive(Debug, StructOpt)]
+struct FilterOpt {
+    /// Only keep paths matching this glob. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    include: Vec<String>,
+    /// Skip paths matching this glob, and everything below them. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
 }
 
-fn div_rem<T: std::ops::Div<Output = T> + std::ops::Rem<Output = T> + Copy>(x: T, y: T) -> (T, T) {
-    let quot = x / y;
-    let rem = x % y;
-    (quot, rem)
+fn parse_size(size: &str) -> Result<u64, String> {
+    budget::parse_size(size).ok_or_else(|| format!("Invalid size {}.", size))
 }
 
-impl<'a> Context<'a> {
-    fn new(rom_path: &'a Path, model: &'a Path) -> Result<Context<'a>, Error> {
-        if !model.is_dir() {
-            return Err(Error::new(
-                ErrorKind::NotFound,
-                format!("Unable to open {}.", model.display()),
-            ));
+fn parse_buffer_size(size: &str) -> Result<usize, String> {
+    parse_size(size)
+        .and_then(|size| {
+            usize::try_from(size).map_err(|_| format!("Buffer size {} is too large.", size))
+        })
+        .and_then(|size| match size {
+            0 => Err("The buffer size must be at least one byte.".to_string()),
+            size => Ok(size),
+        })
+}
+
+impl From<FilterOpt> for glob::Filter {
+    fn from(opt: FilterOpt) -> glob::Filter {
+        glob::Filter {
+            include: opt.include,
+            exclude: opt.exclude,
         }
-        if !rom_path.is_file() {
-            return Err(Error::new(
-                ErrorKind::NotFound,
-                format!("Unable to open {}.", rom_path.display()),
-            ));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Dir,
+    Tar,
+    Zip,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "dir" => Ok(Format::Dir),
+            "tar" => Ok(Format::Tar),
+            "zip" => Ok(Format::Zip),
+            _ => Err(format!("Unknown format {} (expected dir, tar or zip).", s)),
         }
+    }
+}
 
-        let length = fs::metadata(&rom_path)?.len();
-        // This opens the file like fopen(rom_file, "r+") in C.
-        let rom = BufWriter::new(
-            OpenOptions::new()
-                .write(true)
-                .truncate(false)
-                .open(&rom_path)?,
-        );
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MapFormat {
+    Svg,
+    Dot,
+}
+
+impl FromStr for MapFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MapFormat, String> {
+        match s {
+            "svg" => Ok(MapFormat::Svg),
+            "dot" => Ok(MapFormat::Dot),
+            _ => Err(format!("Unknown format {} (expected svg or dot).", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Export {
+    Dot,
+}
+
+impl FromStr for Export {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Export, String> {
+        match s {
+            "dot" => Ok(Export::Dot),
+            _ => Err(format!("Unknown export format {} (expected dot).", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Writes a model into the filesystem of a ROM; what regenkfs does without a subcommand.
+    Build(Box<BuildOpt>),
+    /// Copies a file or directory out of the filesystem in a ROM.
+    Extract {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Path inside the filesystem to extract.
+        #[structopt(default_value = "/")]
+        path: String,
+        #[structopt(flatten)]
+        filter: FilterOpt,
+        /// Directory to extract into, or with an archive format the archive
+        /// to write ('-', the default, is stdout).
+        #[structopt(long, parse(from_os_str))]
+        to: Option<PathBuf>,
+        /// Write a directory tree (dir), or a tar or zip archive (tar, zip).
+        #[structopt(long, default_value = "dir")]
+        format: Format,
+        /// How entry flags map to host permissions: none or flags.
+        #[structopt(long, default_value = "none")]
+        permissions: extract::Permissions,
+        /// Don't print progress.
+        #[structopt(short, long)]
+        quiet: bool,
+    },
+    /// Lists the paths stored in the filesystem of a ROM.
+    Ls {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Path inside the filesystem to list.
+        #[structopt(default_value = "/")]
+        path: String,
+        #[structopt(flatten)]
+        filter: FilterOpt,
+        /// Show type, flags, size, first section, parent id and name.
+        #[structopt(short, long)]
+        long: bool,
+        /// Columns of the long listing, comma-separated: type, flags, size, section, parent, name.
+        #[structopt(long)]
+        format: Option<listing::Columns>,
+        /// Print the tree in another format instead: dot, a Graphviz graph with symlinks drawn to their targets.
+        #[structopt(long, conflicts_with_all = &["long", "format"])]
+        export: Option<Export>,
+    },
+    /// Prints the entries whose name matches a glob, with their metadata as in ls -l.
+    Find {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Glob to match names against, such as 'lib*.so'. With a `/` it is matched against whole paths.
+        pattern: String,
+        /// Columns to print, comma-separated: type, flags, size, section, parent, name (the default is all of them).
+        #[structopt(long)]
+        format: Option<listing::Columns>,
+    },
+    /// Prints an mtree specification (type, size, SHA-256, link target) of a ROM's filesystem.
+    Manifest {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Reports how fragmented the section chains of a ROM's files are.
+    Fragmentation {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Prints a map of each data page's blocks, colored by the file owning them.
+    PageMap {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Draw an SVG grid (svg) or a Graphviz graph (dot).
+        #[structopt(long, default_value = "svg")]
+        format: MapFormat,
+    },
+    /// Reports which pages and blocks of two ROMs differ.
+    Cmp {
+        #[structopt(parse(from_os_str))]
+        a: PathBuf,
+        #[structopt(parse(from_os_str))]
+        b: PathBuf,
+        /// Also print the differing bytes of each block.
+        #[structopt(long)]
+        hexdump: bool,
+    },
+    /// Shows which blocks of each data page are in use, and how much space is left.
+    Usage {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Prints the FAT bytes, data blocks and pages a model needs, and the smallest ROM that holds it.
+    Estimate {
+        /// Directory (or a cpio archive of one) to estimate.
+        #[structopt(parse(from_os_str))]
+        model: PathBuf,
+    },
+    /// Runs every check of a model that needs no ROM (names, sizes, symlinks, duplicates, special files, layout, limits and budgets) and reports pass or fail, as for CI. Layout findings are only warnings unless --strict.
+    ValidateModel {
+        /// Directory (or a cpio archive of one) to check.
+        #[structopt(parse(from_os_str))]
+        model: PathBuf,
+        /// Fail on warnings too, such as those of the layout check.
+        #[structopt(long)]
+        strict: bool,
+    },
+    /// Creates a ROM from a kernel image, then writes and verifies a filesystem in it.
+    Assemble {
+        /// Kernel image to copy to the start of the new ROM.
+        #[structopt(long, parse(from_os_str))]
+        kernel: PathBuf,
+        /// Directory (or a cpio archive of one) that will be copied into / on the filesystem.
+        #[structopt(long, parse(from_os_str))]
+        model: PathBuf,
+        /// Size of the ROM, such as 2M or 128 pages. Defaults to the size of the kernel image.
+        #[structopt(long)]
+        size: Option<String>,
+        /// The ROM file to create, or - for stdout.
+        #[structopt(parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// Erases the data and FAT pages of a ROM, leaving only the kernel.
+    Strip {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Erases pages of a ROM, such as 0x10..0x14. Kernel pages are refused without --force.
+    Blank {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Pages to erase: a page (0x10), a range (0x10..0x14) or an inclusive range (0x10..=0x14).
+        #[structopt(required = true, parse(try_from_str = erase::parse_pages))]
+        pages: Vec<RangeInclusive<u8>>,
+        /// Also erase pages belonging to the kernel.
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Writes an empty filesystem into a ROM, without needing a model.
+    Format {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Compares the FAT of a ROM written with --mirror-fat against its copy.
+    VerifyMirror {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Checks every entry of the filesystem in a ROM against a model, as --verify does after a build.
+    Verify {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        #[structopt(parse(from_os_str))]
+        model: PathBuf,
+    },
+    /// Prints where the filesystem of a ROM is, what it holds and how full it is.
+    Info {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Checks the filesystem of a ROM: its FAT, each file's section chain and checksum, and the integrity page, if it has one.
+    Fsck {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+    /// Flips bits of a ROM's FAT, data or section headers, picked from a seed, to test how a kernel copes with a damaged filesystem.
+    Corrupt {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// How many bits to flip.
+        #[structopt(long, default_value = "1")]
+        flip_bits: u64,
+        /// What to damage: fat, dat or headers.
+        #[structopt(long)]
+        target: corrupt::Target,
+        /// Seed picking the bits, so the same damage can be done again. Defaults to one from the clock, which is printed.
+        #[structopt(long)]
+        seed: Option<u64>,
+    },
+    /// Writes a pseudo-random model made from a seed into a ROM, for fuzzing a kernel's filesystem code with inputs that can be made again.
+    GenFixture {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Seed the model is made from. Defaults to one from the clock, which is printed.
+        #[structopt(long)]
+        seed: Option<u64>,
+        /// How deep directories nest.
+        #[structopt(long, default_value = "3")]
+        depth: u32,
+        /// How many files to make.
+        #[structopt(long, default_value = "20")]
+        files: u32,
+        /// How big files are: small (up to a block), mixed or large (1 KiB to 64 KiB).
+        #[structopt(long, default_value = "mixed")]
+        sizes: fixture::Sizes,
+        /// Symlinks to make for every file, from 0 to 1.
+        #[structopt(long, default_value = "0.1")]
+        symlink_ratio: f64,
+        /// Also make an empty file, files of a block and a block and a byte, an empty directory and unusual symlinks.
+        #[structopt(long)]
+        niche_cases: bool,
+    },
+    /// Checks a ROM against the page checksums recorded with --checksums.
+    VerifyChecksums {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        #[structopt(parse(from_os_str))]
+        checksums: PathBuf,
+    },
+    /// Shrinks a model directory that fails to build, or with --against builds differently from the C genkfs, to a minimal reproducer for a bug report.
+    Minimize {
+        /// ROM to build into. It is only read.
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        #[structopt(parse(from_os_str))]
+        model: PathBuf,
+        /// Directory to write the minimized model to, which must not exist yet.
+        #[structopt(parse(from_os_str))]
+        out: PathBuf,
+        /// Look for images that differ from those this genkfs binary writes, instead of for build errors.
+        #[structopt(long, parse(from_os_str))]
+        against: Option<PathBuf>,
+    },
+    /// Makes the writes logged with --record-writes again, creating an erased ROM if there is none.
+    Replay {
+        #[structopt(parse(from_os_str))]
+        log: PathBuf,
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+    },
+}
 
-        let fat_start: u8 = if cfg!(feature = "c-undef") {
-            // C original has undefined behavior: context.fat_start = length / PAGE_LENGTH - 0x9;
-            TryInto::<u8>::try_into(length / u64::from(PAGE_LENGTH))
-                .unwrap()
-                .wrapping_sub(9)
+fn extract(
+    rom_path: &Path,
+    offset: Option<Span>,
+    path: &str,
+    to: Option<PathBuf>,
+    format: Format,
+    extract_options: &extract::ExtractOptions,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    // `extract --format tar rom.bin -` streams the whole image.
+    let (path, to) = match (format, path, to) {
+        (Format::Tar, "-", None) | (Format::Zip, "-", None) => ("/", PathBuf::from("-")),
+        (Format::Dir, path, to) => (path, to.unwrap_or_else(|| PathBuf::from("."))),
+        (_, path, to) => (path, to.unwrap_or_else(|| PathBuf::from("-"))),
+    };
+    if format == Format::Dir {
+        let count = extract::extract(&image, path, &to, extract_options)?;
+        if !extract_options.quiet {
+            eprintln!("Extracted {} entries to {}.", count, to.display());
+        }
+        return Ok(());
+    }
+    let out: Box<dyn Write> = if to == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(&to)?)
+    };
+    let out = BufWriter::new(out);
+    let count = match format {
+        Format::Tar => extract::extract_to(
+            &image,
+            path,
+            &mut TarSink::new(out, extract_options.permissions),
+            extract_options,
+        )?,
+        _ => extract::extract_to(
+            &image,
+            path,
+            &mut ZipSink::new(out, extract_options.permissions),
+            extract_options,
+        )?,
+    };
+    if !extract_options.quiet {
+        let to = if to == Path::new("-") {
+            "stdout".into()
         } else {
-            // Safe version
-            TryInto::<u8>::try_into(length / u64::from(PAGE_LENGTH) - 9)
-                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+            to.display().to_string()
         };
-        Ok(Context {
-            rom_path: rom_path.to_path_buf(),
-            model,
-            fat_start,
-            dat_start: 0x04,
-            rom,
-        })
+        eprintln!("Extracted {} entries to {}.", count, to);
     }
+    Ok(())
+}
 
-    fn write_fat(&mut self, entry: Vec<u8>, length: u16, fatptr: &mut u32) -> Result<(), Error> {
-        *fatptr -= u32::from(length);
-        self.rom.seek(SeekFrom::Start(u64::from(*fatptr)))?;
-        self.rom.write_all(&entry[..usize::from(length)])?;
-        self.rom.flush()
-    }
-
-    fn write_block(&mut self, file: &mut BufReader<File>, section_id: u16) -> Result<(), Error> {
-        let [l, h] = section_id.to_le_bytes();
-        let flash_page: u16 = u16::from(h);
-        let index: u16 = u16::from(l);
-        self.rom.seek(SeekFrom::Start(
-            u64::from(flash_page) * u64::from(PAGE_LENGTH)
-                + u64::from(index) * u64::from(BLOCK_SIZE),
-        ))?;
-        let mut block: [u8; BLOCK_SIZE as usize] = [0x0; BLOCK_SIZE as usize];
-        let len = file.read(&mut block)?;
-        self.rom.write_all(&block[..len])?;
-        self.rom.flush()
-    }
-
-    fn write_dat(
-        &mut self,
-        file: &mut BufReader<File>,
-        length: u32,
-        section_id: &mut u16,
-    ) -> Result<(), Error> {
-        let mut length = length;
-        let mut pSID: u16 = 0xFFFF;
-        file.seek(SeekFrom::Start(0))?;
-        while length > 0 {
-            /* Prep */
-            let [l, h] = section_id.to_le_bytes();
-            let mut flash_page: u16 = u16::from(h);
-            let mut index: u8 = l;
-            let mut nSID: u16 = 0xFFFF;
-            let header_addr: u32 =
-                u32::from(PAGE_LENGTH) * u32::from(flash_page) + u32::from(index) * 4;
-            index += 1;
-            if index > 0x3F {
-                index = 1;
-                flash_page += 1;
-                /* Write the magic number */
-                self.rom.seek(SeekFrom::Start(
-                    u64::from(flash_page) * u64::from(PAGE_LENGTH),
-                ))?;
-                self.rom.write_all(b"KFS")?;
-                self.rom.write_all(&[0xFF << KFS_VERSION])?;
-            }
-            if length > u32::from(BLOCK_SIZE) {
-                nSID = (flash_page << 8) | u16::from(index);
+fn ls(
+    rom_path: &Path,
+    offset: Option<Span>,
+    path: &str,
+    filter: &glob::Filter,
+    columns: Option<&listing::Columns>,
+    export: Option<Export>,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let selected = extract::select(&image, path)?;
+    let nodes: Vec<_> = selected
+        .iter()
+        .map(|(_, node)| node)
+        .filter(|node| filter.keeps(&node.path))
+        .collect();
+    let mut out = BufWriter::new(io::stdout().lock());
+    match (export, columns) {
+        (Some(Export::Dot), _) => listing::write_dot(&mut out, &nodes)?,
+        (None, Some(columns)) => listing::write_long(&mut out, &nodes, columns)?,
+        (None, None) => {
+            for node in nodes {
+                writeln!(out, "{}", listing::Name(node))?;
             }
+        }
+    }
+    out.flush()
+}
 
-            /* Section header */
-            self.rom.seek(SeekFrom::Start(u64::from(header_addr)))?;
+fn find(
+    rom_path: &Path,
+    offset: Option<Span>,
+    pattern: &str,
+    columns: &listing::Columns,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let found = listing::find(&image, pattern)?;
+    let nodes: Vec<_> = found.iter().collect();
+    let mut out = BufWriter::new(io::stdout().lock());
+    listing::write_long(&mut out, &nodes, columns)?;
+    out.flush()
+}
 
-            pSID &= 0x7FFF; // Mark this section in use
+fn mtree(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    manifest::write_mtree(&mut out, &image)?;
+    out.flush()
+}
 
-            // Warning: original C code uses fwrite which is
-            // arch-dependent.  We choose little endian here.
-            self.rom.write_all(&pSID.to_le_bytes())?;
-            self.rom.write_all(&nSID.to_le_bytes())?;
+fn fragmentation(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    println!("{}", Fragmentation::analyze(&image)?);
+    Ok(())
+}
 
-            /* Block data */
-            self.write_block(file, *section_id)?;
-            self.rom.flush()?;
+fn page_map(
+    rom_path: &Path,
+    offset: Option<Span>,
+    format: MapFormat,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    match format {
+        MapFormat::Svg => page_fill::write_svg(&mut out, &image)?,
+        MapFormat::Dot => page_fill::write_dot(&mut out, &image)?,
+    }
+    out.flush()
+}
 
-            length = length.saturating_sub(u32::from(BLOCK_SIZE));
-            pSID = *section_id;
-            *section_id = (flash_page << 8) | u16::from(index);
+fn compare(a_path: &Path, b_path: &Path, offset: Option<Span>, hexdump: bool) -> Result<(), Error> {
+    let a = window::read(a_path, offset)?;
+    let b = window::read(b_path, offset)?;
+    let diffs = cmp::compare(&a, &b);
+    let mut out = BufWriter::new(io::stdout().lock());
+    for diff in &diffs {
+        let blocks: Vec<String> = diff.blocks.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(
+            out,
+            "page {:02x}: {} bytes differ in blocks {}",
+            diff.page,
+            diff.bytes,
+            blocks.join(" ")
+        )?;
+        if hexdump {
+            for &block in &diff.blocks {
+                writeln!(out, "  block {:02x}:", block)?;
+                cmp::write_hexdump(&mut out, &a, &b, diff.page, block)?;
+            }
         }
-        Ok(())
     }
+    out.flush()?;
+    if a.len() != b.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} is {} bytes but {} is {}.",
+                a_path.display(),
+                a.len(),
+                b_path.display(),
+                b.len()
+            ),
+        ));
+    }
+    if !diffs.is_empty() {
+        let blocks: usize = diffs.iter().map(|diff| diff.blocks.len()).sum();
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} and {} differ in {} blocks across {} pages.",
+                a_path.display(),
+                b_path.display(),
+                blocks,
+                diffs.len()
+            ),
+        ));
+    }
+    println!(
+        "{} and {} are identical.",
+        a_path.display(),
+        b_path.display()
+    );
+    Ok(())
+}
 
-    fn write_recursive(
-        &mut self,
-        model: PathBuf,
-        parent_id: &mut u16,
-        section_id: &mut u16,
-        fatptr: &mut u32,
-    ) -> Result<(), Error> {
-        let parent: u16 = *parent_id;
-
-        // Put paths into a Vec to sort alphabetically.
-        let mut paths: Vec<DirEntry> = fs::read_dir(model)?
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?;
-        paths.sort_by_key(|dir| dir.path());
-        for entry in paths {
-            let path = entry.path();
-            let entry_name: OsString = entry.file_name();
-            let entry_str: &str = entry_name.to_str().ok_or_else(|| {
-                Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Filename {} does not contain valid UTF-8.", path.display()),
-                )
-            })?;
-            let entry_name_bytes: &[u8] = entry_str.as_bytes();
-
-            if entry.file_type()?.is_symlink() {
-                let target = path.read_link()?;
-                println!(
-                    "Adding link from {} to {}...",
-                    path.display(),
-                    target.display()
-                );
-
-                // Use .to_str() instead of .file_name() to avoid
-                // losing relative path.
-                // (i.e. want ../foo.c instead of foo.c)
-                let target_name: &str = target.to_str().ok_or_else(|| {
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!(
-                            "Filename {} does not contain valid UTF-8.",
-                            target.display()
-                        ),
-                    )
-                })?;
-                let target_name_bytes: &[u8] = target_name.as_bytes();
-
-                let dl: u16 = entry_name.len().try_into().unwrap();
-                let tl: u16 = target_name.len().try_into().unwrap();
-
-                let elen: u16 = dl + tl + 5;
-                let mut sentry: Vec<u8> = vec![0x0; usize::from(elen) + 3];
-
-                sentry[0] = KFS_SYM_ID;
-                sentry[1..=2].clone_from_slice(&elen.to_le_bytes());
-                sentry[3..=4].clone_from_slice(&parent.to_le_bytes());
-                sentry[5] = (dl + 1).try_into().unwrap();
-                sentry[6..][..usize::from(dl)].clone_from_slice(entry_name_bytes);
-                sentry[usize::from(7 + dl)..][..usize::from(tl)]
-                    .clone_from_slice(target_name_bytes);
-                sentry.reverse();
-                self.write_fat(sentry, elen + 3, fatptr)?
-            } else if path.is_dir() {
-                let elen: u16 = (entry_name.len() + 6).try_into().map_err(|_| {
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!("Filename too long: {}", entry_str),
-                    )
-                })?;
-
-                let mut fentry: Vec<u8> = vec![0x0; usize::from(elen) + 3];
-                println!("Adding {}...", path.display());
-                fentry[0] = KFS_DIR_ID;
-                fentry[1..=2].clone_from_slice(&elen.to_le_bytes());
-                fentry[3..=4].clone_from_slice(&parent.to_le_bytes());
-                *parent_id += 1;
-                fentry[5..=6].clone_from_slice(&parent_id.to_le_bytes());
-                fentry[7] = 0xFF; // Flags
-                fentry[8..][..entry_name.len()].clone_from_slice(entry_name_bytes);
-                fentry.reverse();
-                self.write_fat(fentry, elen + 3, fatptr)?;
-                self.write_recursive(path, parent_id, section_id, fatptr)?
-            } else if path.is_file() {
-                let elen: u16 = (entry_name.len() + 9).try_into().map_err(|_| {
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!("Filename too long: {}", entry_str),
-                    )
-                })?;
-                let len = entry.metadata()?.len();
-                if len > KFS_MAX_FILE_LEN {
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!(
-                            "Error: {} is larger than the maximum file size.",
-                            path.display()
-                        ),
-                    );
-                }
-                println!("Adding {}...", path.display());
-                // Now safe to coerce len into u32
-                let len: u32 = len.try_into().unwrap();
-                let mut fentry: Vec<u8> = vec![0x0; usize::from(elen) + 3];
-
-                fentry[0] = KFS_FILE_ID;
-                fentry[1..=2].clone_from_slice(&elen.to_le_bytes());
-                fentry[3..=4].clone_from_slice(&parent.to_le_bytes());
-                fentry[5] = 0xFF; // Flags
-                fentry[6..=8].clone_from_slice(&len.to_le_bytes()[0..=2]); // Note: len: u32
-                fentry[9..=10].clone_from_slice(&(section_id.to_le_bytes()));
-                fentry[11..][..entry.file_name().len()].clone_from_slice(entry_name_bytes);
-                fentry.reverse();
-                self.write_fat(fentry, elen + 3, fatptr)?;
-                self.write_dat(&mut BufReader::new(File::open(path)?), len, section_id)?
+fn usage(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let (layout, fat_start) = resolve_layout(options, &rom, rom.len() as u64)?;
+    let map = AllocationMap::scan_layout(&rom, &layout, fat_start)?;
+    print!("{}", map.page_map());
+    let (used, free) = (map.used_blocks(), map.free_blocks());
+    println!(
+        "Data: {} of {} blocks used, {} bytes free.",
+        used,
+        used + free,
+        free * usize::from(layout.geometry.block_size())
+    );
+    println!("FAT: {} bytes free.", map.fat_free());
+    Ok(())
+}
+
+fn estimate(model: &Path, options: &Options) -> Result<(), Error> {
+    let estimate = estimate::estimate(model, options)?;
+    println!(
+        "FAT: {} entries, {} of {} bytes.",
+        estimate.entries, estimate.fat_bytes, estimate.fat_length
+    );
+    println!(
+        "Data: {} blocks ({} bytes); pages used: {}, spanning {}.",
+        estimate.blocks,
+        estimate.blocks * usize::from(estimate.block_size),
+        estimate.pages_used,
+        estimate.pages_spanned
+    );
+    let length = estimate.rom_length();
+    println!(
+        "Smallest ROM: {} pages ({} bytes, {:#x}); the smallest power of two is {} KiB.",
+        estimate.rom_pages,
+        length,
+        length,
+        length.next_power_of_two() / 1024
+    );
+    Ok(())
+}
+
+fn validate_model(model: &Path, strict: bool, options: &Options) -> Result<(), Error> {
+    let checks = validate::validate(model, options)?;
+    for check in &checks {
+        if check.passed() {
+            println!("{}: ok", check.name);
+        } else {
+            let kind = if check.warning {
+                "warnings"
             } else {
-                unreachable!();
+                "problems"
+            };
+            println!("{}: {} {}", check.name, check.problems.len(), kind);
+            for problem in &check.problems {
+                println!("  {}", problem);
             }
         }
+    }
+    let problems = validate::failures(&checks, strict);
+    if problems > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} fails with {} problems.", model.display(), problems),
+        ));
+    }
+    let warnings = validate::failures(&checks, true);
+    if warnings > 0 {
+        println!(
+            "{} passes every check, with {} warnings.",
+            model.display(),
+            warnings
+        );
+    } else {
+        println!("{} passes every check.", model.display());
+    }
+    Ok(())
+}
+
+fn strip(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let (pages, reserved) = edit_rom(rom_path, offset, options, |rom| {
+        let (layout, fat_start) = resolve_layout(options, rom, rom.len() as u64)?;
+        erase::strip(rom, &layout, fat_start)?;
+        Ok((layout.dat_start..=fat_start, !layout.reserved.is_empty()))
+    })?;
+    println!(
+        "Erased pages {:02x}-{:02x} of {}{}.",
+        pages.start(),
+        pages.end(),
+        rom_path.display(),
+        if reserved {
+            ", except those the layout reserves"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+fn blank(
+    rom_path: &Path,
+    offset: Option<Span>,
+    pages: &[RangeInclusive<u8>],
+    force: bool,
+    options: &Options,
+) -> Result<(), Error> {
+    edit_rom(rom_path, offset, options, |rom| {
+        let (layout, fat_start) = resolve_layout(options, rom, rom.len() as u64)?;
+        // The kernel lives below the data pages and in the boot pages
+        // above the FAT.
+        let kernel = pages
+            .iter()
+            .flat_map(|range| range.clone())
+            .find(|&page| page < layout.dat_start || page > fat_start);
+        if let (Some(page), false) = (kernel, force) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Page {:02x} belongs to the kernel; pass --force to erase it anyway.",
+                    page
+                ),
+            ));
+        }
+        let reserved = pages
+            .iter()
+            .flat_map(|range| range.clone())
+            .find(|&page| layout.is_reserved(page));
+        if let (Some(page), false) = (reserved, force) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Page {:02x} is reserved by the layout; pass --force to erase it anyway.",
+                    page
+                ),
+            ));
+        }
+        for range in pages {
+            erase::erase(rom, layout.geometry, range.clone())?;
+        }
         Ok(())
+    })?;
+    for range in pages {
+        println!(
+            "Erased pages {:02x}-{:02x} of {}.",
+            range.start(),
+            range.end(),
+            rom_path.display()
+        );
     }
+    Ok(())
+}
 
-    // Returns the number of data pages (low byte) and fat pages (high
-    // byte) written.
-    fn write_filesystem(&mut self) -> Result<u16, Error> {
-        let mut parent_id: u16 = 0;
-        let mut section_id: u16 = (u16::from(self.dat_start) << 8) | 1;
-        let mut fatptr: u32 = (u32::from(self.fat_start) + 1) * u32::from(PAGE_LENGTH);
-        let fatptr_start: u32 = fatptr;
-        /* Write the first DAT page's magic number */
-        self.rom.seek(SeekFrom::Start(
-            u64::from(self.dat_start) * u64::from(PAGE_LENGTH),
-        ))?;
-        self.rom.write_all(b"KFS")?;
-        self.rom.flush()?;
-        self.write_recursive(
-            self.model.to_path_buf(),
-            &mut parent_id,
-            &mut section_id,
-            &mut fatptr,
-        )?;
+fn format(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    edit_rom(rom_path, offset, options, |rom| {
+        let (layout, fat_start) = resolve_layout(options, rom, rom.len() as u64)?;
+        erase::format(rom, &layout, fat_start, options.mirror_fat)
+    })?;
+    println!("Empty filesystem written to {}.", rom_path.display());
+    Ok(())
+}
+
+fn verify_mirror(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let (layout, fat_start) = resolve_layout(options, &rom, rom.len() as u64)?;
+    let diffs = fat::compare_mirror(&rom, layout.geometry, fat_start)?;
+    if let Some(first) = diffs.first() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "FAT and its mirror differ in {} bytes, the first at {:#x}.",
+                diffs.len(),
+                first
+            ),
+        ));
+    }
+    println!("FAT and its mirror match.");
+    Ok(())
+}
+
+fn verify(
+    rom_path: &Path,
+    model_path: &Path,
+    offset: Option<Span>,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let source = source::open_model(
+        model_path,
+        options.follow_dir_symlinks,
+        options.buffer_size,
+        options.symlinks.as_deref(),
+    )?;
+    let mut filter = model::Filter::default();
+    if let Some(path) = &options.files_from {
+        filter.selection = Some(model::read_selection(path)?);
+    }
+    if options.use_gitignore && model_path.is_dir() {
+        filter.ignored = model::git_ignored(model_path)?;
+    }
+    let problems = verify::verify(&image, &*source, options, &filter)?;
+    if !problems.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} does not match {}:\n  {}",
+                rom_path.display(),
+                model_path.display(),
+                problems.join("\n  ")
+            ),
+        ));
+    }
+    println!("{} matches {}.", rom_path.display(), model_path.display());
+    Ok(())
+}
 
-        let (quot, rem) = div_rem(fatptr_start - fatptr, u32::from(PAGE_LENGTH));
-        // Given that PAGE_LENGTH is sufficiently large, it's safe to
-        // downgrade number size here.
-        let mut result: u16 = quot.try_into().unwrap();
-        if rem > 0 {
-            result += 1;
+fn info(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let geometry = image.geometry;
+    let kernel = &rom[..rom
+        .len()
+        .min(usize::from(KERNEL_PAGES) * usize::from(PAGE_LENGTH))];
+    let platform = Platform::for_length(rom.len() as u64);
+    println!(
+        "Image: {} bytes, {} pages{}.",
+        rom.len(),
+        rom.len() / usize::from(geometry.page_length()),
+        platform.map_or(String::new(), |platform| format!(
+            ", the size of a {}",
+            platform
+        ))
+    );
+    println!(
+        "Filesystem: data from page {:02x}, FAT at page {:02x} ({} pages); {:#x}-byte pages, {}-byte blocks{}.",
+        image.dat_start,
+        image.fat_start,
+        geometry.fat_pages(),
+        geometry.page_length(),
+        geometry.block_size(),
+        match GeometryRecord::find(kernel)? {
+            Some(record) => format!(", as the KFSGEOM record in the kernel at {:#x} says", record.offset),
+            None => String::new(),
         }
-        result <<= 8;
-        // sectionId's high byte is a page number
-        result = if cfg!(feature = "c-undef") {
-            // C original has undefined behavior:  result += (sectionId >> 8) - dat_start + 1;
-            result.wrapping_add((section_id >> 8).wrapping_sub(u16::from(self.dat_start))) + 1
-        } else {
-            // Safe version
-            result + (section_id >> 8) - u16::from(self.dat_start) + 1
-        };
-        Ok(result)
-    }
-    fn run(&mut self) -> Result<(), Error> {
-        let mut blank_page: [u8; PAGE_LENGTH as usize] = [0xFF; PAGE_LENGTH as usize];
-        self.rom.seek(SeekFrom::Start(
-            u64::from(self.dat_start) * u64::from(PAGE_LENGTH),
-        ))?;
-        for p in self.dat_start..=self.fat_start {
-            blank_page[0] = if p <= self.fat_start - 4 { b'K' } else { 0xFF };
-            self.rom.write_all(&blank_page)?;
+    );
+    let (mut directories, mut files, mut symlinks, mut bytes) = (0, 0, 0, 0u64);
+    for node in image.nodes()? {
+        match node.record.entry {
+            fat::Entry::Directory { .. } => directories += 1,
+            fat::Entry::File { length, .. } => {
+                files += 1;
+                bytes += u64::from(length);
+            }
+            fat::Entry::Symlink { .. } => symlinks += 1,
+        }
+    }
+    println!(
+        "Entries: {} directories, {} files ({} bytes), {} symlinks.",
+        directories, files, bytes, symlinks
+    );
+    let map = AllocationMap::scan_with(&rom, geometry, image.dat_start, image.fat_start)?;
+    let (used, free) = (map.used_blocks(), map.free_blocks());
+    println!(
+        "Data: {} of {} blocks used, {} bytes free.",
+        used,
+        used + free,
+        free * usize::from(geometry.block_size())
+    );
+    println!("FAT: {} bytes free.", map.fat_free());
+    Ok(())
+}
+
+fn fsck(rom_path: &Path, offset: Option<Span>, options: &Options) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let image = open_image(options, &rom)?;
+    let nodes = image.nodes()?;
+    let mut problems = Vec::new();
+    for node in &nodes {
+        if let fat::Entry::File {
+            section_id, length, ..
+        } = node.record.entry
+        {
+            match image.contents(section_id, length) {
+                Ok(data) => match node.record.checksum {
+                    Some(checksum) if checksum::crc32(&data) != checksum => problems.push(format!(
+                        "{}: data doesn't match the entry's checksum {:08x}.",
+                        node.path, checksum
+                    )),
+                    _ => {
+                        if let Err(err) = image.read_file(&node.record.entry) {
+                            problems.push(format!("{}: {}", node.path, err));
+                        }
+                    }
+                },
+                Err(err) => problems.push(format!("{}: {}", node.path, err)),
+            }
+        }
+    }
+    match integrity::check(&rom, image.geometry, image.dat_start, image.fat_start) {
+        Ok(Some(page)) => println!("Integrity page {:02x} matches.", page),
+        Ok(None) => {}
+        Err(err) => problems.push(err.to_string()),
+    }
+    if !problems.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} has {} problems:\n  {}",
+                rom_path.display(),
+                problems.len(),
+                problems.join("\n  ")
+            ),
+        ));
+    }
+    println!(
+        "{}: {} entries, no problems found.",
+        rom_path.display(),
+        nodes.len()
+    );
+    Ok(())
+}
+
+fn corrupt(
+    rom_path: &Path,
+    offset: Option<Span>,
+    count: u64,
+    target: corrupt::Target,
+    seed: Option<u64>,
+    options: &Options,
+) -> Result<(), Error> {
+    let seed = seed.unwrap_or_else(random::fresh_seed);
+    let mut rng = random::Rng::new(seed);
+    let flips = edit_rom(rom_path, offset, options, |rom| {
+        let (layout, fat_start) = resolve_layout(options, rom, rom.len() as u64)?;
+        corrupt::corrupt(rom, &layout, fat_start, target, count, &mut rng)
+    })?;
+    for flip in &flips {
+        println!("Flipped bit {} of {:#08x}.", flip.bit, flip.offset);
+    }
+    println!(
+        "Flipped {} bits of the {} of {} with --seed {}.",
+        flips.len(),
+        target,
+        rom_path.display(),
+        seed
+    );
+    Ok(())
+}
+
+fn gen_fixture(
+    rom_path: &Path,
+    offset: Option<Span>,
+    seed: Option<u64>,
+    shape: &fixture::Shape,
+    options: &Options,
+) -> Result<(), Error> {
+    let seed = seed.unwrap_or_else(random::fresh_seed);
+    let model = fixture::generate(seed, shape)?;
+    let source = source::Memory::new(&format!("<fixture {}>", seed), model);
+    edit_rom(rom_path, offset, options, |rom| {
+        let mut context = Context::with_source(
+            rom_path.to_path_buf(),
+            Box::new(source),
+            Cursor::new(rom.to_vec()),
+            options.clone(),
+        )?;
+        context.run()?;
+        rom.copy_from_slice(&context.into_output()?.into_inner());
+        Ok(())
+    })?;
+    println!(
+        "Wrote a fixture of {} files into {} with --seed {}.",
+        shape.files,
+        rom_path.display(),
+        seed
+    );
+    Ok(())
+}
+
+fn verify_checksums(
+    rom_path: &Path,
+    offset: Option<Span>,
+    sums_path: &Path,
+    options: &Options,
+) -> Result<(), Error> {
+    let rom = window::read(rom_path, offset)?;
+    let (layout, _) = resolve_layout(options, &rom, rom.len() as u64)?;
+    let sums = checksum::read_sums(BufReader::new(File::open(sums_path)?))?;
+    let bad = checksum::verify_sums(&rom, layout.geometry, &sums)?;
+    if !bad.is_empty() {
+        let pages: Vec<String> = bad.iter().map(|page| format!("{:02x}", page)).collect();
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Checksum mismatch on pages: {}", pages.join(" ")),
+        ));
+    }
+    println!("All {} page checksums match.", sums.len());
+    Ok(())
+}
+
+fn replay(
+    log_path: &Path,
+    rom_path: &Path,
+    offset: Option<Span>,
+    options: &Options,
+) -> Result<(), Error> {
+    let log = fs::read(log_path)?;
+    let log = replay::Log::parse(&log)?;
+    let (file, _) = window::split_path(rom_path);
+    if !file.exists() {
+        if offset.is_some() || file != rom_path {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} doesn't exist to replay into at an offset.",
+                    file.display()
+                ),
+            ));
         }
-        self.rom.flush()?;
+        let length = usize::try_from(log.length)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "The logged image is too large."))?;
+        fs::write(&file, vec![0xFF; length])?;
+    }
+    let records = edit_rom(rom_path, offset, options, |rom| log.apply(rom))?;
+    println!(
+        "Replayed {} records from {} into {}.",
+        records,
+        log_path.display(),
+        rom_path.display()
+    );
+    Ok(())
+}
 
-        let result = self.write_filesystem()?;
-        self.rom.flush()?;
+fn assemble(
+    kernel: &Path,
+    model: &Path,
+    size: Option<&str>,
+    out: &Path,
+    options: Options,
+) -> Result<(), Error> {
+    let kernel_image = fs::read(kernel)?;
+    let size = match size {
+        Some(size) => budget::parse_size(size).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid ROM size {}.", size),
+            )
+        })?,
+        None => kernel_image.len() as u64,
+    };
+    if size % u64::from(PAGE_LENGTH) != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("A ROM of {} bytes is not a whole number of pages.", size),
+        ));
+    }
+    if kernel_image.len() as u64 > size {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} does not fit in a ROM of {} bytes.",
+                kernel.display(),
+                size
+            ),
+        ));
+    }
+    if options.offset.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Assembling writes a whole ROM; it can't go at an offset.",
+        ));
+    }
+    // Anything the kernel doesn't cover is left erased.
+    let mut rom = vec![0xFF; size as usize];
+    rom[..kernel_image.len()].copy_from_slice(&kernel_image);
+    let options = Options {
+        output: None,
+        verify: true,
+        ..options
+    };
+    if out == Path::new("-") {
+        return to_stdout(rom, None, model, options);
+    }
+    fs::write(out, rom)?;
+    Context::with_options(out, model, options).and_then(|mut c| c.run())
+}
+
+// Writes the filesystem into `rom` (or the span of it) in memory and the
+// finished image to stdout, which then can't take progress or anything
+// else.
+fn to_stdout(
+    mut rom: Vec<u8>,
+    span: Option<Span>,
+    model: &Path,
+    options: Options,
+) -> Result<(), Error> {
+    if options.addresses.as_deref() == Some(Path::new("-")) || options.stats_format.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "With the image on stdout, flash addresses and stats can't go there too.",
+        ));
+    }
+    let options = Options {
+        quiet: true,
+        output: None,
+        ..options
+    };
+    let fs = match span {
+        Some(span) => span.slice(&rom)?.to_vec(),
+        None => rom.clone(),
+    };
+    let mut context =
+        Context::with_output(PathBuf::from("<stdout>"), model, Cursor::new(fs), options)?;
+    context.run()?;
+    let fs = context.into_output()?.into_inner();
+    match span {
+        Some(span) => span.slice_mut(&mut rom)?.copy_from_slice(&fs),
+        None => rom = fs,
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(&rom)?;
+    stdout.flush()
+}
+
+fn minimize(
+    rom_path: &Path,
+    model: &Path,
+    out: &Path,
+    against: Option<PathBuf>,
+    options: &Options,
+) -> Result<(), Error> {
+    // Each try builds in memory, so nothing else is written.
+    let options = Options {
+        quiet: true,
+        output: None,
+        checksums: None,
+        manifest: None,
+        report: None,
+        trace_writes: None,
+        record_writes: None,
+        addresses: None,
+        symbols: None,
+        cache: None,
+        files_from: None,
+        stats_format: None,
+        ..options.clone()
+    };
+    let oracle = match against {
+        Some(genkfs) => minimize::Oracle::Against(genkfs),
+        None => minimize::Oracle::Error,
+    };
+    let rom = window::read(rom_path, options.offset)?;
+    let builds = minimize::minimize(&rom, model, out, &options, &oracle)?;
+    println!(
+        "Minimized {} into {} in {} builds.",
+        model.display(),
+        out.display(),
+        builds
+    );
+    Ok(())
+}
+
+fn dry_run(rom_path: &Path, model: &Path, options: &Options) -> Result<(), Error> {
+    // Nothing but the preview is written, so sidecar files are skipped too.
+    let options = Options {
+        quiet: true,
+        output: None,
+        checksums: None,
+        manifest: None,
+        report: None,
+        trace_writes: None,
+        record_writes: None,
+        addresses: None,
+        symbols: None,
+        cache: None,
+        stats_format: None,
+        ..options.clone()
+    };
+    let rom = window::read(rom_path, options.offset)?;
+    let mut context = Context::in_memory(rom, model, options)?;
+    context.record_writes();
+    context.run()?;
+    println!("Building into {} would modify:", rom_path.display());
+    let touched = context.touched();
+    for (region, range) in &touched {
         println!(
-            "Filesystem successfully written to {}.",
-            self.rom_path.display()
+            "  {:#08x}-{:#08x} {:7} bytes  {}",
+            range.start,
+            range.end - 1,
+            range.end - range.start,
+            region
         );
-        print!("Indexes of written data pages: ");
-        let [lo, hi] = result.to_le_bytes();
-        for i in 0..u32::from(lo) {
-            print!("{:02x} ", u32::from(self.dat_start) + i)
+    }
+    // Regions overlap (blanking covers the rest), so count their union.
+    let mut ranges: Vec<_> = touched.into_iter().map(|(_, range)| range).collect();
+    ranges.sort_by_key(|range| range.start);
+    let (mut total, mut end) = (0, 0);
+    for range in ranges {
+        total += range.end.saturating_sub(range.start.max(end));
+        end = end.max(range.end);
+    }
+    println!("{} bytes in total. Nothing was written.", total);
+    Ok(())
+}
+
+// The file a build into `rom` with `options` writes.
+fn built(rom: &Path, options: &Options) -> PathBuf {
+    match &options.output {
+        Some(output) => output.clone(),
+        None => window::split_path(rom).0,
+    }
+}
+
+fn build(
+    input: PathBuf,
+    model: PathBuf,
+    extra: Vec<PathBuf>,
+    options: Options,
+    preview: bool,
+    run_after: &[String],
+) -> Result<(), Error> {
+    let mut targets = glob::expand(&input)?;
+    if targets.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No ROM matches {}.", input.display()),
+        ));
+    }
+    targets.extend(extra);
+    if model == Path::new("-") {
+        if options.files_from.as_deref() == Some(Path::new("-")) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The model and the file list can't both come from stdin.",
+            ));
         }
-        print!("\nIndexes of written FAT pages: ");
-        for i in 0..u32::from(hi) {
-            print!("{:02x} ", u32::from(self.fat_start) - i)
+        if targets.len() > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A model read from stdin can only be built into one ROM.",
+            ));
         }
-        println!("\nThe rest of the pages (except kernels' 00-03) are empty.");
-        Ok(())
     }
+    if preview {
+        return targets
+            .iter()
+            .try_for_each(|rom| dry_run(rom, &model, &options));
+    }
+    if let [rom] = &targets[..] {
+        if options.output.as_deref() == Some(Path::new("-")) {
+            if !run_after.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "With the image on stdout, there is no ROM file to run a command on.",
+                ));
+            }
+            let (file, suffix) = window::split_path(rom);
+            let span = suffix.or(options.offset);
+            return to_stdout(fs::read(file)?, span, &model, options);
+        }
+        let path = built(rom, &options);
+        Context::with_options(rom, &model, options).and_then(|mut c| c.run())?;
+        return run_after
+            .iter()
+            .try_for_each(|command| hook::run(command, &path));
+    }
+    let results = batch::build_all(&targets, &model, &options)?;
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    for (rom, result) in &results {
+        match result {
+            Ok(()) => println!("ok      {}", rom.display()),
+            Err(e) => println!("FAILED  {}: {}", rom.display(), e.get_ref().unwrap_or(e)),
+        }
+    }
+    if failed > 0 {
+        return Err(Error::other(format!(
+            "{} of {} images failed to build.",
+            failed,
+            results.len()
+        )));
+    }
+    println!("All {} images built.", results.len());
+    for (rom, _) in &results {
+        let path = built(rom, &options.for_target(rom));
+        for command in run_after {
+            hook::run(command, &path)?;
+        }
+    }
+    Ok(())
+}
+
+// Runs `build` between the hooks of the configuration file, with any
+// --run-after commands after its post_build ones.
+fn build_with_hooks(
+    input: PathBuf,
+    model: PathBuf,
+    extra: Vec<PathBuf>,
+    options: Options,
+    preview: bool,
+    hooks: &hook::Hooks,
+) -> Result<(), Error> {
+    let errors_json = options.errors_json;
+    let result = hooks
+        .pre_build
+        .iter()
+        .try_for_each(|command| hook::run(command, &input))
+        .and_then(|()| {
+            build(
+                input.clone(),
+                model,
+                extra,
+                options,
+                preview,
+                &hooks.post_build,
+            )
+        });
+    if let Err(err) = &result {
+        for command in &hooks.on_failure {
+            // The build's own error is the one worth reporting.
+            if let Err(hook_err) = hook::run_on_failure(command, &input, err) {
+                Diagnostic::error(&hook_err).print(errors_json);
+            }
+        }
+    }
+    result
+}
+
+// A ROM whose name looks like a subcommand, such as blank.rom or a file
+// called find, is still built into as `regenkfs <input> <model>` did
+// before there were subcommands: when the arguments don't parse, they
+// are parsed again as a build, and kept if that names a ROM that's there.
+fn parse_args() -> Opt {
+    let args: Vec<OsString> = env::args_os().collect();
+    let err = match Opt::from_iter_safe(&args) {
+        Ok(opt) => return opt,
+        Err(err) => err,
+    };
+    if !matches!(
+        err.kind,
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed
+    ) {
+        let mut build = args.clone();
+        build.insert(build.len().min(1), "build".into());
+        if let Ok(opt) = Opt::from_iter_safe(&build) {
+            let input = match &opt.cmd {
+                Some(Command::Build(build)) => build.input.as_deref(),
+                _ => None,
+            };
+            if input.is_some_and(|input| {
+                input.exists() || glob::has_wildcards(&input.to_string_lossy())
+            }) {
+                return opt;
+            }
+        }
+    }
+    err.exit()
 }
 
 fn main() {
-    let opt: Opt = Opt::from_args();
-    match Context::new(&opt.input, &opt.model).and_then(|mut c| c.run()) {
+    let opt = parse_args();
+    let (build, cmd) = match opt.cmd {
+        Some(Command::Build(build)) => {
+            if opt.build != BuildOpt::from_iter(["regenkfs"]) {
+                clap::Error::with_description(
+                    "Give the flags of a build after `build`, as in `regenkfs build --verify <input> <model>`.",
+                    clap::ErrorKind::ArgumentConflict,
+                )
+                .exit();
+            }
+            (*build, None)
+        }
+        cmd => (opt.build, cmd),
+    };
+    let offset = build.offset;
+    let errors_json = build.errors_json;
+    let options = Options {
+        quiet: build.quiet,
+        output: build.output,
+        mirror_fat: build.mirror_fat,
+        checksums: build.checksums,
+        manifest: build.manifest,
+        report: build.report,
+        lint: build.lint,
+        budgets: build.budgets,
+        verify: build.verify,
+        trace_writes: build.trace_writes,
+        platform: build.platform,
+        ignore_kernel_geometry: build.ignore_kernel_geometry,
+        record_writes: build.record_writes,
+        placement: build.placement,
+        hints: build.hints,
+        addresses: build.addresses,
+        symbols: build.symbols,
+        layout: build.layout,
+        prune_empty: build.prune_empty,
+        hidden: build.hidden,
+        skip_too_large: build.skip_too_large,
+        follow_dir_symlinks: build.follow_dir_symlinks,
+        symlinks: build.symlinks,
+        cache: build.cache,
+        sort: build.sort,
+        expect_sha256: build.expect_sha256,
+        instance: build.instance,
+        files_from: build.files_from,
+        stats_format: build.stats_format,
+        use_gitignore: build.use_gitignore,
+        read_only: build.read_only,
+        blanking: if build.no_blank {
+            erase::Blanking::None
+        } else if build.blank_all {
+            erase::Blanking::All
+        } else {
+            erase::Blanking::Filesystem
+        },
+        offset: build.offset,
+        keep_going: build.keep_going,
+        max_files: build.max_files,
+        max_total_size: build.max_total_size,
+        threads: build.threads,
+        buffer_size: build.buffer_size,
+        io_uring: build.io_uring,
+        chmod: build.chmod,
+        errors_json: build.errors_json,
+        paranoid: build.paranoid,
+        build_info: build.build_info,
+        integrity_page: build.integrity_page,
+        entry_checksums: build.experimental_entry_checksums,
+        compress: build.experimental_compress,
+    };
+    let result = match cmd {
+        Some(Command::Extract {
+            rom,
+            path,
+            filter,
+            to,
+            format,
+            permissions,
+            quiet,
+        }) => {
+            let extract_options = extract::ExtractOptions {
+                filter: filter.into(),
+                permissions,
+                quiet,
+            };
+            extract(&rom, offset, &path, to, format, &extract_options, &options)
+        }
+        Some(Command::Ls {
+            rom,
+            path,
+            filter,
+            long,
+            format,
+            export,
+        }) => {
+            let columns = format.or_else(|| Some(listing::Columns::default()).filter(|_| long));
+            ls(
+                &rom,
+                offset,
+                &path,
+                &filter.into(),
+                columns.as_ref(),
+                export,
+                &options,
+            )
+        }
+        Some(Command::Find {
+            rom,
+            pattern,
+            format,
+        }) => find(
+            &rom,
+            offset,
+            &pattern,
+            &format.unwrap_or_default(),
+            &options,
+        ),
+        Some(Command::Manifest { rom }) => mtree(&rom, offset, &options),
+        Some(Command::Fragmentation { rom }) => fragmentation(&rom, offset, &options),
+        Some(Command::PageMap { rom, format }) => page_map(&rom, offset, format, &options),
+        Some(Command::Cmp { a, b, hexdump }) => compare(&a, &b, offset, hexdump),
+        Some(Command::Usage { rom }) => usage(&rom, offset, &options),
+        Some(Command::Estimate { model }) => estimate(&model, &options),
+        Some(Command::ValidateModel { model, strict }) => validate_model(&model, strict, &options),
+        Some(Command::Assemble {
+            kernel,
+            model,
+            size,
+            out,
+        }) => assemble(&kernel, &model, size.as_deref(), &out, options),
+        Some(Command::Strip { rom }) => strip(&rom, offset, &options),
+        Some(Command::Blank { rom, pages, force }) => blank(&rom, offset, &pages, force, &options),
+        Some(Command::Format { rom }) => format(&rom, offset, &options),
+        Some(Command::VerifyMirror { rom }) => verify_mirror(&rom, offset, &options),
+        Some(Command::Verify { rom, model }) => verify(&rom, &model, offset, &options),
+        Some(Command::Info { rom }) => info(&rom, offset, &options),
+        Some(Command::Fsck { rom }) => fsck(&rom, offset, &options),
+        Some(Command::Corrupt {
+            rom,
+            flip_bits,
+            target,
+            seed,
+        }) => corrupt(&rom, offset, flip_bits, target, seed, &options),
+        Some(Command::GenFixture {
+            rom,
+            seed,
+            depth,
+            files,
+            sizes,
+            symlink_ratio,
+            niche_cases,
+        }) => {
+            let shape = fixture::Shape {
+                depth,
+                files,
+                sizes,
+                symlink_ratio,
+                niche_cases,
+            };
+            gen_fixture(&rom, offset, seed, &shape, &options)
+        }
+        Some(Command::VerifyChecksums { rom, checksums }) => {
+            verify_checksums(&rom, offset, &checksums, &options)
+        }
+        Some(Command::Minimize {
+            rom,
+            model,
+            out,
+            against,
+        }) => minimize(&rom, &model, &out, against, &options),
+        Some(Command::Replay { log, rom }) => replay(&log, &rom, offset, &options),
+        Some(Command::Build(_)) | None => {
+            let (input, model) = match (build.input, build.model) {
+                (Some(input), Some(model)) => (input, model),
+                _ => clap::Error::with_description(
+                    "The following required arguments were not provided:\n    <input>\n    <model>",
+                    clap::ErrorKind::MissingRequiredArgument,
+                )
+                .exit(),
+            };
+            let hooks = match &build.config {
+                Some(path) => hook::read_config(path),
+                None => Ok(hook::Hooks::default()),
+            };
+            let (targets, dry_run, run_after) = (build.targets, build.dry_run, build.run_after);
+            hooks.and_then(|mut hooks| {
+                hooks.post_build.extend(run_after);
+                build_with_hooks(input, model, targets, options, dry_run, &hooks)
+            })
+        }
+    };
+    match result {
         Ok(()) => exit(0),
         Err(e) => {
-            eprintln!("{}", e.get_ref().unwrap_or(&e));
+            Diagnostic::error(&e).print(errors_json);
             exit(1);
         }
     }
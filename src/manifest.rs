@@ -0,0 +1,56 @@
+use std::io::{Error, Write};
+
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::sha256::{hex, sha256};
+
+/// Writes one `<sha256> <size> <path>` line for every file stored in
+/// the image, in FAT order.
+pub fn write_manifest<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    for node in image.nodes()? {
+        if let Entry::File { .. } = node.record.entry {
+            let data = image.read_file(&node.record.entry)?;
+            writeln!(out, "{} {} {}", hex(&sha256(&data)), data.len(), node.path)?;
+        }
+    }
+    Ok(())
+}
+
+// mtree encodes whitespace, `\`, `#`, `=` and non-printable bytes as
+// backslash-octal escapes.
+fn mtree_escape(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'!'..=b'~' if b != b'\\' && b != b'#' && b != b'=' => char::from(b).to_string(),
+            _ => format!("\\{:03o}", b),
+        })
+        .collect()
+}
+
+/// Writes an mtree(5) specification of the image, one full path per
+/// line, which `mtree -f` and bsdtar can check an extracted tree
+/// against.
+pub fn write_mtree<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    writeln!(out, "#mtree")?;
+    writeln!(out, ". type=dir")?;
+    for node in image.nodes()? {
+        let path = format!(".{}", mtree_escape(&node.path));
+        match node.record.entry {
+            Entry::Directory { .. } => writeln!(out, "{} type=dir", path)?,
+            Entry::File { .. } => {
+                let data = image.read_file(&node.record.entry)?;
+                writeln!(
+                    out,
+                    "{} type=file size={} sha256digest={}",
+                    path,
+                    data.len(),
+                    hex(&sha256(&data))
+                )?
+            }
+            Entry::Symlink { ref target, .. } => {
+                writeln!(out, "{} type=link link={}", path, mtree_escape(target))?
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::context::{Context, Options};
+
+/// What makes a model worth reporting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Oracle {
+    /// The build fails with the same message as the original model's.
+    Error,
+    /// The image differs from the one the C genkfs at this path writes,
+    /// or only one of the two builds fails.
+    Against(PathBuf),
+}
+
+/// How one build of a candidate model went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Built(Vec<u8>),
+    Failed(String),
+}
+
+struct Minimizer<'a> {
+    rom: &'a [u8],
+    dir: &'a Path,
+    // Where entries are moved while a build is tried without them.
+    aside: PathBuf,
+    options: &'a Options,
+    oracle: &'a Oracle,
+    // The message the original model fails with, for Oracle::Error.
+    failure: String,
+    builds: usize,
+}
+
+impl Minimizer<'_> {
+    fn ours(&self) -> Outcome {
+        let built = Context::in_memory(self.rom.to_vec(), self.dir, self.options.clone()).and_then(
+            |mut context| {
+                context.run()?;
+                context.into_output()
+            },
+        );
+        match built {
+            Ok(rom) => Outcome::Built(rom.into_inner()),
+            Err(err) => Outcome::Failed(err.get_ref().map_or(err.to_string(), |e| e.to_string())),
+        }
+    }
+
+    fn theirs(&self, genkfs: &Path) -> Result<Outcome, Error> {
+        let rom = self.aside.with_extension("rom");
+        fs::write(&rom, self.rom)?;
+        let output = Command::new(genkfs).arg(&rom).arg(self.dir).output()?;
+        let outcome = if output.status.success() {
+            Outcome::Built(fs::read(&rom)?)
+        } else {
+            Outcome::Failed(String::from_utf8_lossy(&output.stderr).into_owned())
+        };
+        fs::remove_file(&rom)?;
+        Ok(outcome)
+    }
+
+    // Whether the model as it stands still shows the problem.
+    fn interesting(&mut self) -> Result<bool, Error> {
+        self.builds += 1;
+        let ours = self.ours();
+        Ok(match self.oracle {
+            Oracle::Error => ours == Outcome::Failed(self.failure.clone()),
+            Oracle::Against(genkfs) => match (ours, self.theirs(genkfs)?) {
+                (Outcome::Built(ours), Outcome::Built(theirs)) => ours != theirs,
+                (Outcome::Failed(_), Outcome::Failed(_)) => false,
+                _ => true,
+            },
+        })
+    }
+
+    // Tries the model without `path`, keeping it out if the problem stays.
+    fn try_removing(&mut self, path: &Path) -> Result<bool, Error> {
+        fs::rename(path, &self.aside)?;
+        if self.interesting()? {
+            if self.aside.is_dir() && !self.aside.is_symlink() {
+                fs::remove_dir_all(&self.aside)?;
+            } else {
+                fs::remove_file(&self.aside)?;
+            }
+            Ok(true)
+        } else {
+            fs::rename(&self.aside, path)?;
+            Ok(false)
+        }
+    }
+
+    // Halves the file at `path` from the end for as long as the problem
+    // stays.
+    fn truncate(&mut self, path: &Path) -> Result<bool, Error> {
+        let data = fs::read(path)?;
+        let mut len = data.len();
+        while len > 0 {
+            fs::write(path, &data[..len / 2])?;
+            if !self.interesting()? {
+                fs::write(path, &data[..len])?;
+                break;
+            }
+            len /= 2;
+        }
+        Ok(len < data.len())
+    }
+
+    // Removes whatever entries of `dir` it can, then shrinks what's left.
+    fn shrink(&mut self, dir: &Path) -> Result<bool, Error> {
+        let mut shrunk = false;
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        entries.sort();
+        for path in entries {
+            if self.try_removing(&path)? {
+                shrunk = true;
+                continue;
+            }
+            let file_type = fs::symlink_metadata(&path)?.file_type();
+            if file_type.is_dir() {
+                shrunk |= self.shrink(&path)?;
+            } else if file_type.is_file() {
+                shrunk |= self.truncate(&path)?;
+            }
+        }
+        Ok(shrunk)
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path) -> Result<(), Error> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(&fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, path: &Path) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        format!("Cannot create symlink {} on this platform.", path.display()),
+    ))
+}
+
+/// Copies the model directory `model` to `out`, then shrinks the copy
+/// while building it into `rom` with `options` still shows the problem
+/// `oracle` looks for: entries are removed and files cut down until
+/// nothing more can go. Returns the number of builds tried.
+pub fn minimize(
+    rom: &[u8],
+    model: &Path,
+    out: &Path,
+    options: &Options,
+    oracle: &Oracle,
+) -> Result<usize, Error> {
+    if !model.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Minimizing needs a model directory.",
+        ));
+    }
+    if out.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Error: {} already exists.", out.display()),
+        ));
+    }
+    copy_tree(model, out)?;
+    let mut minimizer = Minimizer {
+        rom,
+        dir: out,
+        aside: PathBuf::from(format!("{}.removed", out.display())),
+        options,
+        oracle,
+        failure: String::new(),
+        builds: 0,
+    };
+    let unminimizable = match (oracle, minimizer.ours()) {
+        (Oracle::Error, Outcome::Failed(message)) => {
+            minimizer.failure = message;
+            None
+        }
+        (Oracle::Error, Outcome::Built(_)) => Some(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} builds without error; nothing to minimize.",
+                model.display()
+            ),
+        )),
+        (Oracle::Against(genkfs), _) if !minimizer.interesting()? => Some(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} builds the same as with {}; nothing to minimize.",
+                model.display(),
+                genkfs.display()
+            ),
+        )),
+        (Oracle::Against(_), _) => None,
+    };
+    if let Some(err) = unminimizable {
+        fs::remove_dir_all(out)?;
+        return Err(err);
+    }
+    // Removing one entry can make another removable, so go again until
+    // a pass changes nothing.
+    while minimizer.shrink(out)? {}
+    Ok(minimizer.builds)
+}
@@ -0,0 +1,450 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::extract::normalize;
+use crate::source::{self, KfsSource};
+
+/// A model held in memory, as read from an archive. Names within a
+/// directory are kept in byte order, which matches how directory models
+/// are sorted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tree {
+    Directory(BTreeMap<String, Tree>),
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+/// Inserts `node` at the `/` separated `path` below `root`, creating
+/// missing parent directories on the way.
+pub fn insert(root: &mut BTreeMap<String, Tree>, path: &str, node: Tree) -> Result<(), Error> {
+    let mut parts: Vec<&str> = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    if parts.contains(&"..") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Refusing model path {} outside the root.", path),
+        ));
+    }
+    let name = match parts.pop() {
+        Some(name) => name,
+        // The root itself carries nothing worth keeping.
+        None => return Ok(()),
+    };
+    let mut dir = root;
+    for part in parts {
+        let child = dir
+            .entry(part.to_string())
+            .or_insert_with(|| Tree::Directory(BTreeMap::new()));
+        dir = match child {
+            Tree::Directory(children) => children,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is a parent of {} but not a directory.", part, path),
+                ))
+            }
+        };
+    }
+    match (dir.get_mut(name), node) {
+        // A directory listed after its contents keeps them.
+        (Some(Tree::Directory(_)), Tree::Directory(_)) => {}
+        (_, node) => {
+            dir.insert(name.to_string(), node);
+        }
+    }
+    Ok(())
+}
+
+/// Whether dotfiles and dot-directories of a model are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Hidden {
+    #[default]
+    Include,
+    Exclude,
+}
+
+impl Hidden {
+    /// Whether an entry called `name` is kept.
+    pub fn keeps(self, name: &str) -> bool {
+        self == Hidden::Include || !name.starts_with('.')
+    }
+}
+
+impl FromStr for Hidden {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Hidden, String> {
+        match s {
+            "include" => Ok(Hidden::Include),
+            "exclude" => Ok(Hidden::Exclude),
+            _ => Err(format!(
+                "Unknown hidden file policy {} (expected include or exclude).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Hidden {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Hidden::Include => "include",
+            Hidden::Exclude => "exclude",
+        })
+    }
+}
+
+/// Whether a model directory holds anything `hidden` keeps but (possibly
+/// nested) empty directories, i.e. whether it survives
+/// `Options::prune_empty`.
+pub fn dir_has_content(dir: &Path, hidden: Hidden) -> Result<bool, Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !hidden.keeps(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if !entry.file_type()?.is_dir() || dir_has_content(&entry.path(), hidden)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Limits on how large a model may be, so a build pointed at something
+/// like `/` by mistake fails before reading all of it. Only files count,
+/// and only those `Hidden` keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub files: Option<u64>,
+    /// Bytes of file contents in all.
+    pub total_size: Option<u64>,
+}
+
+impl Limits {
+    /// Walks the model in `source`, failing as soon as a limit is
+    /// passed.
+    pub fn check(self, source: &dyn KfsSource, hidden: Hidden) -> Result<(), Error> {
+        let mut seen = (0, 0);
+        source::walk(source, &mut |_, member| {
+            if !hidden.keeps(&member.name.to_string_lossy()) {
+                return Ok(false);
+            }
+            if let source::Kind::File { length } = member.kind {
+                self.count(length, &mut seen)?;
+            }
+            Ok(true)
+        })
+    }
+
+    // Adds a file of `length` bytes to the files and bytes `seen` so far.
+    fn count(self, length: u64, seen: &mut (u64, u64)) -> Result<(), Error> {
+        let (files, bytes) = seen;
+        *files += 1;
+        *bytes += length;
+        if let Some(max) = self.files.filter(|&max| *files > max) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Error: The model holds more than {} files.", max),
+            ));
+        }
+        if let Some(max) = self.total_size.filter(|&max| *bytes > max) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Error: The model holds more than {} bytes.", max),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The order entries of a directory are written in, which is the order
+/// the OS lists them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sort {
+    /// Byte by byte, as strcmp does. This is what genkfs gets from
+    /// `alphasort` in the C locale, so it's what gives identical images.
+    #[default]
+    Bytes,
+    /// With runs of digits compared by value, so `prog2` comes before
+    /// `prog10`.
+    Natural,
+}
+
+impl Sort {
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Sort::Bytes => a.as_bytes().cmp(b.as_bytes()),
+            Sort::Natural => natural_cmp(a, b),
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Sort, String> {
+        match s {
+            "bytes" => Ok(Sort::Bytes),
+            "natural" => Ok(Sort::Natural),
+            _ => Err(format!(
+                "Unknown sort order {} (expected bytes or natural).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sort::Bytes => "bytes",
+            Sort::Natural => "natural",
+        })
+    }
+}
+
+// Splits the leading run of ASCII digits off `s`.
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Compares names with runs of digits taken as numbers. Names that only
+/// differ in leading zeros fall back to byte order, so no two distinct
+/// names compare equal.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut x, mut y) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (x.first(), y.first()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(c), Some(d)) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                let (n, rest_x) = split_digits(x);
+                let (m, rest_y) = split_digits(y);
+                let n = &n[n.iter().take_while(|&&c| c == b'0').count()..];
+                let m = &m[m.iter().take_while(|&&c| c == b'0').count()..];
+                match n.len().cmp(&m.len()).then(n.cmp(m)) {
+                    Ordering::Equal => {}
+                    ordering => return ordering,
+                }
+                x = rest_x;
+                y = rest_y;
+            }
+            (Some(c), Some(d)) if c != d => return c.cmp(d),
+            _ => {
+                x = &x[1..];
+                y = &y[1..];
+            }
+        }
+    }
+}
+
+/// The paths of a model to write, as listed by `--files-from`, along
+/// with every directory above one of them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Selection {
+    /// The paths listed, in the `/a/b` form of `Node::path`.
+    pub listed: BTreeSet<String>,
+    parents: BTreeSet<String>,
+}
+
+impl Selection {
+    /// Reads a list of paths relative to the model, one per line or,
+    /// if the list holds any NUL (as `find -print0` writes), one per
+    /// NUL-terminated field. A leading `./` is ignored.
+    pub fn parse(list: &[u8]) -> Result<Selection, Error> {
+        let separator = if list.contains(&0) { 0 } else { b'\n' };
+        let mut selection = Selection::default();
+        for field in list.split(|&b| b == separator) {
+            let field = std::str::from_utf8(field).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "File list entry {} is not valid UTF-8.",
+                        String::from_utf8_lossy(field)
+                    ),
+                )
+            })?;
+            let path = normalize(field.strip_suffix('\r').unwrap_or(field));
+            if path.is_empty() {
+                continue;
+            }
+            let mut parent = path.as_str();
+            while let Some(i) = parent.rfind('/').filter(|&i| i > 0) {
+                parent = &parent[..i];
+                selection.parents.insert(parent.to_string());
+            }
+            selection.listed.insert(path);
+        }
+        Ok(selection)
+    }
+
+    /// Whether the entry at `path` is written.
+    pub fn keeps(&self, path: &str) -> bool {
+        self.listed.contains(path) || self.parents.contains(path)
+    }
+}
+
+/// Reads a `--files-from` list from `path`, or from stdin if it is `-`.
+pub fn read_selection(path: &Path) -> Result<Selection, Error> {
+    if path == Path::new("-") {
+        let mut list = Vec::new();
+        io::stdin().lock().read_to_end(&mut list)?;
+        Selection::parse(&list)
+    } else {
+        Selection::parse(&fs::read(path)?)
+    }
+}
+
+/// Which entries of a model are written, besides what `Hidden` leaves
+/// out.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Filter {
+    /// The `--files-from` list, if there is one.
+    pub selection: Option<Selection>,
+    /// Paths git ignores, with `--use-gitignore`.
+    pub ignored: BTreeSet<String>,
+}
+
+impl Filter {
+    pub fn is_ignored(&self, path: &str) -> bool {
+        self.ignored.contains(path)
+    }
+
+    /// Whether the entry at `path` is written.
+    pub fn keeps(&self, path: &str) -> bool {
+        !self.is_ignored(path)
+            && self
+                .selection
+                .as_ref()
+                .is_none_or(|selection| selection.keeps(path))
+    }
+}
+
+/// The paths of the model directory `model` that git ignores, in the
+/// `/a/b` form of `Node::path`. A directory ignored as a whole is listed
+/// alone, without what it holds. Asks git itself, so nested
+/// `.gitignore` files, `info/exclude` and the global excludes all count.
+pub fn git_ignored(model: &Path) -> Result<BTreeSet<String>, Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(model)
+        .args([
+            "ls-files",
+            "-z",
+            "--others",
+            "--ignored",
+            "--exclude-standard",
+        ])
+        .arg("--directory")
+        .output()
+        .map_err(|err| Error::new(err.kind(), format!("Cannot run git: {}", err)))?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "git can't tell which files of {} are ignored: {}",
+            model.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| normalize(&String::from_utf8_lossy(path)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn digits_compare_by_value() {
+        assert_eq!(natural_cmp("a2", "a10"), Ordering::Less);
+        assert_eq!(natural_cmp("a10", "a2"), Ordering::Greater);
+        assert_eq!(Sort::Bytes.compare("a2", "a10"), Ordering::Greater);
+        assert_eq!(
+            sorted(&[
+                "prog10",
+                "prog2",
+                "prog1",
+                "prog",
+                "prog10a",
+                "prog10b2",
+                "prog10b10"
+            ]),
+            [
+                "prog",
+                "prog1",
+                "prog2",
+                "prog10",
+                "prog10a",
+                "prog10b2",
+                "prog10b10"
+            ]
+        );
+        assert_eq!(
+            natural_cmp("99999999999999999999999", "100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn leading_zeros_only_break_ties() {
+        assert_eq!(natural_cmp("a007", "a10"), Ordering::Less);
+        assert_eq!(natural_cmp("a010", "a9"), Ordering::Greater);
+        // Equal values fall back to bytes, so distinct names never tie.
+        assert_eq!(natural_cmp("a01", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("a1", "a01"), Ordering::Greater);
+        assert_eq!(natural_cmp("a0", "a00"), Ordering::Less);
+        assert_eq!(natural_cmp("a1", "a1"), Ordering::Equal);
+        assert_eq!(
+            sorted(&["f02", "f1", "f2", "f10", "f001"]),
+            ["f001", "f1", "f02", "f2", "f10"]
+        );
+    }
+
+    #[test]
+    fn case_is_compared_by_byte() {
+        // As strcmp does: every capital comes before every small letter.
+        assert_eq!(natural_cmp("B", "a"), Ordering::Less);
+        assert_eq!(natural_cmp("a", "A"), Ordering::Greater);
+        assert_eq!(natural_cmp("File2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("file2", "File10"), Ordering::Greater);
+        assert_eq!(
+            sorted(&["b1", "A10", "a2", "B2"]),
+            ["A10", "B2", "a2", "b1"]
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_compare_by_utf8_bytes() {
+        assert_eq!(natural_cmp("z", "é"), Ordering::Less);
+        assert_eq!(natural_cmp("é2", "é10"), Ordering::Less);
+        assert_eq!(natural_cmp("ü10", "ü9"), Ordering::Greater);
+        // Other scripts' digits are letters, not numbers.
+        assert_eq!(natural_cmp("a٢", "a١٠"), Ordering::Greater);
+        assert_eq!(
+            sorted(&["日本10", "日本2", "éa", "e"]),
+            ["e", "éa", "日本2", "日本10"]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{Error, Write};
+
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::*;
+
+const BLOCKS_PER_PAGE: usize = (PAGE_LENGTH / BLOCK_SIZE) as usize;
+const CELL: usize = 10;
+const LABEL_WIDTH: usize = 30;
+
+/// Which file owns each block of each data page.
+struct Fill {
+    first_page: u8,
+    files: Vec<String>,
+    /// Per page, the index into `files` of each block's owner.
+    pages: Vec<[Option<usize>; BLOCKS_PER_PAGE]>,
+}
+
+impl Fill {
+    fn new(image: &Image) -> Result<Fill, Error> {
+        let first_page = image.dat_start;
+        let last_page = image.fat_start.saturating_sub(image.geometry.fat_pages());
+        let mut fill = Fill {
+            first_page,
+            files: Vec::new(),
+            pages: vec![[None; BLOCKS_PER_PAGE]; usize::from(last_page - first_page) + 1],
+        };
+        for node in image.nodes()? {
+            if let Entry::File {
+                length, section_id, ..
+            } = node.record.entry
+            {
+                let owner = fill.files.len();
+                for (section_id, _) in image.chain(section_id, length)? {
+                    let [index, page] = section_id.to_le_bytes();
+                    if let Some(blocks) = page
+                        .checked_sub(first_page)
+                        .and_then(|page| fill.pages.get_mut(usize::from(page)))
+                    {
+                        blocks[usize::from(index)] = Some(owner);
+                    }
+                }
+                fill.files.push(node.path);
+            }
+        }
+        Ok(fill)
+    }
+}
+
+// Spreads hues by the golden angle so neighbouring files contrast.
+fn hue(owner: usize) -> usize {
+    (owner * 137) % 360
+}
+
+fn color(owner: usize) -> String {
+    format!("hsl({},65%,55%)", hue(owner))
+}
+
+// Graphviz takes colors as "hue saturation value" fractions.
+fn dot_color(owner: usize) -> String {
+    format!("{:.3} 0.5 0.9", hue(owner) as f64 / 360.0)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes an SVG with a row per data page and a cell per block, colored
+/// by the file owning it. Hovering a cell shows the file's path.
+pub fn write_svg<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    let fill = Fill::new(image)?;
+    let width = LABEL_WIDTH + BLOCKS_PER_PAGE * CELL;
+    let height = fill.pages.len() * CELL;
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"8\">",
+        width, height
+    )?;
+    for (row, blocks) in fill.pages.iter().enumerate() {
+        let y = row * CELL;
+        writeln!(
+            out,
+            "<text x=\"0\" y=\"{}\">{:02x}</text>",
+            y + CELL - 2,
+            usize::from(fill.first_page) + row
+        )?;
+        for (index, owner) in blocks.iter().enumerate() {
+            let x = LABEL_WIDTH + index * CELL;
+            let (fill_color, title) = match owner {
+                // Block 0 holds the page's section headers.
+                _ if index == 0 => ("#999".to_string(), "section headers".to_string()),
+                Some(owner) => (color(*owner), escape(&fill.files[*owner])),
+                None => ("#eee".to_string(), "free".to_string()),
+            };
+            writeln!(
+                out,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"#fff\"><title>{}</title></rect>",
+                x, y, CELL, CELL, fill_color, title
+            )?;
+        }
+    }
+    writeln!(out, "</svg>")
+}
+
+/// Writes a Graphviz graph linking every data page to the files stored
+/// in it, each edge labelled with the number of blocks.
+pub fn write_dot<W: Write>(out: &mut W, image: &Image) -> Result<(), Error> {
+    let fill = Fill::new(image)?;
+    writeln!(out, "digraph pages {{")?;
+    writeln!(out, "  rankdir=LR;")?;
+    for (owner, path) in fill.files.iter().enumerate() {
+        writeln!(
+            out,
+            "  file{} [label=\"{}\", shape=box, style=filled, fillcolor=\"{}\"];",
+            owner,
+            path.replace('\\', "\\\\").replace('"', "\\\""),
+            dot_color(owner)
+        )?;
+    }
+    for (row, blocks) in fill.pages.iter().enumerate() {
+        let page = usize::from(fill.first_page) + row;
+        let used = blocks.iter().filter(|owner| owner.is_some()).count();
+        writeln!(
+            out,
+            "  page{:02x} [label=\"page {:02x}\\n{}/{} blocks\"];",
+            page,
+            page,
+            used,
+            BLOCKS_PER_PAGE - 1
+        )?;
+        let mut counts: Vec<(usize, usize)> = Vec::new();
+        for owner in blocks.iter().flatten() {
+            match counts.iter_mut().find(|(o, _)| o == owner) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((*owner, 1)),
+            }
+        }
+        for (owner, count) in counts {
+            writeln!(
+                out,
+                "  page{:02x} -> file{} [label=\"{}\"];",
+                page, owner, count
+            )?;
+        }
+    }
+    writeln!(out, "}}")
+}
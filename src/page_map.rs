@@ -0,0 +1,208 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+
+use crate::geometry::Geometry;
+use crate::section::SectionId;
+
+/// Which blocks of a run of data pages are allocated.
+///
+/// Bit `n` of a page's word is set when block `n` of that page holds a
+/// section. Block 0 carries the section headers and is always set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageMap {
+    first_page: u8,
+    /// Below `first_page` for a map of no pages.
+    last_page: u8,
+    max_index: u8,
+    pages: Vec<u64>,
+}
+
+impl PageMap {
+    /// An empty map of the pages from `first_page` through `last_page`.
+    pub fn new(first_page: u8, last_page: u8) -> PageMap {
+        PageMap::with_geometry(first_page, last_page, Geometry::default())
+    }
+
+    /// Like `new`, for pages split into blocks as `geometry` says.
+    pub fn with_geometry(first_page: u8, last_page: u8, geometry: Geometry) -> PageMap {
+        let count = usize::from(last_page.saturating_sub(first_page)) + 1;
+        PageMap {
+            first_page,
+            last_page,
+            max_index: geometry.max_index(),
+            pages: vec![1; if last_page < first_page { 0 } else { count }],
+        }
+    }
+
+    /// The highest block index of each page.
+    pub fn max_index(&self) -> u8 {
+        self.max_index
+    }
+
+    /// Range of pages covered by the map, which may end at page ff.
+    pub fn pages(&self) -> RangeInclusive<u8> {
+        self.first_page..=self.last_page
+    }
+
+    fn word(&mut self, id: SectionId) -> Option<&mut u64> {
+        id.page()
+            .checked_sub(self.first_page)
+            .and_then(move |p| self.pages.get_mut(usize::from(p)))
+    }
+
+    /// Marks a section allocated, failing if it already is or lies
+    /// outside the map.
+    pub fn allocate(&mut self, id: SectionId) -> Result<(), Error> {
+        let bit = 1 << id.index();
+        let max_index = self.max_index;
+        match self.word(id).filter(|_| id.index() <= max_index) {
+            Some(used) if *used & bit == 0 => {
+                *used |= bit;
+                Ok(())
+            }
+            Some(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Section {} is allocated twice.", id),
+            )),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Section {} is outside the data pages.", id),
+            )),
+        }
+    }
+
+    /// Marks a section free again.
+    pub fn free(&mut self, id: SectionId) {
+        if let Some(used) = self.word(id) {
+            *used &= !(1 << id.index());
+        }
+    }
+
+    /// Marks every section of `page` allocated, so nothing is placed in
+    /// it.
+    pub fn reserve(&mut self, page: u8) {
+        let max_index = self.max_index;
+        if let Some(used) = self.word(SectionId::first(page)) {
+            *used = u64::MAX >> (63 - max_index);
+        }
+    }
+
+    /// Whether a section is allocated. Anything outside the map, or not
+    /// a valid section at all, counts as used.
+    pub fn is_used(&self, section_id: u16) -> bool {
+        let [index, page] = section_id.to_le_bytes();
+        match page
+            .checked_sub(self.first_page)
+            .and_then(|p| self.pages.get(usize::from(p)))
+        {
+            Some(used) if index <= self.max_index => used & 1 << index != 0,
+            _ => true,
+        }
+    }
+
+    /// Every free section, in allocation order.
+    pub fn free_sections(&self) -> impl Iterator<Item = u16> + '_ {
+        self.pages().flat_map(move |page| {
+            (1..=self.max_index)
+                .map(move |index| (u16::from(page) << 8) | u16::from(index))
+                .filter(move |&section_id| !self.is_used(section_id))
+        })
+    }
+
+    /// Allocated blocks of one page, not counting its header block.
+    pub fn used_in(&self, page: u8) -> usize {
+        page.checked_sub(self.first_page)
+            .and_then(|p| self.pages.get(usize::from(p)))
+            .map_or(0, |used| used.count_ones() as usize - 1)
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.free_sections().count()
+    }
+
+    pub fn used_blocks(&self) -> usize {
+        self.pages().map(|page| self.used_in(page)).sum()
+    }
+}
+
+/// Prints a row per page: `#` for an allocated block and `.` for a free
+/// one, after the header block's `h`.
+impl fmt::Display for PageMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (page, used) in self.pages().zip(&self.pages) {
+            let blocks: String = (0..=self.max_index)
+                .map(|index| match index {
+                    0 => 'h',
+                    _ if used & 1 << index != 0 => '#',
+                    _ => '.',
+                })
+                .collect();
+            writeln!(
+                f,
+                "{:02x} {} {:2}/{}",
+                page,
+                blocks,
+                self.used_in(page),
+                self.max_index
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_reach_the_top_page() {
+        let map = PageMap::new(0xF0, 0xFF);
+        assert_eq!(map.pages(), 0xF0..=0xFF);
+        assert_eq!(map.pages().len(), 16);
+        assert_eq!(map.free_blocks(), 16 * usize::from(map.max_index()));
+        assert_eq!(map.to_string().lines().last().unwrap().get(..2), Some("ff"));
+        assert_eq!(PageMap::new(0x00, 0xFF).pages().len(), 256);
+        assert_eq!(PageMap::new(0xFF, 0xFF).pages(), 0xFF..=0xFF);
+    }
+
+    #[test]
+    fn a_map_with_no_pages_is_empty() {
+        let map = PageMap::new(0x05, 0x04);
+        assert_eq!(map.pages().count(), 0);
+        assert_eq!(map.free_blocks(), 0);
+        assert!(map.is_used(SectionId::first(0x05).into()));
+    }
+
+    #[test]
+    fn sections_on_the_top_page_are_allocated_and_freed() {
+        let mut map = PageMap::new(0xFE, 0xFF);
+        let id = SectionId::new(0xFF, map.max_index()).unwrap();
+        map.allocate(id).unwrap();
+        assert!(map.is_used(id.into()));
+        assert_eq!(map.used_in(0xFF), 1);
+        assert!(map
+            .allocate(id)
+            .unwrap_err()
+            .to_string()
+            .contains("allocated twice"));
+        map.free(id);
+        assert!(!map.is_used(id.into()));
+        map.reserve(0xFF);
+        assert_eq!(map.used_in(0xFF), usize::from(map.max_index()));
+        assert_eq!(map.free_sections().count(), usize::from(map.max_index()));
+    }
+
+    #[test]
+    fn sections_outside_the_map_count_as_used() {
+        let mut map = PageMap::new(0x04, 0x05);
+        let outside = SectionId::first(0x06);
+        assert!(map.is_used(outside.into()));
+        assert!(map
+            .allocate(outside)
+            .unwrap_err()
+            .to_string()
+            .contains("outside the data pages"));
+        assert!(map.is_used(0x0400), "index 0 is the header block");
+    }
+}
@@ -0,0 +1,175 @@
+use std::convert::TryFrom;
+use std::ops::{Range, RangeInclusive};
+
+use crate::fat::Entry;
+use crate::spec::{ENTRY_HEADER_LENGTH, INTEGRITY_MAGIC, MAGIC, SECTION_HEADER_LENGTH};
+use crate::*;
+
+/// Where a build may write what, for `--paranoid` to hold each write
+/// to.
+#[derive(Debug, Clone)]
+pub struct Bounds {
+    pub geometry: Geometry,
+    /// The pages data sections and magic may go in.
+    pub data_pages: RangeInclusive<u8>,
+    /// Offsets of the FAT, and of its mirror if it has one.
+    pub fat: Range<u64>,
+    pub mirror: Option<Range<u64>>,
+}
+
+impl Bounds {
+    fn page_of(&self, offset: u64) -> (u64, u64) {
+        let page_length = u64::from(self.geometry.page_length());
+        (offset / page_length, offset % page_length)
+    }
+
+    fn in_data_pages(&self, page: u64) -> bool {
+        u8::try_from(page).is_ok_and(|page| self.data_pages.contains(&page))
+    }
+
+    fn in_data(&self, id: u16) -> bool {
+        let [index, page] = id.to_le_bytes();
+        (1..=self.geometry.max_index()).contains(&index) && self.data_pages.contains(&page)
+    }
+
+    /// Checks that writing `bytes` at `offset` as `region` keeps the
+    /// format's invariants, returning the one it would break:
+    ///
+    /// * magic only at the start of a data page;
+    /// * section headers only in block 0 of a data page, in the slot of
+    ///   a data block, naming sections that exist;
+    /// * data only in whole blocks;
+    /// * FAT entries only in the FAT (or its mirror), each one whole,
+    ///   its length field matching its name (and checksum), and a
+    ///   file's section id one that exists;
+    /// * blanks only of erased flash, except the `K` genkfs starts a
+    ///   blanked data page with.
+    pub fn check(&self, offset: u64, bytes: &[u8], region: Region) -> Result<(), String> {
+        let (page, in_page) = self.page_of(offset);
+        let end = offset + bytes.len() as u64;
+        let block_size = u64::from(self.geometry.block_size());
+        match region {
+            Region::Magic => {
+                if in_page != 0 {
+                    return Err("magic not at the start of a page".to_string());
+                }
+                if !self.in_data_pages(page) {
+                    return Err(format!(
+                        "magic on page {:02x}, outside the data pages",
+                        page
+                    ));
+                }
+                if !bytes.starts_with(MAGIC) || bytes.len() > MAGIC.len() + 1 {
+                    return Err("magic that isn't KFS and a version byte".to_string());
+                }
+            }
+            Region::SectionHeader => {
+                if in_page >= block_size {
+                    return Err("section header outside block 0".to_string());
+                }
+                let index = in_page / u64::from(SECTION_HEADER_LENGTH);
+                if bytes.len() != usize::from(SECTION_HEADER_LENGTH)
+                    || in_page % u64::from(SECTION_HEADER_LENGTH) != 0
+                    || !(1..=u64::from(self.geometry.max_index())).contains(&index)
+                {
+                    return Err("section header not in the slot of a data block".to_string());
+                }
+                if !self.in_data_pages(page) {
+                    return Err(format!(
+                        "section header on page {:02x}, outside the data pages",
+                        page
+                    ));
+                }
+                // The pSID of a chain's first section is 0x7FFF, and each
+                // one has the in-use bit (the page's top bit) cleared.
+                let pSID = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let nSID = u16::from_le_bytes([bytes[2], bytes[3]]);
+                let [p_index, p_page] = pSID.to_le_bytes();
+                let previous = pSID == 0x7FFF
+                    || ((1..=self.geometry.max_index()).contains(&p_index)
+                        && self.data_pages.clone().any(|page| page & 0x7F == p_page));
+                if !previous {
+                    return Err(format!("pSID {:04x} isn't a data section", pSID));
+                }
+                if nSID != KFS_NO_SECTION && !self.in_data(nSID) {
+                    return Err(format!("nSID {:04x} isn't a data section", nSID));
+                }
+            }
+            Region::Block => {
+                if in_page % block_size != 0 || in_page == 0 {
+                    return Err("data not at the start of a data block".to_string());
+                }
+                if bytes.len() as u64 > block_size {
+                    return Err("data longer than a block".to_string());
+                }
+                if !self.in_data_pages(page) {
+                    return Err(format!("data on page {:02x}, outside the data pages", page));
+                }
+            }
+            Region::Fat | Region::FatMirror => {
+                let within = if region == Region::Fat {
+                    Some(&self.fat)
+                } else {
+                    self.mirror.as_ref()
+                };
+                if !within.is_some_and(|fat| fat.start <= offset && end <= fat.end) {
+                    return Err(format!("{} entry outside the {}", region, region));
+                }
+                self.check_entry(bytes)?;
+            }
+            Region::Integrity => {
+                if in_page != 0 || bytes.len() != usize::from(self.geometry.page_length()) {
+                    return Err("integrity page that isn't a whole page".to_string());
+                }
+                if !self.in_data_pages(page) || !bytes.starts_with(INTEGRITY_MAGIC) {
+                    return Err("integrity page outside the data pages".to_string());
+                }
+            }
+            Region::Blank => {
+                let erased = |(i, &byte): (usize, &u8)| {
+                    byte == 0xFF || (i == 0 && in_page == 0 && byte == b'K')
+                };
+                if !bytes.iter().enumerate().all(erased) {
+                    return Err("a blank that isn't erased flash".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checks a FAT entry as stored, byte-reversed.
+    fn check_entry(&self, stored: &[u8]) -> Result<(), String> {
+        let mut bytes = stored.to_vec();
+        bytes.reverse();
+        let entry = Entry::decode(&bytes, 0).map_err(|e| format!("undecodable entry: {}", e))?;
+        let length = usize::from(u16::from_le_bytes([bytes[1], bytes[2]]));
+        let target = match &entry {
+            Entry::Symlink { target, .. } => target.len(),
+            _ => 0,
+        };
+        let checksum = if entry.has_checksum() {
+            spec::ENTRY_CHECKSUM_LENGTH
+        } else {
+            0
+        };
+        let expected = spec::entry_length(bytes[0], entry.name().len(), target)
+            .map(|expected| expected + checksum);
+        if length + ENTRY_HEADER_LENGTH != bytes.len() || expected != Some(bytes.len()) {
+            return Err(format!(
+                "entry {} whose length {} doesn't match its name",
+                entry.name(),
+                length
+            ));
+        }
+        if let Entry::File { section_id, .. } = entry {
+            if section_id != KFS_NO_SECTION && !self.in_data(section_id) {
+                return Err(format!(
+                    "entry {} with section id {:04x}, which isn't a data section",
+                    entry.name(),
+                    section_id
+                ));
+            }
+        }
+        Ok(())
+    }
+}
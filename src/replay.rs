@@ -0,0 +1,308 @@
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Write};
+
+/// Starts every write log.
+pub const MAGIC: &[u8; 8] = b"KFSWLOG\x01";
+
+// Runs of a repeated byte at least this long are logged as fills, which
+// is what keeps blanked pages small.
+const MIN_FILL: usize = 16;
+
+const LITERAL: u8 = 0;
+const FILL: u8 = 1;
+const END: u8 = 2;
+
+fn write_varint<W: Write>(out: &mut W, mut n: u64) -> Result<(), Error> {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Logs every write to a ROM as its offset and bytes, so `Log::apply`
+/// can make them again on a blank ROM.
+///
+/// The log is the magic number and the length of the ROM, then one
+/// record per write or run in it: a tag, the offset and length as LEB128
+/// varints, and either the bytes (tag 0) or the one byte repeated (tag 1).
+/// A lone tag 2 ends it, so a log cut short is caught.
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Starts a log of writes to a ROM of `length` bytes.
+    pub fn new(mut out: W, length: u64) -> Result<Recorder<W>, Error> {
+        out.write_all(MAGIC)?;
+        write_varint(&mut out, length)?;
+        Ok(Recorder { out })
+    }
+
+    pub fn record(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        // Where the bytes not yet logged start.
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let run = bytes[i..].iter().take_while(|&&b| b == bytes[i]).count();
+            if run >= MIN_FILL {
+                self.literal(offset + start as u64, &bytes[start..i])?;
+                self.out.write_all(&[FILL])?;
+                write_varint(&mut self.out, offset + i as u64)?;
+                write_varint(&mut self.out, run as u64)?;
+                self.out.write_all(&[bytes[i]])?;
+                start = i + run;
+            }
+            i += run;
+        }
+        self.literal(offset + start as u64, &bytes[start..])
+    }
+
+    fn literal(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.out.write_all(&[LITERAL])?;
+        write_varint(&mut self.out, offset)?;
+        write_varint(&mut self.out, bytes.len() as u64)?;
+        self.out.write_all(bytes)
+    }
+
+    /// Ends the log, returning what it was written to.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.out.write_all(&[END])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn corrupt(what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Corrupt write log: {}.", what),
+    )
+}
+
+// Reads what `Recorder` writes, field by field.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .data
+                .split_first()
+                .ok_or_else(|| corrupt("it ends early"))?;
+            self.data = rest;
+            n |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(corrupt("a number is too long"))
+    }
+
+    fn bytes(&mut self, len: u64) -> Result<&'a [u8], Error> {
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.data.len())
+            .ok_or_else(|| corrupt("it ends early"))?;
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+}
+
+/// A write log read back, as `Recorder` wrote it.
+#[derive(Debug, Clone, Copy)]
+pub struct Log<'a> {
+    /// The length of the ROM the writes were made to.
+    pub length: u64,
+    records: &'a [u8],
+}
+
+impl<'a> Log<'a> {
+    pub fn parse(log: &'a [u8]) -> Result<Log<'a>, Error> {
+        let records = log
+            .strip_prefix(&MAGIC[..])
+            .ok_or_else(|| corrupt("it doesn't start with the magic number"))?;
+        let mut reader = Reader { data: records };
+        let length = reader.varint()?;
+        Ok(Log {
+            length,
+            records: reader.data,
+        })
+    }
+
+    /// Makes the logged writes to `rom` in order, returning how many
+    /// records there were.
+    pub fn apply(&self, rom: &mut [u8]) -> Result<usize, Error> {
+        if rom.len() as u64 != self.length {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The writes were logged for a {} byte image, not {} bytes.",
+                    self.length,
+                    rom.len()
+                ),
+            ));
+        }
+        let mut reader = Reader { data: self.records };
+        let mut records = 0;
+        loop {
+            let (&tag, rest) = reader
+                .data
+                .split_first()
+                .ok_or_else(|| corrupt("it ends early"))?;
+            reader.data = rest;
+            if tag == END {
+                return Ok(records);
+            }
+            let offset = reader.varint()?;
+            let len = reader.varint()?;
+            let end = offset
+                .checked_add(len)
+                .filter(|&end| end <= self.length)
+                .ok_or_else(|| corrupt("a write runs past the end of the image"))?;
+            let target = &mut rom[offset as usize..end as usize];
+            match tag {
+                LITERAL => target.copy_from_slice(reader.bytes(len)?),
+                FILL => target.fill(reader.bytes(1)?[0]),
+                _ => return Err(corrupt("a record has an unknown tag")),
+            }
+            records += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(length: u64, writes: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut recorder = Recorder::new(Vec::new(), length).unwrap();
+        for &(offset, bytes) in writes {
+            recorder.record(offset, bytes).unwrap();
+        }
+        recorder.finish().unwrap()
+    }
+
+    fn error(log: &[u8], length: usize) -> String {
+        Log::parse(log)
+            .and_then(|log| log.apply(&mut vec![0xFF; length]))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn writes_replay_in_order() {
+        let mut page = vec![0xFF; 100];
+        page[..3].copy_from_slice(b"KFS");
+        let written = log(
+            300,
+            &[
+                (0, &page),
+                (150, b"abc"),
+                (151, b"X"),
+                (299, b"z"),
+                (10, b""),
+            ],
+        );
+        // The run of 0xFF after the magic number is a fill.
+        assert!(written.len() < 40, "{} bytes", written.len());
+        let log = Log::parse(&written).unwrap();
+        assert_eq!(log.length, 300);
+        let mut rom = vec![0; 300];
+        assert_eq!(log.apply(&mut rom).unwrap(), 5);
+        assert_eq!(&rom[..100], &page[..]);
+        assert_eq!(&rom[150..153], b"aXc");
+        assert_eq!(rom[299], b'z');
+        assert!(rom[100..150].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn short_runs_stay_literal() {
+        let bytes: Vec<u8> = (0..40).map(|i| if i < 15 { 7 } else { i }).collect();
+        let written = log(40, &[(0, &bytes)]);
+        let mut rom = vec![0; 40];
+        assert_eq!(Log::parse(&written).unwrap().apply(&mut rom).unwrap(), 1);
+        assert_eq!(rom, bytes);
+    }
+
+    #[test]
+    fn logs_cut_short_are_errors() {
+        let written = log(64, &[(0, &[1; 8]), (32, &[2; 32])]);
+        for end in MAGIC.len() + 1..written.len() {
+            assert_eq!(
+                error(&written[..end], 64),
+                "Corrupt write log: it ends early.",
+                "cut to {} bytes",
+                end
+            );
+        }
+        assert_eq!(
+            error(&written[..MAGIC.len()], 64),
+            "Corrupt write log: it ends early."
+        );
+        assert_eq!(
+            error(b"KFSWLOG\x02\x40\x02", 64),
+            "Corrupt write log: it doesn't start with the magic number."
+        );
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        let mut written = MAGIC.to_vec();
+        written.extend_from_slice(&[0x80; 10]);
+        written.push(0);
+        assert_eq!(
+            error(&written, 0),
+            "Corrupt write log: a number is too long."
+        );
+
+        let mut written = MAGIC.to_vec();
+        written.extend_from_slice(&[0x40, 9, 0, 1, 0, END]);
+        assert_eq!(
+            error(&written, 64),
+            "Corrupt write log: a record has an unknown tag."
+        );
+    }
+
+    #[test]
+    fn writes_past_the_image_are_errors() {
+        let written = log(64, &[(60, b"abcd")]);
+        assert_eq!(
+            Log::parse(&written).unwrap().apply(&mut [0; 64]).unwrap(),
+            1
+        );
+        let written = log(64, &[(61, b"abcd")]);
+        assert_eq!(
+            error(&written, 64),
+            "Corrupt write log: a write runs past the end of the image."
+        );
+        let mut written = MAGIC.to_vec();
+        written.extend_from_slice(&[0x40, FILL]);
+        written.extend_from_slice(&[0xFF; 9]);
+        written.extend_from_slice(&[0x01, 0x02, 0x00, END]);
+        assert_eq!(
+            error(&written, 64),
+            "Corrupt write log: a write runs past the end of the image."
+        );
+    }
+
+    #[test]
+    fn logs_only_apply_to_images_of_their_length() {
+        let written = log(64, &[(0, b"a")]);
+        assert_eq!(
+            error(&written, 65),
+            "The writes were logged for a 64 byte image, not 65 bytes."
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io::{Error, Read, Seek, SeekFrom, Write};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::UringFile;
+
+/// A ROM opened on disk, written to directly or, with `--io-uring`,
+/// through an io_uring submission queue.
+pub enum RomFile {
+    Plain(File),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Box<UringFile>),
+}
+
+impl RomFile {
+    pub fn open(file: File, io_uring: bool) -> Result<RomFile, Error> {
+        if !io_uring {
+            return Ok(RomFile::Plain(file));
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        return Ok(RomFile::Uring(Box::new(UringFile::new(file)?)));
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Error: regenkfs was built without io_uring support (the io-uring feature, on Linux).",
+        ))
+    }
+}
+
+impl Read for RomFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            RomFile::Plain(file) => file.read(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RomFile::Uring(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for RomFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            RomFile::Plain(file) => file.write(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RomFile::Uring(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            RomFile::Plain(file) => file.flush(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RomFile::Uring(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for RomFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        match self {
+            RomFile::Plain(file) => file.seek(pos),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            RomFile::Uring(file) => file.seek(pos),
+        }
+    }
+}
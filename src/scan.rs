@@ -0,0 +1,115 @@
+use std::io::Error;
+
+use crate::fat::{read_fat, Record};
+use crate::layout::Layout;
+use crate::page_map::PageMap;
+use crate::section::SectionId;
+use crate::spec::INTEGRITY_MAGIC;
+use crate::*;
+
+/// Which data blocks and how much FAT space of an image are in use.
+#[derive(Debug, Clone)]
+pub struct AllocationMap {
+    pages: PageMap,
+    fat_ptr: u32,
+    fat_limit: u32,
+    records: Vec<Record>,
+}
+
+impl AllocationMap {
+    /// Scans a ROM image of `geometry` whose data starts at `dat_start`
+    /// and whose FAT starts at `fat_start`.
+    pub fn scan_with(
+        rom: &[u8],
+        geometry: Geometry,
+        dat_start: u8,
+        fat_start: u8,
+    ) -> Result<AllocationMap, Error> {
+        let (records, fat_ptr) = read_fat(rom, geometry, fat_start)?;
+        let fat_limit = geometry
+            .fat_end(fat_start)
+            .saturating_sub(geometry.fat_length());
+        let last = fat_start.saturating_sub(geometry.fat_pages());
+        let mut pages = PageMap::with_geometry(dat_start, last, geometry);
+        for page in dat_start..=last {
+            // An integrity page is taken whole, and holds no sections.
+            let start = geometry.page_offset(page) as usize;
+            if rom
+                .get(start..)
+                .is_some_and(|page| page.starts_with(INTEGRITY_MAGIC))
+            {
+                pages.reserve(page);
+                continue;
+            }
+            for index in 1..=geometry.max_index() {
+                let id = SectionId::new(page, index).unwrap();
+                let header = geometry.header_offset(id) as usize;
+                let pSID = u16::from_le_bytes([rom[header], rom[header + 1]]);
+                // A cleared high bit marks the section in use.
+                if pSID & 0x8000 == 0 {
+                    pages.allocate(id)?;
+                }
+            }
+        }
+        Ok(AllocationMap {
+            pages,
+            fat_ptr,
+            fat_limit,
+            records,
+        })
+    }
+
+    /// Scans the filesystem `layout` puts in a ROM image, whose FAT
+    /// starts at `fat_start`, counting the pages it reserves as taken.
+    pub fn scan_layout(rom: &[u8], layout: &Layout, fat_start: u8) -> Result<AllocationMap, Error> {
+        let mut map = AllocationMap::scan_with(rom, layout.geometry, layout.dat_start, fat_start)?;
+        for page in map.pages() {
+            if layout.is_reserved(page) {
+                map.pages.reserve(page);
+            }
+        }
+        Ok(map)
+    }
+
+    /// The FAT entries found while scanning.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// The data blocks in use.
+    pub fn page_map(&self) -> &PageMap {
+        &self.pages
+    }
+
+    /// Range of data pages covered by the map.
+    pub fn pages(&self) -> std::ops::RangeInclusive<u8> {
+        self.pages.pages()
+    }
+
+    pub fn is_used(&self, section_id: u16) -> bool {
+        self.pages.is_used(section_id)
+    }
+
+    /// Every free section, in allocation order.
+    pub fn free_sections(&self) -> impl Iterator<Item = u16> + '_ {
+        self.pages.free_sections()
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.pages.free_blocks()
+    }
+
+    pub fn used_blocks(&self) -> usize {
+        self.pages.used_blocks()
+    }
+
+    /// Address one past the lowest FAT byte in use.
+    pub fn fat_ptr(&self) -> u32 {
+        self.fat_ptr
+    }
+
+    /// Bytes still available to new FAT entries.
+    pub fn fat_free(&self) -> u32 {
+        self.fat_ptr - self.fat_limit
+    }
+}
@@ -0,0 +1,172 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::*;
+
+/// The highest block index of a page in the stock geometry, and the
+/// highest any geometry may use. Block 0 holds the section headers.
+pub const MAX_INDEX: u8 = (PAGE_LENGTH / BLOCK_SIZE - 1) as u8;
+
+/// Identifies a data section: the flash page in the high byte and the
+/// block within it, from 1 to `MAX_INDEX`, in the low byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SectionId(u16);
+
+impl SectionId {
+    pub fn new(page: u8, index: u8) -> Option<SectionId> {
+        if (1..=MAX_INDEX).contains(&index) {
+            Some(SectionId(u16::from_le_bytes([index, page])))
+        } else {
+            None
+        }
+    }
+
+    /// The first section of `page`.
+    pub fn first(page: u8) -> SectionId {
+        SectionId(u16::from_le_bytes([1, page]))
+    }
+
+    pub fn page(self) -> u8 {
+        self.0.to_le_bytes()[1]
+    }
+
+    pub fn index(self) -> u8 {
+        self.0.to_le_bytes()[0]
+    }
+
+    /// The section after this one, moving on to the next page after the
+    /// last block. `None` past page 0xFF.
+    pub fn next(self) -> Option<SectionId> {
+        self.next_within(MAX_INDEX)
+    }
+
+    /// Like `next`, for pages whose last block is `max_index`.
+    pub fn next_within(self, max_index: u8) -> Option<SectionId> {
+        if self.index() < max_index {
+            Some(SectionId(self.0 + 1))
+        } else {
+            self.page().checked_add(1).map(SectionId::first)
+        }
+    }
+
+    /// Whether this is the first section of its page.
+    pub fn starts_page(self) -> bool {
+        self.index() == 1
+    }
+
+    /// ROM offset of the section's block in the stock geometry. See
+    /// `Geometry` for others.
+    pub fn offset(self) -> u64 {
+        page_offset(self.page()) + u64::from(self.index()) * u64::from(BLOCK_SIZE)
+    }
+
+    /// ROM offset of the section's pSID/nSID header in the stock
+    /// geometry.
+    pub fn header_offset(self) -> u64 {
+        page_offset(self.page()) + u64::from(spec::section_header_offset(self.index()))
+    }
+}
+
+/// ROM offset of the start of `page`.
+pub fn page_offset(page: u8) -> u64 {
+    u64::from(page) * u64::from(PAGE_LENGTH)
+}
+
+impl From<SectionId> for u16 {
+    fn from(id: SectionId) -> u16 {
+        id.0
+    }
+}
+
+impl TryFrom<u16> for SectionId {
+    type Error = u16;
+
+    fn try_from(id: u16) -> Result<SectionId, u16> {
+        let [index, page] = id.to_le_bytes();
+        SectionId::new(page, index).ok_or(id)
+    }
+}
+
+impl fmt::Display for SectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Geometry;
+
+    #[test]
+    fn next_rolls_over_to_the_next_page() {
+        let last = SectionId::new(0x04, MAX_INDEX).unwrap();
+        assert_eq!(MAX_INDEX, 0x3F);
+        assert_eq!(last.next(), Some(SectionId::first(0x05)));
+        assert!(last.next().unwrap().starts_page());
+        assert_eq!(
+            SectionId::new(0x04, 0x3E).unwrap().next(),
+            Some(last),
+            "0x3e is followed by 0x3f on the same page"
+        );
+    }
+
+    #[test]
+    fn next_within_rolls_over_at_a_smaller_last_block() {
+        let id = SectionId::new(0x04, 0x1F).unwrap();
+        assert_eq!(id.next_within(0x1F), Some(SectionId::first(0x05)));
+        assert_eq!(id.next_within(0x3F), SectionId::new(0x04, 0x20));
+    }
+
+    #[test]
+    fn next_runs_out_after_page_ff() {
+        let last = SectionId::new(0xFF, MAX_INDEX).unwrap();
+        assert_eq!(last.next(), None);
+        assert_eq!(last.next_within(0x1F), None);
+        assert_eq!(
+            SectionId::new(0xFF, 1).unwrap().next(),
+            SectionId::new(0xFF, 2)
+        );
+    }
+
+    #[test]
+    fn try_from_refuses_what_isnt_a_section() {
+        assert_eq!(SectionId::try_from(KFS_NO_SECTION), Err(KFS_NO_SECTION));
+        // Block 0 holds the headers, and 0x40 is past the last block.
+        assert_eq!(SectionId::try_from(0x0400), Err(0x0400));
+        assert_eq!(SectionId::try_from(0x0440), Err(0x0440));
+        assert_eq!(SectionId::new(0x04, 0), None);
+        assert_eq!(SectionId::new(0x04, MAX_INDEX + 1), None);
+    }
+
+    #[test]
+    fn ids_round_trip_through_u16() {
+        for &(page, index) in &[(0x00, 1), (0x04, 0x3F), (0xFF, 1), (0xFF, MAX_INDEX)] {
+            let id = SectionId::new(page, index).unwrap();
+            let raw = u16::from(id);
+            assert_eq!(raw, u16::from(page) << 8 | u16::from(index));
+            assert_eq!(SectionId::try_from(raw), Ok(id));
+            assert_eq!((id.page(), id.index()), (page, index));
+            assert_eq!(id.to_string(), format!("{:04x}", raw));
+        }
+    }
+
+    #[test]
+    fn offsets_match_the_stock_geometry() {
+        let geometry = Geometry::default();
+        for &(page, index) in &[(0x04, 1), (0x05, 0x20), (0xFF, MAX_INDEX)] {
+            let id = SectionId::new(page, index).unwrap();
+            assert_eq!(id.offset(), geometry.block_offset(id));
+            assert_eq!(id.header_offset(), geometry.header_offset(id));
+            assert_eq!(
+                id.offset(),
+                page_offset(page) + u64::from(index) * u64::from(BLOCK_SIZE)
+            );
+            // Headers sit in block 0, before the section they describe.
+            assert!(id.header_offset() < page_offset(page) + u64::from(BLOCK_SIZE));
+            assert_eq!(id.header_offset() - page_offset(page), u64::from(index) * 4);
+        }
+        let top = SectionId::new(0xFF, MAX_INDEX).unwrap();
+        assert_eq!(top.offset() + u64::from(BLOCK_SIZE), 0x100 * 0x4000);
+    }
+}
@@ -0,0 +1,814 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use crate::model::{self, Hidden, Tree};
+use crate::{cache, cpio, long_path, tar, zip};
+
+/// What a member of a model directory is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File {
+        length: u64,
+    },
+    Directory,
+    Symlink,
+    /// Neither of those, such as a FIFO, or gone since it was listed.
+    Other,
+}
+
+/// A member of a model directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: OsString,
+    pub kind: Kind,
+}
+
+/// Somewhere a model is read from: a directory on the host, or an
+/// archive of one read into memory. Paths are `/` separated from the top
+/// of the model, which is `""`, as in `/bin/init`.
+pub trait KfsSource {
+    /// The members of the directory at `path`, in no particular order.
+    fn list(&self, path: &str) -> Result<Vec<Member>, Error>;
+
+    /// The contents of the file at `path`.
+    fn open(&self, path: &str) -> Result<Box<dyn Read>, Error>;
+
+    /// The target of the symlink at `path`.
+    fn read_link(&self, path: &str) -> Result<String, Error>;
+
+    /// Where the file at `path` is on the host, for sources whose files
+    /// can be read ahead on other threads.
+    fn host_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// How `path` is shown in messages.
+    fn display(&self, path: &str) -> String;
+
+    /// When the file at `path` was last modified, if that's known, for
+    /// the build cache.
+    fn mtime(&self, _path: &str) -> Result<Option<(u64, u32)>, Error> {
+        Ok(None)
+    }
+
+    /// Where the directory at `path` really is, for sources where
+    /// followed symlinks can loop back into their own ancestors.
+    fn real_path(&self, _path: &str) -> Result<Option<PathBuf>, Error> {
+        Ok(None)
+    }
+
+    /// Whether the directory at `path` holds anything `hidden` keeps but
+    /// (possibly nested) empty directories.
+    fn has_content(&self, path: &str, hidden: Hidden) -> Result<bool, Error> {
+        for member in self.list(path)? {
+            let name = member.name.to_string_lossy();
+            if !hidden.keeps(&name) {
+                continue;
+            }
+            let child = format!("{}/{}", path, name);
+            if member.kind != Kind::Directory || self.has_content(&child, hidden)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn not_found(path: &str, what: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{} is not {} in the model.", path, what),
+    )
+}
+
+/// A model directory on the host.
+#[derive(Debug, Clone)]
+pub struct Directory {
+    // The root as given, for messages, and as opened on the host.
+    shown: PathBuf,
+    root: PathBuf,
+    // Whether symlinks to directories are listed as the directories
+    // they point to.
+    follow: bool,
+    // Bytes files are read in, or `None` for `BufReader`'s default.
+    buffer_size: Option<usize>,
+    // Symlinks declared by a sidecar file, by their path from the top of
+    // the model, without the leading `/`.
+    symlinks: BTreeMap<String, String>,
+}
+
+impl Directory {
+    pub fn new(root: &Path, follow_dir_symlinks: bool) -> Directory {
+        Directory {
+            shown: root.to_path_buf(),
+            root: long_path::extended(root),
+            follow: follow_dir_symlinks,
+            buffer_size: None,
+            symlinks: BTreeMap::new(),
+        }
+    }
+
+    /// Reads files `size` bytes at a time, which can help on network
+    /// filesystems.
+    pub fn with_buffer_size(self, size: Option<usize>) -> Directory {
+        Directory {
+            buffer_size: size,
+            ..self
+        }
+    }
+
+    /// Treats each of `symlinks` (path from the top of the model, then
+    /// target) as a symlink, whatever is at its path on disk, if anything.
+    /// This is for models checked out where symlinks can't be made, as
+    /// git does on Windows: each is left a plain file holding its target.
+    pub fn with_symlinks(self, symlinks: BTreeMap<String, String>) -> Result<Directory, Error> {
+        for path in symlinks.keys() {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            if !self.host(parent).is_dir() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Symlink /{} is declared in {}, which is not a directory in the model.",
+                        path,
+                        self.display(parent)
+                    ),
+                ));
+            }
+        }
+        Ok(Directory { symlinks, ..self })
+    }
+
+    // Joined a part at a time, as an extended-length Windows path takes
+    // `/` literally.
+    fn host(&self, path: &str) -> PathBuf {
+        in_root(&self.root, path)
+    }
+}
+
+fn in_root(root: &Path, path: &str) -> PathBuf {
+    let mut host = root.to_path_buf();
+    host.extend(path.split('/').filter(|part| !part.is_empty()));
+    host
+}
+
+impl KfsSource for Directory {
+    fn list(&self, path: &str) -> Result<Vec<Member>, Error> {
+        let dir = path.trim_start_matches('/');
+        let declared = |name: &OsStr| match dir {
+            "" => name.to_string_lossy().into_owned(),
+            dir => format!("{}/{}", dir, name.to_string_lossy()),
+        };
+        let mut members = Vec::new();
+        for entry in fs::read_dir(self.host(path))? {
+            let entry = entry?;
+            let path = entry.path();
+            let symlink = self.symlinks.contains_key(&declared(&entry.file_name()))
+                || entry.file_type()?.is_symlink() && !(self.follow && path.is_dir());
+            let kind = if symlink {
+                Kind::Symlink
+            } else if path.is_dir() {
+                Kind::Directory
+            } else if path.is_file() {
+                Kind::File {
+                    length: entry.metadata()?.len(),
+                }
+            } else {
+                Kind::Other
+            };
+            members.push(Member {
+                name: entry.file_name(),
+                kind,
+            });
+        }
+        // Declared symlinks with nothing at their path at all.
+        for link in self.symlinks.keys() {
+            let (parent, name) = link.rsplit_once('/').unwrap_or(("", link));
+            if parent == dir && !members.iter().any(|member| member.name == *name) {
+                members.push(Member {
+                    name: OsString::from(name),
+                    kind: Kind::Symlink,
+                });
+            }
+        }
+        Ok(members)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn Read>, Error> {
+        let file = File::open(self.host(path))?;
+        Ok(match self.buffer_size {
+            Some(size) => Box::new(BufReader::with_capacity(size, file)),
+            None => Box::new(BufReader::new(file)),
+        })
+    }
+
+    fn host_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.host(path))
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, Error> {
+        if let Some(target) = self.symlinks.get(path.trim_start_matches('/')) {
+            return Ok(target.clone());
+        }
+        let target = fs::read_link(self.host(path))?;
+        // Kept as it is rather than resolved, so ../foo.c stays relative.
+        let target = target.to_str().map(str::to_string).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Filename {} does not contain valid UTF-8.",
+                    target.display()
+                ),
+            )
+        })?;
+        #[cfg(windows)]
+        let target = windows_target(&self.root, &self.display(path), &target)?;
+        Ok(target)
+    }
+
+    fn display(&self, path: &str) -> String {
+        in_root(&self.shown, path).display().to_string()
+    }
+
+    fn mtime(&self, path: &str) -> Result<Option<(u64, u32)>, Error> {
+        cache::mtime(&self.host(path))
+    }
+
+    fn real_path(&self, path: &str) -> Result<Option<PathBuf>, Error> {
+        match self.follow {
+            true => fs::canonicalize(self.host(path)).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    // Symlinks count as content without being followed, so one that
+    // loops can't loop here.
+    fn has_content(&self, path: &str, hidden: Hidden) -> Result<bool, Error> {
+        let dir = path.trim_start_matches('/');
+        let declared = self.symlinks.keys().any(|link| {
+            let inside = match dir {
+                "" => Some(link.as_str()),
+                dir => link
+                    .strip_prefix(dir)
+                    .and_then(|rest| rest.strip_prefix('/')),
+            };
+            inside.is_some_and(|inside| inside.split('/').all(|part| hidden.keeps(part)))
+        });
+        Ok(declared || model::dir_has_content(&self.host(path), hidden)?)
+    }
+}
+
+// Junctions, and symlinks some Windows tools make, hold absolute paths
+// such as \\?\C:\model\lib. One into the model is stored as the path
+// from its top, /lib; `\` separators become `/` either way.
+#[cfg(windows)]
+fn windows_target(root: &Path, link: &str, target: &str) -> Result<String, Error> {
+    let root = fs::canonicalize(root)?;
+    model_target(&root.to_string_lossy(), link, target)
+}
+
+// `windows_target`, given the canonical path of the model's top. Only
+// string handling, so it's tested on every platform.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn model_target(root: &str, link: &str, target: &str) -> Result<String, Error> {
+    fn verbatim(path: &str) -> &str {
+        path.strip_prefix(r"\\?\")
+            .or_else(|| path.strip_prefix(r"\??\"))
+            .unwrap_or(path)
+    }
+    // C:\..., or a share's \\server\...
+    fn is_absolute(path: &str) -> bool {
+        match path.as_bytes() {
+            [drive, b':', b'\\', ..] | [drive, b':', b'/', ..] => drive.is_ascii_alphabetic(),
+            [b'\\', b'\\', ..] => true,
+            _ => false,
+        }
+    }
+    let target = verbatim(target).replace('/', "\\");
+    if !is_absolute(&target) {
+        return Ok(target.replace('\\', "/"));
+    }
+    let root = verbatim(root).trim_end_matches('\\');
+    match (target.get(..root.len()), target.get(root.len()..)) {
+        (Some(prefix), Some(rest))
+            if prefix.eq_ignore_ascii_case(root) && (rest.is_empty() || rest.starts_with('\\')) =>
+        {
+            Ok(format!(
+                "/{}",
+                rest.trim_start_matches('\\').replace('\\', "/")
+            ))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} points outside the model, to {}.", link, target),
+        )),
+    }
+}
+
+/// Parses a file declaring symlinks, one per line as the link's path
+/// from the top of the model, `->`, then its target; blank lines and
+/// lines starting with `#` are skipped:
+///
+/// ```text
+/// bin/sh -> busybox
+/// lib/libc.so -> /lib/libc-1.0.so
+/// ```
+pub fn parse_symlinks(text: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut symlinks = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |what: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Line {} of the symlinks file {}.", i + 1, what),
+            )
+        };
+        let (path, target) = line
+            .split_once("->")
+            .ok_or_else(|| invalid("is not `path -> target`"))?;
+        let path = path.trim().replace('\\', "/");
+        let path = path.trim_matches('/');
+        let target = target.trim();
+        if path.is_empty() || target.is_empty() {
+            return Err(invalid("is not `path -> target`"));
+        }
+        if path.split('/').any(|part| matches!(part, "" | "." | "..")) {
+            return Err(invalid("has a path that isn't plainly inside the model"));
+        }
+        if symlinks
+            .insert(path.to_string(), target.to_string())
+            .is_some()
+        {
+            return Err(invalid(&format!("declares /{} again", path)));
+        }
+    }
+    Ok(symlinks)
+}
+
+pub fn read_symlinks(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    parse_symlinks(&fs::read_to_string(path)?)
+}
+
+/// A model held in memory, such as one read from an archive.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    // Shown before paths in messages.
+    name: String,
+    tree: BTreeMap<String, Tree>,
+}
+
+impl Memory {
+    /// A model of `tree`, shown as `name` in messages.
+    pub fn new(name: &str, tree: BTreeMap<String, Tree>) -> Memory {
+        Memory {
+            name: name.to_string(),
+            tree,
+        }
+    }
+
+    pub fn tree(&self) -> &BTreeMap<String, Tree> {
+        &self.tree
+    }
+
+    fn find(&self, path: &str) -> Option<&Tree> {
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        let mut node = self.tree.get(parts.next()?)?;
+        for part in parts {
+            node = match node {
+                Tree::Directory(children) => children.get(part)?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    fn children(&self, path: &str) -> Option<&BTreeMap<String, Tree>> {
+        if path.split('/').all(str::is_empty) {
+            return Some(&self.tree);
+        }
+        match self.find(path)? {
+            Tree::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+}
+
+impl KfsSource for Memory {
+    fn list(&self, path: &str) -> Result<Vec<Member>, Error> {
+        let children = self
+            .children(path)
+            .ok_or_else(|| not_found(path, "a directory"))?;
+        Ok(children
+            .iter()
+            .map(|(name, node)| Member {
+                name: name.into(),
+                kind: match node {
+                    Tree::Directory(_) => Kind::Directory,
+                    Tree::File(data) => Kind::File {
+                        length: data.len() as u64,
+                    },
+                    Tree::Symlink(_) => Kind::Symlink,
+                },
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn Read>, Error> {
+        match self.find(path) {
+            Some(Tree::File(data)) => Ok(Box::new(Cursor::new(data.clone()))),
+            _ => Err(not_found(path, "a file")),
+        }
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, Error> {
+        match self.find(path) {
+            Some(Tree::Symlink(target)) => Ok(target.clone()),
+            _ => Err(not_found(path, "a symlink")),
+        }
+    }
+
+    fn display(&self, path: &str) -> String {
+        format!("{}{}", self.name, path)
+    }
+}
+
+/// An in-memory model for tests. Unlike `Memory` it can hold anything a
+/// host directory can: names that aren't UTF-8, entries that can't be
+/// read, and symlinks to be followed into the directories they point to,
+/// loops included.
+#[derive(Debug, Clone, Default)]
+pub struct Vfs {
+    root: BTreeMap<OsString, Node>,
+    follow: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Directory(BTreeMap<OsString, Node>),
+    File(Vec<u8>),
+    Symlink(String),
+    // Listed, but denied when read.
+    Unreadable { directory: bool },
+    // Neither a file, a directory nor a symlink, like a FIFO.
+    Special,
+}
+
+// How many symlinks a path may go through, as Linux allows.
+const MAX_SYMLINKS: usize = 40;
+
+fn parts(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+}
+
+impl Vfs {
+    pub fn new() -> Vfs {
+        Vfs::default()
+    }
+
+    /// Lists symlinks to directories as the directories they point to,
+    /// as `Options::follow_dir_symlinks` does for a host directory.
+    pub fn follow_dir_symlinks(mut self, follow: bool) -> Vfs {
+        self.follow = follow;
+        self
+    }
+
+    pub fn dir(&mut self, path: &str) -> &mut Vfs {
+        self.insert(path, Node::Directory(BTreeMap::new()))
+    }
+
+    pub fn file(&mut self, path: &str, data: &[u8]) -> &mut Vfs {
+        self.insert(path, Node::File(data.to_vec()))
+    }
+
+    pub fn symlink(&mut self, path: &str, target: &str) -> &mut Vfs {
+        self.insert(path, Node::Symlink(target.to_string()))
+    }
+
+    /// A file, or with `directory` a directory, that can't be read.
+    pub fn unreadable(&mut self, path: &str, directory: bool) -> &mut Vfs {
+        self.insert(path, Node::Unreadable { directory })
+    }
+
+    /// Something neither a file, a directory nor a symlink, like a FIFO.
+    pub fn special(&mut self, path: &str) -> &mut Vfs {
+        self.insert(path, Node::Special)
+    }
+
+    /// A file named `name` in the directory at `dir`, for names that
+    /// aren't UTF-8.
+    pub fn file_named(&mut self, dir: &str, name: OsString, data: &[u8]) -> &mut Vfs {
+        self.dir(dir);
+        if let Some(Node::Directory(children)) = self.lookup_mut(dir) {
+            children.insert(name, Node::File(data.to_vec()));
+        }
+        self
+    }
+
+    fn lookup_mut(&mut self, path: &str) -> Option<&mut Node> {
+        let mut parts = parts(path);
+        let mut node = self.root.get_mut(OsStr::new(parts.next()?))?;
+        for part in parts {
+            node = match node {
+                Node::Directory(children) => children.get_mut(OsStr::new(part))?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    // Puts `node` at `path`, making the directories above it, and
+    // replacing whatever was there unless both are directories.
+    fn insert(&mut self, path: &str, node: Node) -> &mut Vfs {
+        let mut parts: Vec<&str> = parts(path).collect();
+        let name = parts.pop().expect("a path below the top of the model");
+        let mut dir = &mut self.root;
+        for part in parts {
+            let child = dir
+                .entry(part.into())
+                .or_insert_with(|| Node::Directory(BTreeMap::new()));
+            if !matches!(child, Node::Directory(_)) {
+                *child = Node::Directory(BTreeMap::new());
+            }
+            dir = match child {
+                Node::Directory(children) => children,
+                _ => unreachable!(),
+            };
+        }
+        match (dir.get(OsStr::new(name)), node) {
+            (Some(Node::Directory(_)), Node::Directory(_)) => {}
+            (_, node) => {
+                dir.insert(name.into(), node);
+            }
+        }
+        self
+    }
+
+    // Finds the node at `path` and the `/` separated path it really has,
+    // going through the symlinks on the way there, and through one at
+    // the end too if `follow_last`. The node is `None` for the root.
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<(String, Option<&Node>), Error> {
+        let mut pending: Vec<String> = parts(path).rev().map(str::to_string).collect();
+        let mut real: Vec<String> = Vec::new();
+        let mut node: Option<&Node> = None;
+        let mut links = 0;
+        while let Some(part) = pending.pop() {
+            if part == ".." {
+                real.pop();
+                node = None;
+                continue;
+            }
+            let dir = match node {
+                None => self.directory(&real)?,
+                Some(Node::Directory(children)) => children,
+                Some(_) => return Err(not_found(path, "a directory")),
+            };
+            let child = dir
+                .iter()
+                .find(|(name, _)| name.to_string_lossy() == part)
+                .map(|(_, child)| child)
+                .ok_or_else(|| not_found(path, "anything"))?;
+            match child {
+                Node::Symlink(target) if follow_last || !pending.is_empty() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Too many levels of symbolic links in {}.", path),
+                        ));
+                    }
+                    if target.starts_with('/') {
+                        real.clear();
+                    }
+                    pending.extend(parts(target).rev().map(str::to_string));
+                    node = None;
+                }
+                _ => {
+                    real.push(part);
+                    node = Some(child);
+                }
+            }
+        }
+        Ok((format!("/{}", real.join("/")), node))
+    }
+
+    // The children of the real directory at `real`.
+    fn directory(&self, real: &[String]) -> Result<&BTreeMap<OsString, Node>, Error> {
+        let mut dir = &self.root;
+        for part in real {
+            dir = match dir.iter().find(|(name, _)| name.to_string_lossy() == *part) {
+                Some((_, Node::Directory(children))) => children,
+                _ => return Err(not_found(part, "a directory")),
+            };
+        }
+        Ok(dir)
+    }
+
+    fn kind(&self, path: &str, node: &Node) -> Kind {
+        match node {
+            Node::Directory(_) | Node::Unreadable { directory: true } => Kind::Directory,
+            Node::File(data) => Kind::File {
+                length: data.len() as u64,
+            },
+            Node::Unreadable { directory: false } => Kind::File { length: 0 },
+            Node::Symlink(_) if self.follow => match self.resolve(path, true) {
+                Ok((_, None)) | Ok((_, Some(Node::Directory(_)))) => Kind::Directory,
+                _ => Kind::Symlink,
+            },
+            Node::Symlink(_) => Kind::Symlink,
+            Node::Special => Kind::Other,
+        }
+    }
+}
+
+fn denied(path: &str) -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        format!("{} can't be read", path),
+    )
+}
+
+// Whether `children` holds anything `hidden` keeps but (possibly nested)
+// empty directories, not following symlinks.
+fn vfs_has_content(children: &BTreeMap<OsString, Node>, hidden: Hidden) -> bool {
+    children
+        .iter()
+        .filter(|(name, _)| hidden.keeps(&name.to_string_lossy()))
+        .any(|(_, node)| match node {
+            Node::Directory(children) => vfs_has_content(children, hidden),
+            _ => true,
+        })
+}
+
+impl KfsSource for Vfs {
+    fn list(&self, path: &str) -> Result<Vec<Member>, Error> {
+        let children = match self.resolve(path, true)? {
+            (_, None) => &self.root,
+            (_, Some(Node::Directory(children))) => children,
+            (_, Some(Node::Unreadable { .. })) => return Err(denied(path)),
+            _ => return Err(not_found(path, "a directory")),
+        };
+        Ok(children
+            .iter()
+            .map(|(name, node)| Member {
+                name: name.clone(),
+                kind: self.kind(&format!("{}/{}", path, name.to_string_lossy()), node),
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn Read>, Error> {
+        match self.resolve(path, true)? {
+            (_, Some(Node::File(data))) => Ok(Box::new(Cursor::new(data.clone()))),
+            (_, Some(Node::Unreadable { .. })) => Err(denied(path)),
+            _ => Err(not_found(path, "a file")),
+        }
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, Error> {
+        match self.resolve(path, false)? {
+            (_, Some(Node::Symlink(target))) => Ok(target.clone()),
+            _ => Err(not_found(path, "a symlink")),
+        }
+    }
+
+    fn display(&self, path: &str) -> String {
+        path.to_string()
+    }
+
+    fn real_path(&self, path: &str) -> Result<Option<PathBuf>, Error> {
+        match self.follow {
+            true => Ok(Some(PathBuf::from(self.resolve(path, true)?.0))),
+            false => Ok(None),
+        }
+    }
+
+    fn has_content(&self, path: &str, hidden: Hidden) -> Result<bool, Error> {
+        match self.resolve(path, true)? {
+            (_, None) => Ok(vfs_has_content(&self.root, hidden)),
+            (_, Some(Node::Directory(children))) => Ok(vfs_has_content(children, hidden)),
+            (_, Some(Node::Unreadable { .. })) => Err(denied(path)),
+            _ => Err(not_found(path, "a directory")),
+        }
+    }
+}
+
+/// Calls `visit` with the path and member of everything in the model,
+/// each directory before what it holds, and goes into the directories
+/// it returns true for. A directory a followed symlink leads back to
+/// isn't gone into again.
+pub fn walk<F>(source: &dyn KfsSource, visit: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&str, &Member) -> Result<bool, Error>,
+{
+    let mut ancestors = Vec::new();
+    walk_dir(source, "", &mut ancestors, visit)
+}
+
+fn walk_dir<F>(
+    source: &dyn KfsSource,
+    dir: &str,
+    ancestors: &mut Vec<PathBuf>,
+    visit: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(&str, &Member) -> Result<bool, Error>,
+{
+    let real = source.real_path(dir)?;
+    if let Some(real) = &real {
+        if ancestors.contains(real) {
+            return Ok(());
+        }
+        ancestors.push(real.clone());
+    }
+    for member in source.list(dir)? {
+        let path = format!("{}/{}", dir, member.name.to_string_lossy());
+        if visit(&path, &member)? && member.kind == Kind::Directory {
+            walk_dir(source, &path, ancestors, visit)?;
+        }
+    }
+    if real.is_some() {
+        ancestors.pop();
+    }
+    Ok(())
+}
+
+/// The source of the model at `model`: a directory, a cpio, tar or zip
+/// archive of one, or `-` for a tar stream on stdin. Files of a
+/// directory are read `buffer_size` bytes at a time, if given, and the
+/// file `symlinks` can declare symlinks in it, as `parse_symlinks` reads.
+pub fn open_model(
+    model: &Path,
+    follow_dir_symlinks: bool,
+    buffer_size: Option<usize>,
+    symlinks: Option<&Path>,
+) -> Result<Box<dyn KfsSource>, Error> {
+    if model.is_dir() {
+        let mut directory =
+            Directory::new(model, follow_dir_symlinks).with_buffer_size(buffer_size);
+        if let Some(symlinks) = symlinks {
+            directory = directory.with_symlinks(read_symlinks(symlinks)?)?;
+        }
+        return Ok(Box::new(directory));
+    }
+    if symlinks.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Error: --symlinks only applies to model directories; archives hold their own symlinks.",
+        ));
+    }
+    let tree = if model == Path::new("-") {
+        tar::read_tar(&mut io::stdin().lock())?
+    } else {
+        let data = fs::read(model)?;
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            zip::read_zip(&data)?
+        } else if data.get(257..262) == Some(b"ustar") {
+            tar::read_tar(&mut data.as_slice())?
+        } else {
+            cpio::read_cpio(&mut data.as_slice())?
+        }
+    };
+    Ok(Box::new(Memory::new(&model.display().to_string(), tree)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_windows_targets_get_forward_slashes() {
+        let target = model_target(r"C:\model", "/bin/sh", r"..\lib\busybox").unwrap();
+        assert_eq!(target, "../lib/busybox");
+    }
+
+    #[test]
+    fn absolute_windows_targets_into_the_model_are_made_model_absolute() {
+        for (root, target) in &[
+            (r"\\?\C:\model", r"\\?\C:\model\lib\libc.so"),
+            (r"C:\model\", r"\??\C:\MODEL\lib\libc.so"),
+            (r"\\?\C:\model", "C:/model/lib/libc.so"),
+        ] {
+            let resolved = model_target(root, "/bin/sh", target).unwrap();
+            assert_eq!(resolved, "/lib/libc.so", "{} in {}", target, root);
+        }
+        assert_eq!(
+            model_target(r"\\?\C:\model", "/top", r"C:\model").unwrap(),
+            "/"
+        );
+    }
+
+    #[test]
+    fn absolute_windows_targets_outside_the_model_are_refused() {
+        for target in &[r"C:\models\lib", r"D:\model\lib", r"\\server\share\lib"] {
+            let err = model_target(r"\\?\C:\model", "/bin/sh", target).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("points outside the model"));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! The KFS on-disk format, as constants and the small helpers that
+//! place things in it, so other KnightOS tooling (kernel tests,
+//! emulators) can share them with `regenkfs`.
+//!
+//! A KFS filesystem lives in flash pages of the stock geometry,
+//! `PAGE_LENGTH` bytes each, between the kernel's pages and the FAT:
+//!
+//! * The first data page starts with `MAGIC` and a version byte
+//!   (`version_byte`), in the header slot of block 0, which holds no
+//!   data.
+//! * Each data page's block 0 holds a four-byte section header per block
+//!   of the page, at `SECTION_HEADER_LENGTH * index`: the previous and
+//!   next section id of the chain (pSID, nSID) as little-endian words,
+//!   `KFS_NO_SECTION` ending it. Blocks 1 up to
+//!   `section::MAX_INDEX` hold data.
+//! * The FAT runs down from the top of its first page, `fat_end`. Each
+//!   entry is stored byte-reversed, so reading down from the top gives
+//!   the id, then the 16-bit length of the rest, then the fields below.
+//!
+//! Flash erases to ones, so 0xFF everywhere is blank.
+
+/// Bytes in a flash page of the stock geometry.
+pub const PAGE_LENGTH: u16 = 0x4000;
+/// Bytes in a data block, the unit a page's sections are made of.
+pub const BLOCK_SIZE: u16 = 0x100;
+
+/// Id, the first byte stored, of each kind of FAT entry.
+pub const KFS_FILE_ID: u8 = 0x7F;
+pub const KFS_DIR_ID: u8 = 0xBF;
+pub const KFS_SYM_ID: u8 = 0xDF;
+
+/// The filesystem version `regenkfs` writes and reads.
+pub const KFS_VERSION: u8 = 0x0;
+
+/// The largest file, whose length must fit in 24 bits.
+pub const KFS_MAX_FILE_LEN: u64 = 0xFFFFFF;
+
+/// Section id of no section, as erased flash reads: the nSID ending a
+/// chain, and the section id of an empty file, which has no data.
+pub const KFS_NO_SECTION: u16 = 0xFFFF;
+
+/// Entry flag bits. Flash erases to ones, so a flag is set by clearing
+/// its bit and 0xFF is a plain entry.
+pub const KFS_FLAG_EXECUTABLE: u8 = 0x01;
+pub const KFS_FLAG_READ_ONLY: u8 = 0x02;
+pub const KFS_FLAG_HIDDEN: u8 = 0x04;
+
+/// Experimental, and unknown to the kernel: a file entry's name is
+/// followed by a CRC-32 of the file's data, `ENTRY_CHECKSUM_LENGTH`
+/// bytes little-endian, counted in the entry's length so readers that
+/// don't know the flag skip it.
+pub const KFS_FLAG_CHECKSUM: u8 = 0x80;
+
+/// Experimental, and unknown to the kernel: a file's data is stored
+/// compressed, as `compress::compress` describes, and its entry's
+/// length is that of the compressed data.
+pub const KFS_FLAG_COMPRESSED: u8 = 0x40;
+
+/// Bytes of the checksum `KFS_FLAG_CHECKSUM` adds to an entry.
+pub const ENTRY_CHECKSUM_LENGTH: usize = 4;
+
+/// Whether `flag` is set in an entry's `flags` byte.
+pub fn has_flag(flags: u8, flag: u8) -> bool {
+    flags & flag == 0
+}
+
+/// Number of pages at the top of the filesystem reserved for the FAT.
+pub const FAT_PAGES: u8 = 4;
+
+/// Distance from a FAT byte to its copy when the FAT is mirrored.
+pub const MIRROR_OFFSET: u32 = FAT_PAGES as u32 * PAGE_LENGTH as u32;
+
+/// Starts the first data page, followed by `version_byte`.
+pub const MAGIC: &[u8; 3] = b"KFS";
+
+/// The byte after `MAGIC` for filesystem `version`: ones with the low
+/// `version` bits cleared, so a newer version is written over an older
+/// one without an erase.
+pub fn version_byte(version: u8) -> u8 {
+    0xFF << version
+}
+
+/// The version a byte after `MAGIC` says, if it's one `version_byte`
+/// makes.
+pub fn version_of(byte: u8) -> Option<u8> {
+    (0..8).find(|&version| version_byte(version) == byte)
+}
+
+/// Starts the integrity page `--integrity-page` adds, an extension
+/// `integrity::Integrity` describes. Unlike `MAGIC`, so tools that don't
+/// know it see a page that isn't part of the filesystem.
+pub const INTEGRITY_MAGIC: &[u8; 4] = b"KSUM";
+
+/// Bytes of a section header: the pSID and nSID words.
+pub const SECTION_HEADER_LENGTH: u16 = 4;
+
+/// A section id: the page in the high byte, the block in the low one.
+pub fn section_id(page: u8, index: u8) -> u16 {
+    u16::from_le_bytes([index, page])
+}
+
+/// Offset in its page of the header of the section in block `index`.
+pub fn section_header_offset(index: u8) -> u16 {
+    u16::from(index) * SECTION_HEADER_LENGTH
+}
+
+/// Bytes before an entry's fields: its id and the 16-bit length of the
+/// rest.
+pub const ENTRY_HEADER_LENGTH: usize = 3;
+
+/// Offsets of the fields of each kind of entry, in file order (read
+/// down from the top of the entry), and the length of the shortest one,
+/// whose names are empty.
+pub mod entry {
+    /// Every entry's parent directory id comes right after the header.
+    pub const PARENT: usize = 3;
+
+    pub const FILE_FLAGS: usize = 5;
+    /// The low 24 bits of the length, little-endian.
+    pub const FILE_LENGTH: usize = 6;
+    pub const FILE_SECTION_ID: usize = 9;
+    pub const FILE_NAME: usize = 11;
+    pub const FILE_MIN_LENGTH: usize = 12;
+
+    pub const DIR_ID: usize = 5;
+    pub const DIR_FLAGS: usize = 7;
+    pub const DIR_NAME: usize = 8;
+    pub const DIR_MIN_LENGTH: usize = 9;
+
+    /// Offset of the target from the start of the name, which is the
+    /// name's length plus its NUL.
+    pub const SYM_TARGET_OFFSET: usize = 5;
+    pub const SYM_NAME: usize = 6;
+    pub const SYM_MIN_LENGTH: usize = 8;
+}
+
+/// The length of an encoded entry with a name of `name_length` bytes,
+/// and for a symlink a target of `target_length`, including the header.
+pub fn entry_length(id: u8, name_length: usize, target_length: usize) -> Option<usize> {
+    match id {
+        KFS_FILE_ID => Some(entry::FILE_NAME + name_length + 1),
+        KFS_DIR_ID => Some(entry::DIR_NAME + name_length + 1),
+        KFS_SYM_ID => Some(entry::SYM_NAME + name_length + 1 + target_length + 1),
+        _ => None,
+    }
+}
@@ -0,0 +1,96 @@
+//! Reads (and, for `--cache`, hashes) the files of a model directory on
+//! several threads ahead of the build, which then writes them in order
+//! from memory.
+use std::collections::BTreeMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::sha256::sha256;
+
+/// A file read ahead of the build.
+#[derive(Debug, Clone)]
+pub struct Staged {
+    pub data: Rc<[u8]>,
+    /// Its SHA-256, if it was asked for.
+    pub sha256: Option<[u8; 32]>,
+}
+
+/// Threads to use for `threads`, or `None` for one per core.
+pub fn thread_count(threads: Option<NonZeroUsize>) -> usize {
+    threads
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, |n| n.get())
+}
+
+/// Reads each `(path, host)` file on up to `threads` threads, hashing
+/// them too with `hash`, keyed by `path`. Files that can't be read are
+/// left out, for the build to read itself and report.
+pub fn read_all(
+    files: &[(String, PathBuf)],
+    threads: usize,
+    hash: bool,
+) -> BTreeMap<String, Staged> {
+    let next = AtomicUsize::new(0);
+    let read = Mutex::new(Vec::with_capacity(files.len()));
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()) {
+            scope.spawn(|| {
+                while let Some((path, host)) = files.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    if let Ok(data) = fs::read(host) {
+                        let digest = hash.then(|| sha256(&data));
+                        read.lock().unwrap().push((path.clone(), data, digest));
+                    }
+                }
+            });
+        }
+    });
+    read.into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|(path, data, sha256)| {
+            let data = data.into();
+            (path, Staged { data, sha256 })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn every_readable_file_is_read_whatever_the_thread_count() {
+        let dir = env::temp_dir().join(format!("regenkfs-stage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let host = dir.join(format!("file{}", i));
+            fs::write(&host, vec![i as u8; i * 100]).unwrap();
+            files.push((format!("/file{}", i), host));
+        }
+        files.push(("/missing".to_string(), dir.join("missing")));
+        for &threads in &[1, 3, 64] {
+            let staged = read_all(&files, threads, true);
+            assert_eq!(staged.len(), 20, "with {} threads", threads);
+            assert!(!staged.contains_key("/missing"));
+            let file = &staged["/file7"];
+            assert_eq!(&file.data[..], &vec![7; 700][..]);
+            assert_eq!(file.sha256, Some(sha256(&vec![7; 700])));
+        }
+        assert_eq!(read_all(&files, 2, false)["/file3"].sha256, None);
+        assert!(read_all(&[], 4, false).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn one_thread_per_core_by_default() {
+        assert_eq!(thread_count(NonZeroUsize::new(3)), 3);
+        assert!(thread_count(None) >= 1);
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::io::{Error, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::fat::Entry;
+use crate::image::Image;
+use crate::json;
+use crate::scan::AllocationMap;
+use crate::Geometry;
+
+/// How `--stats-format` prints a build's metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// One JSON object.
+    Json,
+    /// The Prometheus text exposition format, as the node exporter's
+    /// textfile collector reads it.
+    Prometheus,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatsFormat, String> {
+        match s {
+            "json" => Ok(StatsFormat::Json),
+            "prometheus" => Ok(StatsFormat::Prometheus),
+            _ => Err(format!(
+                "Unknown stats format {} (expected json or prometheus).",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StatsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StatsFormat::Json => "json",
+            StatsFormat::Prometheus => "prometheus",
+        })
+    }
+}
+
+/// Metrics of a finished build, for tracking how an image grows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Bytes of file contents.
+    pub bytes: u64,
+    /// Data pages holding at least one block in use, out of
+    /// `data_pages`.
+    pub data_pages_used: usize,
+    pub data_pages: usize,
+    /// Bytes of data blocks still free.
+    pub free_bytes: u64,
+    /// Bytes of FAT in use and still free.
+    pub fat_bytes_used: u32,
+    pub fat_bytes_free: u32,
+    /// How long the build took.
+    pub duration: Duration,
+}
+
+impl Stats {
+    pub fn collect(
+        image: &Image,
+        map: &AllocationMap,
+        geometry: Geometry,
+        fat_start: u8,
+        duration: Duration,
+    ) -> Stats {
+        let mut stats = Stats {
+            files: 0,
+            directories: 0,
+            symlinks: 0,
+            bytes: 0,
+            data_pages_used: map
+                .pages()
+                .filter(|&page| map.page_map().used_in(page) > 0)
+                .count(),
+            data_pages: map.pages().len(),
+            free_bytes: (map.free_blocks() * usize::from(geometry.block_size())) as u64,
+            fat_bytes_used: geometry.fat_end(fat_start) - map.fat_ptr(),
+            fat_bytes_free: map.fat_free(),
+            duration,
+        };
+        for record in image.records() {
+            match record.entry {
+                Entry::File { length, .. } => {
+                    stats.files += 1;
+                    stats.bytes += u64::from(length);
+                }
+                Entry::Directory { .. } => stats.directories += 1,
+                Entry::Symlink { .. } => stats.symlinks += 1,
+            }
+        }
+        stats
+    }
+
+    // Name, help and value of each metric, in the order printed.
+    fn metrics(&self) -> Vec<(&'static str, &'static str, json::Value)> {
+        vec![
+            ("files", "Files in the filesystem.", self.files.into()),
+            (
+                "directories",
+                "Directories in the filesystem.",
+                self.directories.into(),
+            ),
+            (
+                "symlinks",
+                "Symlinks in the filesystem.",
+                self.symlinks.into(),
+            ),
+            ("bytes", "Bytes of file contents.", self.bytes.into()),
+            (
+                "data_pages_used",
+                "Data pages holding any block in use.",
+                self.data_pages_used.into(),
+            ),
+            (
+                "data_pages",
+                "Data pages of the filesystem.",
+                self.data_pages.into(),
+            ),
+            (
+                "free_bytes",
+                "Bytes of data blocks still free.",
+                self.free_bytes.into(),
+            ),
+            (
+                "fat_bytes_used",
+                "Bytes of FAT in use.",
+                self.fat_bytes_used.into(),
+            ),
+            (
+                "fat_bytes_free",
+                "Bytes of FAT still free.",
+                self.fat_bytes_free.into(),
+            ),
+            (
+                "duration_seconds",
+                "How long the build took.",
+                self.duration.as_secs_f64().into(),
+            ),
+        ]
+    }
+}
+
+/// Writes `stats` in `format`. Prometheus metrics are gauges prefixed
+/// with `regenkfs_`.
+pub fn write_stats<W: Write>(out: &mut W, stats: &Stats, format: StatsFormat) -> Result<(), Error> {
+    let metrics = stats.metrics();
+    match format {
+        StatsFormat::Json => {
+            let pairs = metrics
+                .into_iter()
+                .map(|(name, _, value)| (name, value))
+                .collect();
+            writeln!(out, "{}", json::Value::object(pairs))
+        }
+        StatsFormat::Prometheus => {
+            for (name, help, value) in metrics {
+                writeln!(out, "# HELP regenkfs_{} {}", name, help)?;
+                writeln!(out, "# TYPE regenkfs_{} gauge", name)?;
+                writeln!(out, "regenkfs_{} {}", name, value)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{insert, Tree};
+    use crate::source::Memory;
+    use crate::{Context, Options, PAGE_LENGTH};
+    use std::collections::BTreeMap;
+    use std::io::{Cursor, ErrorKind};
+    use std::path::PathBuf;
+
+    fn stats() -> Stats {
+        Stats {
+            files: 3,
+            directories: 1,
+            symlinks: 0,
+            bytes: 1350,
+            data_pages_used: 1,
+            data_pages: 24,
+            free_bytes: 98_304,
+            fat_bytes_used: 40,
+            fat_bytes_free: 16_344,
+            duration: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn formats_parse() {
+        for format in &[StatsFormat::Json, StatsFormat::Prometheus] {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+        assert_eq!(
+            "csv".parse::<StatsFormat>(),
+            Err("Unknown stats format csv (expected json or prometheus).".to_string())
+        );
+    }
+
+    #[test]
+    fn json_is_one_object() {
+        let mut out = Vec::new();
+        write_stats(&mut out, &stats(), StatsFormat::Json).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(
+            out.starts_with("{\"files\":3,\"directories\":1,"),
+            "{}",
+            out
+        );
+        assert!(out.contains("\"fat_bytes_free\":16344,"), "{}", out);
+        assert!(out.ends_with("\"duration_seconds\":1.5}\n"), "{}", out);
+    }
+
+    #[test]
+    fn prometheus_metrics_are_gauges() {
+        let mut out = Vec::new();
+        write_stats(&mut out, &stats(), StatsFormat::Prometheus).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3 * 10);
+        assert_eq!(
+            lines[..3],
+            [
+                "# HELP regenkfs_files Files in the filesystem.",
+                "# TYPE regenkfs_files gauge",
+                "regenkfs_files 3",
+            ]
+        );
+        assert!(out.contains("\nregenkfs_free_bytes 98304\n"));
+        assert!(out.ends_with("\nregenkfs_duration_seconds 1.5\n"));
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        // Too short for either format.
+        let mut full = [0; 16];
+        for &format in &[StatsFormat::Json, StatsFormat::Prometheus] {
+            let err = write_stats(&mut &mut full[..], &stats(), format).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WriteZero);
+        }
+    }
+
+    #[test]
+    fn stats_are_collected_from_the_image() {
+        let mut tree = BTreeMap::new();
+        insert(&mut tree, "var/log", Tree::File(vec![1; 1000])).unwrap();
+        insert(&mut tree, "var/tmp/a", Tree::File(vec![2; 300])).unwrap();
+        insert(&mut tree, "link", Tree::Symlink("var/log".to_string())).unwrap();
+        let mut context = Context::with_source(
+            PathBuf::from("<memory>"),
+            Box::new(Memory::new("<memory>", tree)),
+            Cursor::new(vec![0xFF; 32 * usize::from(PAGE_LENGTH)]),
+            Options {
+                quiet: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        context.run().unwrap();
+        let (geometry, fat_start) = (context.layout().geometry, context.fat_start());
+        let pages = context.data_pages().len();
+        let rom = context.into_output().unwrap().into_inner();
+        let image = Image::open(&rom).unwrap();
+        let map = AllocationMap::scan_with(&rom, geometry, image.dat_start, fat_start).unwrap();
+        let stats = Stats::collect(&image, &map, geometry, fat_start, Duration::from_secs(2));
+        assert_eq!((stats.files, stats.directories, stats.symlinks), (2, 2, 1));
+        assert_eq!(stats.bytes, 1300);
+        assert_eq!((stats.data_pages_used, stats.data_pages), (1, pages));
+        let blocks = pages * usize::from(geometry.max_index()) - 6;
+        assert_eq!(
+            stats.free_bytes,
+            (blocks * usize::from(geometry.block_size())) as u64
+        );
+        assert_eq!(
+            stats.fat_bytes_used + stats.fat_bytes_free,
+            u32::from(geometry.fat_pages()) * u32::from(geometry.page_length())
+        );
+        assert_eq!(stats.duration, Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::extract::{mode, Permissions, Sink};
+use crate::model::{insert, Tree};
+
+/// Writes extracted entries as a ustar archive.
+pub struct TarSink<W: Write> {
+    out: W,
+    permissions: Permissions,
+}
+
+impl<W: Write> TarSink<W> {
+    pub fn new(out: W, permissions: Permissions) -> TarSink<W> {
+        TarSink { out, permissions }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn header(
+        &mut self,
+        path: &str,
+        mode: u32,
+        size: usize,
+        kind: u8,
+        link: &str,
+    ) -> Result<(), Error> {
+        let too_long = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is too long for a tar header.", path),
+            )
+        };
+        // Names over 100 bytes are split into a prefix at a `/`.
+        let (prefix, name) = if path.len() <= 100 {
+            ("", path)
+        } else {
+            let split = path[..path.len().min(156)]
+                .rfind('/')
+                .filter(|&i| path.len() - i - 1 <= 100)
+                .ok_or_else(too_long)?;
+            (&path[..split], &path[split + 1..])
+        };
+        if link.len() > 100 {
+            return Err(too_long());
+        }
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        // A fixed mtime keeps archives of the same image identical.
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = kind;
+        header[157..][..link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..][..prefix.len()].copy_from_slice(prefix.as_bytes());
+        // The checksum is computed with its own field set to spaces.
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        self.out.write_all(&header)
+    }
+}
+
+impl<W: Write> Sink for TarSink<W> {
+    fn directory(&mut self, path: &str, flags: u8) -> Result<(), Error> {
+        let mode = mode(self.permissions, flags, true);
+        self.header(&format!("{}/", path), mode, 0, b'5', "")
+    }
+
+    fn file(&mut self, path: &str, flags: u8, data: &[u8]) -> Result<(), Error> {
+        let mode = mode(self.permissions, flags, false);
+        self.header(path, mode, data.len(), b'0', "")?;
+        self.out.write_all(data)?;
+        let padding = (512 - data.len() % 512) % 512;
+        self.out.write_all(&[0; 512][..padding])
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), Error> {
+        self.header(path, 0o777, 0, b'2', target)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.out.write_all(&[0; 1024])?;
+        self.out.flush()
+    }
+}
+
+fn invalid(what: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid tar archive: {}.", what),
+    )
+}
+
+// A NUL-terminated header field.
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// A numeric field: octal, or base-256 big-endian (a GNU extension for
+// large sizes) when its high bit is set.
+fn number(field: &[u8]) -> Result<u64, Error> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7F), |n, &b| (n << 8) | u64::from(b)));
+    }
+    let digits = text(field);
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid(format!("bad header field {:?}", digits)))
+}
+
+// The data of a member, skipping the padding to the next header.
+fn data<R: Read>(input: &mut R, size: u64) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    input.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(invalid("archive ends inside a member".to_string()));
+    }
+    let padding = ((512 - size % 512) % 512) as usize;
+    input.read_exact(&mut [0; 512][..padding])?;
+    Ok(data)
+}
+
+// The `path` and `linkpath` records of a pax extended header.
+fn pax(records: &[u8]) -> (Option<String>, Option<String>) {
+    let (mut path, mut link) = (None, None);
+    let mut rest = records;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let len: usize = match std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+        {
+            // Past the space and the newline that ends the record.
+            Some(len) if len > space + 1 && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            match key {
+                "path" => path = Some(value.to_string()),
+                "linkpath" => link = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        rest = &rest[len..];
+    }
+    (path, link)
+}
+
+fn find<'t>(root: &'t BTreeMap<String, Tree>, path: &str) -> Option<&'t Tree> {
+    let mut parts = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".");
+    let mut node = root.get(parts.next()?)?;
+    for part in parts {
+        node = match node {
+            Tree::Directory(children) => children.get(part)?,
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// Reads a tar archive (ustar, with the GNU and pax extensions for long
+/// names) into a model tree. Hard links become copies of the file they
+/// link to.
+pub fn read_tar<R: Read>(input: &mut R) -> Result<BTreeMap<String, Tree>, Error> {
+    let mut root = BTreeMap::new();
+    // Long names given by the member before the one they belong to.
+    let (mut long_name, mut long_link) = (None, None);
+    loop {
+        let mut header = [0u8; 512];
+        let mut read = 0;
+        while read < header.len() {
+            match input.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        match read {
+            // Some writers leave out the two zero blocks at the end.
+            0 => return Ok(root),
+            512 => {}
+            _ => return Err(invalid("archive ends inside a header".to_string())),
+        }
+        if header.iter().all(|&b| b == 0) {
+            return Ok(root);
+        }
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        if number(&header[148..156])? != sum {
+            return Err(invalid("bad header checksum".to_string()));
+        }
+        let size = number(&header[124..136])?;
+        let mut name = text(&header[..100]);
+        // Only POSIX ustar keeps a name prefix here; GNU puts times there.
+        if &header[257..263] == b"ustar\0" {
+            let prefix = text(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        let name = long_name.take().unwrap_or(name);
+        let link = long_link.take().unwrap_or_else(|| text(&header[157..257]));
+        let data = data(input, size)?;
+        let node = match header[156] {
+            b'0' | b'\0' | b'7' => Tree::File(data),
+            b'5' => Tree::Directory(BTreeMap::new()),
+            b'2' => Tree::Symlink(link),
+            b'1' => match find(&root, &link) {
+                Some(Tree::File(data)) => Tree::File(data.clone()),
+                _ => {
+                    return Err(invalid(format!(
+                        "{} links to {}, which is not a file",
+                        name, link
+                    )))
+                }
+            },
+            b'L' => {
+                long_name = Some(text(&data));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(text(&data));
+                continue;
+            }
+            b'x' => {
+                let (path, link) = pax(&data);
+                long_name = path;
+                long_link = link;
+                continue;
+            }
+            // Global pax headers hold nothing a model needs.
+            b'g' => continue,
+            _ => return Err(invalid(format!("{} is a special file", name))),
+        };
+        insert(&mut root, &name, node)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut sink = TarSink::new(Vec::new(), Permissions::None);
+        sink.directory("bin", 0xFF).unwrap();
+        sink.file("bin/init", 0xFF, b"init").unwrap();
+        sink.symlink("init", "bin/init").unwrap();
+        // Split into a ustar prefix and name.
+        let long = format!("{}/{}", "d".repeat(60), "f".repeat(60));
+        sink.file(&long, 0xFF, &[0x55; 513]).unwrap();
+        sink.finish().unwrap();
+        sink.into_inner()
+    }
+
+    // Sets a header's checksum to match its contents.
+    fn seal(header: &mut [u8]) {
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header[..512].iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let root = read_tar(&mut &archive()[..]).unwrap();
+        let mut expected = BTreeMap::new();
+        insert(&mut expected, "bin/init", Tree::File(b"init".to_vec())).unwrap();
+        insert(&mut expected, "init", Tree::Symlink("bin/init".to_string())).unwrap();
+        let long = format!("{}/{}", "d".repeat(60), "f".repeat(60));
+        insert(&mut expected, &long, Tree::File(vec![0x55; 513])).unwrap();
+        assert_eq!(root, expected);
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let archive = archive();
+        assert!(read_tar(&mut &archive[..300]).is_err());
+        // Inside the long file's data.
+        let cut = archive.len() - 1024 - 512 - 100;
+        assert!(read_tar(&mut &archive[..cut]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut archive = archive();
+        archive[0] ^= 1;
+        assert!(read_tar(&mut &archive[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_end() {
+        let mut archive = archive();
+        let init = 512;
+        // The file's size in base-256, far more than the archive holds.
+        archive[init + 124..init + 136]
+            .copy_from_slice(&[0x80, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+        seal(&mut archive[init..]);
+        assert!(read_tar(&mut &archive[..]).is_err());
+        archive[init + 124..init + 136].copy_from_slice(b"77777777777\0");
+        seal(&mut archive[init..]);
+        assert!(read_tar(&mut &archive[..]).is_err());
+    }
+
+    #[test]
+    fn skips_malformed_pax_records() {
+        assert_eq!(pax(b"2 x"), (None, None));
+        assert_eq!(pax(b"99 path=a\n"), (None, None));
+        assert_eq!(pax(b"11 path=ab\n"), (Some("ab".to_string()), None));
+    }
+}
@@ -0,0 +1,266 @@
+use std::io::{Error, ErrorKind};
+
+/// A value in the subset of TOML that configuration files use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// A `[table]` and its keys, in file order. Keys before the first
+/// header belong to a table named "".
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Table {
+    pub name: String,
+    pub entries: Vec<(String, Value)>,
+}
+
+fn error(line: usize, what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Line {}: {}.", line, what))
+}
+
+/// An error for `key` of `table` holding the wrong kind of value.
+/// Keys of the top-level table are named on their own.
+pub fn expected(table: &str, key: &str, what: &str, value: &Value) -> Error {
+    let key = match table {
+        "" => key.to_string(),
+        _ => format!("[{}] {}", table, key),
+    };
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} should be {}, not {}.", key, what, value.describe()),
+    )
+}
+
+// Strips a trailing comment, leaving `#` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(text: &str, line: usize) -> Result<(String, &str), Error> {
+    let mut out = String::new();
+    let mut chars = text[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &text[i + 2..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, c @ '"')) | Some((_, c @ '\\')) => out.push(c),
+                _ => return Err(error(line, "unknown escape in string")),
+            },
+            c => out.push(c),
+        }
+    }
+    Err(error(line, "unterminated string"))
+}
+
+fn parse_integer(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(octal) = digits.strip_prefix("0o") {
+        i64::from_str_radix(octal, 8).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+// Parses a value at the start of `text`, returning it and the rest.
+fn parse_value(text: &str, line: usize) -> Result<(Value, &str), Error> {
+    let text = text.trim_start();
+    if text.starts_with('"') {
+        let (s, rest) = parse_string(text, line)?;
+        return Ok((Value::String(s), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest, line)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err(error(line, "expected , or ] in array"));
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::Integer(parse_integer(word).ok_or_else(|| error(line, "invalid value"))?),
+    };
+    Ok((value, rest))
+}
+
+fn parse_key(text: &str, line: usize) -> Result<String, Error> {
+    let text = text.trim();
+    if text.starts_with('"') {
+        let (key, rest) = parse_string(text, line)?;
+        if !rest.trim().is_empty() {
+            return Err(error(line, "unexpected text after key"));
+        }
+        return Ok(key);
+    }
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return Err(error(line, "invalid key"));
+    }
+    Ok(text.to_string())
+}
+
+/// Parses tables of `key = value` pairs. Table names may be bare or
+/// quoted, so `[/bin/init]` and `["/bin/init"]` are the same table.
+/// Nested tables, inline tables, dates and floats are not supported.
+pub fn parse(text: &str) -> Result<Vec<Table>, Error> {
+    let mut tables = vec![Table::default()];
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .ok_or_else(|| error(line_no, "unterminated table header"))?;
+            tables.push(Table {
+                name: parse_key(name, line_no)?,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(line_no, "expected key = value"))?;
+        let key = parse_key(key, line_no)?;
+        let (value, rest) = parse_value(value, line_no)?;
+        if !rest.trim().is_empty() {
+            return Err(error(line_no, "unexpected text after value"));
+        }
+        let table = tables.last_mut().unwrap();
+        if table.entries.iter().any(|(k, _)| *k == key) {
+            return Err(error(line_no, &format!("duplicate key {}", key)));
+        }
+        table.entries.push((key, value));
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Result<Value, Error> {
+        let tables = parse(&format!("key = {}", text))?;
+        Ok(tables[0].entries[0].1.clone())
+    }
+
+    fn fails(text: &str, message: &str) {
+        let err = parse(text).unwrap_err();
+        assert_eq!(err.to_string(), message, "parsing {:?}", text);
+    }
+
+    #[test]
+    fn reads_escapes_in_strings() {
+        assert_eq!(
+            value(r#""a\tb\nc \"d\" \\ # not a comment""#).unwrap(),
+            Value::String("a\tb\nc \"d\" \\ # not a comment".to_string())
+        );
+        fails(r#"key = "\q""#, "Line 1: unknown escape in string.");
+        fails(r#"key = "open"#, "Line 1: unterminated string.");
+    }
+
+    #[test]
+    fn reads_integers_in_every_base() {
+        for (text, n) in [
+            ("0x3b", 0x3b),
+            ("0o17", 0o17),
+            ("0b101", 5),
+            ("1_000", 1000),
+            ("-4", -4),
+            ("+4", 4),
+        ] {
+            assert_eq!(value(text).unwrap(), Value::Integer(n), "{}", text);
+        }
+        fails("key = 0x", "Line 1: invalid value.");
+        fails("key = 99999999999999999999", "Line 1: invalid value.");
+    }
+
+    #[test]
+    fn nests_arrays() {
+        assert_eq!(
+            value("[[0x10, 0x13], 0x20, [],]   # trailing comma").unwrap(),
+            Value::Array(vec![
+                Value::Array(vec![Value::Integer(0x10), Value::Integer(0x13)]),
+                Value::Integer(0x20),
+                Value::Array(Vec::new()),
+            ])
+        );
+        fails("key = [1, 2", "Line 1: expected , or ] in array.");
+        fails("key = [1 2]", "Line 1: expected , or ] in array.");
+    }
+
+    #[test]
+    fn reads_tables_in_order() {
+        let text = "top = true\n\n[a]\nx = 1\n[\"/bin/init\"]\n\"quoted key\" = false\n";
+        let tables = parse(text).unwrap();
+        let names: Vec<&str> = tables.iter().map(|table| table.name.as_str()).collect();
+        assert_eq!(names, ["", "a", "/bin/init"]);
+        assert_eq!(
+            tables[0].entries,
+            [("top".to_string(), Value::Boolean(true))]
+        );
+        assert_eq!(
+            tables[2].entries,
+            [("quoted key".to_string(), Value::Boolean(false))]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        fails("ok = 1\n[table", "Line 2: unterminated table header.");
+        fails("just words", "Line 1: expected key = value.");
+        fails("= 1", "Line 1: invalid key.");
+        fails("two words = 1", "Line 1: invalid key.");
+        fails("key = 1 2", "Line 1: unexpected text after value.");
+        fails("key = yes", "Line 1: invalid value.");
+        fails("key = 1\nkey = 2", "Line 2: duplicate key key.");
+    }
+}
@@ -0,0 +1,431 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::raw::{c_int, c_long, c_void};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// The same on every architecture, as they were added after the syscall
+// tables were unified.
+const SYS_IO_URING_SETUP: c_long = 425;
+const SYS_IO_URING_ENTER: c_long = 426;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: c_long = 1;
+const IORING_OP_WRITE: u8 = 23;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+const MAP_POPULATE: c_int = 0x8000;
+
+/// Writes queued before they are submitted together.
+pub const DEPTH: u32 = 64;
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+    fn mmap(
+        addr: *mut c_void,
+        length: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, length: usize) -> c_int;
+    fn close(fd: c_int) -> c_int;
+}
+
+// The kernel's struct io_uring_params and the ring offsets in it.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+// A submission queue entry, struct io_uring_sqe.
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+// A completion queue entry, struct io_uring_cqe.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// The result of a raw syscall, or the error it set errno to.
+fn check(ret: c_long) -> Result<c_long, Error> {
+    if ret >= 0 {
+        return Ok(ret);
+    }
+    Err(Error::last_os_error())
+}
+
+// A part of the ring mapped into memory.
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: c_int, len: usize, offset: i64) -> Result<Map, Error> {
+        // SAFETY: a fresh shared mapping of the ring, checked below.
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Map {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    // The field `offset` bytes into the mapping, which the kernel's
+    // offsets guarantee is in bounds and aligned.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + std::mem::size_of::<T>() <= self.len);
+        self.ptr.wrapping_add(offset as usize) as *mut T
+    }
+
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: ring heads and tails are aligned u32s shared with the
+        // kernel, which is what AtomicU32 is for.
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: unmaps what `new` mapped, once.
+        unsafe {
+            munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
+
+// An io_uring instance: its submission queue, its completion queue and
+// the array of submission entries.
+struct Ring {
+    fd: c_int,
+    params: Params,
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+}
+
+impl Ring {
+    fn new(entries: u32) -> Result<Ring, Error> {
+        let mut params = Params::default();
+        // SAFETY: io_uring_setup fills in `params`, which lives through
+        // the call.
+        let fd = check(unsafe {
+            syscall(
+                SYS_IO_URING_SETUP,
+                c_long::from(entries),
+                &mut params as *mut Params,
+            )
+        })
+        .map_err(|err| Error::new(err.kind(), format!("io_uring is not available: {}.", err)))?
+            as c_int;
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let maps = Map::new(fd, sq_len, IORING_OFF_SQ_RING).and_then(|sq| {
+            let cq = Map::new(fd, cq_len, IORING_OFF_CQ_RING)?;
+            let sqes = Map::new(fd, sqes_len, IORING_OFF_SQES)?;
+            Ok((sq, cq, sqes))
+        });
+        match maps {
+            Ok((sq, cq, sqes)) => Ok(Ring {
+                fd,
+                params,
+                sq,
+                cq,
+                sqes,
+            }),
+            Err(err) => {
+                // SAFETY: the ring is closed once, as nothing else has it.
+                unsafe { close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32) -> Result<u32, Error> {
+        loop {
+            // SAFETY: no signal mask is passed, so nothing is read but
+            // the ring.
+            let ret = unsafe {
+                syscall(
+                    SYS_IO_URING_ENTER,
+                    c_long::from(self.fd),
+                    c_long::from(to_submit),
+                    c_long::from(min_complete),
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<c_void>(),
+                    0 as c_long,
+                )
+            };
+            match check(ret) {
+                Ok(n) => return Ok(n as u32),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Writes each of `writes` to `file` at its offset, returning how many
+    // bytes of each were written. At most `sq_entries` at a time. Errors
+    // of single writes are returned once all of them are done with, so
+    // only a failure of io_uring_enter itself can leave the kernel
+    // holding buffers.
+    fn write(&self, file: &File, writes: &[(u64, Vec<u8>)]) -> Result<Vec<usize>, Error> {
+        let n = writes.len() as u32;
+        debug_assert!(n <= self.params.sq_entries);
+        let off = &self.params.sq_off;
+        let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        let tail = self.sq.atomic(off.tail).load(Ordering::Acquire);
+        for (i, (offset, data)) in writes.iter().enumerate() {
+            let index = tail.wrapping_add(i as u32) & mask;
+            let sqe = Sqe {
+                opcode: IORING_OP_WRITE,
+                flags: 0,
+                ioprio: 0,
+                fd: file.as_raw_fd(),
+                off: *offset,
+                addr: data.as_ptr() as u64,
+                len: data.len() as u32,
+                rw_flags: 0,
+                user_data: i as u64,
+                buf_index: 0,
+                personality: 0,
+                splice_fd_in: 0,
+                addr3: 0,
+                pad: 0,
+            };
+            // SAFETY: `index` is masked into the arrays the kernel sized,
+            // and the buffer outlives the wait for its completion below.
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+                ptr::write(self.sq.at::<u32>(off.array).add(index as usize), index);
+            }
+        }
+        self.sq
+            .atomic(off.tail)
+            .store(tail.wrapping_add(n), Ordering::Release);
+
+        let mut written = vec![0; writes.len()];
+        let mut failure = None;
+        let mut submitted = 0;
+        let mut completed = 0;
+        while completed < n {
+            submitted += self.enter(n - submitted, 1)?;
+            completed += self.reap(&mut written, &mut failure);
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(written),
+        }
+    }
+
+    // Takes every completion off the queue, noting the bytes written by
+    // each write or the first error, and returns how many there were.
+    fn reap(&self, written: &mut [usize], failure: &mut Option<Error>) -> u32 {
+        let off = &self.params.cq_off;
+        let mask = unsafe { *self.cq.at::<u32>(off.ring_mask) };
+        let head = self.cq.atomic(off.head);
+        let tail = self.cq.atomic(off.tail).load(Ordering::Acquire);
+        let mut seen = head.load(Ordering::Relaxed);
+        let mut reaped = 0;
+        while seen != tail {
+            // SAFETY: entries between head and tail are the kernel's
+            // finished ones, masked into its array.
+            let cqe = unsafe { ptr::read(self.cq.at::<Cqe>(off.cqes).add((seen & mask) as usize)) };
+            match usize::try_from(cqe.res) {
+                Ok(n) => written[cqe.user_data as usize] = n,
+                Err(_) => {
+                    failure.get_or_insert_with(|| Error::from_raw_os_error(-cqe.res));
+                }
+            }
+            seen = seen.wrapping_add(1);
+            reaped += 1;
+        }
+        head.store(seen, Ordering::Release);
+        reaped
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: the mappings are dropped after this, which the kernel
+        // allows; the descriptor is closed once.
+        unsafe { close(self.fd) };
+    }
+}
+
+/// A file written through io_uring: positioned writes are queued and
+/// submitted `DEPTH` at a time instead of one syscall each, which is
+/// what a build's many small section header and FAT entry writes cost.
+/// Reading (or flushing) submits what's queued first.
+pub struct UringFile {
+    file: File,
+    ring: Ring,
+    pos: u64,
+    len: u64,
+    queue: Vec<(u64, Vec<u8>)>,
+}
+
+impl UringFile {
+    pub fn new(file: File) -> Result<UringFile, Error> {
+        let len = file.metadata()?.len();
+        Ok(UringFile {
+            file,
+            ring: Ring::new(DEPTH)?,
+            pos: 0,
+            len,
+            queue: Vec::new(),
+        })
+    }
+
+    // Writes out everything queued, finishing by hand any write the
+    // kernel only did part of.
+    fn submit(&mut self) -> Result<(), Error> {
+        let queue = std::mem::take(&mut self.queue);
+        let batch = self.ring.params.sq_entries as usize;
+        for writes in queue.chunks(batch) {
+            let written = match self.ring.write(&self.file, writes) {
+                Ok(written) => written,
+                Err(err) => {
+                    // The kernel may still read the buffers.
+                    std::mem::forget(queue);
+                    return Err(err);
+                }
+            };
+            for ((offset, data), n) in writes.iter().zip(written) {
+                if n < data.len() {
+                    self.file.write_all_at(&data[n..], offset + n as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.submit()?;
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for UringFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = buf.len().min(u32::MAX as usize);
+        self.queue.push((self.pos, buf[..len].to_vec()));
+        self.pos += len as u64;
+        self.len = self.len.max(self.pos);
+        if self.queue.len() >= DEPTH as usize {
+            self.submit()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.submit()
+    }
+}
+
+impl Seek for UringFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = target.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Seek before the start of the file.",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        // Flushing is where errors are seen; this only keeps writes from
+        // being lost when it wasn't done.
+        let _ = self.submit();
+    }
+}
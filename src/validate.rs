@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Error;
+use std::path::Path;
+
+use crate::budget::{self, Target};
+use crate::context::Options;
+use crate::estimate;
+use crate::fat::Entry;
+use crate::lint;
+use crate::model::Limits;
+use crate::source::{self, KfsSource, Kind};
+use crate::*;
+
+/// One of the checks `validate` runs, with what it found wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub problems: Vec<String>,
+    /// Whether its problems are only warnings, as a build gives for
+    /// them, which fail validation only when it is strict.
+    pub warning: bool,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// How many of the problems `checks` found fail validation: all of them
+/// if `strict`, or else all but the warnings.
+pub fn failures(checks: &[Check], strict: bool) -> usize {
+    checks
+        .iter()
+        .filter(|check| strict || !check.warning)
+        .map(|check| check.problems.len())
+        .sum()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Found {
+    Directory,
+    File(u64),
+    Symlink(String),
+    Other,
+}
+
+// Symlinks followed in resolving a path before it counts as a loop.
+const MAX_HOPS: usize = 16;
+
+// Where `path` leads in the model, following symlinks: the path of what
+// it names, or why it leads nowhere.
+fn resolve(
+    entries: &BTreeMap<String, Found>,
+    path: &str,
+    hops: &mut usize,
+) -> Result<String, &'static str> {
+    let mut resolved = String::new();
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        match part {
+            "." => {}
+            ".." => {
+                let parent = resolved.rfind('/').unwrap_or(0);
+                resolved.truncate(parent);
+            }
+            name => {
+                let candidate = format!("{}/{}", resolved, name);
+                resolved = match entries.get(&candidate) {
+                    None => return Err("dangles"),
+                    Some(Found::Symlink(target)) => {
+                        *hops += 1;
+                        if *hops > MAX_HOPS {
+                            return Err("loops");
+                        }
+                        let base = if target.starts_with('/') {
+                            ""
+                        } else {
+                            &resolved
+                        };
+                        resolve(entries, &format!("{}/{}", base, target), hops)?
+                    }
+                    Some(_) => candidate,
+                };
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+// Every path of the model that `hidden` keeps, and what it is.
+fn walk(source: &dyn KfsSource, options: &Options) -> Result<BTreeMap<String, Found>, Error> {
+    let mut entries = BTreeMap::new();
+    source::walk(source, &mut |path, member| {
+        if !options.hidden.keeps(&member.name.to_string_lossy()) {
+            return Ok(false);
+        }
+        let found = match member.kind {
+            Kind::Directory => Found::Directory,
+            Kind::File { length } => Found::File(length),
+            Kind::Symlink => Found::Symlink(source.read_link(path)?),
+            Kind::Other => Found::Other,
+        };
+        entries.insert(path.to_string(), found);
+        Ok(true)
+    })?;
+    Ok(entries)
+}
+
+fn names(entries: &BTreeMap<String, Found>) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (path, found) in entries {
+        let name = &path[path.rfind('/').map_or(0, |slash| slash + 1)..];
+        if name.contains(char::REPLACEMENT_CHARACTER) {
+            problems.push(format!("{} isn't valid UTF-8.", path));
+            continue;
+        }
+        // Checked the way the FAT stores it.
+        let entry = match found {
+            Found::Symlink(target) => Entry::Symlink {
+                parent: 0,
+                name: name.to_string(),
+                target: target.to_string(),
+            },
+            _ => Entry::Directory {
+                parent: 0,
+                id: 0,
+                flags: 0xFF,
+                name: name.to_string(),
+            },
+        };
+        if let Err(err) = entry.encode() {
+            problems.push(format!("{} can't go in the FAT: {}.", path, err));
+        }
+    }
+    problems
+}
+
+fn sizes(entries: &BTreeMap<String, Found>) -> Vec<String> {
+    entries
+        .iter()
+        .filter_map(|(path, found)| match found {
+            Found::File(length) if *length > KFS_MAX_FILE_LEN => Some(format!(
+                "{} is {} bytes, larger than the maximum file size of {}.",
+                path, length, KFS_MAX_FILE_LEN
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+fn symlinks(entries: &BTreeMap<String, Found>) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (path, found) in entries {
+        let target = match found {
+            Found::Symlink(target) => target,
+            _ => continue,
+        };
+        if target.is_empty() {
+            problems.push(format!("{} has an empty target.", path));
+            continue;
+        }
+        let dir = &path[..path.rfind('/').unwrap_or(0)];
+        let base = if target.starts_with('/') { "" } else { dir };
+        if let Err(why) = resolve(entries, &format!("{}/{}", base, target), &mut 0) {
+            problems.push(format!("{} -> {} {}.", path, target, why));
+        }
+    }
+    problems
+}
+
+// Names that only differ in case, which collide when the image is
+// extracted onto a case-insensitive host.
+fn duplicates(entries: &BTreeMap<String, Found>) -> Vec<String> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut problems = Vec::new();
+    for path in entries.keys() {
+        if let Some(first) = seen.insert(path.to_lowercase(), path) {
+            problems.push(format!("{} and {} differ only in case.", first, path));
+        }
+    }
+    problems
+}
+
+fn special_files(entries: &BTreeMap<String, Found>) -> Vec<String> {
+    entries
+        .iter()
+        .filter(|(_, found)| **found == Found::Other)
+        .map(|(path, _)| {
+            format!(
+                "{} isn't a file, directory or symlink, so can't be stored.",
+                path
+            )
+        })
+        .collect()
+}
+
+// Bytes of file data at or below `dir`.
+fn size_below(entries: &BTreeMap<String, Found>, dir: &str) -> u64 {
+    entries
+        .iter()
+        .filter(|(path, _)| {
+            dir.is_empty()
+                || *path == dir
+                || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+        })
+        .map(|(_, found)| match found {
+            Found::File(length) => *length,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn limits(
+    source: &dyn KfsSource,
+    model: &Path,
+    options: &Options,
+    entries: &BTreeMap<String, Found>,
+) -> Result<Vec<String>, Error> {
+    let mut problems = Vec::new();
+    let limits = Limits {
+        files: options.max_files,
+        total_size: options.max_total_size,
+    };
+    if let Err(err) = limits.check(source, options.hidden) {
+        problems.push(err.to_string().trim_start_matches("Error: ").to_string());
+    }
+    let budgets = match &options.budgets {
+        Some(path) => budget::read_budgets(path)?,
+        None => Vec::new(),
+    };
+    // Only a build knows how much of the FAT and data the model takes.
+    let built = if budgets
+        .iter()
+        .any(|budget| !matches!(budget.target, Target::Path(_)))
+    {
+        Some(estimate::estimate(model, options))
+    } else {
+        None
+    };
+    for budget in &budgets {
+        let (name, used) = match &budget.target {
+            Target::Path(path) => (
+                if path.is_empty() { "/" } else { path },
+                size_below(entries, path),
+            ),
+            target => {
+                let estimate = match &built {
+                    Some(Ok(estimate)) => estimate,
+                    Some(Err(err)) => {
+                        problems.push(format!("Couldn't estimate the build: {}", err));
+                        continue;
+                    }
+                    None => unreachable!("estimated for every FAT or data budget"),
+                };
+                match target {
+                    Target::Fat => ("The FAT", u64::from(estimate.fat_bytes)),
+                    _ => (
+                        "File data",
+                        estimate.blocks as u64 * u64::from(estimate.block_size),
+                    ),
+                }
+            }
+        };
+        if used > budget.limit {
+            problems.push(format!(
+                "{} takes {} bytes, over its budget of {}.",
+                name, used, budget.limit
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Runs every check of the model at `model` that needs no ROM: that its
+/// names fit in FAT entries, its files fit in the filesystem, its
+/// symlinks lead somewhere in it, no names differ only in case, it holds
+/// no special files, it has what KnightOS needs to boot, and it keeps
+/// within `options`' limits and budgets. FAT and data budgets are
+/// checked against a build into memory, as `estimate` makes. What
+/// KnightOS needs to boot is only a warning, as `lint` gives it.
+pub fn validate(model: &Path, options: &Options) -> Result<Vec<Check>, Error> {
+    let source = source::open_model(
+        model,
+        options.follow_dir_symlinks,
+        options.buffer_size,
+        options.symlinks.as_deref(),
+    )?;
+    let entries = walk(&*source, options)?;
+    let lint = lint::lint(&lint::entries(&*source)?);
+    Ok(vec![
+        Check {
+            name: "names",
+            problems: names(&entries),
+            warning: false,
+        },
+        Check {
+            name: "sizes",
+            problems: sizes(&entries),
+            warning: false,
+        },
+        Check {
+            name: "symlinks",
+            problems: symlinks(&entries),
+            warning: false,
+        },
+        Check {
+            name: "duplicates",
+            problems: duplicates(&entries),
+            warning: false,
+        },
+        Check {
+            name: "special files",
+            problems: special_files(&entries),
+            warning: false,
+        },
+        Check {
+            name: "layout",
+            problems: lint,
+            warning: true,
+        },
+        Check {
+            name: "limits",
+            problems: limits(&*source, model, options, &entries)?,
+            warning: false,
+        },
+    ])
+}
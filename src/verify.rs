@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::io::{Error, Read};
+
+use crate::context::Options;
+use crate::fat::Entry;
+use crate::image::{Image, Node};
+use crate::model::Filter;
+use crate::source::{KfsSource, Kind};
+use crate::KFS_MAX_FILE_LEN;
+
+/// What the model holds at a path.
+enum Expected<'a> {
+    Directory,
+    File(&'a [u8]),
+    Symlink(&'a str),
+}
+
+struct Verifier<'i, 'a> {
+    image: &'i Image<'a>,
+    options: &'i Options,
+    filter: &'i Filter,
+    nodes: HashMap<String, Node>,
+    problems: Vec<String>,
+}
+
+impl<'i, 'a> Verifier<'i, 'a> {
+    fn new(
+        image: &'i Image<'a>,
+        options: &'i Options,
+        filter: &'i Filter,
+    ) -> Result<Verifier<'i, 'a>, Error> {
+        let nodes = image
+            .nodes()?
+            .into_iter()
+            .map(|node| (node.path.clone(), node))
+            .collect();
+        Ok(Verifier {
+            image,
+            options,
+            filter,
+            nodes,
+            problems: Vec::new(),
+        })
+    }
+
+    // What was found wrong, then every entry the model didn't account for.
+    fn problems(self) -> Vec<String> {
+        let mut extra: Vec<String> = self.nodes.into_keys().collect();
+        extra.sort();
+        let mut problems = self.problems;
+        problems.extend(
+            extra
+                .into_iter()
+                .map(|path| format!("{} is not in the model.", path)),
+        );
+        problems
+    }
+
+    fn check(&mut self, path: &str, expected: Expected) -> Result<(), Error> {
+        let node = match self.nodes.remove(path) {
+            Some(node) => node,
+            None => {
+                self.problems.push(format!("{} is missing.", path));
+                return Ok(());
+            }
+        };
+        let problem = match (expected, &node.record.entry) {
+            (Expected::Directory, Entry::Directory { .. }) => None,
+            (Expected::File(data), entry @ Entry::File { .. }) => {
+                let stored = self.image.read_file(entry)?;
+                if stored.len() != data.len() {
+                    Some(format!(
+                        "{} holds {} bytes instead of {}.",
+                        path,
+                        stored.len(),
+                        data.len()
+                    ))
+                } else if stored != data {
+                    Some(format!("{} differs from the model.", path))
+                } else {
+                    None
+                }
+            }
+            (Expected::Symlink(target), Entry::Symlink { target: stored, .. }) => {
+                if stored != target {
+                    Some(format!(
+                        "{} points to {} instead of {}.",
+                        path, stored, target
+                    ))
+                } else {
+                    None
+                }
+            }
+            _ => Some(format!("{} has the wrong type.", path)),
+        };
+        self.problems.extend(problem);
+        Ok(())
+    }
+
+    // Whether a file of `length` bytes was left out of the build.
+    fn skips(&self, length: u64) -> bool {
+        self.options.skip_too_large && length > KFS_MAX_FILE_LEN
+    }
+
+    // Whether the model entry at `path` was written, given any
+    // `--files-from` list and `--use-gitignore`.
+    fn selects(&self, path: &str) -> bool {
+        self.filter.keeps(path)
+    }
+
+    fn walk(&mut self, source: &dyn KfsSource, dir: &str) -> Result<(), Error> {
+        for member in source.list(dir)? {
+            let name = member.name.to_string_lossy();
+            if !self.options.hidden.keeps(&name) {
+                continue;
+            }
+            let path = format!("{}/{}", dir, name);
+            if !self.selects(&path) {
+                continue;
+            }
+            match member.kind {
+                Kind::Symlink => {
+                    let target = source.read_link(&path)?;
+                    self.check(&path, Expected::Symlink(&target))?;
+                }
+                Kind::Directory => {
+                    if self.options.prune_empty
+                        && !source.has_content(&path, self.options.hidden)?
+                    {
+                        continue;
+                    }
+                    self.check(&path, Expected::Directory)?;
+                    self.walk(source, &path)?;
+                }
+                Kind::File { length } if self.skips(length) => {}
+                Kind::File { .. } => {
+                    let mut data = Vec::new();
+                    source.open(&path)?.read_to_end(&mut data)?;
+                    self.check(&path, Expected::File(&data))?;
+                }
+                // Never written.
+                Kind::Other => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the filesystem in `image` against the model in `source` it
+/// was written from with `options`, returning a message for every entry
+/// that is missing, extra, of the wrong type or holding the wrong bytes.
+/// Only the entries `filter` keeps are expected.
+pub fn verify(
+    image: &Image,
+    source: &dyn KfsSource,
+    options: &Options,
+    filter: &Filter,
+) -> Result<Vec<String>, Error> {
+    let mut verifier = Verifier::new(image, options, filter)?;
+    verifier.walk(source, "")?;
+    Ok(verifier.problems())
+}
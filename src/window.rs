@@ -0,0 +1,190 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::long_path;
+
+/// Where a filesystem image sits inside a larger one, such as a full
+/// flash dump with other partitions: `offset`, or `offset+length`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub offset: u64,
+    /// Bytes from `offset`, or `None` for the rest of the image.
+    pub length: Option<u64>,
+}
+
+fn number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid offset {}.", s))
+}
+
+/// Parses `0x100000` or `0x100000+0x80000`, in hex or decimal.
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Span, String> {
+        let (offset, length) = match s.split_once('+') {
+            Some((offset, length)) => (offset, Some(number(length)?)),
+            None => (s, None),
+        };
+        Ok(Span {
+            offset: number(offset)?,
+            length,
+        })
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.offset)?;
+        match self.length {
+            Some(length) => write!(f, "+{:#x}", length),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Span {
+    // The byte range of the span in an image of `len` bytes.
+    fn range(self, len: u64) -> Result<(u64, u64), Error> {
+        let end = match self.length {
+            Some(length) => self.offset.checked_add(length),
+            None => Some(len),
+        };
+        match end {
+            Some(end) if self.offset <= end && end <= len => Ok((self.offset, end)),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Offset {} is past the end of a {} byte image.", self, len),
+            )),
+        }
+    }
+
+    /// The part of `image` the span covers.
+    pub fn slice(self, image: &[u8]) -> Result<&[u8], Error> {
+        let (start, end) = self.range(image.len() as u64)?;
+        Ok(&image[start as usize..end as usize])
+    }
+
+    pub fn slice_mut(self, image: &mut [u8]) -> Result<&mut [u8], Error> {
+        let (start, end) = self.range(image.len() as u64)?;
+        Ok(&mut image[start as usize..end as usize])
+    }
+}
+
+/// Splits `rom.img:0x100000` into the file and its span. A path that
+/// names an existing file, or whose suffix isn't a span, is taken whole.
+pub fn split_path(path: &Path) -> (PathBuf, Option<Span>) {
+    if path.exists() {
+        return (path.to_path_buf(), None);
+    }
+    let text = path.to_string_lossy();
+    match text.rsplit_once(':') {
+        Some((file, span)) if !file.is_empty() => match span.parse() {
+            Ok(span) => (PathBuf::from(file), Some(span)),
+            Err(_) => (path.to_path_buf(), None),
+        },
+        _ => (path.to_path_buf(), None),
+    }
+}
+
+/// Reads the image at `path`, or the span of it its `:offset` suffix or
+/// else `span` names.
+pub fn read(path: &Path, span: Option<Span>) -> Result<Vec<u8>, Error> {
+    let (file, suffix) = split_path(path);
+    let image = fs::read(long_path::extended(&file))?;
+    match suffix.or(span) {
+        Some(span) => Ok(span.slice(&image)?.to_vec()),
+        None => Ok(image),
+    }
+}
+
+/// A span of a larger image, read, written and sought as if it were
+/// the whole of it.
+#[derive(Debug)]
+pub struct Window<F> {
+    inner: F,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<F: Seek> Window<F> {
+    pub fn new(mut inner: F, span: Span) -> Result<Window<F>, Error> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        let (start, end) = span.range(len)?;
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Window {
+            inner,
+            start,
+            len: end - start,
+            pos: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.inner
+    }
+
+    // Bytes left before the end of the window.
+    fn left(&self) -> usize {
+        usize::try_from(self.len.saturating_sub(self.pos)).unwrap_or(usize::MAX)
+    }
+}
+
+impl<F: Read + Seek> Read for Window<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.left());
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: Write + Seek> Write for Window<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if !buf.is_empty() && self.left() == 0 {
+            return Err(Error::new(
+                ErrorKind::WriteZero,
+                "Write past the end of the image's span.",
+            ));
+        }
+        let len = buf.len().min(self.left());
+        let n = self.inner.write(&buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+impl<F: Seek> Seek for Window<F> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        let target = target.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Seek before the start of the image.",
+            )
+        })?;
+        self.inner.seek(SeekFrom::Start(self.start + target))?;
+        self.pos = target;
+        Ok(target)
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Write};
+
+use crate::checksum::crc32;
+use crate::extract::{mode, Permissions, Sink};
+use crate::model::{insert, Tree};
+
+// 1980-01-01 00:00, the earliest DOS timestamp, so archives of the same
+// image are identical.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    attributes: u32,
+    offset: u32,
+}
+
+/// Writes extracted entries as a zip archive with stored (uncompressed)
+/// members. Symlinks are encoded the way Info-ZIP does: Unix mode bits
+/// in the external attributes and the target as the contents.
+pub struct ZipSink<W: Write> {
+    out: W,
+    permissions: Permissions,
+    offset: u32,
+    entries: Vec<CentralEntry>,
+}
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "Archive is too large for zip.")
+}
+
+impl<W: Write> ZipSink<W> {
+    pub fn new(out: W, permissions: Permissions) -> ZipSink<W> {
+        ZipSink {
+            out,
+            permissions,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn member(&mut self, name: String, mode: u32, data: &[u8]) -> Result<(), Error> {
+        let crc = crc32(data);
+        let size: u32 = data.len().try_into().map_err(|_| too_large())?;
+        let name_len: u16 = name.len().try_into().map_err(|_| too_large())?;
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&10u16.to_le_bytes()); // Version needed
+        header.extend_from_slice(&0u16.to_le_bytes()); // Flags
+        header.extend_from_slice(&0u16.to_le_bytes()); // Stored
+        header.extend_from_slice(&DOS_TIME.to_le_bytes());
+        header.extend_from_slice(&DOS_DATE.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&name_len.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+        header.extend_from_slice(name.as_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        let is_dir = mode & 0o170000 == S_IFDIR;
+        self.entries.push(CentralEntry {
+            name,
+            crc,
+            size,
+            // The low byte holds the MS-DOS directory attribute.
+            attributes: (mode << 16) | if is_dir { 0x10 } else { 0 },
+            offset: self.offset,
+        });
+        self.offset = (header.len() as u32)
+            .checked_add(size)
+            .and_then(|len| self.offset.checked_add(len))
+            .ok_or_else(too_large)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for ZipSink<W> {
+    fn directory(&mut self, path: &str, flags: u8) -> Result<(), Error> {
+        let mode = S_IFDIR | mode(self.permissions, flags, true);
+        self.member(format!("{}/", path), mode, &[])
+    }
+
+    fn file(&mut self, path: &str, flags: u8, data: &[u8]) -> Result<(), Error> {
+        let mode = S_IFREG | mode(self.permissions, flags, false);
+        self.member(path.to_string(), mode, data)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), Error> {
+        self.member(path.to_string(), S_IFLNK | 0o777, target.as_bytes())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let start = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&((3 << 8) | 10u16).to_le_bytes()); // Made by Unix
+            directory.extend_from_slice(&10u16.to_le_bytes()); // Version needed
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Flags
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Stored
+            directory.extend_from_slice(&DOS_TIME.to_le_bytes());
+            directory.extend_from_slice(&DOS_DATE.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 8]); // Extra, comment, disk, internal attributes
+            directory.extend_from_slice(&entry.attributes.to_le_bytes());
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let count: u16 = self.entries.len().try_into().map_err(|_| too_large())?;
+        let size: u32 = directory.len().try_into().map_err(|_| too_large())?;
+        directory.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&[0; 4]); // Disk numbers
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&start.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+        self.out.write_all(&directory)?;
+        self.out.flush()
+    }
+}
+
+fn invalid(what: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid zip archive: {}.", what),
+    )
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, Error> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("it ends early".to_string()))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, Error> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("it ends early".to_string()))
+}
+
+/// Reads a zip archive into a model tree. Members may be stored or
+/// deflated, and symlinks are read the way `ZipSink` writes them.
+pub fn read_zip(data: &[u8]) -> Result<BTreeMap<String, Tree>, Error> {
+    // The end of central directory record, before an archive comment of
+    // up to 64 KiB.
+    let end = (0..=data.len().saturating_sub(22))
+        .rev()
+        .take(0x10000 + 22)
+        .find(|&at| data[at..].starts_with(&0x0605_4b50u32.to_le_bytes()))
+        .ok_or_else(|| invalid("no end of central directory record".to_string()))?;
+    let count = u16_at(data, end + 10)?;
+    let mut at = u32_at(data, end + 16)? as usize;
+    if count == 0xFFFF || at == 0xFFFF_FFFF {
+        return Err(invalid("zip64 isn't supported".to_string()));
+    }
+    let mut root = BTreeMap::new();
+    for _ in 0..count {
+        if u32_at(data, at)? != 0x0201_4b50 {
+            return Err(invalid("bad central directory entry".to_string()));
+        }
+        let made_by = u16_at(data, at + 4)?;
+        let flags = u16_at(data, at + 8)?;
+        let method = u16_at(data, at + 10)?;
+        let crc = u32_at(data, at + 16)?;
+        let compressed = u32_at(data, at + 20)? as usize;
+        let size = u32_at(data, at + 24)? as usize;
+        let name_len = usize::from(u16_at(data, at + 28)?);
+        let extra_len = usize::from(u16_at(data, at + 30)?);
+        let comment_len = usize::from(u16_at(data, at + 32)?);
+        let attributes = u32_at(data, at + 38)?;
+        let offset = u32_at(data, at + 42)? as usize;
+        let name = data
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid("it ends early".to_string()))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_len + extra_len + comment_len;
+        if flags & 1 != 0 {
+            return Err(invalid(format!("{} is encrypted", name)));
+        }
+        if u32_at(data, offset)? != 0x0403_4b50 {
+            return Err(invalid(format!("bad local header for {}", name)));
+        }
+        let start = offset
+            + 30
+            + usize::from(u16_at(data, offset + 26)?)
+            + usize::from(u16_at(data, offset + 28)?);
+        let stored = data
+            .get(start..start + compressed)
+            .ok_or_else(|| invalid("it ends early".to_string()))?;
+        let contents = match method {
+            0 => stored.to_vec(),
+            8 => crate::inflate::inflate(stored)?,
+            _ => {
+                return Err(invalid(format!(
+                    "{} is compressed with unsupported method {}",
+                    name, method
+                )))
+            }
+        };
+        if contents.len() != size || crc32(&contents) != crc {
+            return Err(invalid(format!("{} is corrupt", name)));
+        }
+        // Unix mode bits are only there when a Unix host made the member.
+        let mode = if made_by >> 8 == 3 {
+            attributes >> 16
+        } else {
+            0
+        };
+        let node = if name.ends_with('/') || mode & 0o170000 == S_IFDIR {
+            Tree::Directory(BTreeMap::new())
+        } else if mode & 0o170000 == S_IFLNK {
+            Tree::Symlink(String::from_utf8_lossy(&contents).into_owned())
+        } else {
+            Tree::File(contents)
+        };
+        insert(&mut root, &name, node)?;
+    }
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut sink = ZipSink::new(Vec::new(), Permissions::None);
+        sink.directory("bin", 0xFF).unwrap();
+        sink.file("bin/init", 0xFF, b"init").unwrap();
+        sink.symlink("init", "bin/init").unwrap();
+        sink.directory("empty", 0xFF).unwrap();
+        sink.finish().unwrap();
+        sink.into_inner()
+    }
+
+    // An archive of one member called `name`, compressed with `method`,
+    // `size` bytes long once read and with CRC `crc`.
+    fn one_member(name: &str, method: u16, crc: u32, size: u32, stored: &[u8]) -> Vec<u8> {
+        let fields = |out: &mut Vec<u8>| {
+            out.extend_from_slice(&20u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&DOS_TIME.to_le_bytes());
+            out.extend_from_slice(&DOS_DATE.to_le_bytes());
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+        };
+        let mut out = 0x0403_4b50u32.to_le_bytes().to_vec();
+        fields(&mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(stored);
+        let start = out.len() as u32;
+        out.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        out.extend_from_slice(&((3 << 8) | 20u16).to_le_bytes());
+        fields(&mut out);
+        out.extend_from_slice(&[0; 6]); // Comment, disk, internal attributes
+        out.extend_from_slice(&(S_IFREG << 16).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        let size = out.len() as u32 - start;
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    // "hello hello hello", deflated.
+    const HELLO: [u8; 10] = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let root = read_zip(&archive()).unwrap();
+        let mut expected = BTreeMap::new();
+        insert(&mut expected, "bin/init", Tree::File(b"init".to_vec())).unwrap();
+        insert(&mut expected, "init", Tree::Symlink("bin/init".to_string())).unwrap();
+        insert(&mut expected, "empty", Tree::Directory(BTreeMap::new())).unwrap();
+        assert_eq!(root, expected);
+    }
+
+    #[test]
+    fn reads_deflated_members() {
+        let hello = b"hello hello hello";
+        let archive = one_member("hello", 8, crc32(hello), 17, &HELLO);
+        let root = read_zip(&archive).unwrap();
+        assert_eq!(root["hello"], Tree::File(hello.to_vec()));
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let archive = archive();
+        assert!(read_zip(&[]).is_err());
+        assert!(read_zip(&archive[..archive.len() - 1]).is_err());
+        // The end record kept, but not all of the directory before it.
+        assert!(read_zip(&archive[archive.len() - 22..]).is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end() {
+        let mut archive = archive();
+        let end = archive.len() - 22;
+        archive[end + 16..end + 20].copy_from_slice(&0x00FF_FFFFu32.to_le_bytes());
+        assert!(read_zip(&archive).is_err());
+        let mut archive = one_member("hello", 0, crc32(b"hi"), 2, b"hi");
+        let central = archive.len() - 22 - 46 - 5;
+        archive[central + 20..central + 24].copy_from_slice(&0xFFFF_FF00u32.to_le_bytes());
+        assert!(read_zip(&archive).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_members() {
+        let hello = b"hello hello hello";
+        assert!(read_zip(&one_member("hello", 8, !crc32(hello), 17, &HELLO)).is_err());
+        assert!(read_zip(&one_member("hello", 8, crc32(hello), 16, &HELLO)).is_err());
+        assert!(read_zip(&one_member("hello", 8, crc32(hello), 17, &[0x07])).is_err());
+        assert!(read_zip(&one_member("hello", 12, crc32(hello), 17, &HELLO)).is_err());
+    }
+}
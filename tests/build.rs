@@ -0,0 +1,1021 @@
+//! Builds small models into in-memory ROMs and reads them back.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regenkfs::allocator::Placement;
+use regenkfs::context::resolve_layout;
+use regenkfs::fat::{self, Entry};
+use regenkfs::{erase, kernel};
+use regenkfs::{AllocationMap, Context, Image, Options, BLOCK_SIZE, KFS_NO_SECTION, PAGE_LENGTH};
+
+// A fresh, empty model directory unique to `name`.
+fn model_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("regenkfs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Writes `model` into an erased 32-page ROM, verifying it on the way.
+fn build(model: &Path, options: Options) -> Vec<u8> {
+    let rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let options = Options {
+        quiet: true,
+        verify: true,
+        ..options
+    };
+    let mut context = Context::in_memory(rom, model, options).unwrap();
+    context.run().unwrap();
+    context.into_output().unwrap().into_inner()
+}
+
+// Every path in the image, with a trailing `/` for directories.
+fn listing(rom: &[u8]) -> Vec<String> {
+    Image::open(rom)
+        .unwrap()
+        .nodes()
+        .unwrap()
+        .into_iter()
+        .map(|node| match node.record.entry {
+            Entry::Directory { .. } => format!("{}/", node.path),
+            _ => node.path,
+        })
+        .collect()
+}
+
+fn model_with_empty_dirs(name: &str) -> PathBuf {
+    let model = model_dir(name);
+    fs::create_dir_all(model.join("bin")).unwrap();
+    fs::write(model.join("bin/init"), b"init").unwrap();
+    fs::create_dir_all(model.join("empty")).unwrap();
+    fs::create_dir_all(model.join("nested/inner")).unwrap();
+    model
+}
+
+#[test]
+fn empty_directories_round_trip() {
+    let model = model_with_empty_dirs("empty-dirs");
+    let rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(
+        listing(&rom),
+        [
+            "/bin/",
+            "/bin/init",
+            "/empty/",
+            "/nested/",
+            "/nested/inner/"
+        ]
+    );
+}
+
+#[test]
+fn prune_empty_drops_empty_directories() {
+    let model = model_with_empty_dirs("prune-empty");
+    let options = Options {
+        prune_empty: true,
+        ..Options::default()
+    };
+    let rom = build(&model, options);
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(listing(&rom), ["/bin/", "/bin/init"]);
+}
+
+// The length and section id of every file in the image, by path.
+fn files(rom: &[u8]) -> Vec<(String, u32, u16)> {
+    Image::open(rom)
+        .unwrap()
+        .nodes()
+        .unwrap()
+        .into_iter()
+        .filter_map(|node| match node.record.entry {
+            Entry::File {
+                length, section_id, ..
+            } => Some((node.path, length, section_id)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn largest_first_moves_data_but_not_entries() {
+    let model = model_dir("largest-first");
+    fs::write(model.join("a"), vec![b'a'; 100]).unwrap();
+    fs::write(model.join("b"), vec![b'b'; 3000]).unwrap();
+    fs::write(model.join("c"), vec![b'c'; 500]).unwrap();
+    let sequential = build(&model, Options::default());
+    let largest_first = build(
+        &model,
+        Options {
+            placement: Placement::LargestFirst,
+            ..Options::default()
+        },
+    );
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(listing(&largest_first), listing(&sequential));
+    let sections = |rom: &[u8]| -> Vec<u16> { files(rom).into_iter().map(|file| file.2).collect() };
+    let (a, b, c) = match sections(&sequential)[..] {
+        [a, b, c] => (a, b, c),
+        _ => unreachable!(),
+    };
+    assert!(a < b && b < c, "{:04x} {:04x} {:04x}", a, b, c);
+    // /b takes /a's first section, then /c and /a follow it.
+    let placed = sections(&largest_first);
+    assert_eq!(placed[1], a);
+    assert!(
+        placed[1] < placed[2] && placed[2] < placed[0],
+        "{:04x?}",
+        placed
+    );
+}
+
+#[test]
+fn largest_first_still_stops_at_the_fat() {
+    let model = model_dir("largest-first-full");
+    fs::write(model.join("small"), b"small").unwrap();
+    fs::write(model.join("huge"), vec![0; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+    let rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let options = Options {
+        quiet: true,
+        placement: Placement::LargestFirst,
+        ..Options::default()
+    };
+    let mut context = Context::in_memory(rom, &model, options).unwrap();
+    let err = context.run().unwrap_err();
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(err.to_string(), "Error: data would overwrite the FAT.");
+}
+
+// Writes `model` into an erased 32-page ROM with the placement hints in
+// `hints`, also returning its data pages.
+fn build_hinted(
+    model: &Path,
+    hints: &str,
+) -> Result<(Vec<u8>, std::ops::RangeInclusive<u8>), std::io::Error> {
+    let path = model.with_extension("hints");
+    fs::write(&path, hints).unwrap();
+    let rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let options = Options {
+        quiet: true,
+        verify: true,
+        hints: Some(path.clone()),
+        ..Options::default()
+    };
+    let mut context = Context::in_memory(rom, model, options)?;
+    let built = context.run();
+    fs::remove_file(&path).unwrap();
+    built?;
+    let pages = context.data_pages();
+    Ok((context.into_output()?.into_inner(), pages))
+}
+
+// The section the file at `path` starts at.
+fn section_of(rom: &[u8], path: &str) -> u16 {
+    files(rom)
+        .into_iter()
+        .find(|file| file.0 == path)
+        .unwrap()
+        .2
+}
+
+#[test]
+fn aligned_files_start_a_fresh_page() {
+    let model = model_dir("align");
+    fs::write(model.join("a"), vec![b'a'; 100]).unwrap();
+    fs::create_dir_all(model.join("bin")).unwrap();
+    fs::write(model.join("bin/init"), vec![b'i'; 300]).unwrap();
+    let (rom, pages) = build_hinted(&model, "[\"/bin/init\"]\nalign = true\n").unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    let first = u16::from(*pages.start()) << 8;
+    assert_eq!(section_of(&rom, "/a"), first | 1);
+    assert_eq!(
+        section_of(&rom, "/bin/init"),
+        first + 0x0101,
+        "block 1 of the next page"
+    );
+}
+
+#[test]
+fn aligning_with_no_fresh_page_is_an_error() {
+    let model = model_dir("align-full");
+    // Enough to take a block of every data page.
+    let (_, pages) = build_hinted(&model, "").unwrap();
+    let per_page = usize::from(PAGE_LENGTH - BLOCK_SIZE);
+    fs::write(model.join("a"), vec![0; (pages.len() - 1) * per_page + 1]).unwrap();
+    fs::write(model.join("b"), b"b").unwrap();
+    let err = build_hinted(&model, "[\"/b\"]\nalign = true\n").unwrap_err();
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(err.to_string(), "Error: no free page left to align /b to.");
+}
+
+#[test]
+fn pinned_files_start_on_their_page() {
+    let model = model_dir("pin");
+    fs::write(model.join("a"), vec![b'a'; 100]).unwrap();
+    fs::write(model.join("b"), vec![b'b'; 300]).unwrap();
+    fs::write(model.join("c"), vec![b'c'; 10]).unwrap();
+    let (_, pages) = build_hinted(&model, "").unwrap();
+    let page = pages.start() + 3;
+    let hints = format!("[\"/c\"]\npin = {0}\n[\"/b\"]\npin = {0}\n", page);
+    let (rom, _) = build_hinted(&model, &hints).unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    // Both go before /a, in FAT order, and /a fills from the bottom.
+    let first = u16::from(page) << 8;
+    assert_eq!(section_of(&rom, "/b"), first | 1);
+    assert_eq!(section_of(&rom, "/c"), first | 3);
+    assert_eq!(section_of(&rom, "/a"), u16::from(*pages.start()) << 8 | 1);
+}
+
+#[test]
+fn pins_outside_the_data_pages_are_errors() {
+    let model = model_dir("pin-outside");
+    fs::write(model.join("a"), b"a").unwrap();
+    let (_, pages) = build_hinted(&model, "").unwrap();
+    for page in &[*pages.start() - 1, *pages.end() + 1, 0xFF] {
+        let err = build_hinted(&model, &format!("[\"/a\"]\npin = {}\n", page)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Error: cannot pin /a to page {:02x}, which is not a data page.",
+                page
+            )
+        );
+    }
+    fs::remove_dir_all(&model).unwrap();
+}
+
+#[test]
+fn full_or_used_pinned_pages_are_errors() {
+    let model = model_dir("pin-full");
+    let per_page = usize::from(PAGE_LENGTH - BLOCK_SIZE);
+    fs::write(model.join("a"), vec![b'a'; per_page]).unwrap();
+    fs::write(model.join("b"), b"b").unwrap();
+    let (_, pages) = build_hinted(&model, "").unwrap();
+    let page = *pages.start() + 1;
+    let hints = format!("[\"/a\"]\npin = {0}\n[\"/b\"]\npin = {0}\n", page);
+    let err = build_hinted(&model, &hints).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("Error: page {:02x} has no room left for /b.", page)
+    );
+    let hints = format!(
+        "[\"/a\"]\npin = {0}\n[\"/b\"]\npin = {0}\nalign = true\n",
+        page
+    );
+    let err = build_hinted(&model, &hints).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Error: cannot align /b to page {:02x}, which is already in use.",
+            page
+        )
+    );
+    fs::remove_dir_all(&model).unwrap();
+}
+
+#[test]
+fn empty_files_have_no_section() {
+    let model = model_dir("empty-file");
+    fs::write(model.join("empty"), b"").unwrap();
+    let rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(files(&rom), [("/empty".to_string(), 0, KFS_NO_SECTION)]);
+    let image = Image::open(&rom).unwrap();
+    assert!(image.contents(KFS_NO_SECTION, 0).unwrap().is_empty());
+    // No data was written, so the first section is still free.
+    let header = 4 * usize::from(PAGE_LENGTH) + 4;
+    assert_eq!(rom[header..header + 4], [0xFF; 4]);
+}
+
+#[test]
+fn empty_files_take_no_room_from_others() {
+    let model = model_dir("empty-between");
+    fs::write(model.join("a"), vec![b'a'; 300]).unwrap();
+    fs::write(model.join("b"), b"").unwrap();
+    fs::write(model.join("c"), b"c").unwrap();
+    let rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    assert_eq!(
+        files(&rom),
+        [
+            ("/a".to_string(), 300, 0x0401),
+            ("/b".to_string(), 0, KFS_NO_SECTION),
+            ("/c".to_string(), 1, 0x0403),
+        ]
+    );
+    let image = Image::open(&rom).unwrap();
+    assert_eq!(image.contents(0x0403, 1).unwrap(), b"c");
+}
+
+#[test]
+fn declared_symlinks_replace_checked_out_placeholders() {
+    // As git checks symlinks out without symlink support: a file holding
+    // the target.
+    let model = model_dir("declared-symlinks");
+    fs::create_dir_all(model.join("bin")).unwrap();
+    fs::write(model.join("bin/busybox"), b"busybox").unwrap();
+    fs::write(model.join("bin/sh"), b"busybox").unwrap();
+    let symlinks = model.with_extension("symlinks");
+    fs::write(
+        &symlinks,
+        "# placeholder\nbin/sh -> busybox\nbin/ls -> busybox\n",
+    )
+    .unwrap();
+    let options = Options {
+        symlinks: Some(symlinks.clone()),
+        ..Options::default()
+    };
+    let rom = build(&model, options);
+    fs::remove_dir_all(&model).unwrap();
+    fs::remove_file(&symlinks).unwrap();
+    let links: Vec<_> = Image::open(&rom)
+        .unwrap()
+        .nodes()
+        .unwrap()
+        .into_iter()
+        .filter_map(|node| match node.record.entry {
+            Entry::Symlink { target, .. } => Some((node.path, target)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        links,
+        [
+            ("/bin/ls".to_string(), "busybox".to_string()),
+            ("/bin/sh".to_string(), "busybox".to_string()),
+        ]
+    );
+    assert_eq!(files(&rom).len(), 1);
+}
+
+#[test]
+fn paranoid_rejects_writes_that_break_the_format() {
+    use regenkfs::paranoid::Bounds;
+    use regenkfs::{Geometry, Region};
+
+    let page = u64::from(PAGE_LENGTH);
+    let bounds = Bounds {
+        geometry: Geometry::default(),
+        data_pages: 4..=0x17,
+        fat: 0x18 * page..0x1C * page,
+        mirror: None,
+    };
+    let magic = b"KFS\xFF";
+    assert!(bounds.check(4 * page, magic, Region::Magic).is_ok());
+    assert!(bounds.check(4 * page + 1, magic, Region::Magic).is_err());
+    // Block 0 holds headers, not data.
+    assert!(bounds.check(4 * page, &[0; 16], Region::Block).is_err());
+    assert!(bounds
+        .check(4 * page + 0x100, &[0xFF; 4], Region::SectionHeader)
+        .is_err());
+
+    let entry = Entry::File {
+        parent: 0,
+        flags: 0xFF,
+        length: 0,
+        section_id: KFS_NO_SECTION,
+        name: "a".to_string(),
+    };
+    let mut stored = entry.encode().unwrap();
+    stored.reverse();
+    let top = 0x1C * page - stored.len() as u64;
+    assert!(bounds.check(top, &stored, Region::Fat).is_ok());
+    assert!(bounds.check(top, &stored, Region::FatMirror).is_err());
+    // A length field one short of the name.
+    let last = stored.len() - 2;
+    stored[last] -= 1;
+    assert!(bounds.check(top, &stored, Region::Fat).is_err());
+}
+
+#[test]
+fn build_info_describes_the_image() {
+    let model = model_with_empty_dirs("build-info");
+    let options = Options {
+        build_info: true,
+        ..Options::default()
+    };
+    let rom = build(&model, options);
+    let source = regenkfs::source::Directory::new(&model, false);
+    let model_sha256 = regenkfs::build_info::model_sha256(&source).unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    assert!(listing(&rom).contains(&"/etc/build-info".to_string()));
+    let image = Image::open(&rom).unwrap();
+    let info = image
+        .nodes()
+        .unwrap()
+        .into_iter()
+        .find_map(|node| match node.record.entry {
+            Entry::File {
+                section_id, length, ..
+            } if node.path == "/etc/build-info" => {
+                Some(image.contents(section_id, length).unwrap())
+            }
+            _ => None,
+        })
+        .unwrap();
+    let info = String::from_utf8(info).unwrap();
+    let lines: Vec<&str> = info.lines().collect();
+    assert_eq!(
+        lines[0],
+        concat!("tool: regenkfs ", env!("CARGO_PKG_VERSION"))
+    );
+    assert!(lines[1].starts_with("built: "));
+    assert_eq!(
+        lines[2],
+        format!("model-sha256: {}", regenkfs::sha256::hex(&model_sha256))
+    );
+}
+
+#[test]
+fn integrity_page_catches_a_changed_byte() {
+    use regenkfs::integrity;
+
+    let model = model_with_empty_dirs("integrity-page");
+    let options = Options {
+        integrity_page: true,
+        ..Options::default()
+    };
+    let mut rom = build(&model, options);
+    fs::remove_dir_all(&model).unwrap();
+    let image = Image::open(&rom).unwrap();
+    let (geometry, dat_start, fat_start) = (image.geometry, image.dat_start, image.fat_start);
+    let page = integrity::check(&rom, geometry, dat_start, fat_start).unwrap();
+    assert_eq!(page, Some(fat_start - geometry.fat_pages()));
+    // A byte of /bin/init's data.
+    let at = rom.windows(4).position(|bytes| bytes == b"init").unwrap();
+    rom[at] ^= 1;
+    assert!(integrity::check(&rom, geometry, dat_start, fat_start).is_err());
+}
+
+#[test]
+fn compressed_files_read_back_whole() {
+    let model = model_dir("compress");
+    let zeros = vec![0; 5000];
+    let mixed: Vec<u8> = (0..3000u32).map(|i| (i / 10 % 7) as u8).collect();
+    fs::write(model.join("zeros"), &zeros).unwrap();
+    fs::write(model.join("mixed"), &mixed).unwrap();
+    fs::write(model.join("short"), b"ab").unwrap();
+    let options = Options {
+        compress: true,
+        ..Options::default()
+    };
+    // `build` verifies the files against the model too.
+    let rom = build(&model, options);
+    fs::remove_dir_all(&model).unwrap();
+    let image = Image::open(&rom).unwrap();
+    for node in image.nodes().unwrap() {
+        let (flags, length) = match node.record.entry {
+            Entry::File { flags, length, .. } => (flags, length),
+            _ => continue,
+        };
+        let data = image.read_file(&node.record.entry).unwrap();
+        let expected: &[u8] = match node.path.as_str() {
+            "/zeros" => &zeros,
+            "/mixed" => &mixed,
+            _ => b"ab",
+        };
+        assert_eq!(data, expected);
+        // Stored as is where compressing wouldn't help.
+        let compressed = regenkfs::has_flag(flags, regenkfs::KFS_FLAG_COMPRESSED);
+        assert_eq!(compressed, node.path != "/short", "{}", node.path);
+        assert_eq!(compressed, (length as usize) < data.len());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn validate_model_finds_broken_symlinks_and_case_clashes() {
+    use std::os::unix::fs::symlink;
+
+    let model = model_dir("validate");
+    for dir in &["bin", "etc", "lib"] {
+        fs::create_dir(model.join(dir)).unwrap();
+    }
+    fs::write(model.join("bin/init"), b"init").unwrap();
+    fs::write(model.join("etc/inittab"), b"").unwrap();
+    let clean = regenkfs::validate::validate(&model, &Options::default()).unwrap();
+    assert!(clean.iter().all(|check| check.passed()), "{:?}", clean);
+
+    fs::write(model.join("bin/INIT"), b"init").unwrap();
+    symlink("../nowhere", model.join("bin/dangling")).unwrap();
+    symlink("init", model.join("bin/ok")).unwrap();
+    symlink("b", model.join("etc/a")).unwrap();
+    symlink("a", model.join("etc/b")).unwrap();
+    let checks = regenkfs::validate::validate(&model, &Options::default()).unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    let problems = |name: &str| {
+        checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap()
+            .problems
+            .clone()
+    };
+    assert_eq!(
+        problems("symlinks"),
+        vec![
+            "/bin/dangling -> ../nowhere dangles.",
+            "/etc/a -> b loops.",
+            "/etc/b -> a loops.",
+        ]
+    );
+    assert_eq!(
+        problems("duplicates"),
+        vec!["/bin/INIT and /bin/init differ only in case."]
+    );
+    assert!(problems("layout").is_empty());
+}
+
+#[test]
+fn validate_model_only_warns_about_the_layout() {
+    let model = model_dir("validate-layout");
+    fs::write(model.join("motd"), b"hello").unwrap();
+    let checks = regenkfs::validate::validate(&model, &Options::default()).unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    let layout = checks.iter().find(|check| check.name == "layout").unwrap();
+    assert!(layout.warning && !layout.passed());
+    assert_eq!(regenkfs::validate::failures(&checks, false), 0);
+    assert_eq!(
+        regenkfs::validate::failures(&checks, true),
+        layout.problems.len()
+    );
+}
+
+#[test]
+fn a_model_that_doesnt_fit_leaves_the_rom_untouched() {
+    let small = model_dir("fits");
+    fs::write(small.join("motd"), b"hello").unwrap();
+    let big = model_dir("doesnt-fit");
+    fs::write(big.join("first"), b"written first").unwrap();
+    fs::write(big.join("huge"), vec![0x55; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+    let rom_path = env::temp_dir().join(format!("regenkfs-fit-{}.rom", std::process::id()));
+    fs::write(&rom_path, build(&small, Options::default())).unwrap();
+    let before = fs::read(&rom_path).unwrap();
+
+    let options = Options {
+        quiet: true,
+        ..Options::default()
+    };
+    let result = Context::with_options(&rom_path, &big, options).and_then(|mut c| c.run());
+    let after = fs::read(&rom_path).unwrap();
+    fs::remove_dir_all(&small).unwrap();
+    fs::remove_dir_all(&big).unwrap();
+    fs::remove_file(&rom_path).unwrap();
+    assert!(result.is_err());
+    assert!(before == after, "the ROM was written to");
+}
+
+#[test]
+fn a_failed_build_puts_the_rom_back() {
+    let model = model_dir("roll-back");
+    fs::write(model.join("motd"), b"hello").unwrap();
+    let rom_path = env::temp_dir().join(format!("regenkfs-roll-back-{}.rom", std::process::id()));
+    let before = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    fs::write(&rom_path, &before).unwrap();
+
+    // Fails only once the whole filesystem has been written.
+    let options = Options {
+        quiet: true,
+        expect_sha256: Some("00".to_string()),
+        ..Options::default()
+    };
+    let result = Context::with_options(&rom_path, &model, options).and_then(|mut c| c.run());
+    let after = fs::read(&rom_path).unwrap();
+    fs::remove_dir_all(&model).unwrap();
+    fs::remove_file(&rom_path).unwrap();
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("not the expected 00"));
+    assert!(before == after, "the ROM was left half written");
+}
+
+#[test]
+fn corrupt_flips_the_same_bits_for_the_same_seed() {
+    use regenkfs::corrupt::{self, Target};
+    use regenkfs::random::Rng;
+
+    let model = model_dir("corrupt");
+    fs::write(model.join("motd"), vec![b'x'; 1000]).unwrap();
+    let rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    let (layout, fat_start) = resolve_layout(&Options::default(), &rom, rom.len() as u64).unwrap();
+    for &target in &[Target::Fat, Target::Dat, Target::Headers] {
+        let regions = corrupt::regions(&rom, &layout, fat_start, target).unwrap();
+        let mut damaged = rom.clone();
+        let flips = corrupt::corrupt(
+            &mut damaged,
+            &layout,
+            fat_start,
+            target,
+            5,
+            &mut Rng::new(42),
+        )
+        .unwrap();
+        let mut again = rom.clone();
+        let same =
+            corrupt::corrupt(&mut again, &layout, fat_start, target, 5, &mut Rng::new(42)).unwrap();
+        assert_eq!(flips, same);
+        assert!(damaged == again);
+        assert_eq!(flips.len(), 5);
+        for flip in &flips {
+            assert!(regions.iter().any(|region| region.contains(&flip.offset)));
+            assert_ne!(rom[flip.offset as usize], damaged[flip.offset as usize]);
+        }
+        let changed: u32 = rom
+            .iter()
+            .zip(&damaged)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(changed, 5);
+    }
+}
+
+#[test]
+fn fixtures_are_the_same_for_the_same_seed() {
+    use regenkfs::fixture::{self, Shape};
+    use regenkfs::source::Memory;
+
+    let shape = Shape {
+        niche_cases: true,
+        ..Shape::default()
+    };
+    let model = fixture::generate(7, &shape).unwrap();
+    assert_eq!(model, fixture::generate(7, &shape).unwrap());
+    assert_ne!(model, fixture::generate(8, &shape).unwrap());
+
+    let mut context = Context::with_source(
+        PathBuf::from("<fixture>"),
+        Box::new(Memory::new("<fixture>", model)),
+        std::io::Cursor::new(vec![0xFF; 32 * usize::from(PAGE_LENGTH)]),
+        Options {
+            quiet: true,
+            verify: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    context.run().unwrap();
+    let rom = context.into_output().unwrap().into_inner();
+    let paths = listing(&rom);
+    assert_eq!(files(&rom).len(), 20 + 3);
+    assert!(paths.contains(&"/niche/dangling".to_string()));
+    assert!(paths.contains(&"/niche/empty-dir/".to_string()));
+}
+
+// Renames the entry `from` to `to`, of the same length, in the FAT of
+// `rom`, where entries are stored byte-reversed.
+fn rename_in_fat(rom: &mut [u8], from: &str, to: &str) {
+    let reversed = |name: &str| name.bytes().rev().collect::<Vec<u8>>();
+    let (from, to) = (reversed(from), reversed(to));
+    let at = rom
+        .windows(from.len())
+        .position(|bytes| bytes == &from[..])
+        .unwrap();
+    rom[at..at + to.len()].copy_from_slice(&to);
+}
+
+#[test]
+fn extract_refuses_absolute_names() {
+    use regenkfs::extract::{self, ExtractOptions};
+
+    // A root entry named with the absolute path of a file outside the
+    // directory extracted to.
+    let outside = env::temp_dir().join(format!("regenkfs-evil-{}", std::process::id()));
+    let outside = outside.to_str().unwrap().to_string();
+    let placeholder = outside.replace('/', "_");
+    let model = model_dir("absolute");
+    fs::write(model.join(&placeholder), b"evil").unwrap();
+    let mut rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    rename_in_fat(&mut rom, &placeholder, &outside);
+
+    let to = model_dir("absolute-to");
+    let image = Image::open(&rom).unwrap();
+    let options = ExtractOptions {
+        quiet: true,
+        ..ExtractOptions::default()
+    };
+    let result = extract::extract(&image, "/", &to, &options);
+    let escaped = Path::new(&outside).exists();
+    let _ = fs::remove_file(&outside);
+    fs::remove_dir_all(&to).unwrap();
+    assert!(result.is_err());
+    assert!(!escaped, "extracted to {}", outside);
+}
+
+#[cfg(unix)]
+#[test]
+fn extract_refuses_to_write_through_symlinks() {
+    use regenkfs::extract::{self, ExtractOptions};
+
+    let outside = model_dir("symlinked-outside");
+    // A symlink out of the directory extracted to, then a directory of
+    // the same name holding a file.
+    let model = model_dir("through-symlink");
+    std::os::unix::fs::symlink(&outside, model.join("linkq")).unwrap();
+    fs::create_dir(model.join("linkz")).unwrap();
+    fs::write(model.join("linkz/evil.bin"), b"evil").unwrap();
+    let mut rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    rename_in_fat(&mut rom, "linkz", "linkq");
+
+    let to = model_dir("through-symlink-to");
+    let image = Image::open(&rom).unwrap();
+    let options = ExtractOptions {
+        quiet: true,
+        ..ExtractOptions::default()
+    };
+    let result = extract::extract(&image, "/", &to, &options);
+    let escaped = outside.join("evil.bin").exists();
+    fs::remove_dir_all(&outside).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+    assert!(result.is_err());
+    assert!(!escaped, "extracted through the symlink");
+}
+
+#[test]
+fn format_keeps_to_the_layout() {
+    let model = model_dir("format-layout");
+    fs::write(model.join("motd"), b"hello").unwrap();
+    let layout_path = model.with_extension("toml");
+    fs::write(
+        &layout_path,
+        "dat_start = 0x06\nreserved = [0x08]\nmagic = \"all\"\n",
+    )
+    .unwrap();
+    let options = Options {
+        layout: Some(layout_path.clone()),
+        ..Options::default()
+    };
+    let mut rom = build(&model, options.clone());
+    fs::remove_dir_all(&model).unwrap();
+    let page = |page: usize| page * usize::from(PAGE_LENGTH);
+    rom[page(0x05)] = 0x12;
+    rom[page(0x08) + 0x10] = 0x34;
+
+    let (layout, fat_start) = resolve_layout(&options, &rom, rom.len() as u64).unwrap();
+    fs::remove_file(&layout_path).unwrap();
+    erase::format(&mut rom, &layout, fat_start, false).unwrap();
+    assert_eq!(rom[page(0x05)], 0x12);
+    assert_eq!(rom[page(0x08) + 0x10], 0x34);
+    assert_eq!(&rom[page(0x07)..page(0x07) + 3], b"KFS");
+    let image = Image::open_with(&rom, layout.geometry, 0x06, fat_start).unwrap();
+    assert!(image.records().is_empty());
+    // The reserved page is the only one taken.
+    let map = AllocationMap::scan_layout(&rom, &layout, fat_start).unwrap();
+    assert_eq!(map.used_blocks(), usize::from(layout.geometry.max_index()));
+}
+
+#[test]
+fn page_commands_follow_the_kernel_geometry_record() {
+    let mut rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let page = |page: usize| page * usize::from(PAGE_LENGTH);
+    let mut record = kernel::MAGIC.to_vec();
+    record.extend_from_slice(&[0, 0x05, 0x15, 1, 0x00, 0x40, 0x00, 0x01, 0xFF, 0xFF]);
+    rom[0x100..0x100 + record.len()].copy_from_slice(&record);
+    rom[page(0x04)] = 0x12;
+
+    let (layout, fat_start) = resolve_layout(&Options::default(), &rom, rom.len() as u64).unwrap();
+    assert_eq!((layout.dat_start, fat_start), (0x05, 0x15));
+    erase::format(&mut rom, &layout, fat_start, true).unwrap();
+    assert_eq!(rom[page(0x04)], 0x12);
+    assert_eq!(&rom[page(0x05)..page(0x05) + 3], b"KFS");
+    assert!(Image::open(&rom).unwrap().records().is_empty());
+    assert!(fat::compare_mirror(&rom, layout.geometry, fat_start)
+        .unwrap()
+        .is_empty());
+
+    let ignoring = Options {
+        ignore_kernel_geometry: true,
+        ..Options::default()
+    };
+    let (layout, _) = resolve_layout(&ignoring, &rom, rom.len() as u64).unwrap();
+    assert_eq!(layout.dat_start, 0x04);
+    let elsewhere = Options {
+        platform: Some(regenkfs::geometry::PLATFORMS[0]),
+        ..Options::default()
+    };
+    assert!(resolve_layout(&elsewhere, &rom, rom.len() as u64).is_err());
+}
+
+#[test]
+fn a_batch_refuses_targets_whose_outputs_collide() {
+    let model = model_dir("batch-collide");
+    fs::write(model.join("motd"), b"hello").unwrap();
+    let targets = [
+        model.join("a/ti84.rom"),
+        model.join("b/ti84.rom"),
+        model.join("b/ti83.rom"),
+    ];
+    let options = Options {
+        report: Some(model.join("{rom}.json")),
+        ..Options::default()
+    };
+    let err = regenkfs::batch::build_all(&targets, &model, &options).unwrap_err();
+    let distinct = regenkfs::batch::build_all(&targets[1..], &model, &options);
+    fs::remove_dir_all(&model).unwrap();
+    assert!(err.to_string().contains("would both write"), "{}", err);
+    // Builds that fail, with no ROMs to write, but get as far as trying.
+    assert!(distinct.unwrap().iter().all(|(_, result)| result.is_err()));
+}
+
+// An output that counts how often it's flushed, which is how often the
+// io_uring output submits what it has queued.
+struct Counting {
+    rom: std::io::Cursor<Vec<u8>>,
+    flushes: std::rc::Rc<std::cell::Cell<usize>>,
+}
+
+impl std::io::Read for Counting {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.rom.read(buf)
+    }
+}
+
+impl std::io::Write for Counting {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.rom.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes.set(self.flushes.get() + 1);
+        Ok(())
+    }
+}
+
+impl std::io::Seek for Counting {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.rom.seek(pos)
+    }
+}
+
+#[test]
+fn writes_are_flushed_together() {
+    let flushes = |files: usize| {
+        let model = model_dir(&format!("flushes-{}", files));
+        for i in 0..files {
+            fs::write(model.join(format!("file{}", i)), vec![b'x'; 300]).unwrap();
+        }
+        let count = std::rc::Rc::new(std::cell::Cell::new(0));
+        let rom = Counting {
+            rom: std::io::Cursor::new(vec![0xFF; 32 * usize::from(PAGE_LENGTH)]),
+            flushes: count.clone(),
+        };
+        let options = Options {
+            quiet: true,
+            ..Options::default()
+        };
+        let mut context = Context::with_output("counting".into(), &model, rom, options).unwrap();
+        context.run().unwrap();
+        let rom = context.into_output().unwrap().rom.into_inner();
+        fs::remove_dir_all(&model).unwrap();
+        assert_eq!(listing(&rom).len(), files);
+        count.get()
+    };
+    assert_eq!(flushes(2), flushes(40));
+}
+
+#[test]
+fn builds_are_the_same_on_any_number_of_threads() {
+    use std::num::NonZeroUsize;
+
+    let model = model_dir("threads");
+    fs::create_dir_all(model.join("bin")).unwrap();
+    for i in 0..30 {
+        fs::write(model.join(format!("bin/prog{}", i)), vec![i as u8; 50 * i]).unwrap();
+    }
+    fs::write(model.join("motd"), b"hello").unwrap();
+    let cache = model.with_extension("cache");
+    let build_with = |threads| {
+        let _ = fs::remove_file(&cache);
+        let options = Options {
+            threads: NonZeroUsize::new(threads),
+            cache: Some(cache.clone()),
+            ..Options::default()
+        };
+        let rom = build(&model, options.clone());
+        let first = fs::read(&cache).unwrap();
+        // Built again from the cache, every file hashes the same.
+        let mut context = Context::in_memory(
+            rom.clone(),
+            &model,
+            Options {
+                quiet: true,
+                ..options
+            },
+        )
+        .unwrap();
+        context.run().unwrap();
+        assert!(context.into_output().unwrap().into_inner() == rom);
+        (rom, first)
+    };
+    let (one, one_cache) = build_with(1);
+    for &threads in &[2, 8] {
+        let (rom, cache) = build_with(threads);
+        assert!(rom == one, "{} threads built a different ROM", threads);
+        assert_eq!(cache, one_cache);
+    }
+    assert_eq!(listing(&one).len(), 32);
+    fs::remove_dir_all(&model).unwrap();
+    let _ = fs::remove_file(&cache);
+}
+
+// Builds `model` into `rom` with the build cache at `cache`, returning
+// the new ROM and the data blocks written.
+fn rebuild(rom: Vec<u8>, model: &Path, cache: &Path) -> (Vec<u8>, Vec<std::ops::Range<u64>>) {
+    let options = Options {
+        quiet: true,
+        verify: true,
+        cache: Some(cache.to_path_buf()),
+        ..Options::default()
+    };
+    let mut context = Context::in_memory(rom, model, options).unwrap();
+    context.record_writes();
+    context.run().unwrap();
+    let blocks = context
+        .touched()
+        .into_iter()
+        .filter(|(region, _)| *region == regenkfs::Region::Block)
+        .map(|(_, range)| range)
+        .collect();
+    (context.into_output().unwrap().into_inner(), blocks)
+}
+
+#[test]
+fn the_cache_keeps_unchanged_files_and_rewrites_changed_ones() {
+    let model = model_dir("reuse");
+    fs::write(model.join("keep"), vec![b'k'; 1000]).unwrap();
+    fs::write(model.join("change"), vec![b'a'; 1000]).unwrap();
+    let cache = model.with_extension("cache");
+    let _ = fs::remove_file(&cache);
+    let (rom, written) = rebuild(vec![0xFF; 32 * usize::from(PAGE_LENGTH)], &model, &cache);
+    assert!(!written.is_empty());
+    let before = files(&rom);
+
+    // A hit: nothing changed, so no data is written again.
+    let (rom, written) = rebuild(rom, &model, &cache);
+    assert!(written.is_empty(), "rewrote {:x?}", written);
+    assert_eq!(files(&rom), before);
+
+    // The same size and new contents, with a new mtime as an edit gives.
+    let change = model.join("change");
+    fs::write(&change, vec![b'b'; 1000]).unwrap();
+    let file = fs::File::options().write(true).open(&change).unwrap();
+    file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+        .unwrap();
+    drop(file);
+    let (rom, written) = rebuild(rom, &model, &cache);
+    assert!(!written.is_empty(), "the changed file wasn't written");
+    let image = Image::open(&rom).unwrap();
+    let node = |path: &str| {
+        let nodes = image.nodes().unwrap();
+        let node = nodes.into_iter().find(|node| node.path == path).unwrap();
+        image.read_file(&node.record.entry).unwrap()
+    };
+    assert_eq!(node("/change"), vec![b'b'; 1000]);
+    assert_eq!(node("/keep"), vec![b'k'; 1000]);
+    let keep = |files: &[(String, u32, u16)]| files.iter().find(|f| f.0 == "/keep").cloned();
+    assert_eq!(keep(&files(&rom)), keep(&before), "/keep moved");
+
+    fs::remove_dir_all(&model).unwrap();
+    let _ = fs::remove_file(&cache);
+}
+
+#[test]
+fn the_cache_finds_files_by_their_contents() {
+    let model = model_dir("rehash");
+    fs::write(model.join("touch"), vec![b't'; 1000]).unwrap();
+    fs::write(model.join("keep"), vec![b'k'; 1000]).unwrap();
+    let cache = model.with_extension("cache");
+    let _ = fs::remove_file(&cache);
+    let (rom, _) = rebuild(vec![0xFF; 32 * usize::from(PAGE_LENGTH)], &model, &cache);
+    let before = files(&rom);
+    let find = |files: &[(String, u32, u16)], path: &str| {
+        files.iter().find(|f| f.0 == path).cloned().unwrap()
+    };
+
+    // Touched but the same: the mtime misses and the hash hits.
+    let file = fs::File::options()
+        .write(true)
+        .open(model.join("touch"))
+        .unwrap();
+    file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+        .unwrap();
+    drop(file);
+    let (rom, written) = rebuild(rom, &model, &cache);
+    assert!(written.is_empty(), "rewrote {:x?}", written);
+    assert_eq!(find(&files(&rom), "/touch"), find(&before, "/touch"));
+
+    // A miss for a new file; a renamed one takes its old data.
+    fs::rename(model.join("keep"), model.join("kept")).unwrap();
+    fs::write(model.join("new"), vec![b'n'; 300]).unwrap();
+    let (rom, written) = rebuild(rom, &model, &cache);
+    let written: u64 = written.iter().map(|range| range.end - range.start).sum();
+    assert_eq!(written, 300, "only /new is written");
+    assert_eq!(find(&files(&rom), "/kept").2, find(&before, "/keep").2);
+
+    fs::remove_dir_all(&model).unwrap();
+    let _ = fs::remove_file(&cache);
+}
@@ -0,0 +1,167 @@
+//! Runs the `regenkfs` binary, for what only its argument parsing decides.
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use regenkfs::{Image, PAGE_LENGTH};
+
+#[test]
+fn builds_into_roms_named_like_subcommands() {
+    let dir = env::temp_dir().join(format!("regenkfs-cli-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("model")).unwrap();
+    fs::write(dir.join("model/motd"), b"hello").unwrap();
+    for (rom, name) in &[
+        ("blank.rom", "blank.rom"),
+        ("verify.rom", "./verify.rom"),
+        ("find", "find"),
+    ] {
+        fs::write(dir.join(rom), vec![0xFF; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_regenkfs"))
+            .current_dir(&dir)
+            .args(["--quiet", name, "model"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "building into {} failed:\n{}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let rom = fs::read(dir.join(rom)).unwrap();
+        let nodes = Image::open(&rom).unwrap().nodes().unwrap();
+        assert_eq!(nodes[0].path, "/motd");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn lists_what_it_built_with_the_same_layout() {
+    let dir = env::temp_dir().join(format!("regenkfs-cli-layout-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("model/etc")).unwrap();
+    fs::write(dir.join("model/etc/motd"), b"hello").unwrap();
+    fs::write(dir.join("layout.toml"), "dat_start = 0x08\n").unwrap();
+    fs::write(dir.join("a.rom"), vec![0xFF; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+    let regenkfs = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_regenkfs"))
+            .current_dir(&dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "regenkfs {} failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+    regenkfs(&["--quiet", "--layout", "layout.toml", "a.rom", "model"]);
+    let rom = fs::read(dir.join("a.rom")).unwrap();
+    assert!(Image::open(&rom).is_err(), "page 04 was left erased");
+    let listing = regenkfs(&["--layout", "layout.toml", "ls", "a.rom"]);
+    assert_eq!(listing, "/etc/\n/etc/motd\n");
+    let found = regenkfs(&["--layout", "layout.toml", "find", "a.rom", "*motd"]);
+    assert!(found.contains("/etc/motd"), "{}", found);
+    regenkfs(&["--layout", "layout.toml", "fsck", "a.rom"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn edits_a_rom_in_place_as_a_build_would() {
+    let dir = env::temp_dir().join(format!("regenkfs-cli-edit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let rom = dir.join("a.rom");
+    fs::write(&rom, vec![0xFF; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+    let format = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_regenkfs"))
+            .args(args)
+            .arg("format")
+            .arg(&rom)
+            .output()
+            .unwrap()
+    };
+
+    let held = fs::File::open(&rom).unwrap();
+    held.try_lock().unwrap();
+    let output = format(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is locked by another process"));
+    drop(held);
+
+    let mut permissions = fs::metadata(&rom).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&rom, permissions).unwrap();
+    // Root can write to a read-only file anyway.
+    if fs::OpenOptions::new().write(true).open(&rom).is_err() {
+        let output = format(&[]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("pass --chmod"));
+    }
+    let output = format(&["--chmod"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(fs::metadata(&rom).unwrap().permissions().readonly());
+    let image = fs::read(&rom).unwrap();
+    assert!(Image::open(&image).unwrap().nodes().unwrap().is_empty());
+
+    let mut permissions = fs::metadata(&rom).unwrap().permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(&rom, permissions).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn still_suggests_subcommands_for_typos() {
+    let output = Command::new(env!("CARGO_BIN_EXE_regenkfs"))
+        .args(["lss", "missing.rom"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Did you mean 'ls'?"));
+}
+
+#[test]
+fn prints_warnings_and_errors_as_json() {
+    let dir = env::temp_dir().join(format!("regenkfs-cli-json-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("model")).unwrap();
+    fs::write(dir.join("model/a"), b"a").unwrap();
+    fs::write(dir.join("model/b"), b"b").unwrap();
+    fs::write(dir.join("a.rom"), vec![0xFF; 32 * usize::from(PAGE_LENGTH)]).unwrap();
+    let regenkfs = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_regenkfs"))
+            .current_dir(&dir)
+            .args(["--quiet", "--errors-json"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = regenkfs(&["--lint", "a.rom", "model"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.is_empty());
+    for line in stderr.lines() {
+        assert!(
+            line.starts_with("{\"severity\":\"warning\",\"code\":\"lint\",\"path\":null,"),
+            "{}",
+            line
+        );
+    }
+
+    let output = regenkfs(&["--max-files", "1", "a.rom", "model"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"severity\":\"error\",\"code\":\"invalid-input\",\"path\":null,\
+         \"message\":\"The model holds more than 1 files.\"}\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
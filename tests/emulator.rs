@@ -0,0 +1,85 @@
+//! End-to-end check of the writer: boots a generated ROM in a headless
+//! emulator and compares the filesystem it reports against the model.
+//!
+//! This needs a kernel and an emulator, so it only runs when configured:
+//!
+//! - `REGENKFS_KERNEL`: a ROM image holding a KnightOS kernel.
+//! - `REGENKFS_EMULATOR`: a shell command booting `{rom}` (e.g. in z80e
+//!   with a program that walks the filesystem) and printing every path it
+//!   finds, one per line, in the format of `regenkfs ls`: `/dir/`,
+//!   `/file`, `/link -> target`.
+//! - `REGENKFS_MODEL` (optional): the model to write, defaulting to
+//!   `tests/fixtures/basic`.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regenkfs::{Context, Options};
+
+// What `regenkfs ls` would print for the model, in sorted order.
+fn expected_listing(model: &Path, prefix: &str, out: &mut Vec<String>) {
+    let mut entries: Vec<_> = fs::read_dir(model)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = format!("{}/{}", prefix, entry.file_name().to_str().unwrap());
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap();
+            out.push(format!("{} -> {}", path, target.display()));
+        } else if file_type.is_dir() {
+            out.push(format!("{}/", path));
+            expected_listing(&entry.path(), &path, out);
+        } else {
+            out.push(path);
+        }
+    }
+}
+
+#[test]
+fn emulator_sees_the_model() {
+    let (kernel, emulator) = match (env::var("REGENKFS_KERNEL"), env::var("REGENKFS_EMULATOR")) {
+        (Ok(kernel), Ok(emulator)) => (kernel, emulator),
+        _ => {
+            eprintln!("Skipping: set REGENKFS_KERNEL and REGENKFS_EMULATOR to run.");
+            return;
+        }
+    };
+    let model = env::var("REGENKFS_MODEL")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/basic"));
+
+    let rom = env::temp_dir().join(format!("regenkfs-emulator-{}.rom", std::process::id()));
+    fs::copy(&kernel, &rom).unwrap();
+    let options = Options {
+        quiet: true,
+        ..Options::default()
+    };
+    Context::with_options(&rom, &model, options)
+        .and_then(|mut c| c.run())
+        .unwrap();
+
+    let command = emulator.replace("{rom}", &rom.display().to_string());
+    let output = Command::new("sh").arg("-c").arg(&command).output().unwrap();
+    fs::remove_file(&rom).unwrap();
+    assert!(
+        output.status.success(),
+        "{} failed:\n{}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let mut seen: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    seen.sort();
+    let mut expected = Vec::new();
+    expected_listing(&model, "", &mut expected);
+    expected.sort();
+    assert_eq!(seen, expected);
+}
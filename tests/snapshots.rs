@@ -0,0 +1,137 @@
+//! Snapshots of the exact FAT bytes and section headers written for a
+//! few models, so any change to the encoding shows up in review.
+//!
+//! Each snapshot lives in `tests/snapshots/<name>.snap`. A test whose
+//! output differs writes it next to the snapshot as `<name>.snap.new`
+//! and fails; run with `REGENKFS_BLESS=1` to accept the new output.
+//! This is the part of `insta` these tests need, kept in a few lines
+//! so the crate still builds with nothing beyond structopt, even for
+//! its tests.
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regenkfs::{AllocationMap, Context, Geometry, Options, SectionId, PAGE_LENGTH};
+
+fn model_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("regenkfs-snap-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn build(model: &Path, options: Options) -> Vec<u8> {
+    let rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let options = Options {
+        quiet: true,
+        verify: true,
+        ..options
+    };
+    let mut context = Context::in_memory(rom, model, options).unwrap();
+    context.run().unwrap();
+    context.into_output().unwrap().into_inner()
+}
+
+// `rom[from..to]` under a heading, 16 bytes a line.
+fn hexdump(out: &mut String, heading: &str, rom: &[u8], from: u32, to: u32) {
+    writeln!(out, "{} {:#07x}..{:#07x}", heading, from, to).unwrap();
+    let bytes = &rom[from as usize..to as usize];
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(out, "{:06x}  {}", from as usize + 16 * i, bytes.join(" ")).unwrap();
+    }
+}
+
+// The FAT from its lowest entry up, then with `mirror` the same span of
+// its copy a whole FAT below, then the pSID and nSID of every section in
+// use.
+fn dump(rom: &[u8], mirror: bool) -> String {
+    let geometry = Geometry::default();
+    let fat_start = regenkfs::fat_start_for(rom.len() as u64).unwrap();
+    let map = AllocationMap::scan_with(rom, geometry, 0x04, fat_start).unwrap();
+    let (from, to) = (map.fat_ptr(), geometry.fat_end(fat_start));
+    let mut out = String::new();
+    hexdump(&mut out, "FAT", rom, from, to);
+    if mirror {
+        let length = geometry.fat_length();
+        hexdump(&mut out, "Mirror", rom, from - length, to - length);
+    }
+    writeln!(out, "Sections").unwrap();
+    for page in map.pages() {
+        for index in 1..=geometry.max_index() {
+            let id = SectionId::new(page, index).unwrap();
+            if !map.is_used(id.into()) {
+                continue;
+            }
+            let header = geometry.header_offset(id) as usize;
+            let psid = u16::from_le_bytes([rom[header], rom[header + 1]]);
+            let nsid = u16::from_le_bytes([rom[header + 2], rom[header + 3]]);
+            writeln!(
+                out,
+                "{:04x}  prev {:04x} next {:04x}",
+                u16::from(id),
+                psid,
+                nsid
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+fn assert_snapshot(name: &str, actual: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+    let path = dir.join(format!("{}.snap", name));
+    let new = dir.join(format!("{}.snap.new", name));
+    if env::var_os("REGENKFS_BLESS").is_some() {
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, actual).unwrap();
+        let _ = fs::remove_file(&new);
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    if expected != actual {
+        fs::write(&new, actual).unwrap();
+        panic!(
+            "{} no longer matches; compare it with {}, and rerun with REGENKFS_BLESS=1 to accept it.",
+            path.display(),
+            new.display()
+        );
+    }
+    let _ = fs::remove_file(&new);
+}
+
+#[test]
+fn basic_fixture() {
+    let model = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/basic");
+    assert_snapshot("basic", &dump(&build(&model, Options::default()), false));
+}
+
+#[test]
+fn basic_fixture_mirrored() {
+    let model = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/basic");
+    let options = Options {
+        mirror_fat: true,
+        ..Options::default()
+    };
+    let rom = build(&model, options);
+    assert_snapshot("basic_mirrored", &dump(&rom, true));
+}
+
+#[test]
+fn chained_and_empty_files() {
+    let model = model_dir("chained");
+    // Three sections, the last one partly used.
+    fs::write(
+        model.join("big"),
+        (0..600).map(|i| i as u8).collect::<Vec<_>>(),
+    )
+    .unwrap();
+    fs::write(model.join("empty"), b"").unwrap();
+    fs::create_dir_all(model.join("dir")).unwrap();
+    fs::write(model.join("dir/small"), b"small").unwrap();
+    let rom = build(&model, Options::default());
+    fs::remove_dir_all(&model).unwrap();
+    assert_snapshot("chained", &dump(&rom, false));
+}
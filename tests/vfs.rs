@@ -0,0 +1,165 @@
+//! Builds models held in a `Vfs`, for trees that are awkward or
+//! impossible to make on the host.
+use std::io::{Cursor, Error};
+use std::path::PathBuf;
+
+use regenkfs::fat::Entry;
+use regenkfs::source::Vfs;
+use regenkfs::{Context, Image, Options, PAGE_LENGTH};
+
+// Writes `vfs` into an erased 32-page ROM, verifying it on the way.
+fn try_build(vfs: Vfs, options: Options) -> Result<Vec<u8>, Error> {
+    let rom = vec![0xFF; 32 * usize::from(PAGE_LENGTH)];
+    let options = Options {
+        quiet: true,
+        verify: true,
+        ..options
+    };
+    let mut context = Context::with_source(
+        PathBuf::from("<vfs>"),
+        Box::new(vfs),
+        Cursor::new(rom),
+        options,
+    )?;
+    context.run()?;
+    Ok(context.into_output()?.into_inner())
+}
+
+fn build(vfs: Vfs, options: Options) -> Vec<u8> {
+    try_build(vfs, options).unwrap()
+}
+
+// Every path in the image, with a trailing `/` for directories and the
+// target of symlinks.
+fn listing(rom: &[u8]) -> Vec<String> {
+    Image::open(rom)
+        .unwrap()
+        .nodes()
+        .unwrap()
+        .into_iter()
+        .map(|node| match node.record.entry {
+            Entry::Directory { .. } => format!("{}/", node.path),
+            Entry::Symlink { target, .. } => format!("{} -> {}", node.path, target),
+            _ => node.path,
+        })
+        .collect()
+}
+
+#[test]
+fn deep_nesting_round_trips() {
+    let path = vec!["d"; 100].join("/");
+    let mut vfs = Vfs::new();
+    vfs.file(&format!("{}/leaf", path), b"leaf");
+    let rom = build(vfs, Options::default());
+    let listing = listing(&rom);
+    assert_eq!(listing.len(), 101);
+    assert_eq!(listing[100], format!("/{}/leaf", path));
+}
+
+#[test]
+fn unusual_names_round_trip() {
+    let long = "x".repeat(200);
+    let mut vfs = Vfs::new();
+    vfs.file("with space", b"1")
+        .file("-leading-dash", b"2")
+        .file("ünïcødé ✓", b"3")
+        .file(&long, b"4");
+    let rom = build(vfs, Options::default());
+    assert_eq!(
+        listing(&rom),
+        [
+            "/-leading-dash".to_string(),
+            "/with space".to_string(),
+            format!("/{}", long),
+            "/ünïcødé ✓".to_string(),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn names_that_are_not_utf8_fail() {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut vfs = Vfs::new();
+    vfs.file_named("bin", OsString::from_vec(b"bad\xFF".to_vec()), b"");
+    let err = try_build(vfs, Options::default()).unwrap_err();
+    assert!(err.to_string().contains("valid UTF-8"), "{}", err);
+}
+
+fn looping() -> Vfs {
+    let mut vfs = Vfs::new();
+    vfs.file("a/file", b"file").symlink("a/up", "..");
+    vfs
+}
+
+#[test]
+fn symlink_loops_are_stored_as_links() {
+    let rom = build(looping(), Options::default());
+    assert_eq!(listing(&rom), ["/a/", "/a/file", "/a/up -> .."]);
+}
+
+#[test]
+fn followed_symlink_loops_fail() {
+    let options = Options {
+        follow_dir_symlinks: true,
+        ..Options::default()
+    };
+    let vfs = looping().follow_dir_symlinks(true);
+    let err = try_build(vfs, options).unwrap_err();
+    assert!(err.to_string().contains("loops back"), "{}", err);
+}
+
+#[test]
+fn followed_symlinks_copy_their_directory() {
+    let options = Options {
+        follow_dir_symlinks: true,
+        ..Options::default()
+    };
+    let mut vfs = Vfs::new().follow_dir_symlinks(true);
+    vfs.file("lib/libc", b"c").symlink("usr/lib", "/lib");
+    let rom = build(vfs, options);
+    assert_eq!(
+        listing(&rom),
+        ["/lib/", "/lib/libc", "/usr/", "/usr/lib/", "/usr/lib/libc"]
+    );
+}
+
+#[test]
+fn keep_going_leaves_out_unreadable_entries() {
+    let mut vfs = Vfs::new();
+    vfs.file("ok", b"ok")
+        .unreadable("secret", false)
+        .unreadable("private", true);
+    let options = Options {
+        keep_going: true,
+        ..Options::default()
+    };
+    let err = try_build(vfs.clone(), options).unwrap_err();
+    assert!(err.to_string().contains("2 model entries"), "{}", err);
+    assert!(try_build(vfs, Options::default()).is_err());
+}
+
+#[test]
+fn special_files_fail() {
+    let mut vfs = Vfs::new();
+    vfs.special("dev/fifo");
+    assert!(try_build(vfs, Options::default()).is_err());
+}
+
+#[test]
+fn hidden_and_empty_entries_are_left_out() {
+    let mut vfs = Vfs::new();
+    vfs.file("bin/init", b"init")
+        .file(".git/HEAD", b"ref")
+        .dir("empty/inner")
+        .file("only-hidden/.keep", b"");
+    let options = Options {
+        hidden: "exclude".parse().unwrap(),
+        prune_empty: true,
+        ..Options::default()
+    };
+    let rom = build(vfs, options);
+    assert_eq!(listing(&rom), ["/bin/", "/bin/init"]);
+}